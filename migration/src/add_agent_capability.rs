@@ -0,0 +1,84 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_physical_agent::PhysicalAgent;
+use crate::create_task_active::TaskActive;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentCapability {
+    Simulator,
+    Gates,
+    Noise,
+    MaxShots,
+    MemoryMb,
+    Requirements,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .add_column(
+                        ColumnDef::new(AgentCapability::Simulator)
+                            .string()
+                            .not_null()
+                            .default("statevector"),
+                    )
+                    .add_column(ColumnDef::new(AgentCapability::Gates).json_binary().null())
+                    .add_column(
+                        ColumnDef::new(AgentCapability::Noise)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .add_column(ColumnDef::new(AgentCapability::MaxShots).integer().null())
+                    .add_column(
+                        ColumnDef::new(AgentCapability::MemoryMb)
+                            .big_integer()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .add_column(
+                        ColumnDef::new(AgentCapability::Requirements)
+                            .json_binary()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .drop_column(AgentCapability::Requirements)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .drop_column(AgentCapability::Simulator)
+                    .drop_column(AgentCapability::Gates)
+                    .drop_column(AgentCapability::Noise)
+                    .drop_column(AgentCapability::MaxShots)
+                    .drop_column(AgentCapability::MemoryMb)
+                    .to_owned(),
+            )
+            .await
+    }
+}
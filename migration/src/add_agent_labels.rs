@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_physical_agent::PhysicalAgent;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentLabels {
+    Labels,
+    Pool,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .add_column(ColumnDef::new(AgentLabels::Labels).json_binary().null())
+                    .add_column(ColumnDef::new(AgentLabels::Pool).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_physical_agent_pool")
+                    .table(PhysicalAgent::Table)
+                    .col(AgentLabels::Pool)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_physical_agent_pool")
+                    .table(PhysicalAgent::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .drop_column(AgentLabels::Labels)
+                    .drop_column(AgentLabels::Pool)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,36 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_physical_agent::PhysicalAgent;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentLostTime {
+    LostTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .add_column(ColumnDef::new(AgentLostTime::LostTime).timestamp().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .drop_column(AgentLostTime::LostTime)
+                    .to_owned(),
+            )
+            .await
+    }
+}
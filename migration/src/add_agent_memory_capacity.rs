@@ -0,0 +1,73 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_capacity_reservation::CapacityReservation;
+use crate::create_physical_agent::PhysicalAgent;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentMemoryCapacity {
+    MemoryIdleMb,
+    Slots,
+    SlotsIdle,
+    MemoryMb,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .add_column(
+                        ColumnDef::new(AgentMemoryCapacity::MemoryIdleMb)
+                            .big_integer()
+                            .null(),
+                    )
+                    .add_column(ColumnDef::new(AgentMemoryCapacity::Slots).integer().null())
+                    .add_column(
+                        ColumnDef::new(AgentMemoryCapacity::SlotsIdle)
+                            .integer()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CapacityReservation::Table)
+                    .add_column(
+                        ColumnDef::new(AgentMemoryCapacity::MemoryMb)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PhysicalAgent::Table)
+                    .drop_column(AgentMemoryCapacity::MemoryIdleMb)
+                    .drop_column(AgentMemoryCapacity::Slots)
+                    .drop_column(AgentMemoryCapacity::SlotsIdle)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CapacityReservation::Table)
+                    .drop_column(AgentMemoryCapacity::MemoryMb)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,57 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_task_assignment::TaskAssignment;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AssignmentTime {
+    CreatedTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskAssignment::Table)
+                    .add_column(
+                        ColumnDef::new(AssignmentTime::CreatedTime)
+                            .timestamp()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_task_assignment_agent_id")
+                    .table(TaskAssignment::Table)
+                    .col(TaskAssignment::AgentId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_task_assignment_agent_id")
+                    .table(TaskAssignment::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskAssignment::Table)
+                    .drop_column(AssignmentTime::CreatedTime)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_circuit::Circuit;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum CircuitDeletedTime {
+    DeletedTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Circuit::Table)
+                    .add_column(
+                        ColumnDef::new(CircuitDeletedTime::DeletedTime)
+                            .timestamp()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Circuit::Table)
+                    .drop_column(CircuitDeletedTime::DeletedTime)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,36 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_agent_drain::AgentDrain;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum DrainAction {
+    Action,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AgentDrain::Table)
+                    .add_column(ColumnDef::new(DrainAction::Action).json_binary().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AgentDrain::Table)
+                    .drop_column(DrainAction::Action)
+                    .to_owned(),
+            )
+            .await
+    }
+}
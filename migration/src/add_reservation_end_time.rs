@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_capacity_reservation::CapacityReservation;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum ReservationEndTime {
+    ExpectedEndTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CapacityReservation::Table)
+                    .add_column(
+                        ColumnDef::new(ReservationEndTime::ExpectedEndTime)
+                            .timestamp()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CapacityReservation::Table)
+                    .drop_column(ReservationEndTime::ExpectedEndTime)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,53 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_task::Task;
+use crate::create_task_active::TaskActive;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum TaskCircuit {
+    Circuit,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .add_column(ColumnDef::new(TaskCircuit::Circuit).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Task::Table)
+                    .add_column(ColumnDef::new(TaskCircuit::Circuit).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .drop_column(TaskCircuit::Circuit)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Task::Table)
+                    .drop_column(TaskCircuit::Circuit)
+                    .to_owned(),
+            )
+            .await
+    }
+}
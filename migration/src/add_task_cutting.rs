@@ -0,0 +1,67 @@
+use sea_orm_migration::prelude::*;
+
+use crate::create_task::Task;
+use crate::create_task_active::TaskActive;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum TaskCutting {
+    ParentId,
+    Cutting,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .add_column(ColumnDef::new(TaskCutting::ParentId).uuid().null())
+                    .add_column(ColumnDef::new(TaskCutting::Cutting).json_binary().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Task::Table)
+                    .add_column(ColumnDef::new(TaskCutting::ParentId).uuid().null())
+                    .add_column(ColumnDef::new(TaskCutting::Cutting).json_binary().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_task_parent_id")
+                    .table(Task::Table)
+                    .col(TaskCutting::ParentId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .drop_column(TaskCutting::ParentId)
+                    .drop_column(TaskCutting::Cutting)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Task::Table)
+                    .drop_column(TaskCutting::ParentId)
+                    .drop_column(TaskCutting::Cutting)
+                    .to_owned(),
+            )
+            .await
+    }
+}
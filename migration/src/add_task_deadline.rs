@@ -0,0 +1,48 @@
+use sea_orm_migration::{prelude::*, sea_query::extension::postgres::Type};
+
+use crate::create_task::TaskStatus;
+use crate::create_task_active::TaskActive;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum TaskDeadline {
+    Deadline,
+    Expired,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_type(
+                Type::alter()
+                    .name(TaskStatus::Table)
+                    .add_value(TaskDeadline::Expired)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .add_column(ColumnDef::new(TaskDeadline::Deadline).timestamp().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // postgres can not drop a value of an enum type, the `expired` value
+        // of `task_status` is kept
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(TaskActive::Table)
+                    .drop_column(TaskDeadline::Deadline)
+                    .to_owned(),
+            )
+            .await
+    }
+}
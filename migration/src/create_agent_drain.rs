@@ -0,0 +1,101 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{EnumIter, Iterable},
+    sea_query::extension::postgres::Type,
+};
+
+use crate::create_physical_agent::PhysicalAgentStatus;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentDrain {
+    Table,
+    Id,
+    AgentId,
+    Status,
+    CreatedTime,
+    Deadline,
+    FinishedTime,
+}
+
+#[derive(DeriveIden, EnumIter)]
+pub enum DrainStatus {
+    Table,
+    Draining,
+    Drained,
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(DeriveIden)]
+pub enum AgentDraining {
+    Draining,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_type(
+                Type::alter()
+                    .name(PhysicalAgentStatus::Table)
+                    .add_value(AgentDraining::Draining)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_type(
+                Type::create()
+                    .as_enum(DrainStatus::Table)
+                    .values(DrainStatus::iter().skip(1))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(AgentDrain::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AgentDrain::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(AgentDrain::AgentId).uuid().not_null())
+                    .col(
+                        ColumnDef::new(AgentDrain::Status)
+                            .enumeration(DrainStatus::Table, DrainStatus::iter().skip(1))
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentDrain::CreatedTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AgentDrain::Deadline).timestamp().not_null())
+                    .col(ColumnDef::new(AgentDrain::FinishedTime).timestamp().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // postgres can not drop a value of an enum type, the `draining` value
+        // of `physical_agent_status` is kept
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(AgentDrain::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_type(Type::drop().name(DrainStatus::Table).if_exists().to_owned())
+            .await
+    }
+}
@@ -0,0 +1,89 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum AgentReservation {
+    Table,
+    Id,
+    Username,
+    Agents,
+    Pool,
+    StartTime,
+    EndTime,
+    CreatedTime,
+    CancelledTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AgentReservation::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AgentReservation::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentReservation::Username)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentReservation::Agents)
+                            .json_binary()
+                            .null(),
+                    )
+                    .col(ColumnDef::new(AgentReservation::Pool).string().null())
+                    .col(
+                        ColumnDef::new(AgentReservation::StartTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentReservation::EndTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentReservation::CreatedTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AgentReservation::CancelledTime)
+                            .timestamp()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_agent_reservation_end_time")
+                    .table(AgentReservation::Table)
+                    .col(AgentReservation::EndTime)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(AgentReservation::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+}
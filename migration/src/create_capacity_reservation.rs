@@ -0,0 +1,75 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum CapacityReservation {
+    Table,
+    Id,
+    AgentId,
+    TaskId,
+    Qubits,
+    CreatedTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(CapacityReservation::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(CapacityReservation::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(CapacityReservation::AgentId)
+                            .uuid()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(CapacityReservation::TaskId)
+                            .uuid()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(CapacityReservation::Qubits)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(CapacityReservation::CreatedTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_capacity_reservation_agent_id")
+                    .table(CapacityReservation::Table)
+                    .col(CapacityReservation::AgentId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(CapacityReservation::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+}
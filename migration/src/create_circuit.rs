@@ -0,0 +1,60 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum Circuit {
+    Table,
+    Id,
+    Name,
+    Version,
+    Source,
+    Description,
+    Metadata,
+    CreatedTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Circuit::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Circuit::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(Circuit::Name).string().not_null())
+                    .col(ColumnDef::new(Circuit::Version).integer().not_null())
+                    .col(ColumnDef::new(Circuit::Source).string().not_null())
+                    .col(ColumnDef::new(Circuit::Description).string().null())
+                    .col(ColumnDef::new(Circuit::Metadata).json_binary().null())
+                    .col(ColumnDef::new(Circuit::CreatedTime).timestamp().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_circuit_name_version")
+                    .table(Circuit::Table)
+                    .col(Circuit::Name)
+                    .col(Circuit::Version)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Circuit::Table).if_exists().to_owned())
+            .await
+    }
+}
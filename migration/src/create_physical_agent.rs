@@ -20,7 +20,7 @@ pub enum PhysicalAgent {
 }
 
 #[derive(DeriveIden, EnumIter)]
-enum PhysicalAgentStatus {
+pub enum PhysicalAgentStatus {
     Table,
     Down,
     Running,
@@ -0,0 +1,89 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[derive(DeriveIden)]
+pub enum UsageRecord {
+    Table,
+    Id,
+    AssignmentId,
+    TaskId,
+    AgentId,
+    Username,
+    Shots,
+    Qubits,
+    Depth,
+    WallTimeMs,
+    StartedTime,
+    FinishedTime,
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UsageRecord::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UsageRecord::Id)
+                            .uuid()
+                            .not_null()
+                            .primary_key()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(UsageRecord::AssignmentId)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(UsageRecord::TaskId).uuid().not_null())
+                    .col(ColumnDef::new(UsageRecord::AgentId).uuid().not_null())
+                    .col(ColumnDef::new(UsageRecord::Username).string().not_null())
+                    .col(ColumnDef::new(UsageRecord::Shots).integer().not_null())
+                    .col(ColumnDef::new(UsageRecord::Qubits).integer().not_null())
+                    .col(ColumnDef::new(UsageRecord::Depth).integer().not_null())
+                    .col(
+                        ColumnDef::new(UsageRecord::WallTimeMs)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UsageRecord::StartedTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UsageRecord::FinishedTime)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_usage_record_username_finished_time")
+                    .table(UsageRecord::Table)
+                    .col(UsageRecord::Username)
+                    .col(UsageRecord::FinishedTime)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(
+                Table::drop()
+                    .table(UsageRecord::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+}
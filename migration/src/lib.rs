@@ -1,9 +1,32 @@
 pub use sea_orm_migration::prelude::*;
 
+mod add_agent_capability;
+mod add_agent_discovery;
+mod add_agent_labels;
+mod add_agent_lease;
+mod add_agent_lost_time;
+mod add_agent_memory_capacity;
+mod add_agent_qasm3;
+mod add_assignment_time;
+mod add_circuit_deleted_time;
+mod add_drain_action;
+mod add_reservation_end_time;
+mod add_task_circuit;
+mod add_task_cutting;
+mod add_task_deadline;
+mod add_task_noise;
+mod add_task_parameters;
+mod add_task_source_hash;
+mod add_task_username;
+mod create_agent_drain;
+mod create_agent_reservation;
+mod create_capacity_reservation;
+mod create_circuit;
 mod create_physical_agent;
 mod create_task;
 mod create_task_active;
 mod create_task_assignment;
+mod create_usage_record;
 
 pub struct Migrator;
 
@@ -15,6 +38,29 @@ impl MigratorTrait for Migrator {
             Box::new(create_task::Migration),
             Box::new(create_task_active::Migration),
             Box::new(create_task_assignment::Migration),
+            Box::new(add_task_username::Migration),
+            Box::new(create_usage_record::Migration),
+            Box::new(add_task_deadline::Migration),
+            Box::new(add_agent_lease::Migration),
+            Box::new(add_agent_discovery::Migration),
+            Box::new(add_agent_capability::Migration),
+            Box::new(add_task_noise::Migration),
+            Box::new(add_task_source_hash::Migration),
+            Box::new(create_circuit::Migration),
+            Box::new(add_task_circuit::Migration),
+            Box::new(add_task_parameters::Migration),
+            Box::new(add_agent_qasm3::Migration),
+            Box::new(add_task_cutting::Migration),
+            Box::new(create_capacity_reservation::Migration),
+            Box::new(add_agent_memory_capacity::Migration),
+            Box::new(add_reservation_end_time::Migration),
+            Box::new(create_agent_drain::Migration),
+            Box::new(add_assignment_time::Migration),
+            Box::new(add_agent_labels::Migration),
+            Box::new(create_agent_reservation::Migration),
+            Box::new(add_drain_action::Migration),
+            Box::new(add_circuit_deleted_time::Migration),
+            Box::new(add_agent_lost_time::Migration),
         ]
     }
 }
@@ -0,0 +1,313 @@
+//! The module that backfills the waiting tasks, like the EASY backfilling of
+//! batch schedulers. When the `backfill` [policy](crate::config::SchedPolicy)
+//! is configured and a waiting task can not be placed, e.g. a wide circuit
+//! while small tasks hold part of every agent, the task that is first in
+//! the order of the consume loop gets a [reservation](Reservation): the agent
+//! that is estimated to have room for it the earliest, and the time of it.
+//! The estimate replays the expected end times of the running chunks on the
+//! agent, which come from the [chunk duration](crate::chunking::chunk_seconds)
+//! estimate. The later tasks are dispatched on the other agents freely, and
+//! on the reserved agent only if their chunk is estimated to finish before
+//! the reserved time, so they fill the gap without delaying the wide task.
+
+use crate::capability::{memory_estimate_mb, supports_circuit, TaskRequirements};
+use crate::config::{CapacityConfig, CapacityModel};
+use crate::entity::{agent_reservation, capacity_reservation, physical_agent, task_active};
+use crate::error::Result;
+use crate::service;
+use chrono::NaiveDateTime;
+use sea_orm::DbConn;
+use uuid::Uuid;
+
+/// ## Reservation
+/// The agent reserved for the task that can not be placed.
+/// - `task_id`: The task of the reservation.
+/// - `agent_id`: The reserved agent.
+/// - `start_time`: The estimated time when the agent has room for the task.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub task_id: Uuid,
+    pub agent_id: Uuid,
+    pub start_time: NaiveDateTime,
+}
+
+impl Reservation {
+    /// Whether a chunk on the agent that is estimated to finish at the given
+    /// time does not delay the reservation.
+    pub fn allows(&self, agent_id: Uuid, end_time: NaiveDateTime) -> bool {
+        agent_id != self.agent_id || end_time <= self.start_time
+    }
+}
+
+/// The idle capacity of an agent.
+struct Idle {
+    qubits: i32,
+    memory_mb: Option<i64>,
+    slots: Option<i32>,
+}
+
+impl Idle {
+    /// Whether a task of the given qubits fits the idle capacity of the
+    /// agent, like the [capacity condition](service::physical_agent::capacity_condition).
+    fn fits(&self, capacity: &CapacityConfig, agent: &physical_agent::Model, qubits: i32) -> bool {
+        if capacity.model == CapacityModel::Qubits {
+            return self.qubits >= qubits;
+        }
+        let memory = match self.memory_mb {
+            Some(memory_mb) => {
+                memory_mb >= memory_estimate_mb(&agent.simulator, qubits, capacity.overhead_mb)
+            }
+            None => self.qubits >= qubits,
+        };
+        memory && self.slots.is_none_or(|slots| slots >= 1)
+    }
+}
+
+/// ## Start Time
+/// The estimated time when the agent has room for a task of the given qubits,
+/// replaying the release of its live reservations in the order of their
+/// expected end times. A reservation that is overdue, or without an expected
+/// end time, is expected to be released now. Return `None` if the agent has
+/// no room for the task even when all the reservations are released.
+pub fn start_time(
+    capacity: &CapacityConfig,
+    agent: &physical_agent::Model,
+    reservations: &[capacity_reservation::Model],
+    qubits: i32,
+    now: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let mut idle = Idle {
+        qubits: agent.qubit_idle,
+        memory_mb: agent.memory_idle_mb,
+        slots: agent.slots_idle,
+    };
+    if idle.fits(capacity, agent, qubits) {
+        return Some(now);
+    }
+
+    let mut reservations: Vec<&capacity_reservation::Model> = reservations.iter().collect();
+    reservations.sort_by_key(|reservation| reservation.expected_end_time.unwrap_or(now));
+    for reservation in reservations {
+        idle.qubits += reservation.qubits;
+        idle.memory_mb = idle
+            .memory_mb
+            .map(|memory_mb| memory_mb + reservation.memory_mb);
+        idle.slots = idle.slots.map(|slots| slots + 1);
+        if idle.fits(capacity, agent, qubits) {
+            return Some(reservation.expected_end_time.unwrap_or(now).max(now));
+        }
+    }
+    None
+}
+
+/// ## Reserve
+/// Reserve the agent that is estimated to have room for the task the
+/// earliest among the running agents that are big enough, meet the
+/// requirements of the task, support its circuit and are not booked by the
+/// other users in the given [agent reservations](service::agent_reservation).
+/// Return `None` if there is no such agent.
+pub async fn reserve(
+    db: &DbConn,
+    capacity: &CapacityConfig,
+    task: &task_active::Model,
+    requirements: &TaskRequirements,
+    agent_reservations: &[agent_reservation::Model],
+    now: NaiveDateTime,
+) -> Result<Option<Reservation>> {
+    let agents = service::physical_agent::PhysicalAgent::get_physical_agent_available(
+        db,
+        capacity,
+        task.qubits,
+        task.depth,
+        requirements,
+    )
+    .await?;
+
+    let mut reservation: Option<Reservation> = None;
+    for agent in agents {
+        if !service::agent_reservation::allows(agent_reservations, &agent, &task.username)
+            || !supports_circuit(&agent, &task.source)
+        {
+            continue;
+        }
+        let reservations =
+            service::capacity::Capacity::get_agent_reservations(db, agent.id).await?;
+        let Some(start_time) = start_time(capacity, &agent, &reservations, task.qubits, now) else {
+            continue;
+        };
+        if reservation
+            .as_ref()
+            .is_none_or(|reservation| start_time < reservation.start_time)
+        {
+            reservation = Some(Reservation {
+                task_id: task.id,
+                agent_id: agent.id,
+                start_time,
+            });
+        }
+    }
+    Ok(reservation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::sea_orm_active_enums::PhysicalAgentStatus;
+    use chrono::TimeDelta;
+
+    fn now() -> NaiveDateTime {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0)
+            .unwrap()
+            .naive_utc()
+    }
+
+    fn at(seconds: i64) -> NaiveDateTime {
+        now() + TimeDelta::seconds(seconds)
+    }
+
+    fn agent(qubit_idle: i32) -> physical_agent::Model {
+        physical_agent::Model {
+            id: Uuid::new_v4(),
+            status: PhysicalAgentStatus::Running,
+            ip: "10.0.0.1".to_owned(),
+            port: 8080,
+            qubit_count: 20,
+            qubit_idle,
+            circuit_depth: 100,
+            lease_expire_time: None,
+            discovery: None,
+            simulator: String::new(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb: None,
+            qasm3: false,
+            memory_idle_mb: None,
+            slots: None,
+            slots_idle: None,
+            labels: None,
+            pool: None,
+            lost_time: None,
+        }
+    }
+
+    fn reservation(
+        agent: &physical_agent::Model,
+        qubits: i32,
+        memory_mb: i64,
+        end: Option<i64>,
+    ) -> capacity_reservation::Model {
+        capacity_reservation::Model {
+            id: Uuid::new_v4(),
+            agent_id: agent.id,
+            task_id: Uuid::new_v4(),
+            qubits,
+            memory_mb,
+            created_time: now(),
+            expected_end_time: end.map(at),
+        }
+    }
+
+    #[test]
+    fn start_time_replays_the_running_chunks() {
+        let capacity = CapacityConfig::default();
+        let agent = agent(4);
+        let reservations = [
+            reservation(&agent, 6, 0, Some(10)),
+            reservation(&agent, 10, 0, Some(5)),
+        ];
+        // the head of the queue fits once the chunk ending first is released
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 12, now()),
+            Some(at(5))
+        );
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 16, now()),
+            Some(at(10))
+        );
+        // a task that fits the idle capacity starts now
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 4, now()),
+            Some(now())
+        );
+        // even the whole agent is too small
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 21, now()),
+            None
+        );
+    }
+
+    #[test]
+    fn start_time_of_overdue_chunks_is_now() {
+        let capacity = CapacityConfig::default();
+        let agent = agent(0);
+        let reservations = [
+            reservation(&agent, 8, 0, Some(-30)),
+            reservation(&agent, 8, 0, None),
+            reservation(&agent, 4, 0, Some(20)),
+        ];
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 16, now()),
+            Some(now())
+        );
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 20, now()),
+            Some(at(20))
+        );
+    }
+
+    #[test]
+    fn start_time_in_the_memory_model() {
+        let capacity = CapacityConfig {
+            model: CapacityModel::Memory,
+            overhead_mb: 64,
+        };
+        // a state vector of 24 qubits needs 256 + 64 MiB
+        let agent = physical_agent::Model {
+            memory_mb: Some(1024),
+            memory_idle_mb: Some(100),
+            slots: Some(2),
+            slots_idle: Some(0),
+            ..agent(20)
+        };
+        let reservations = [
+            reservation(&agent, 10, 250, Some(5)),
+            reservation(&agent, 10, 700, Some(15)),
+        ];
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 24, now()),
+            Some(at(5))
+        );
+        let agent = physical_agent::Model {
+            memory_idle_mb: Some(0),
+            ..agent
+        };
+        assert_eq!(
+            start_time(&capacity, &agent, &reservations, 24, now()),
+            Some(at(15))
+        );
+    }
+
+    #[test]
+    fn reservation_blocks_only_the_late_chunks_on_its_agent() {
+        let capacity = CapacityConfig::default();
+        let reserved = agent(4);
+        let other = agent(4);
+        let running = [reservation(&reserved, 16, 0, Some(60))];
+        // the head of the queue is blocked until the running chunk ends
+        let head = Reservation {
+            task_id: Uuid::new_v4(),
+            agent_id: reserved.id,
+            start_time: start_time(&capacity, &reserved, &running, 20, now()).unwrap(),
+        };
+        assert_eq!(head.start_time, at(60));
+
+        // a small chunk that ends before it fills the gap
+        assert!(head.allows(reserved.id, at(30)));
+        assert!(head.allows(reserved.id, at(60)));
+        // a longer one would delay the head of the queue
+        assert!(!head.allows(reserved.id, at(61)));
+        // the other agents are not reserved
+        assert!(head.allows(other.id, at(3600)));
+    }
+}
@@ -0,0 +1,190 @@
+//! The module that contains the capability document advertised by the agents
+//! and the requirements stated by the tasks. A task is only placed on an agent
+//! whose capability meets the requirements of the task, besides the number of
+//! qubits and the circuit depth, and whose gates the circuit of the task can
+//! be [decomposed](supports_circuit) into.
+
+use crate::entity::physical_agent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// ## Agent Capability
+/// The capability document of the agent.
+/// - `simulator`: The simulator type of the agent, e.g. `statevector`,
+///   `density_matrix` or `stabilizer`. `statevector` by default.
+/// - `gates`: The supported gates of the agent, e.g. `["h", "cx", "u3"]`. If
+///   it is missing, the agent supports all the gates.
+/// - `noise`: Whether the agent supports noise models, false by default.
+/// - `max_shots`: The maximum shots of one run on the agent, optional. The
+///   shots of a task are split into chunks no larger than it.
+/// - `memory_mb`: The memory of the agent in MiB, optional. The tasks are
+///   packed by it in the `memory` [capacity model](crate::config::CapacityModel).
+/// - `qasm3`: Whether the agent runs OpenQASM 3 programs, false by default.
+/// - `slots`: The number of tasks the agent runs at the same time, optional.
+///   It is only checked in the `memory` capacity model, an agent without
+///   slots is not limited.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct AgentCapability {
+    #[serde(default = "default_simulator")]
+    pub simulator: String,
+    #[serde(default)]
+    pub gates: Option<Vec<String>>,
+    #[serde(default)]
+    pub noise: bool,
+    #[serde(default)]
+    pub max_shots: Option<u32>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub qasm3: bool,
+    #[serde(default)]
+    pub slots: Option<u32>,
+}
+
+fn default_simulator() -> String {
+    "statevector".to_owned()
+}
+
+impl Default for AgentCapability {
+    fn default() -> Self {
+        Self {
+            simulator: default_simulator(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb: None,
+            qasm3: false,
+            slots: None,
+        }
+    }
+}
+
+impl AgentCapability {
+    /// Set the capability columns of the physical agent. The memory and the
+    /// slots of the agent are all idle.
+    pub fn apply_to(self, agent: &mut physical_agent::Model) {
+        let capability = self.normalized();
+        agent.simulator = capability.simulator;
+        agent.gates = capability.gates.map(Into::into);
+        agent.noise = capability.noise;
+        agent.max_shots = capability.max_shots.map(|max_shots| max_shots as i32);
+        agent.memory_mb = capability.memory_mb.map(|memory_mb| memory_mb as i64);
+        agent.qasm3 = capability.qasm3;
+        agent.memory_idle_mb = agent.memory_mb;
+        agent.slots = capability.slots.map(|slots| slots as i32);
+        agent.slots_idle = agent.slots;
+    }
+
+    /// Normalize the simulator type and the gate names to lowercase, so that
+    /// they can be matched with the requirements of the tasks.
+    pub fn normalized(mut self) -> Self {
+        self.simulator = self.simulator.trim().to_lowercase();
+        self.gates = self.gates.map(normalize_gates);
+        self
+    }
+}
+
+/// ## Task Requirements
+/// The requirements of the task on the agent, every requirement is optional.
+/// - `simulator`: The simulator type the task must run on.
+/// - `gates`: The gates the agent must support.
+/// - `noise`: Whether the agent must support noise models.
+/// - `memory_mb`: The minimum memory of the agent in MiB. The agents whose
+///   memory is unknown are not excluded.
+/// - `qasm3`: Whether the agent must run OpenQASM 3 programs, it is set for
+///   the OpenQASM 3 programs that can not be translated to OpenQASM 2.
+/// - `pool`: The pool of agents the task must run in.
+/// - `node_selector`: The labels the agent must have, e.g.
+///   `{"team": "chem"}`.
+/// - `anti_affinity`: The labels the agent must not have, an agent is
+///   avoided if it has any of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct TaskRequirements {
+    #[serde(default)]
+    pub simulator: Option<String>,
+    #[serde(default)]
+    pub gates: Vec<String>,
+    #[serde(default)]
+    pub noise: bool,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub qasm3: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub anti_affinity: BTreeMap<String, String>,
+}
+
+impl TaskRequirements {
+    /// Normalize the simulator type and the gate names to lowercase, please
+    /// refer to [AgentCapability::normalized].
+    pub fn normalized(mut self) -> Self {
+        self.simulator = self
+            .simulator
+            .map(|simulator| simulator.trim().to_lowercase());
+        self.gates = normalize_gates(self.gates);
+        self
+    }
+
+    /// Read the requirements saved with the task, a task without requirements
+    /// has the default ones.
+    pub fn from_json(requirements: Option<&serde_json::Value>) -> crate::error::Result<Self> {
+        Ok(match requirements {
+            Some(requirements) => serde_json::from_value(requirements.clone())?,
+            None => Self::default(),
+        })
+    }
+}
+
+/// ## Agent Gates
+/// The gate set of the agent, `None` if the agent supports all the gates.
+pub fn agent_gates(agent: &physical_agent::Model) -> crate::error::Result<Option<Vec<String>>> {
+    Ok(agent
+        .gates
+        .clone()
+        .map(serde_json::from_value)
+        .transpose()?)
+}
+
+/// ## Supports Circuit
+/// Whether every gate of the circuit can be decomposed into the gate set of
+/// the agent, a task is only placed on such agents. An agent whose gate set
+/// can not be read supports no circuit.
+pub fn supports_circuit(agent: &physical_agent::Model, source: &str) -> bool {
+    agent_gates(agent).is_ok_and(|gates| crate::transpile::can_transpile(source, gates.as_deref()))
+}
+
+/// ## Memory Estimate
+/// The memory in MiB to run a circuit of the given qubits on a simulator of
+/// the given type, plus the overhead of the simulator process. A
+/// `density_matrix` simulator stores `4^n` complex amplitudes, a `stabilizer`
+/// simulator a tableau of `(2n)^2` bits, and the other simulators are assumed
+/// to store a state vector of `2^n` complex amplitudes of 16 bytes.
+pub fn memory_estimate_mb(simulator: &str, qubits: i32, overhead_mb: u64) -> i64 {
+    let qubits = qubits.clamp(0, 60);
+    let state_mb = match simulator {
+        "density_matrix" => 2f64.powi(2 * qubits + 4 - 20),
+        "stabilizer" => (2.0 * qubits as f64).powi(2) / 8.0 / 2f64.powi(20),
+        _ => 2f64.powi(qubits + 4 - 20),
+    };
+    // saturate instead of overflow for the circuits that never fit
+    (state_mb.ceil().min(i64::MAX as f64 / 2.0) as i64)
+        .saturating_add(overhead_mb.min(i64::MAX as u64) as i64)
+        .max(1)
+}
+
+/// Lowercase, sort and deduplicate the gate names.
+fn normalize_gates(gates: Vec<String>) -> Vec<String> {
+    let mut gates: Vec<String> = gates
+        .into_iter()
+        .map(|gate| gate.trim().to_lowercase())
+        .filter(|gate| !gate.is_empty())
+        .collect();
+    gates.sort();
+    gates.dedup();
+    gates
+}
@@ -0,0 +1,197 @@
+//! The module that sizes the shot chunks of the tasks. The throughput of an
+//! agent in shots per second is learned from its completed assignments in the
+//! [usage ledger](crate::entity::usage_record::Model), keyed on the qubits and
+//! the depth of the circuits:
+//! - The assignments with the same qubits and a depth within a factor of two
+//!   give the throughput directly.
+//! - Otherwise all the assignments of the agent are scaled by the work of one
+//!   shot, `depth * 2^qubits` like a state vector simulation, to the size of
+//!   the task.
+//!
+//! A chunk is sized to run for the configured time slice, with at least one
+//! shot and at most the configured ceiling. An agent without completed
+//! assignments falls back to the fixed formula of the scheduler. The duration
+//! of a chunk is estimated from the same throughput, it is used by the
+//! [backfill](crate::backfill) of the waiting tasks and the qubit-seconds
+//! [quota](crate::service::quota::Quota) of the users.
+
+use crate::config::ChunkingConfig;
+use crate::entity::usage_record;
+
+/// The work of one shot of a circuit relative to the others.
+fn work(qubits: i32, depth: i32) -> f64 {
+    depth.max(1) as f64 * 2f64.powi(qubits.clamp(0, 60))
+}
+
+/// The wall time of the assignment in seconds, at least one millisecond.
+fn seconds(record: &usage_record::Model) -> f64 {
+    record.wall_time_ms.max(1) as f64 / 1000.0
+}
+
+/// Estimate the shots per second of the agent for the circuit of the given
+/// qubits and depth from its completed assignments. Return `None` if there
+/// is no assignment.
+fn shots_per_second(history: &[usage_record::Model], qubits: i32, depth: i32) -> Option<f64> {
+    let similar: Vec<&usage_record::Model> = history
+        .iter()
+        .filter(|record| {
+            record.qubits == qubits && record.depth <= depth * 2 && depth <= record.depth * 2
+        })
+        .collect();
+    if !similar.is_empty() {
+        let shots: f64 = similar.iter().map(|record| record.shots as f64).sum();
+        let seconds: f64 = similar.iter().map(|record| seconds(record)).sum();
+        return Some(shots / seconds);
+    }
+
+    if history.is_empty() {
+        return None;
+    }
+    let work_done: f64 = history
+        .iter()
+        .map(|record| record.shots as f64 * work(record.qubits, record.depth))
+        .sum();
+    let seconds: f64 = history.iter().map(seconds).sum();
+    Some(work_done / seconds / work(qubits, depth))
+}
+
+/// ## Chunk Shots
+/// The shots of the next chunk of the circuit of the given qubits and depth
+/// on the agent with the given history. `fallback` is the shots of the fixed
+/// formula, which is used if the agent has no history. The result is at
+/// least one and at most the ceiling of the configuration.
+pub fn chunk_shots(
+    config: &ChunkingConfig,
+    history: &[usage_record::Model],
+    qubits: i32,
+    depth: i32,
+    fallback: f32,
+) -> i32 {
+    let shots = match shots_per_second(history, qubits, depth) {
+        Some(rate) => rate * config.time_slice,
+        None => fallback as f64,
+    };
+    (shots.floor() as i64).clamp(1, config.max_shots.max(1) as i64) as i32
+}
+
+/// ## Chunk Seconds
+/// The estimated seconds to run the given shots of the circuit of the given
+/// qubits and depth on the agent with the given history. An agent without
+/// history is assumed to run a chunk in the time slice of the configuration.
+pub fn chunk_seconds(
+    config: &ChunkingConfig,
+    history: &[usage_record::Model],
+    qubits: i32,
+    depth: i32,
+    shots: i32,
+) -> f64 {
+    estimate_seconds(history, qubits, depth, shots).unwrap_or(config.time_slice)
+}
+
+/// ## Estimate Seconds
+/// The estimated seconds to run the given shots of the circuit of the given
+/// qubits and depth with the given history, which may span several agents.
+/// Return `None` if there is no history.
+pub fn estimate_seconds(
+    history: &[usage_record::Model],
+    qubits: i32,
+    depth: i32,
+    shots: i32,
+) -> Option<f64> {
+    shots_per_second(history, qubits, depth).map(|rate| shots as f64 / rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(time_slice: f64, max_shots: u32) -> ChunkingConfig {
+        ChunkingConfig {
+            time_slice,
+            max_shots,
+            history: 100,
+        }
+    }
+
+    fn record(shots: i32, qubits: i32, depth: i32, wall_time_ms: i64) -> usage_record::Model {
+        let now = chrono::Utc::now().naive_utc();
+        usage_record::Model {
+            id: uuid::Uuid::new_v4(),
+            assignment_id: uuid::Uuid::new_v4(),
+            task_id: uuid::Uuid::new_v4(),
+            agent_id: uuid::Uuid::new_v4(),
+            username: "alice".to_owned(),
+            shots,
+            qubits,
+            depth,
+            wall_time_ms,
+            started_time: now,
+            finished_time: now,
+        }
+    }
+
+    #[test]
+    fn chunk_shots_from_similar_assignments() {
+        // 1000 shots per second, the depth 15 is within a factor of two of 10
+        let history = [record(1000, 10, 10, 500), record(2000, 10, 20, 2500)];
+        assert_eq!(
+            chunk_shots(&config(2.0, 10000), &history, 10, 15, 7.0),
+            2000
+        );
+        assert_eq!(
+            chunk_seconds(&config(2.0, 10000), &history, 10, 15, 500),
+            0.5
+        );
+    }
+
+    #[test]
+    fn chunk_shots_scaled_by_the_work() {
+        // 1000 shots per second of 10 qubits, a shot of 12 qubits is 4 times
+        // the work and a shot of depth 40 is 4 times more
+        let history = [record(1000, 10, 10, 1000)];
+        assert_eq!(chunk_shots(&config(1.0, 10000), &history, 12, 10, 7.0), 250);
+        assert_eq!(chunk_shots(&config(1.0, 10000), &history, 10, 40, 7.0), 250);
+        assert_eq!(
+            chunk_seconds(&config(1.0, 10000), &history, 12, 40, 125),
+            2.0
+        );
+    }
+
+    #[test]
+    fn chunk_shots_falls_back_without_history() {
+        assert_eq!(chunk_shots(&config(1.0, 10000), &[], 10, 10, 42.9), 42);
+        assert_eq!(chunk_seconds(&config(3.0, 10000), &[], 10, 10, 42), 3.0);
+        assert_eq!(estimate_seconds(&[], 10, 10, 42), None);
+    }
+
+    #[test]
+    fn estimate_seconds_of_all_the_shots() {
+        // 1000 shots per second, the estimate is not capped by the ceiling
+        let history = [record(1000, 10, 10, 1000)];
+        assert_eq!(estimate_seconds(&history, 10, 10, 50_000), Some(50.0));
+        assert_eq!(estimate_seconds(&history, 11, 10, 500), Some(1.0));
+    }
+
+    #[test]
+    fn chunk_shots_is_clamped() {
+        let fast = [record(1_000_000, 2, 1, 1)];
+        // at most the ceiling
+        assert_eq!(chunk_shots(&config(5.0, 10000), &fast, 2, 1, 7.0), 10000);
+        assert_eq!(chunk_shots(&config(1.0, 500), &[], 2, 1, 1e9), 500);
+        // a zero ceiling still allows one shot
+        assert_eq!(chunk_shots(&config(5.0, 0), &fast, 2, 1, 7.0), 1);
+
+        let slow = [record(1, 30, 100, 60_000)];
+        // at least one shot
+        assert_eq!(chunk_shots(&config(5.0, 10000), &slow, 30, 100, 7.0), 1);
+        assert_eq!(chunk_shots(&config(5.0, 10000), &[], 30, 100, 0.0), 1);
+        assert_eq!(chunk_shots(&config(5.0, 10000), &[], 30, 100, -3.0), 1);
+        assert_eq!(chunk_shots(&config(5.0, 10000), &[], 30, 100, f32::NAN), 1);
+        // the wall time is at least one millisecond
+        let instant = [record(10, 2, 1, 0)];
+        assert_eq!(
+            chunk_shots(&config(1.0, 100_000), &instant, 2, 1, 7.0),
+            10000
+        );
+    }
+}
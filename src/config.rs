@@ -1,4 +1,6 @@
 use serde::Deserialize;
+use std::collections::HashMap;
+
 #[derive(Deserialize, Clone)]
 pub struct QSchedulerConfig {
     pub sched_min_gran: u32,
@@ -7,6 +9,39 @@ pub struct QSchedulerConfig {
     pub listen_port: u32,
     pub db_url: String,
     pub agent_file: String,
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub sched_order: SchedOrder,
+    #[serde(default)]
+    pub sched_policy: SchedPolicy,
+    #[serde(default = "default_agent_lease_ttl")]
+    pub agent_lease_ttl: u64,
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(default)]
+    pub noise_presets: HashMap<String, crate::noise::NoiseModel>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub cutting: CuttingConfig,
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
+    #[serde(default)]
+    pub dispatch: DispatchConfig,
+    #[serde(default)]
+    pub leader: LeaderConfig,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    #[serde(default)]
+    pub drain: DrainConfig,
+}
+
+/// The seconds of the lease of a self registered agent, 30 by default.
+fn default_agent_lease_ttl() -> u64 {
+    30
 }
 
 impl Default for QSchedulerConfig {
@@ -18,10 +53,393 @@ impl Default for QSchedulerConfig {
             listen_port: 3000,
             db_url: "".to_string(),
             agent_file: "".to_string(),
+            admission: AdmissionConfig::default(),
+            quota: QuotaConfig::default(),
+            sched_order: SchedOrder::default(),
+            sched_policy: SchedPolicy::default(),
+            agent_lease_ttl: default_agent_lease_ttl(),
+            discovery: None,
+            noise_presets: HashMap::new(),
+            cache: CacheConfig::default(),
+            cutting: CuttingConfig::default(),
+            chunking: ChunkingConfig::default(),
+            dispatch: DispatchConfig::default(),
+            leader: LeaderConfig::default(),
+            capacity: CapacityConfig::default(),
+            drain: DrainConfig::default(),
+        }
+    }
+}
+
+/// ## Discovery Config
+/// The discovery of the agent replicas behind a Kubernetes service. The
+/// agents are added and removed as the replicas come and go.
+/// - `mode`: How to find the replicas, `dns` resolves all the A records of
+///   the (headless) service name, `endpoints` reads the Endpoints of the
+///   service through the Kubernetes API. `dns` by default.
+/// - `service`: The name of the service.
+/// - `namespace`: The namespace of the service for the `endpoints` mode, the
+///   namespace of the server pod by default.
+/// - `port`: The port of the agents.
+/// - `qubit_count`: The number of qubits of every agent.
+/// - `circuit_depth`: The circuit depth of every agent.
+/// - `interval`: The seconds between two discoveries, 10 by default.
+/// - `capability`: The [capability document](crate::capability::AgentCapability)
+///   of every agent, the default one if it is missing.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DiscoveryConfig {
+    #[serde(default)]
+    pub mode: DiscoveryMode,
+    pub service: String,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    pub port: u32,
+    pub qubit_count: u32,
+    pub circuit_depth: u32,
+    #[serde(default = "default_discovery_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub capability: crate::capability::AgentCapability,
+}
+
+fn default_discovery_interval() -> u64 {
+    10
+}
+
+/// ## Discovery Mode
+/// Please refer to [DiscoveryConfig].
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    #[default]
+    Dns,
+    Endpoints,
+}
+
+/// ## Scheduling Order
+/// The order in which the consume thread dispatches the waiting tasks.
+/// - `fair`: The task with the least virtual executed shots first, this is the
+///   default.
+/// - `edf`: The task with the earliest deadline first, the tasks without
+///   deadline are dispatched in the `fair` order after them.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedOrder {
+    #[default]
+    Fair,
+    Edf,
+}
+
+/// ## Drain Config
+/// The [drain](crate::router::physical_agent::drain_agent) of the agents.
+/// - `timeout`: The seconds to wait for the running chunks of a draining
+///   agent when the request does not give a timeout, 300 by default. It is
+///   also the timeout of the updates and the removal of the agents.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DrainConfig {
+    #[serde(default = "default_drain_timeout")]
+    pub timeout: u64,
+}
+
+fn default_drain_timeout() -> u64 {
+    300
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_drain_timeout(),
+        }
+    }
+}
+
+/// ## Capacity Model
+/// How the tasks are packed on the agents.
+/// - `qubits`: The qubits of the agents are a divisible resource, e.g. a
+///   20-qubit agent runs two 10-qubit tasks at the same time. This is the
+///   default, for compatibility.
+/// - `memory`: The tasks are packed by the [memory estimate](crate::capability::memory_estimate_mb)
+///   of their circuit on the simulator of the agent, and by the slots of the
+///   agent. An agent without memory is packed by qubits.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CapacityModel {
+    #[default]
+    Qubits,
+    Memory,
+}
+
+/// ## Capacity Config
+/// The [capacity model](CapacityModel) of the agents.
+/// - `model`: The capacity model, `qubits` by default.
+/// - `overhead_mb`: The memory in MiB of the simulator process that is added
+///   to the memory estimate of every task, 64 by default.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CapacityConfig {
+    #[serde(default)]
+    pub model: CapacityModel,
+    #[serde(default = "default_overhead_mb")]
+    pub overhead_mb: u64,
+}
+
+fn default_overhead_mb() -> u64 {
+    64
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        Self {
+            model: CapacityModel::default(),
+            overhead_mb: default_overhead_mb(),
+        }
+    }
+}
+
+/// ## Scheduling Policy
+/// What the consume thread does when a waiting task can not be placed.
+/// - `strict`: Stop dispatching until the next iteration, so no task overtakes
+///   it. This is the default.
+/// - `backfill`: The task that can not be placed first reserves the agent
+///   that is [estimated](crate::backfill) to have room for it the earliest,
+///   and the later tasks are dispatched as long as they do not delay that
+///   reservation.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedPolicy {
+    #[default]
+    Strict,
+    Backfill,
+}
+
+/// ## Admission Config
+/// The limits checked before a task is added to the waiting queue. Every limit
+/// is optional, a missing limit is not checked.
+/// - `max_waiting_tasks`: The maximum number of waiting tasks of all users.
+/// - `max_pending_shots`: The maximum number of not yet executed shots of all
+///   active tasks.
+/// - `max_user_tasks`: The maximum number of active tasks of one user.
+/// - `max_task_shots`: The maximum `shots` of one task.
+/// - `max_code_size`: The maximum size of the `code` of one task in bytes.
+/// - `retry_after`: The seconds returned in the `Retry-After` header when a
+///   queue limit is reached, 5 by default.
+/// - `user_header`: The request header that carries the user of a task, set
+///   by a trusted authenticating proxy, optional. If it is set, the user is
+///   taken from the header and a request without it is rejected. Otherwise
+///   the user is the `username` of the request, so the per user limits and
+///   quotas only hold for cooperative clients.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdmissionConfig {
+    pub max_waiting_tasks: Option<u64>,
+    pub max_pending_shots: Option<i64>,
+    pub max_user_tasks: Option<u64>,
+    pub max_task_shots: Option<usize>,
+    pub max_code_size: Option<usize>,
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+    #[serde(default)]
+    pub user_header: Option<String>,
+}
+
+fn default_retry_after() -> u64 {
+    5
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_waiting_tasks: None,
+            max_pending_shots: None,
+            max_user_tasks: None,
+            max_task_shots: None,
+            max_code_size: None,
+            retry_after: default_retry_after(),
+            user_header: None,
+        }
+    }
+}
+
+/// ## Cache Config
+/// The reuse of the results of identical tasks, which have the same
+/// normalized source and execution parameters.
+/// - `enabled`: Whether a submission reuses the result of an identical
+///   succeeded task, or attaches to an identical in-flight task. False by
+///   default.
+/// - `max_age`: The seconds after which a succeeded task is no longer reused,
+///   optional.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+/// ## Cutting Config
+/// The [cutting](crate::cutting) of the circuits that are too wide for every
+/// agent, which the tasks opt in to.
+/// - `max_cuts`: The maximum number of cut gates of one circuit, 2 by
+///   default. A fragment with `k` cuts runs as `5^k` child tasks.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CuttingConfig {
+    #[serde(default = "default_max_cuts")]
+    pub max_cuts: usize,
+}
+
+fn default_max_cuts() -> usize {
+    2
+}
+
+impl Default for CuttingConfig {
+    fn default() -> Self {
+        Self {
+            max_cuts: default_max_cuts(),
+        }
+    }
+}
+
+/// ## Chunking Config
+/// The sizing of the shot chunks from the
+/// [measured throughput](crate::chunking) of the agents.
+/// - `time_slice`: The target wall time of one chunk in seconds, 5 by
+///   default.
+/// - `max_shots`: The maximum shots of one chunk, 10000 by default. A chunk
+///   has at least one shot.
+/// - `history`: The number of the latest completed assignments of the agent
+///   that its throughput is learned from, 100 by default.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChunkingConfig {
+    #[serde(default = "default_time_slice")]
+    pub time_slice: f64,
+    #[serde(default = "default_chunk_max_shots")]
+    pub max_shots: u32,
+    #[serde(default = "default_chunk_history")]
+    pub history: u64,
+}
+
+fn default_time_slice() -> f64 {
+    5.0
+}
+
+fn default_chunk_max_shots() -> u32 {
+    10000
+}
+
+fn default_chunk_history() -> u64 {
+    100
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            time_slice: default_time_slice(),
+            max_shots: default_chunk_max_shots(),
+            history: default_chunk_history(),
+        }
+    }
+}
+
+/// ## Dispatch Config
+/// The [wakeup](crate::dispatch) of the consume loop.
+/// - `pg_notify`: Whether the dispatch events are exchanged with the other
+///   replicas of the server by Postgres `LISTEN/NOTIFY`, false by default.
+///   Enable it when several replicas share the database.
+/// - `scan_interval`: The seconds between two fallback scans of the waiting
+///   tasks when no event arrives, 10 by default. The expired tasks and agent
+///   leases are also checked on every scan.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DispatchConfig {
+    #[serde(default)]
+    pub pg_notify: bool,
+    #[serde(default = "default_scan_interval")]
+    pub scan_interval: u64,
+}
+
+fn default_scan_interval() -> u64 {
+    10
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self {
+            pg_notify: false,
+            scan_interval: default_scan_interval(),
         }
     }
 }
 
+/// ## Leader Config
+/// The [election](crate::leader) of the replica that runs the consume loop.
+/// - `enabled`: Whether the replicas elect a leader with a Postgres advisory
+///   lock, false by default. Enable it when several replicas share the
+///   database, otherwise every replica dispatches the same tasks.
+/// - `lock_key`: The key of the advisory lock, shared by all the replicas.
+/// - `retry_interval`: The seconds between two attempts of a follower to take
+///   the lock, and between two health checks of the leader's connection, 2 by
+///   default.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LeaderConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_lock_key")]
+    pub lock_key: i64,
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+}
+
+fn default_lock_key() -> i64 {
+    0x7173_6368_6564
+}
+
+fn default_retry_interval() -> u64 {
+    2
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lock_key: default_lock_key(),
+            retry_interval: default_retry_interval(),
+        }
+    }
+}
+
+/// ## Quota Config
+/// The per user usage quotas checked at submit and dispatch time. The usage
+/// is read from the [usage ledger](crate::entity::usage_record::Model).
+/// - `default`: The quota of the users that are not listed in `users`.
+/// - `users`: The quota of the given users, which overrides `default`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub default: UserQuota,
+    #[serde(default)]
+    pub users: HashMap<String, UserQuota>,
+}
+
+impl QuotaConfig {
+    /// Get the quota of the given user.
+    pub fn user_quota(&self, username: &str) -> &UserQuota {
+        self.users.get(username).unwrap_or(&self.default)
+    }
+}
+
+/// ## User Quota
+/// The quota of one user. Every quota is optional, a missing quota is not
+/// checked. The days and months are in UTC.
+/// - `daily_shots`: The maximum shots executed in one day.
+/// - `monthly_shots`: The maximum shots executed in one month.
+/// - `daily_qubit_seconds`: The maximum qubit-seconds (qubits times wall time
+///   of the assignment) used in one day.
+/// - `monthly_qubit_seconds`: The maximum qubit-seconds used in one month.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct UserQuota {
+    pub daily_shots: Option<i64>,
+    pub monthly_shots: Option<i64>,
+    pub daily_qubit_seconds: Option<i64>,
+    pub monthly_qubit_seconds: Option<i64>,
+}
+
 pub fn get_qsched_config(path: &str) -> QSchedulerConfig {
     let qsched_config = std::fs::read_to_string(path).unwrap();
     let qsched_config: QSchedulerConfig = serde_json::from_str(&qsched_config).unwrap();
@@ -0,0 +1,859 @@
+//! The module that cuts a circuit that is too wide for every agent into
+//! fragments that fit the agents. The qubits are partitioned into groups of
+//! at most the qubits of the largest agent, so that the fewest `cx` gates
+//! cross two groups. Every crossing gate is cut: `cx` is rewritten as
+//! `rzz(-pi/2)` between local gates, and the channel of `rzz(theta)` is
+//! replaced with its quasi-probability decomposition into six products of
+//! local operations (Mitarai and Fujii, 2021):
+//!
+//! ```text
+//! rzz(theta) = cos²(theta/2) I⊗I + sin²(theta/2) Z⊗Z
+//!            + cos(theta/2) sin(theta/2) (M⊗R+ - M⊗R- + R+⊗M - R-⊗M)
+//! ```
+//!
+//! where `M` measures the qubit in the middle of the circuit and weights the
+//! outcome with `+1` or `-1`, and `R±` is `rz(±pi/2)`. Every fragment runs
+//! once for every combination of the local operations of its cuts, then the
+//! distribution of the whole circuit is [reconstructed](reconstruct) by
+//! summing the products of the fragment distributions with the coefficients
+//! of the terms. The negative quasi-probabilities caused by the sampling
+//! noise are clipped.
+
+use crate::error::{Error, Result};
+use crate::qasm::is_qasm3;
+use crate::transpile::{Circuit, Gate, Operation};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::f64::consts::PI;
+use uuid::Uuid;
+
+/// The number of classical bits of a fragment is limited by the width of the
+/// outcome.
+const MAX_CLBITS: usize = 128;
+
+/// The local operation applied to one side of a cut gate.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CutAction {
+    Identity,
+    Z,
+    Measure,
+    RotatePlus,
+    RotateMinus,
+}
+
+/// All the local operations, a fragment runs once for every combination of
+/// them on its cuts.
+const ACTIONS: [CutAction; 5] = [
+    CutAction::Identity,
+    CutAction::Z,
+    CutAction::Measure,
+    CutAction::RotatePlus,
+    CutAction::RotateMinus,
+];
+
+/// The terms of the decomposition of `rzz`, the local operations on the
+/// first and the second qubit of the cut gate.
+const TERMS: [(CutAction, CutAction); 6] = [
+    (CutAction::Identity, CutAction::Identity),
+    (CutAction::Z, CutAction::Z),
+    (CutAction::Measure, CutAction::RotatePlus),
+    (CutAction::Measure, CutAction::RotateMinus),
+    (CutAction::RotatePlus, CutAction::Measure),
+    (CutAction::RotateMinus, CutAction::Measure),
+];
+
+/// The coefficients of the [terms](TERMS) of `rzz(theta)`.
+fn term_coefficients(theta: f64) -> [f64; 6] {
+    let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+    [
+        cos * cos,
+        sin * sin,
+        cos * sin,
+        -cos * sin,
+        cos * sin,
+        -cos * sin,
+    ]
+}
+
+/// ## Cut Plan
+/// How a circuit is cut, it is stored in the `cutting` field of the cut task
+/// to reconstruct the distribution from the results of the child tasks.
+/// - `cregs`: The classical registers of the circuit.
+/// - `angles`: The angle of the `rzz` gate of every cut.
+/// - `fragments`: The [fragments](FragmentPlan) of the circuit.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CutPlan {
+    pub cregs: Vec<(String, usize)>,
+    pub angles: Vec<f64>,
+    pub fragments: Vec<FragmentPlan>,
+}
+
+/// ## Fragment Plan
+/// - `qubits`: The qubits of the circuit in the fragment.
+/// - `cuts`: The cuts of the fragment with the side of the gate, `0` for the
+///   first qubit and `1` for the second.
+/// - `variants`: The child task of every combination of the local operations
+///   on the cuts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FragmentPlan {
+    pub qubits: Vec<usize>,
+    pub cuts: Vec<(usize, usize)>,
+    pub variants: Vec<Variant>,
+}
+
+/// ## Variant
+/// The child task that runs the fragment with the given local operations on
+/// its cuts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Variant {
+    pub actions: Vec<CutAction>,
+    pub task_id: Uuid,
+}
+
+impl CutPlan {
+    /// The number of child tasks of the plan.
+    pub fn task_count(&self) -> usize {
+        self.fragments
+            .iter()
+            .map(|fragment| fragment.variants.len())
+            .sum()
+    }
+}
+
+/// ## Fragment Task
+/// The circuit of a child task, the classical bits of the circuit keep their
+/// positions and the outcomes of the cut measurements follow them.
+pub struct FragmentTask {
+    pub id: Uuid,
+    pub source: String,
+    pub qubits: usize,
+    pub depth: usize,
+}
+
+/// ## Cut Circuit
+/// Cut the OpenQASM 2 circuit into fragments of at most `capacity` qubits
+/// with at most `max_cuts` cut gates. Return the plan and the circuits of
+/// the child tasks. If the circuit can not be parsed, has conditioned gates,
+/// fits in `capacity` qubits or needs more cuts, return an invalid request
+/// error.
+pub fn cut_circuit(
+    source: &str,
+    capacity: usize,
+    max_cuts: usize,
+) -> Result<(CutPlan, Vec<FragmentTask>)> {
+    let invalid =
+        |reason: String| Error::InvalidRequest(format!("the circuit can not be cut: {}", reason));
+    if is_qasm3(source) {
+        return Err(invalid("OpenQASM 3 programs are not supported".to_owned()));
+    }
+    let mut circuit = Circuit::parse(source).map_err(invalid)?;
+    if circuit
+        .operations
+        .iter()
+        .any(|operation| matches!(operation, Operation::Gate(gate) if gate.condition.is_some()))
+    {
+        return Err(invalid("conditioned gates are not supported".to_owned()));
+    }
+    let basis = BTreeSet::from(["u3".to_owned(), "cx".to_owned()]);
+    circuit
+        .decompose(&basis)
+        .map_err(|gate| invalid(format!("gate {} can not be decomposed", gate)))?;
+    circuit.optimize();
+    if circuit.qubit_count() <= capacity {
+        return Err(invalid(format!(
+            "its {} qubits fit in one agent",
+            circuit.qubit_count()
+        )));
+    }
+
+    let groups = partition(&circuit, capacity);
+    let group_count = groups.iter().max().map_or(0, |group| group + 1);
+
+    // the cut gates by the index of the operation
+    let mut cuts = HashMap::new();
+    for (i, operation) in circuit.operations.iter().enumerate() {
+        if let Operation::Gate(gate) = operation {
+            if gate.qubits.len() == 2 && groups[gate.qubits[0]] != groups[gate.qubits[1]] {
+                cuts.insert(i, cuts.len());
+            }
+        }
+    }
+    if cuts.len() > max_cuts {
+        return Err(invalid(format!(
+            "it needs {} cuts, more than the maximum {}",
+            cuts.len(),
+            max_cuts
+        )));
+    }
+
+    // every classical bit must be written by one fragment
+    let clbit_count: usize = circuit.cregs.iter().map(|(_, size)| size).sum();
+    let mut writers = HashMap::new();
+    for operation in &circuit.operations {
+        if let Operation::Measure(qubit, clbit) = operation {
+            if *writers.entry(*clbit).or_insert(groups[*qubit]) != groups[*qubit] {
+                return Err(invalid(format!(
+                    "classical bit {} is measured in two fragments",
+                    clbit
+                )));
+            }
+        }
+    }
+
+    let mut plan = CutPlan {
+        cregs: circuit.cregs.clone(),
+        angles: vec![-PI / 2.0; cuts.len()],
+        fragments: vec![],
+    };
+    let mut tasks = vec![];
+    for group in 0..group_count {
+        let qubits: Vec<usize> = (0..groups.len()).filter(|&q| groups[q] == group).collect();
+        let mut fragment_cuts: Vec<(usize, usize)> = cuts
+            .iter()
+            .filter_map(|(&i, &cut)| match &circuit.operations[i] {
+                Operation::Gate(gate) => (0..2)
+                    .find(|&side| groups[gate.qubits[side]] == group)
+                    .map(|side| (cut, side)),
+                _ => None,
+            })
+            .collect();
+        fragment_cuts.sort_unstable();
+        if clbit_count + fragment_cuts.len() > MAX_CLBITS {
+            return Err(invalid(format!(
+                "a fragment has more than {} classical bits",
+                MAX_CLBITS
+            )));
+        }
+
+        let mut variants = vec![];
+        for index in 0..ACTIONS.len().pow(fragment_cuts.len() as u32) {
+            let actions: Vec<CutAction> = (0..fragment_cuts.len())
+                .map(|i| ACTIONS[index / ACTIONS.len().pow(i as u32) % ACTIONS.len()])
+                .collect();
+            let fragment = fragment_circuit(
+                &circuit,
+                &qubits,
+                &cuts,
+                &fragment_cuts
+                    .iter()
+                    .map(|(cut, _)| *cut)
+                    .zip(actions.iter().copied())
+                    .collect(),
+                clbit_count,
+            );
+            let task_id = Uuid::new_v4();
+            tasks.push(FragmentTask {
+                id: task_id,
+                source: fragment.to_qasm(),
+                qubits: qubits.len(),
+                depth: fragment.depth(),
+            });
+            variants.push(Variant { actions, task_id });
+        }
+        plan.fragments.push(FragmentPlan {
+            qubits,
+            cuts: fragment_cuts,
+            variants,
+        });
+    }
+    Ok((plan, tasks))
+}
+
+/// Partition the qubits into groups of at most `capacity` qubits, return the
+/// group of every qubit. The pairs of qubits with the most `cx` gates between
+/// them are joined first, then the groups are packed together.
+fn partition(circuit: &Circuit, capacity: usize) -> Vec<usize> {
+    let count = circuit.qubit_count();
+    let mut weights: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    for operation in &circuit.operations {
+        if let Operation::Gate(gate) = operation {
+            if let [a, b] = gate.qubits[..] {
+                *weights.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+    }
+    let mut edges: Vec<_> = weights.into_iter().collect();
+    edges.sort_by_key(|(_, weight)| Reverse(*weight));
+
+    // join the qubits with a union find
+    let mut parents: Vec<usize> = (0..count).collect();
+    let mut sizes = vec![1; count];
+    fn find(parents: &mut [usize], mut qubit: usize) -> usize {
+        while parents[qubit] != qubit {
+            parents[qubit] = parents[parents[qubit]];
+            qubit = parents[qubit];
+        }
+        qubit
+    }
+    for ((a, b), _) in edges {
+        let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+        if a != b && sizes[a] + sizes[b] <= capacity {
+            parents[b] = a;
+            sizes[a] += sizes[b];
+        }
+    }
+
+    // pack the clusters into the groups, the largest first
+    let mut clusters: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for qubit in 0..count {
+        clusters
+            .entry(find(&mut parents, qubit))
+            .or_default()
+            .push(qubit);
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters.into_values().collect();
+    clusters.sort_by_key(|cluster| Reverse(cluster.len()));
+
+    let mut groups = vec![0; count];
+    let mut group_sizes: Vec<usize> = vec![];
+    for cluster in clusters {
+        let group = match group_sizes
+            .iter()
+            .position(|size| size + cluster.len() <= capacity)
+        {
+            Some(group) => group,
+            None => {
+                group_sizes.push(0);
+                group_sizes.len() - 1
+            }
+        };
+        group_sizes[group] += cluster.len();
+        for qubit in cluster {
+            groups[qubit] = group;
+        }
+    }
+    groups
+}
+
+/// Build the circuit of the fragment on the given qubits with the local
+/// operations of its cuts. A cut `cx` is rewritten as
+/// `h t; s c; s t; rzz(-pi/2) c,t; h t;`, where `rzz` is replaced with the
+/// local operation of the fragment side.
+fn fragment_circuit(
+    circuit: &Circuit,
+    qubits: &[usize],
+    cuts: &HashMap<usize, usize>,
+    actions: &HashMap<usize, CutAction>,
+    clbit_count: usize,
+) -> Circuit {
+    let local: HashMap<usize, usize> = qubits
+        .iter()
+        .enumerate()
+        .map(|(local, &qubit)| (qubit, local))
+        .collect();
+    let mut operations = vec![];
+    let mut measured = 0;
+
+    for (i, operation) in circuit.operations.iter().enumerate() {
+        match operation {
+            Operation::Gate(gate) if cuts.contains_key(&i) => {
+                let Some(side) = (0..2).find(|&side| local.contains_key(&gate.qubits[side])) else {
+                    continue;
+                };
+                let qubit = local[&gate.qubits[side]];
+                let single =
+                    |name: &str, params| Operation::Gate(gate.derive(name, params, vec![qubit]));
+                if side == 1 {
+                    operations.push(single("h", vec![]));
+                }
+                operations.push(single("s", vec![]));
+                match actions[&cuts[&i]] {
+                    CutAction::Identity => {}
+                    CutAction::Z => operations.push(single("z", vec![])),
+                    CutAction::Measure => {
+                        operations.push(Operation::Measure(qubit, clbit_count + measured));
+                        measured += 1;
+                    }
+                    CutAction::RotatePlus => operations.push(single("rz", vec![PI / 2.0])),
+                    CutAction::RotateMinus => operations.push(single("rz", vec![-PI / 2.0])),
+                }
+                if side == 1 {
+                    operations.push(single("h", vec![]));
+                }
+            }
+            Operation::Gate(gate) if local.contains_key(&gate.qubits[0]) => {
+                operations.push(Operation::Gate(Gate {
+                    qubits: gate.qubits.iter().map(|qubit| local[qubit]).collect(),
+                    ..gate.clone()
+                }));
+            }
+            Operation::Measure(qubit, clbit) if local.contains_key(qubit) => {
+                operations.push(Operation::Measure(local[qubit], *clbit));
+            }
+            Operation::Reset(qubit) if local.contains_key(qubit) => {
+                operations.push(Operation::Reset(local[qubit]));
+            }
+            Operation::Barrier(barrier) => {
+                let barrier: Vec<usize> = barrier
+                    .iter()
+                    .filter_map(|qubit| local.get(qubit).copied())
+                    .collect();
+                if !barrier.is_empty() {
+                    operations.push(Operation::Barrier(barrier));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let clbits = clbit_count + measured;
+    Circuit {
+        qregs: vec![("q".to_owned(), qubits.len())],
+        cregs: match clbits {
+            0 => vec![],
+            clbits => vec![("c".to_owned(), clbits)],
+        },
+        operations,
+    }
+}
+
+/// Read the quasi-probability distribution of the circuit bits from the
+/// result of a fragment, every outcome is weighted with the parity of its cut
+/// measurements.
+fn fragment_distribution(result: &Value, clbit_count: usize) -> Result<HashMap<u128, f64>> {
+    let memory = result
+        .get("Memory")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::Agent("fragment result has no Memory field".to_owned()))?;
+    let total: f64 = memory.values().filter_map(Value::as_f64).sum();
+    if total <= 0.0 {
+        return Err(Error::Agent("fragment result has no shots".to_owned()));
+    }
+
+    let mask = match clbit_count {
+        MAX_CLBITS => u128::MAX,
+        count => (1u128 << count) - 1,
+    };
+    let mut distribution = HashMap::new();
+    for (outcome, count) in memory {
+        let outcome = match outcome.strip_prefix("0x") {
+            Some(hex) => u128::from_str_radix(hex, 16),
+            None => u128::from_str_radix(&outcome.replace(' ', ""), 2),
+        }
+        .map_err(|_| Error::Agent(format!("invalid outcome {} of fragment", outcome)))?;
+        let sign = match outcome
+            .checked_shr(clbit_count as u32)
+            .unwrap_or(0)
+            .count_ones()
+            % 2
+        {
+            0 => 1.0,
+            _ => -1.0,
+        };
+        *distribution.entry(outcome & mask).or_default() +=
+            sign * count.as_f64().unwrap_or_default() / total;
+    }
+    Ok(distribution)
+}
+
+/// Write the outcome like the agents, the registers in the reverse order of
+/// the declarations separated by spaces, the first bit of a register is the
+/// last.
+fn format_outcome(outcome: u128, cregs: &[(String, usize)]) -> String {
+    let mut offset = 0;
+    let mut registers = vec![];
+    for (_, size) in cregs {
+        registers.push(
+            (0..*size)
+                .rev()
+                .map(|i| match (outcome >> (offset + i)) & 1 {
+                    0 => '0',
+                    _ => '1',
+                })
+                .collect::<String>(),
+        );
+        offset += size;
+    }
+    registers.reverse();
+    registers.join(" ")
+}
+
+/// ## Reconstruct
+/// Reconstruct the result of the cut circuit from the results of the child
+/// tasks by id, the counts of the outcomes are scaled to the given shots.
+pub fn reconstruct(plan: &CutPlan, results: &HashMap<Uuid, Value>, shots: i32) -> Result<Value> {
+    let clbit_count: usize = plan.cregs.iter().map(|(_, size)| size).sum();
+    let coefficients: Vec<[f64; 6]> = plan
+        .angles
+        .iter()
+        .map(|&angle| term_coefficients(angle))
+        .collect();
+
+    // the distributions of every fragment by the local operations
+    let mut distributions = vec![];
+    for fragment in &plan.fragments {
+        let mut variants = HashMap::new();
+        for variant in &fragment.variants {
+            let result = results.get(&variant.task_id).ok_or_else(|| {
+                Error::Internal(format!(
+                    "result of child task {} is missing",
+                    variant.task_id
+                ))
+            })?;
+            variants.insert(
+                variant.actions.clone(),
+                fragment_distribution(result, clbit_count)?,
+            );
+        }
+        distributions.push(variants);
+    }
+
+    let mut total: HashMap<u128, f64> = HashMap::new();
+    for index in 0..TERMS.len().pow(plan.angles.len() as u32) {
+        let terms: Vec<usize> = (0..plan.angles.len())
+            .map(|cut| index / TERMS.len().pow(cut as u32) % TERMS.len())
+            .collect();
+        let coefficient: f64 = terms
+            .iter()
+            .enumerate()
+            .map(|(cut, &term)| coefficients[cut][term])
+            .product();
+
+        // the fragments write distinct bits, so their outcomes are joined
+        let mut joint = HashMap::from([(0u128, coefficient)]);
+        for (fragment, variants) in plan.fragments.iter().zip(&distributions) {
+            let actions: Vec<CutAction> = fragment
+                .cuts
+                .iter()
+                .map(|&(cut, side)| match side {
+                    0 => TERMS[terms[cut]].0,
+                    _ => TERMS[terms[cut]].1,
+                })
+                .collect();
+            let distribution = variants.get(&actions).ok_or_else(|| {
+                Error::Internal(format!("variant {:?} of fragment is missing", actions))
+            })?;
+            let mut next = HashMap::new();
+            for (outcome, probability) in &joint {
+                for (fragment_outcome, fragment_probability) in distribution {
+                    *next.entry(outcome | fragment_outcome).or_default() +=
+                        probability * fragment_probability;
+                }
+            }
+            joint = next;
+        }
+        for (outcome, probability) in joint {
+            *total.entry(outcome).or_default() += probability;
+        }
+    }
+
+    // clip the negative quasi-probabilities and scale to the shots
+    let norm: f64 = total.values().filter(|p| **p > 0.0).sum();
+    let mut memory = serde_json::Map::new();
+    if norm > 0.0 {
+        for (outcome, probability) in total {
+            let count = (probability.max(0.0) / norm * shots as f64).round() as i64;
+            if count > 0 {
+                memory.insert(format_outcome(outcome, &plan.cregs), json!(count));
+            }
+        }
+    }
+    Ok(json!({ "Memory": memory }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The branches of the simulation, the unnormalized state and the
+    /// classical bits measured so far.
+    type Branches = Vec<(Vec<(f64, f64)>, u128)>;
+
+    /// Apply the single qubit matrix `[[a, b], [c, d]]` to the qubit.
+    fn apply(state: &mut [(f64, f64)], qubit: usize, matrix: [(f64, f64); 4]) {
+        let mul = |(a, b): (f64, f64), (c, d): (f64, f64)| (a * c - b * d, a * d + b * c);
+        let add = |(a, b): (f64, f64), (c, d): (f64, f64)| (a + c, b + d);
+        for index in 0..state.len() {
+            if index >> qubit & 1 == 0 {
+                let (zero, one) = (state[index], state[index | 1 << qubit]);
+                state[index] = add(mul(matrix[0], zero), mul(matrix[1], one));
+                state[index | 1 << qubit] = add(mul(matrix[2], zero), mul(matrix[3], one));
+            }
+        }
+    }
+
+    /// The exact distribution of the classical bits of the circuit with the
+    /// mid-circuit measurements. The single qubit gates are simulated up to
+    /// their global phase.
+    fn simulate(circuit: &Circuit) -> HashMap<u128, f64> {
+        let polar = |r: f64, angle: f64| (r * angle.cos(), r * angle.sin());
+        let mut initial = vec![(0.0, 0.0); 1 << circuit.qubit_count()];
+        initial[0] = (1.0, 0.0);
+        let mut branches: Branches = vec![(initial, 0)];
+
+        for operation in &circuit.operations {
+            match operation {
+                Operation::Gate(gate) if gate.name == "cx" => {
+                    let (control, target) = (gate.qubits[0], gate.qubits[1]);
+                    for (state, _) in &mut branches {
+                        for index in 0..state.len() {
+                            if index >> control & 1 == 1 && index >> target & 1 == 0 {
+                                state.swap(index, index | 1 << target);
+                            }
+                        }
+                    }
+                }
+                Operation::Gate(gate) => {
+                    let params = match gate.name.as_str() {
+                        "u3" => gate.params.clone(),
+                        "h" => vec![PI / 2.0, 0.0, PI],
+                        "s" => vec![0.0, 0.0, PI / 2.0],
+                        "z" => vec![0.0, 0.0, PI],
+                        "rz" => vec![0.0, 0.0, gate.params[0]],
+                        name => panic!("unexpected gate {}", name),
+                    };
+                    let (theta, phi, lambda) = (params[0], params[1], params[2]);
+                    let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+                    let matrix = [
+                        polar(cos, 0.0),
+                        polar(-sin, lambda),
+                        polar(sin, phi),
+                        polar(cos, phi + lambda),
+                    ];
+                    for (state, _) in &mut branches {
+                        apply(state, gate.qubits[0], matrix);
+                    }
+                }
+                Operation::Measure(qubit, clbit) => {
+                    let mut next = vec![];
+                    for (state, clbits) in branches {
+                        for bit in 0..2 {
+                            let mut projected = state.clone();
+                            for (index, amplitude) in projected.iter_mut().enumerate() {
+                                if index >> qubit & 1 != bit {
+                                    *amplitude = (0.0, 0.0);
+                                }
+                            }
+                            if projected.iter().any(|(re, im)| re.hypot(*im) > 1e-12) {
+                                let clbits = clbits & !(1 << clbit) | (bit as u128) << clbit;
+                                next.push((projected, clbits));
+                            }
+                        }
+                    }
+                    branches = next;
+                }
+                Operation::Barrier(_) => {}
+                operation => panic!("unexpected operation {:?}", operation),
+            }
+        }
+
+        let mut distribution = HashMap::new();
+        for (state, clbits) in branches {
+            let probability: f64 = state.iter().map(|(re, im)| re * re + im * im).sum();
+            *distribution.entry(clbits).or_default() += probability;
+        }
+        distribution
+    }
+
+    /// Cut the circuit, run every child task with its exact distribution and
+    /// compare the reconstructed counts with the exact counts of the whole
+    /// circuit.
+    fn assert_reconstructs(source: &str, capacity: usize, cut_count: usize) {
+        const SHOTS: i32 = 1_000_000;
+        let (plan, tasks) = cut_circuit(source, capacity, cut_count).unwrap();
+        assert_eq!(plan.angles.len(), cut_count);
+        assert_eq!(plan.task_count(), tasks.len());
+        assert!(tasks.iter().all(|task| task.qubits <= capacity));
+
+        let mut results = HashMap::new();
+        for task in &tasks {
+            let fragment = Circuit::parse(&task.source).unwrap();
+            let memory: serde_json::Map<String, Value> = simulate(&fragment)
+                .into_iter()
+                .map(|(outcome, probability)| {
+                    (format_outcome(outcome, &fragment.cregs), json!(probability))
+                })
+                .collect();
+            results.insert(task.id, json!({ "Memory": memory }));
+        }
+        let result = reconstruct(&plan, &results, SHOTS).unwrap();
+
+        let mut circuit = Circuit::parse(source).unwrap();
+        circuit
+            .decompose(&BTreeSet::from(["u3".to_owned(), "cx".to_owned()]))
+            .unwrap();
+        let expected = simulate(&circuit);
+        let memory = result["Memory"].as_object().unwrap();
+        for (outcome, probability) in &expected {
+            let count = (probability * SHOTS as f64).round() as i64;
+            if count > 0 {
+                let actual = memory[&format_outcome(*outcome, &circuit.cregs)]
+                    .as_i64()
+                    .unwrap();
+                assert!((actual - count).abs() <= 1, "{} != {}", actual, count);
+            }
+        }
+        assert_eq!(
+            memory.len(),
+            expected
+                .values()
+                .filter(|p| **p * SHOTS as f64 >= 0.5)
+                .count()
+        );
+    }
+
+    const BELL: &str = r#"
+        OPENQASM 2.0;
+        include "qelib1.inc";
+        qreg q[2];
+        creg c[2];
+        h q[0];
+        cx q[0], q[1];
+        measure q -> c;
+    "#;
+
+    const GHZ: &str = r#"
+        OPENQASM 2.0;
+        include "qelib1.inc";
+        qreg q[3];
+        creg c[3];
+        h q[0];
+        cx q[0], q[1];
+        cx q[1], q[2];
+        measure q -> c;
+    "#;
+
+    #[test]
+    fn reconstruct_bell_circuit() {
+        assert_reconstructs(BELL, 1, 1);
+    }
+
+    #[test]
+    fn reconstruct_ghz_circuit() {
+        assert_reconstructs(GHZ, 2, 1);
+        assert_reconstructs(GHZ, 1, 2);
+    }
+
+    #[test]
+    fn reconstruct_uneven_distribution() {
+        let source = r#"
+            OPENQASM 2.0;
+            include "qelib1.inc";
+            qreg a[2];
+            qreg b[1];
+            creg c[1];
+            creg d[2];
+            ry(0.7) a[0];
+            cx a[0], b[0];
+            ry(1.3) b[0];
+            u3(0.4, 0.2, -0.9) a[1];
+            cx b[0], a[1];
+            measure a[0] -> c[0];
+            measure b[0] -> d[1];
+            measure a[1] -> d[0];
+        "#;
+        assert_reconstructs(source, 1, 2);
+    }
+
+    #[test]
+    fn reconstruct_reports_missing_results() {
+        let (plan, _) = cut_circuit(BELL, 1, 1).unwrap();
+        assert!(matches!(
+            reconstruct(&plan, &HashMap::new(), 100),
+            Err(Error::Internal(_))
+        ));
+    }
+
+    #[test]
+    fn cut_circuit_plans_a_variant_for_every_action() {
+        let (plan, tasks) = cut_circuit(GHZ, 1, 2).unwrap();
+        assert_eq!(plan.fragments.len(), 3);
+        // the middle qubit is on both cuts
+        assert_eq!(plan.task_count(), 5 + 25 + 5);
+        let ids: BTreeSet<Uuid> = tasks.iter().map(|task| task.id).collect();
+        assert_eq!(ids.len(), tasks.len());
+        for fragment in &plan.fragments {
+            let actions: BTreeSet<Vec<_>> = fragment
+                .variants
+                .iter()
+                .map(|variant| variant.actions.iter().map(|a| *a as usize).collect())
+                .collect();
+            assert_eq!(actions.len(), fragment.variants.len());
+        }
+    }
+
+    #[test]
+    fn cut_circuit_rejects_invalid_circuits() {
+        for (source, capacity, max_cuts) in [
+            (BELL, 2, 1),
+            (GHZ, 1, 1),
+            ("OPENQASM 3.0; qubit[2] q; h q[0];", 1, 1),
+            (
+                "OPENQASM 2.0; qreg q[2]; creg c[1]; if(c==1) x q[0]; cx q[0], q[1];",
+                1,
+                1,
+            ),
+            (
+                "OPENQASM 2.0; qreg q[2]; creg c[1]; measure q[0] -> c[0]; measure q[1] -> c[0];",
+                1,
+                1,
+            ),
+        ] {
+            assert!(matches!(
+                cut_circuit(source, capacity, max_cuts),
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn partition_joins_the_most_connected_qubits() {
+        let circuit = Circuit::parse(
+            "OPENQASM 2.0; qreg q[5]; cx q[0], q[2]; cx q[2], q[0]; cx q[0], q[2]; \
+             cx q[1], q[3]; cx q[3], q[1]; cx q[0], q[1]; cx q[3], q[4];",
+        )
+        .unwrap();
+        let groups = partition(&circuit, 2);
+        assert_eq!(groups[0], groups[2]);
+        assert_eq!(groups[1], groups[3]);
+        assert_ne!(groups[0], groups[1]);
+        assert!(groups.iter().all(|&group| group < 3));
+
+        let groups = partition(&circuit, 4);
+        assert_eq!(groups[0], groups[1]);
+        assert_eq!(groups[0], groups[2]);
+        assert_eq!(groups[0], groups[3]);
+        assert_ne!(groups[0], groups[4]);
+    }
+
+    #[test]
+    fn fragment_circuit_replaces_the_cut_gate() {
+        let circuit = Circuit::parse(BELL).unwrap();
+        let cuts = HashMap::from([(1, 0)]);
+        let target = fragment_circuit(
+            &circuit,
+            &[1],
+            &cuts,
+            &HashMap::from([(0, CutAction::Measure)]),
+            2,
+        );
+        assert_eq!(target.qubit_count(), 1);
+        assert_eq!(target.cregs, vec![("c".to_owned(), 3)]);
+        let names: Vec<String> = target
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Gate(gate) => gate.name.clone(),
+                Operation::Measure(qubit, clbit) => format!("measure {} {}", qubit, clbit),
+                operation => panic!("unexpected operation {:?}", operation),
+            })
+            .collect();
+        assert_eq!(names, ["h", "s", "measure 0 2", "h", "measure 0 1"]);
+
+        let control = fragment_circuit(
+            &circuit,
+            &[0],
+            &cuts,
+            &HashMap::from([(0, CutAction::RotateMinus)]),
+            2,
+        );
+        assert_eq!(control.cregs, vec![("c".to_owned(), 2)]);
+        assert!(matches!(
+            &control.operations[2],
+            Operation::Gate(gate) if gate.name == "rz" && gate.params == [-PI / 2.0]
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+//! The module that discovers the agents deployed in Kubernetes. The replicas
+//! behind the configured service are found by a [Resolver] every `interval`
+//! seconds:
+//! - [DnsResolver] resolves all the A records of the service name, which
+//!   requires a headless service.
+//! - [EndpointsResolver] reads the ready addresses of the Endpoints of the
+//!   service through the Kubernetes API, the service account of the server
+//!   needs the permission to get the Endpoints.
+//!
+//! A new replica is added as a physical agent, a disappeared replica is
+//! marked as down and removed after its running tasks are finished. Only the
+//! agents added by the discovery are removed, the agents added by the agent
+//! file or the api are not touched.
+
+use crate::config::{DiscoveryConfig, DiscoveryMode};
+use crate::dispatch;
+use crate::entity;
+use crate::entity::sea_orm_active_enums::PhysicalAgentStatus;
+use crate::error::{Error, Result};
+use crate::leader;
+use crate::service;
+use log::{error, info};
+use sea_orm::DbConn;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::future::Future;
+
+/// ## Resolver
+/// Resolve the ip addresses of all the replicas of the service. The
+/// [sync_agents] function is generic over the resolver, so that the
+/// discovery can be driven by a stub resolver.
+pub trait Resolver {
+    fn resolve(&self) -> impl Future<Output = Result<BTreeSet<String>>> + Send;
+}
+
+/// ## DNS Resolver
+/// Resolve all the A records of the service name.
+pub struct DnsResolver {
+    service: String,
+    port: u16,
+}
+
+impl DnsResolver {
+    pub fn new(config: &DiscoveryConfig) -> Self {
+        Self {
+            service: config.service.clone(),
+            port: config.port as u16,
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    async fn resolve(&self) -> Result<BTreeSet<String>> {
+        Ok(tokio::net::lookup_host((self.service.as_str(), self.port))
+            .await
+            .map_err(|err| Error::HostResolution {
+                hostname: self.service.clone(),
+                reason: err.to_string(),
+            })?
+            .map(|address| address.ip().to_string())
+            .collect())
+    }
+}
+
+/// The directory of the service account mounted into the pod.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// ## Endpoints Resolver
+/// Read the ready addresses of the Endpoints of the service through the
+/// Kubernetes API with the service account of the pod.
+pub struct EndpointsResolver {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl EndpointsResolver {
+    /// Create the resolver from the in-cluster configuration, that is, the
+    /// `KUBERNETES_SERVICE_HOST` and `KUBERNETES_SERVICE_PORT` environment
+    /// variables and the mounted service account.
+    pub fn in_cluster(config: &DiscoveryConfig) -> Result<Self> {
+        let env = |name: &str| {
+            std::env::var(name)
+                .map_err(|_| Error::Internal(format!("{} is not set, not running in a pod", name)))
+        };
+        let read = |name: &str| {
+            std::fs::read(format!("{}/{}", SERVICE_ACCOUNT_DIR, name))
+                .map_err(|err| Error::Internal(format!("read service account {}: {}", name, err)))
+        };
+
+        let namespace = match &config.namespace {
+            Some(namespace) => namespace.clone(),
+            None => String::from_utf8_lossy(&read("namespace")?)
+                .trim()
+                .to_owned(),
+        };
+        let certificate = reqwest::Certificate::from_pem(&read("ca.crt")?)?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(certificate)
+            .build()?;
+
+        Ok(Self {
+            client,
+            url: format!(
+                "https://{}:{}/api/v1/namespaces/{}/endpoints/{}",
+                env("KUBERNETES_SERVICE_HOST")?,
+                env("KUBERNETES_SERVICE_PORT")?,
+                namespace,
+                config.service
+            ),
+        })
+    }
+}
+
+impl Resolver for EndpointsResolver {
+    async fn resolve(&self) -> Result<BTreeSet<String>> {
+        // the token is rotated by the kubelet, read it for every request
+        let token = std::fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT_DIR))
+            .map_err(|err| Error::Internal(format!("read service account token: {}", err)))?;
+        let endpoints = self
+            .client
+            .get(&self.url)
+            .bearer_auth(token.trim())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        Ok(endpoints["subsets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|subset| subset["addresses"].as_array().into_iter().flatten())
+            .filter_map(|address| address["ip"].as_str().map(str::to_owned))
+            .collect())
+    }
+}
+
+/// ## Sync Agents
+/// Resolve the replicas of the service and make the discovered agents match
+/// them:
+/// - A replica without agent is added as a running agent.
+/// - A replica whose agent was marked as down by the discovery is set to
+///   running again, an agent set down by the operator stays down.
+/// - An agent whose replica is gone is marked as down, and removed once it
+///   has no live [capacity reservation](service::capacity::Capacity).
+pub async fn sync_agents<R: Resolver>(
+    db: &DbConn,
+    resolver: &R,
+    config: &DiscoveryConfig,
+) -> Result<()> {
+    let ips = resolver.resolve().await?;
+    let agents = service::physical_agent::PhysicalAgent::get_physical_agent_by_discovery(
+        db,
+        &config.service,
+    )
+    .await?;
+
+    for ip in &ips {
+        match agents.iter().find(|agent| &agent.ip == ip) {
+            Some(agent) if agent.status == PhysicalAgentStatus::Down => {
+                if service::physical_agent::PhysicalAgent::revive_physical_agent(db, agent.id)
+                    .await?
+                {
+                    info!("Discovered agent {}:{} is back", agent.ip, agent.port);
+                    dispatch::wake(db).await;
+                }
+            }
+            Some(_) => {}
+            None => {
+                let mut agent = entity::physical_agent::Model {
+                    id: uuid::Uuid::new_v4(),
+                    status: PhysicalAgentStatus::Running,
+                    ip: ip.clone(),
+                    port: config.port as i32,
+                    qubit_count: config.qubit_count as i32,
+                    qubit_idle: config.qubit_count as i32,
+                    circuit_depth: config.circuit_depth as i32,
+                    lease_expire_time: None,
+                    discovery: Some(config.service.clone()),
+                    simulator: String::new(),
+                    gates: None,
+                    noise: false,
+                    max_shots: None,
+                    memory_mb: None,
+                    qasm3: false,
+                    memory_idle_mb: None,
+                    slots: None,
+                    slots_idle: None,
+                    labels: None,
+                    pool: None,
+                    lost_time: None,
+                };
+                config.capability.clone().apply_to(&mut agent);
+                match service::physical_agent::PhysicalAgent::add_physical_agent(db, agent).await {
+                    Ok(agent) => {
+                        info!("Discovered agent {}:{} added", agent.ip, agent.port);
+                        dispatch::wake(db).await;
+                    }
+                    // e.g. the agent is already added by the agent file
+                    Err(err) => error!("Add discovered agent {} failed: {}", ip, err),
+                }
+            }
+        }
+    }
+
+    for agent in agents.iter().filter(|agent| !ips.contains(&agent.ip)) {
+        if service::physical_agent::PhysicalAgent::check_physical_agent_idle(db, agent.id).await? {
+            service::physical_agent::PhysicalAgent::remove_physical_agent(db, agent.id).await?;
+            info!("Discovered agent {}:{} removed", agent.ip, agent.port);
+        } else if service::physical_agent::PhysicalAgent::mark_physical_agent_lost(
+            db,
+            agent.id,
+            chrono::Utc::now().naive_utc(),
+        )
+        .await?
+        {
+            info!(
+                "Discovered agent {}:{} is gone, drain it before removing",
+                agent.ip, agent.port
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// ## Discover Agents
+/// Sync the agents with the replicas of the service every `interval` seconds
+/// with the resolver of the configured mode. Any error is logged and the
+/// discovery is retried in the next round.
+pub async fn discover_agents(db: DbConn, config: DiscoveryConfig) {
+    info!(
+        "Discover agents of service {} by {:?}",
+        config.service, config.mode
+    );
+    match config.mode {
+        DiscoveryMode::Dns => run(&db, &DnsResolver::new(&config), &config).await,
+        DiscoveryMode::Endpoints => match EndpointsResolver::in_cluster(&config) {
+            Ok(resolver) => run(&db, &resolver, &config).await,
+            Err(err) => error!("Create the endpoints resolver failed: {}", err),
+        },
+    }
+}
+
+async fn run<R: Resolver>(db: &DbConn, resolver: &R, config: &DiscoveryConfig) {
+    loop {
+        // the agents are synced by the leader only if several replicas share the database
+        if leader::is_leader() {
+            if let Err(err) = sync_agents(db, resolver, config).await {
+                error!(
+                    "Discover agents of service {} failed: {}",
+                    config.service, err
+                );
+            }
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(config.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entity::{capacity_reservation, physical_agent};
+    use sea_orm::{
+        ActiveModelTrait, ConnectOptions, ConnectionTrait, Database, EntityTrait, Schema,
+    };
+
+    /// The resolver that returns the given addresses, or an error if there is
+    /// none.
+    struct StubResolver(Option<Vec<&'static str>>);
+
+    impl Resolver for StubResolver {
+        async fn resolve(&self) -> Result<BTreeSet<String>> {
+            match &self.0 {
+                Some(ips) => Ok(ips.iter().map(|ip| ip.to_string()).collect()),
+                None => Err(Error::HostResolution {
+                    hostname: "agents".to_owned(),
+                    reason: "stub".to_owned(),
+                }),
+            }
+        }
+    }
+
+    fn config() -> DiscoveryConfig {
+        serde_json::from_value(serde_json::json!({
+            "service": "agents",
+            "port": 8080,
+            "qubit_count": 20,
+            "circuit_depth": 100,
+        }))
+        .unwrap()
+    }
+
+    /// The in-memory database with the physical agent and the capacity
+    /// reservation tables, and the given agents in it.
+    async fn database(agents: &[physical_agent::Model]) -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        for statement in [
+            schema.create_table_from_entity(physical_agent::Entity),
+            schema.create_table_from_entity(capacity_reservation::Entity),
+        ] {
+            db.execute(backend.build(&statement)).await.unwrap();
+        }
+        for agent in agents {
+            physical_agent::ActiveModel::from(agent.clone())
+                .insert(&db)
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    fn agent(
+        ip: &str,
+        discovery: Option<&str>,
+        status: PhysicalAgentStatus,
+    ) -> physical_agent::Model {
+        physical_agent::Model {
+            id: uuid::Uuid::new_v4(),
+            status,
+            ip: ip.to_owned(),
+            port: 8080,
+            qubit_count: 20,
+            qubit_idle: 20,
+            circuit_depth: 100,
+            lease_expire_time: None,
+            discovery: discovery.map(str::to_owned),
+            simulator: String::new(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb: None,
+            qasm3: false,
+            memory_idle_mb: None,
+            slots: None,
+            slots_idle: None,
+            labels: None,
+            pool: None,
+            lost_time: None,
+        }
+    }
+
+    /// Reserve 8 qubits of the agent for a running chunk.
+    async fn reserve(db: &DbConn, agent: &physical_agent::Model) -> capacity_reservation::Model {
+        capacity_reservation::ActiveModel::from(capacity_reservation::Model {
+            id: uuid::Uuid::new_v4(),
+            agent_id: agent.id,
+            task_id: uuid::Uuid::new_v4(),
+            qubits: 8,
+            memory_mb: 0,
+            created_time: chrono::Utc::now().naive_utc(),
+            expected_end_time: None,
+        })
+        .insert(db)
+        .await
+        .unwrap()
+    }
+
+    async fn find(db: &DbConn, ip: &str) -> Option<physical_agent::Model> {
+        physical_agent::Entity::find()
+            .all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|agent| agent.ip == ip)
+    }
+
+    #[tokio::test]
+    async fn sync_agents_registers_updates_and_removes() {
+        let back = physical_agent::Model {
+            lost_time: Some(chrono::Utc::now().naive_utc()),
+            ..agent("10.0.0.2", Some("agents"), PhysicalAgentStatus::Down)
+        };
+        let busy = agent("10.0.0.3", Some("agents"), PhysicalAgentStatus::Running);
+        // the idle qubits are not used, only the live reservations are
+        let idle = physical_agent::Model {
+            qubit_idle: 12,
+            ..agent("10.0.0.4", Some("agents"), PhysicalAgentStatus::Running)
+        };
+        let manual = agent("10.0.0.5", None, PhysicalAgentStatus::Running);
+        let db = database(&[back.clone(), busy.clone(), idle, manual]).await;
+        let reservation = reserve(&db, &busy).await;
+
+        let resolver = StubResolver(Some(vec!["10.0.0.1", "10.0.0.2"]));
+        sync_agents(&db, &resolver, &config()).await.unwrap();
+
+        // the new replica is added with the configured size
+        let added = find(&db, "10.0.0.1").await.unwrap();
+        assert_eq!(added.status, PhysicalAgentStatus::Running);
+        assert_eq!(added.discovery.as_deref(), Some("agents"));
+        assert_eq!(
+            (added.port, added.qubit_count, added.qubit_idle),
+            (8080, 20, 20)
+        );
+        assert_eq!(added.circuit_depth, 100);
+        // the replica that is back is running again
+        let back = find(&db, "10.0.0.2").await.unwrap();
+        assert_eq!(back.status, PhysicalAgentStatus::Running);
+        assert_eq!(back.lost_time, None);
+        // the gone replica with running tasks is drained first
+        let busy = find(&db, "10.0.0.3").await.unwrap();
+        assert_eq!(busy.status, PhysicalAgentStatus::Down);
+        assert!(busy.lost_time.is_some());
+        // the gone idle replica is removed, the manual agent is kept
+        assert!(find(&db, "10.0.0.4").await.is_none());
+        assert!(find(&db, "10.0.0.5").await.is_some());
+
+        // the drained replica is removed once its tasks are finished
+        capacity_reservation::Entity::delete_by_id(reservation.id)
+            .exec(&db)
+            .await
+            .unwrap();
+        sync_agents(&db, &resolver, &config()).await.unwrap();
+        assert!(find(&db, "10.0.0.3").await.is_none());
+        assert_eq!(
+            physical_agent::Entity::find().all(&db).await.unwrap().len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_agents_keeps_the_agents_set_down_by_the_operator() {
+        let stopped = agent("10.0.0.1", Some("agents"), PhysicalAgentStatus::Down);
+        let gone = agent("10.0.0.2", Some("agents"), PhysicalAgentStatus::Running);
+        let db = database(&[stopped, gone.clone()]).await;
+        let reservation = reserve(&db, &gone).await;
+
+        // the replica of the stopped agent is up, it stays down
+        let resolver = StubResolver(Some(vec!["10.0.0.1"]));
+        sync_agents(&db, &resolver, &config()).await.unwrap();
+        let stopped = find(&db, "10.0.0.1").await.unwrap();
+        assert_eq!(stopped.status, PhysicalAgentStatus::Down);
+        assert_eq!(stopped.lost_time, None);
+
+        // the operator stops the busy agent whose replica is gone, it is not
+        // revived when its replica is back
+        let gone = find(&db, "10.0.0.2").await.unwrap();
+        assert!(gone.lost_time.is_some());
+        service::physical_agent::PhysicalAgent::update_physical_agent(
+            &db,
+            gone.id,
+            None,
+            None,
+            None,
+            None,
+            Some(PhysicalAgentStatus::Down),
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let resolver = StubResolver(Some(vec!["10.0.0.1", "10.0.0.2"]));
+        sync_agents(&db, &resolver, &config()).await.unwrap();
+        let gone = find(&db, "10.0.0.2").await.unwrap();
+        assert_eq!(gone.status, PhysicalAgentStatus::Down);
+        assert_eq!(gone.lost_time, None);
+        assert!(capacity_reservation::Entity::find_by_id(reservation.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn sync_agents_fails_without_touching_agents() {
+        let gone = agent("10.0.0.1", Some("agents"), PhysicalAgentStatus::Running);
+        let db = database(std::slice::from_ref(&gone)).await;
+        assert!(sync_agents(&db, &StubResolver(None), &config())
+            .await
+            .is_err());
+        assert_eq!(find(&db, "10.0.0.1").await, Some(gone));
+    }
+}
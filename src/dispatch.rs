@@ -0,0 +1,95 @@
+//! The module that wakes the consume loop when a waiting task may be
+//! dispatched: a task is submitted, a chunk finishes and frees the qubits of
+//! its agent, or an agent comes online. The consume loop of the same process
+//! is woken by an in-process notification. If `dispatch.pg_notify` is enabled
+//! in the configuration, the events are also published with Postgres
+//! `NOTIFY` on the [CHANNEL], and every replica [listens](listen) to it, so a
+//! task submitted to one replica is dispatched by the others at once. An
+//! event carries the [id](replica_id) of the replica that publishes it, so
+//! the listener of that replica skips it, as its consume loop is already
+//! woken. The periodic scan of the consume loop remains as a fallback for
+//! lost events.
+
+use log::{error, info};
+use sea_orm::{ConnectionTrait, DbConn};
+use sqlx::postgres::PgListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use tokio::sync::Notify;
+use tokio::time::Duration;
+
+/// The Postgres channel of the dispatch events.
+pub const CHANNEL: &str = "qsched_dispatch";
+
+/// The wakeup of the consume loop of this process. A wakeup while the loop is
+/// scanning is kept, so the loop scans again right after.
+static WAKEUP: Notify = Notify::const_new();
+
+/// Whether the events are published to the other replicas, it is set when
+/// this replica starts to listen.
+static PG_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// The random id of this replica, the payload of the events it publishes.
+static REPLICA_ID: LazyLock<String> = LazyLock::new(|| uuid::Uuid::new_v4().to_string());
+
+/// ## Replica Id
+/// The id of this replica in the payload of its dispatch events. The events
+/// are published on pooled connections, so the backend process of the
+/// notification does not tell the replica.
+fn replica_id() -> &'static str {
+    &REPLICA_ID
+}
+
+/// ## Wake
+/// Wake the consume loop of this process and, if Postgres notification is
+/// enabled, of the other replicas. A failed notification is logged, the other
+/// replicas then find the task in their fallback scan.
+pub async fn wake(db: &DbConn) {
+    WAKEUP.notify_one();
+    if PG_NOTIFY.load(Ordering::Relaxed) {
+        let notify = format!("NOTIFY {}, '{}'", CHANNEL, replica_id());
+        if let Err(err) = db.execute_unprepared(&notify).await {
+            error!("Notify dispatch channel failed: {}", err);
+        }
+    }
+}
+
+/// ## Wait
+/// Wait until the consume loop is woken, or the fallback interval has passed.
+pub async fn wait(interval: Duration) {
+    tokio::select! {
+        _ = WAKEUP.notified() => {}
+        _ = tokio::time::sleep(interval) => {}
+    }
+}
+
+/// ## Listen
+/// Listen to the dispatch events of all the replicas with Postgres `LISTEN`,
+/// and wake the consume loop of this process on every event of the other
+/// replicas. The listener reconnects after the fallback interval if the
+/// connection is lost.
+pub async fn listen(db_url: String, interval: Duration) {
+    PG_NOTIFY.store(true, Ordering::Relaxed);
+    loop {
+        let listened = async {
+            let mut listener = PgListener::connect(&db_url).await?;
+            listener.listen(CHANNEL).await?;
+            info!("Listen to the dispatch channel {}", CHANNEL);
+            loop {
+                let notification = listener.recv().await?;
+                // this replica has woken its consume loop when it published the event
+                if notification.payload() == replica_id() {
+                    continue;
+                }
+                WAKEUP.notify_one();
+            }
+        }
+        .await;
+        if let Err::<(), sqlx::Error>(err) = listened {
+            error!("Listen to the dispatch channel failed: {}", err);
+        }
+        // scan for the events that may be lost while reconnecting
+        WAKEUP.notify_one();
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -2,8 +2,13 @@
 
 pub mod prelude;
 
+pub mod agent_drain;
+pub mod agent_reservation;
+pub mod capacity_reservation;
+pub mod circuit;
 pub mod physical_agent;
 pub mod sea_orm_active_enums;
 pub mod task;
 pub mod task_active;
 pub mod task_assignment;
+pub mod usage_record;
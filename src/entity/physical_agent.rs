@@ -3,8 +3,10 @@
 use super::sea_orm_active_enums::PhysicalAgentStatus;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, ToSchema)]
+#[schema(as = PhysicalAgent)]
 #[sea_orm(table_name = "physical_agent")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
@@ -15,6 +17,24 @@ pub struct Model {
     pub qubit_count: i32,
     pub qubit_idle: i32,
     pub circuit_depth: i32,
+    pub lease_expire_time: Option<DateTime>,
+    pub discovery: Option<String>,
+    pub simulator: String,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    #[schema(value_type = Option<Vec<String>>)]
+    pub gates: Option<Json>,
+    pub noise: bool,
+    pub max_shots: Option<i32>,
+    pub memory_mb: Option<i64>,
+    pub qasm3: bool,
+    pub memory_idle_mb: Option<i64>,
+    pub slots: Option<i32>,
+    pub slots_idle: Option<i32>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    #[schema(value_type = Option<Object>)]
+    pub labels: Option<Json>,
+    pub pool: Option<String>,
+    pub lost_time: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,6 +1,11 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.12
 
+pub use super::agent_drain::Entity as AgentDrain;
+pub use super::agent_reservation::Entity as AgentReservation;
+pub use super::capacity_reservation::Entity as CapacityReservation;
+pub use super::circuit::Entity as Circuit;
 pub use super::physical_agent::Entity as PhysicalAgent;
 pub use super::task::Entity as Task;
 pub use super::task_active::Entity as TaskActive;
 pub use super::task_assignment::Entity as TaskAssignment;
+pub use super::usage_record::Entity as UsageRecord;
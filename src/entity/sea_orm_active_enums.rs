@@ -2,8 +2,11 @@
 
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize, ToSchema,
+)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "assignment_status")]
 pub enum AssignmentStatus {
     #[sea_orm(string_value = "failed")]
@@ -13,7 +16,23 @@ pub enum AssignmentStatus {
     #[sea_orm(string_value = "succeeded")]
     Succeeded,
 }
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize, ToSchema,
+)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "drain_status")]
+pub enum DrainStatus {
+    #[sea_orm(string_value = "cancelled")]
+    Cancelled,
+    #[sea_orm(string_value = "drained")]
+    Drained,
+    #[sea_orm(string_value = "draining")]
+    Draining,
+    #[sea_orm(string_value = "timed_out")]
+    TimedOut,
+}
+#[derive(
+    Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize, ToSchema,
+)]
 #[sea_orm(
     rs_type = "String",
     db_type = "Enum",
@@ -22,10 +41,14 @@ pub enum AssignmentStatus {
 pub enum PhysicalAgentStatus {
     #[sea_orm(string_value = "down")]
     Down,
+    #[sea_orm(string_value = "draining")]
+    Draining,
     #[sea_orm(string_value = "running")]
     Running,
 }
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize, ToSchema,
+)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "task_active_status")]
 pub enum TaskActiveStatus {
     #[sea_orm(string_value = "running")]
@@ -33,9 +56,13 @@ pub enum TaskActiveStatus {
     #[sea_orm(string_value = "waiting")]
     Waiting,
 }
-#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize, ToSchema,
+)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "task_status")]
 pub enum TaskStatus {
+    #[sea_orm(string_value = "expired")]
+    Expired,
     #[sea_orm(string_value = "failed")]
     Failed,
     #[sea_orm(string_value = "succeeded")]
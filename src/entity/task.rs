@@ -3,8 +3,10 @@
 use super::sea_orm_active_enums::TaskStatus;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, ToSchema)]
+#[schema(as = Task)]
 #[sea_orm(table_name = "task")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
@@ -17,6 +19,19 @@ pub struct Model {
     pub status: TaskStatus,
     pub created_time: DateTime,
     pub updated_time: DateTime,
+    pub username: String,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    #[schema(value_type = Option<NoiseModel>)]
+    pub noise: Option<Json>,
+    pub source_hash: Option<String>,
+    pub circuit: Option<String>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    #[schema(value_type = Option<Object>)]
+    pub parameters: Option<Json>,
+    pub parent_id: Option<Uuid>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    #[schema(value_type = Option<Object>)]
+    pub cutting: Option<Json>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
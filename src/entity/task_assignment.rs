@@ -3,8 +3,10 @@
 use super::sea_orm_active_enums::AssignmentStatus;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, ToSchema)]
+#[schema(as = TaskAssignment)]
 #[sea_orm(table_name = "task_assignment")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
@@ -13,6 +15,7 @@ pub struct Model {
     pub task_id: Uuid,
     pub shots: Option<i32>,
     pub status: AssignmentStatus,
+    pub created_time: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,276 @@
+//! The module that contains the error type of the emulator server. Every
+//! fallible function in the [router](crate::router) and
+//! [service](crate::service) modules returns [Error], which is mapped to an
+//! HTTP status code and a uniform JSON body when it is returned by a handler:
+//! ```json
+//! {
+//!     "code": "not_found",
+//!     "message": "task 6a0f... not found",
+//!     "details": { "resource": "task", "id": "6a0f..." }
+//! }
+//! ```
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header, HeaderValue, StatusCode};
+use log::error;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+use utoipa::ToSchema;
+
+/// The result type used by the router and service modules.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// ## Error
+/// The crate-wide error type. Each variant maps to one HTTP status code:
+/// - `NotFound`: 404, the requested task, agent or assignment does not exist.
+/// - `InvalidRequest`: 400, the request body or query can not be parsed or is
+///   semantically wrong.
+/// - `Unauthorized`: 401, the request does not carry the header of the user
+///   that is required by the configuration.
+/// - `UnsupportedContentType`: 415, the content type is neither
+///   `application/json` nor `application/x-www-form-urlencoded`.
+/// - `Conflict`: 409, the resource already exists, e.g. an agent with the same
+///   ip and port.
+/// - `NoAvailableAgent`: 422, no agent is big enough to run the task.
+/// - `TaskTooLarge`: 413, the task exceeds a per task limit such as the
+///   maximum shots or code size, retrying the same task will not help.
+/// - `TooManyRequests`: 429, a queue limit is reached, the response carries
+///   the `Retry-After` header.
+/// - `QuotaExceeded`: 429, the user has used up a daily or monthly quota, the
+///   response carries the `Retry-After` header with the seconds until the
+///   quota is reset.
+/// - `UnsupportedQasm`: 400, the OpenQASM 3 program can not be translated to
+///   OpenQASM 2 and no agent runs OpenQASM 3, the details list the
+///   [diagnostics](crate::qasm::Diagnostic).
+/// - `HostResolution`: 400, the agent hostname can not be resolved.
+/// - `Agent`: 502, the agent can not be reached or returns an invalid result.
+/// - `Database`: 500, the database operation fails.
+/// - `Internal`: 500, any other unexpected error.
+#[derive(Debug)]
+pub enum Error {
+    NotFound {
+        resource: &'static str,
+        id: String,
+    },
+    InvalidRequest(String),
+    Unauthorized {
+        header: String,
+    },
+    UnsupportedContentType(Option<String>),
+    Conflict(String),
+    NoAvailableAgent {
+        qubits: i32,
+        depth: i32,
+    },
+    TaskTooLarge {
+        limit: &'static str,
+        max: u64,
+        actual: u64,
+    },
+    TooManyRequests {
+        limit: &'static str,
+        retry_after: u64,
+    },
+    QuotaExceeded {
+        quota: &'static str,
+        used: i64,
+        max: i64,
+        retry_after: u64,
+    },
+    UnsupportedQasm(Vec<crate::qasm::Diagnostic>),
+    HostResolution {
+        hostname: String,
+        reason: String,
+    },
+    Agent(String),
+    Database(sea_orm::DbErr),
+    Internal(String),
+}
+
+impl Error {
+    /// Build a [Error::NotFound] error for the given resource and id.
+    pub fn not_found(resource: &'static str, id: impl ToString) -> Self {
+        Error::NotFound {
+            resource,
+            id: id.to_string(),
+        }
+    }
+
+    /// The HTTP status code of the error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound { .. } => StatusCode::NOT_FOUND,
+            Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            Error::UnsupportedContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::NoAvailableAgent { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::TaskTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::TooManyRequests { .. } | Error::QuotaExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::UnsupportedQasm(_) | Error::HostResolution { .. } => StatusCode::BAD_REQUEST,
+            Error::Agent(_) => StatusCode::BAD_GATEWAY,
+            Error::Database(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The machine readable code of the error, used as the `code` field of the
+    /// response body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "not_found",
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::Unauthorized { .. } => "unauthorized",
+            Error::UnsupportedContentType(_) => "unsupported_content_type",
+            Error::Conflict(_) => "conflict",
+            Error::NoAvailableAgent { .. } => "no_available_agent",
+            Error::TaskTooLarge { .. } => "task_too_large",
+            Error::TooManyRequests { .. } => "too_many_requests",
+            Error::QuotaExceeded { .. } => "quota_exceeded",
+            Error::UnsupportedQasm(_) => "unsupported_qasm",
+            Error::HostResolution { .. } => "host_resolution_failed",
+            Error::Agent(_) => "agent_error",
+            Error::Database(_) => "database_error",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The structured details of the error, used as the `details` field of
+    /// the response body.
+    pub fn details(&self) -> Value {
+        match self {
+            Error::NotFound { resource, id } => json!({"resource": resource, "id": id}),
+            Error::Unauthorized { header } => json!({"header": header}),
+            Error::UnsupportedContentType(content_type) => json!({"content_type": content_type}),
+            Error::NoAvailableAgent { qubits, depth } => json!({"qubits": qubits, "depth": depth}),
+            Error::TaskTooLarge { limit, max, actual } => {
+                json!({"limit": limit, "max": max, "actual": actual})
+            }
+            Error::TooManyRequests { limit, retry_after } => {
+                json!({"limit": limit, "retry_after": retry_after})
+            }
+            Error::QuotaExceeded {
+                quota,
+                used,
+                max,
+                retry_after,
+            } => json!({"quota": quota, "used": used, "max": max, "retry_after": retry_after}),
+            Error::UnsupportedQasm(diagnostics) => json!({"diagnostics": diagnostics}),
+            Error::HostResolution { hostname, .. } => json!({"hostname": hostname}),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound { resource, id } => write!(f, "{} {} not found", resource, id),
+            Error::InvalidRequest(msg) => write!(f, "invalid request: {}", msg),
+            Error::Unauthorized { header } => write!(f, "user header {} is missing", header),
+            Error::UnsupportedContentType(Some(content_type)) => {
+                write!(f, "content type {} not supported", content_type)
+            }
+            Error::UnsupportedContentType(None) => write!(f, "content type not specified"),
+            Error::Conflict(msg) => write!(f, "{}", msg),
+            Error::NoAvailableAgent { qubits, depth } => write!(
+                f,
+                "no available physical agent for {} qubits and depth {}",
+                qubits, depth
+            ),
+            Error::TaskTooLarge { limit, max, actual } => {
+                write!(f, "task exceeds {}: {} > {}", limit, actual, max)
+            }
+            Error::TooManyRequests { limit, .. } => {
+                write!(f, "{} reached, please retry later", limit)
+            }
+            Error::QuotaExceeded {
+                quota, used, max, ..
+            } => {
+                write!(f, "{} exceeded: used {} of {}", quota, used, max)
+            }
+            Error::UnsupportedQasm(diagnostics) => {
+                write!(f, "OpenQASM 3 program can not be translated to OpenQASM 2:")?;
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { "; " };
+                    write!(
+                        f,
+                        "{}{}:{}: {}",
+                        separator, diagnostic.line, diagnostic.column, diagnostic.message
+                    )?;
+                }
+                Ok(())
+            }
+            Error::HostResolution { hostname, reason } => {
+                write!(f, "resolve hostname {} failed: {}", hostname, reason)
+            }
+            Error::Agent(msg) => write!(f, "agent error: {}", msg),
+            Error::Database(err) => write!(f, "database error: {}", err),
+            Error::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sea_orm::DbErr> for Error {
+    fn from(err: sea_orm::DbErr) -> Self {
+        Error::Database(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Agent(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
+
+/// ## Error Response
+/// The JSON body of every failed request.
+/// - `code`: The machine readable [code](Error::code) of the error.
+/// - `message`: The human readable message of the error.
+/// - `details`: The structured details of the error, `null` if there is none.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: String,
+    #[schema(value_type = Object)]
+    pub details: Value,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("{}", self);
+        }
+        let mut response = (
+            status,
+            Json(ErrorResponse {
+                code: self.code(),
+                message: self.to_string(),
+                details: self.details(),
+            }),
+        )
+            .into_response();
+        if let Error::TooManyRequests { retry_after, .. }
+        | Error::QuotaExceeded { retry_after, .. } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
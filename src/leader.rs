@@ -0,0 +1,92 @@
+//! The module that elects the replica that runs the consume loop. The HTTP
+//! API is served by every replica, but a waiting task must be dispatched by
+//! one of them only. If `leader.enabled` is set in the configuration, every
+//! replica tries to take a Postgres advisory lock on a connection of its own,
+//! and the replica that holds the lock is the leader. The lock is released by
+//! Postgres when the connection of the leader is closed, so a follower takes
+//! over within the retry interval after the leader is gone. The leader checks
+//! its connection at the same interval, and steps down at once if it is lost.
+
+use crate::dispatch;
+use crate::router::task::requeue_tasks;
+use crate::service;
+use log::{error, info};
+use sea_orm::DbConn;
+use sqlx::{Connection, PgConnection};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::Duration;
+
+/// Whether this replica takes part in the election.
+static ELECTION: AtomicBool = AtomicBool::new(false);
+
+/// Whether this replica holds the advisory lock.
+static LEADER: AtomicBool = AtomicBool::new(false);
+
+/// ## Is Leader
+/// Whether this replica is the leader and may dispatch the waiting tasks.
+/// Without the election, the only replica is always the leader.
+pub fn is_leader() -> bool {
+    !ELECTION.load(Ordering::Relaxed) || LEADER.load(Ordering::Relaxed)
+}
+
+/// Hold the advisory lock until the connection is lost. Return `Ok` if the
+/// lock is held by another replica.
+async fn lead(
+    db: &DbConn,
+    db_url: &str,
+    lock_key: i64,
+    interval: Duration,
+) -> Result<(), sqlx::Error> {
+    let mut conn = PgConnection::connect(db_url).await?;
+    let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+        .bind(lock_key)
+        .fetch_one(&mut conn)
+        .await?;
+    if !locked {
+        return conn.close().await;
+    }
+
+    info!("This replica is elected as the leader");
+    take_over(db).await;
+    LEADER.store(true, Ordering::Relaxed);
+    loop {
+        tokio::time::sleep(interval).await;
+        conn.ping().await?;
+    }
+}
+
+/// ## Take Over
+/// Take over the consume loop: the capacity reservations left by the previous
+/// leader, or by this process before a restart, are released if they are
+/// stale and the idle capacity of the agents is
+/// [rebuilt](crate::service::capacity::Capacity::rebuild_all), then the tasks
+/// left running are [requeued](crate::router::task::requeue_tasks) and
+/// dispatched again.
+pub async fn take_over(db: &DbConn) {
+    if let Err(err) = service::capacity::Capacity::rebuild_all(db).await {
+        error!("Rebuild idle qubits of physical agents failed: {}", err);
+    }
+    requeue_tasks(db).await;
+    dispatch::wake(db).await;
+}
+
+/// ## Start Election
+/// Take part in the election of the leader until the process exits. This
+/// replica is a follower until it takes the lock.
+pub fn start_election(db: DbConn, db_url: String, lock_key: i64, interval: Duration) {
+    ELECTION.store(true, Ordering::Relaxed);
+    tokio::spawn(elect(db, db_url, lock_key, interval));
+}
+
+async fn elect(db: DbConn, db_url: String, lock_key: i64, interval: Duration) {
+    loop {
+        if let Err(err) = lead(&db, &db_url, lock_key, interval).await {
+            if LEADER.swap(false, Ordering::Relaxed) {
+                error!("This replica steps down as the leader: {}", err);
+            } else {
+                error!("Take part in the leader election failed: {}", err);
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -7,7 +7,61 @@
 //!
 //! ## Web Server
 //! The web server is built using the [Axum](https://github.com/tokio-rs/axum) framework.
-//! It listens on 0.0.0.0:3000 by default and has the following endpoints:
+//! It listens on 0.0.0.0:3000 by default and serves the versioned API under
+//! `/api/v1`, whose OpenAPI 3 document is served at `/api/v1/openapi.json`:
+//! - `POST /api/v1/tasks`: [Create](router::task::create_task) a new task.
+//! - `POST /api/v1/tasks/sweep`: [Create](router::task::create_sweep) one
+//!   task for each parameter set of a parameterized circuit.
+//! - `GET /api/v1/tasks/{id}`: [Get](router::task::fetch_task) the task by id.
+//! - `POST /api/v1/agents`: [Add](router::physical_agent::create_agent) a new
+//!   agent.
+//! - `GET /api/v1/agents`: [List](router::physical_agent::list_agents) the
+//!   agents with their utilization, by address and status.
+//! - `GET /api/v1/agents/{id}`: [Get](router::physical_agent::fetch_agent) the
+//!   agent by id with its utilization and assignment history.
+//! - `PATCH /api/v1/agents/{id}`:
+//!   [Update](router::physical_agent::patch_agent) the agent by id.
+//! - `DELETE /api/v1/agents/{id}`:
+//!   [Remove](router::physical_agent::delete_agent) the agent by id.
+//! - `POST /api/v1/agents/register`:
+//!   [Register](router::physical_agent::register_agent) the calling agent
+//!   with a lease.
+//! - `POST /api/v1/agents/{id}/lease`:
+//!   [Renew](router::physical_agent::renew_agent_lease) the lease of the
+//!   agent.
+//! - `POST /api/v1/agents/{id}/deregister`:
+//!   [Deregister](router::physical_agent::deregister_agent) the agent, or
+//!   return the drain job that removes it when it is drained.
+//! - `POST /api/v1/agents/{id}/drain`:
+//!   [Drain](router::physical_agent::drain_agent) the agent and return the
+//!   drain job.
+//! - `GET /api/v1/drains/{id}`: [Get](router::physical_agent::fetch_drain)
+//!   the drain job by id.
+//! - `POST /api/v1/circuits`: [Add](router::circuit::create_circuit) a new
+//!   version of a circuit to the library.
+//! - `GET /api/v1/circuits`: [List](router::circuit::list_circuits) the
+//!   circuit versions, optionally by name.
+//! - `GET /api/v1/circuits/{name}/{version}`:
+//!   [Get](router::circuit::fetch_circuit) the circuit version.
+//! - `PATCH /api/v1/circuits/{name}/{version}`:
+//!   [Update](router::circuit::patch_circuit) the description and metadata of
+//!   the circuit version.
+//! - `DELETE /api/v1/circuits/{name}/{version}`:
+//!   [Remove](router::circuit::delete_circuit) the circuit version.
+//! - `GET /api/v1/usage`: [Report](router::usage::get_usage) the usage of the
+//!   users over a date range.
+//! - `POST /api/v1/reservations`:
+//!   [Book](router::reservation::create_reservation) agents or a pool for a
+//!   user over a time window.
+//! - `GET /api/v1/reservations`:
+//!   [List](router::reservation::list_reservations) the reservations.
+//! - `DELETE /api/v1/reservations/{id}`:
+//!   [Cancel](router::reservation::cancel_reservation) the reservation by id.
+//! - `POST /api/v1/admin/fresh-db`: [Reset](router::fresh_database) the
+//!   database.
+//!
+//! The following legacy endpoints are deprecated aliases of the versioned
+//! API, their responses carry the `Deprecation: true` header:
 //! - `POST /submit`: [Submit](router::task::submit) a new task to the
 //!   scheduler, the content type can be either `application/json` or
 //!   `application/x-www-form-urlencoded`. The body content should be
@@ -19,46 +73,91 @@
 //!   by task id. The task id is passed as a path parameter. For example
 //!   get_task/1.
 //! - `POST /add_agent`: Add a new agent to the scheduler, the content type can
-//!  be either `application/json` or `application/x-www-form-urlencoded`. The
-//! body content should be [AgentInfo](router::physical_agent_utils::AgentInfo).
-//! And if the agent ip and port is the same as the existing agent, the post
-//! request will be ignored.
+//!   be either `application/json` or `application/x-www-form-urlencoded`. The
+//!   body content should be
+//!   [AgentInfo](router::physical_agent_utils::AgentInfo). And if the agent ip
+//!   and port is the same as the existing agent, the post request will be
+//!   ignored.
 //! - `GET /get_agents`: Get all relative information of agents according to the
 //!   ip and port. The ip and port is passed as a query parameter. For example
-//! get_agents?ip=127.0.0.1&port=1234. The port is optional.
+//!   get_agents?ip=127.0.0.1&port=1234. The port is optional.
 //! - `POST /update_agent`: Update the agent information. The content type can
 //!   be either `application/json` or `application/x-www-form-urlencoded`. The
 //!   body content should be
 //!   [AgentInfo](router::physical_agent_utils::AgentInfoUpdate). Except for the
 //!   ID, all other fields are optional.
 //! - `GET /remove_agent`: Remove the agent from the scheduler. The agent id is
-//!  passed as a query parameter. For example remove_agent?id=1.
+//!   passed as a query parameter. For example remove_agent?id=1.
 //! - `POST /fresh_db`: Drop all tables from the database, then reapply all
-//!  migrations. This is used for admin users to reset the database.
+//!   migrations. This is used for admin users to reset the database.
+//!
+//! If a request fails, the response has the status code of the
+//! [error](error::Error) and a JSON body of the form
+//! `{"code": ..., "message": ..., "details": ...}`.
 //!
 //! ## Task Consumer Thread
 //! The task consumer thread is responsible for consuming waiting tasks and
 //! submitting them to idle agents. First, it read the agents information from a
-//! json file and add them to the database. Then, it checks the waiting tasks in
-//! the database every 1 second. If there are waiting tasks:
+//! json file and add them to the database, and starts the
+//! [discovery](discovery) of the agents if it is configured. Then, it checks
+//! the waiting tasks in the database whenever it is [woken](dispatch), and at
+//! least every `scan_interval` seconds. If `leader.enabled` is set, only the
+//! [elected leader](leader) among the replicas checks them and discovers the
+//! agents. First, the agents whose lease has expired are [marked as down](router::physical_agent::expire_agent_leases)
+//! and the tasks running on them are requeued, the tasks whose deadline has
+//! passed are
+//! [expired](router::task::expire_tasks), and the finished drain jobs
+//! [apply](router::physical_agent::finish_drains) the pending changes of
+//! their agents. If there are waiting tasks:
 //! - Retrieve the quantum task with the least virtual execution shots
-//!   [vexec_shots](entity::task_active::Model::v_exec_shots).
-//! - Find the least available agent to run the task.
-//! - If there is an available agent, it will submit the task to the agent by
-//!   [consume_task]. If not, it will break the loop and wait for the next
+//!   [vexec_shots](entity::task_active::Model::v_exec_shots), or with the
+//!   earliest deadline if `sched_order` is
+//!   [edf](config::SchedOrder::Edf).
+//! - Skip the task if its user has used up a [quota](service::quota::Quota).
+//! - Find the least available agent to run the task, whose capability meets
+//!   the [requirements](capability::TaskRequirements) of the task, and size
+//!   the [next chunk](router::task::next_chunk) of the task on it. Skip the
+//!   task if the shots or the qubit-seconds of the chunk would exceed a quota
+//!   of its user.
+//! - If there is an available agent, it will
+//!   [reserve](service::capacity::Capacity::reserve) the capacity of the
+//!   agent for the assignment until the chunk is estimated to finish,
+//!   [claim](service::task_active::TaskActive::claim_task) the task if it is
+//!   still waiting and submit the task to the agent by [consume_task].
+//!   If not, it will break the loop and wait for the next iteration, or with
+//!   the `backfill` [policy](config::SchedPolicy) it will
+//!   [reserve](backfill::reserve) an agent for the first such task and go on
+//!   with the tasks that do not delay it. A task whose fitting agents are
+//!   all booked by other users is [skipped](service::agent_reservation::allowed_agents)
+//!   under either policy.
+//! - If any step fails, the error is logged and the loop waits for the next
 //!   iteration.
 
 use axum::{routing, Router};
-use log::info;
+use log::{error, info};
 use migration::{Migrator, MigratorTrait};
 pub use sea_orm::{ConnectOptions, Database, DbConn};
+pub mod backfill;
+pub mod capability;
+pub mod chunking;
 pub mod config;
+pub mod cutting;
+pub mod discovery;
+pub mod dispatch;
 pub mod entity;
+pub mod error;
+pub mod leader;
+pub mod noise;
+pub mod parameter;
+pub mod qasm;
 pub mod router;
 pub mod service;
+pub mod transpile;
 use router::{
-    physical_agent::{add_physical_agent_from_file, get_agent_info},
-    task::consume_task,
+    physical_agent::{
+        add_physical_agent_from_file, expire_agent_leases, finish_drains, get_agent_info,
+    },
+    task::{consume_task, expire_tasks, next_chunk},
 };
 
 fn main() {
@@ -84,7 +183,7 @@ fn main() {
             let agents = get_agent_info(&agent_file_path);
 
             // disable sqlx logging
-            let mut connection_options = ConnectOptions::new(db_url);
+            let mut connection_options = ConnectOptions::new(db_url.clone());
             connection_options.sqlx_logging(false);
 
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
@@ -93,41 +192,218 @@ fn main() {
             // add agents to the database
             add_physical_agent_from_file(&db, agents).await;
 
+            // wake the consume loop on the events of the other replicas
+            let scan_interval = tokio::time::Duration::from_secs(sched_conf.dispatch.scan_interval);
+            if sched_conf.dispatch.pg_notify {
+                tokio::spawn(dispatch::listen(db_url.clone(), scan_interval));
+            }
+
+            // only the elected leader dispatches and discovers the agents, when several
+            // replicas share the database
+            if sched_conf.leader.enabled {
+                let retry_interval = tokio::time::Duration::from_secs(sched_conf.leader.retry_interval);
+                leader::start_election(db.clone(), db_url, sched_conf.leader.lock_key, retry_interval);
+            } else {
+                leader::take_over(&db).await;
+            }
+
+            // add and remove the agents as the replicas of the service come and go
+            if let Some(discovery) = sched_conf.discovery.clone() {
+                tokio::spawn(discovery::discover_agents(db.clone(), discovery));
+            }
+
             loop {
-                let waiting_tasks = service::task_active::TaskActive::get_asc_tasks(&db)
-                    .await
-                    .unwrap();
+                // the followers serve the HTTP API only, and wait to take over
+                if !leader::is_leader() {
+                    dispatch::wait(scan_interval).await;
+                    continue;
+                }
+
+                // mark the agents whose lease has expired as down, and move the tasks
+                // whose deadline has passed to the task list
+                expire_agent_leases(&db).await;
+                expire_tasks(&db).await;
+                // apply the pending changes of the drained agents
+                finish_drains(&db).await;
+
+                let waiting_tasks = match sched_conf.sched_order {
+                    config::SchedOrder::Fair => service::task_active::TaskActive::get_asc_tasks(&db).await,
+                    config::SchedOrder::Edf => service::task_active::TaskActive::get_edf_tasks(&db).await,
+                };
+                let waiting_tasks = match waiting_tasks {
+                    Ok(waiting_tasks) => waiting_tasks,
+                    Err(err) => {
+                        error!("Get waiting tasks failed: {}", err);
+                        vec![]
+                    }
+                };
+
+                // the reservation of the first task that can not be placed, if backfill is configured
+                let mut reservation: Option<backfill::Reservation> = None;
+
+                // the agents booked by the users for the current window
+                let agent_reservations = match service::agent_reservation::AgentReservation::get_active_reservations(&db, chrono::Utc::now().naive_utc()).await {
+                    Ok(agent_reservations) => agent_reservations,
+                    Err(err) => {
+                        error!("Get active agent reservations failed: {}", err);
+                        dispatch::wait(scan_interval).await;
+                        continue;
+                    }
+                };
 
                 // TODO: if the device is idle, run one task concurrently
                 for waiting_task in waiting_tasks {
-                    match service::physical_agent::PhysicalAgent::get_least_available_physical_agent(
+                    // stop at once if this replica has stepped down as the leader
+                    if !leader::is_leader() {
+                        break;
+                    }
+
+                    // hold the task until the quota of its user is reset
+                    if let Err(err) = service::quota::Quota::check(&db, &sched_conf.quota, &waiting_task.username, 0, 0).await {
+                        info!("Task {:?} is not dispatched: {}", waiting_task.id, err);
+                        continue;
+                    }
+
+                    let requirements = match capability::TaskRequirements::from_json(waiting_task.requirements.as_ref()) {
+                        Ok(requirements) => requirements,
+                        Err(err) => {
+                            error!("Read requirements of task {:?} failed: {}", waiting_task.id, err);
+                            continue;
+                        }
+                    };
+
+                    let agents = match service::physical_agent::PhysicalAgent::get_idle_physical_agents(
                         &db,
+                        &sched_conf.capacity,
                         waiting_task.qubits as u32,
                         waiting_task.depth as u32,
+                        &requirements,
+                    ).await {
+                        Ok(agents) => agents,
+                        Err(err) => {
+                            error!("Get available physical agent failed: {}", err);
+                            break;
+                        }
+                    };
+
+                    // skip the agents booked by the other users, and the agents whose
+                    // gates the circuit can not be decomposed into
+                    let (agents, reserved_away) = service::agent_reservation::allowed_agents(&agent_reservations, agents, &waiting_task.username);
+                    let agents = agents
+                        .into_iter()
+                        .filter(|agent| capability::supports_circuit(agent, &waiting_task.source))
+                        .collect::<Vec<_>>();
+
+                    // take the least available agent whose next chunk does not delay the reservation
+                    let now = chrono::Utc::now().naive_utc();
+                    let mut placement = None;
+                    for agent in agents {
+                        let chunk = match next_chunk(
+                            &db,
+                            sched_conf.sched_min_depth as f32,
+                            sched_conf.sched_min_gran as f32,
+                            &sched_conf.chunking,
+                            &waiting_task,
+                            &agent,
+                        ).await {
+                            Ok(chunk) => chunk,
+                            Err(err) => {
+                                error!("Size the chunk of task {:?} failed: {}", waiting_task.id, err);
+                                continue;
+                            }
+                        };
+                        let end_time = now + chrono::TimeDelta::milliseconds((chunk.seconds * 1000.0).min(i32::MAX as f64) as i64);
+                        if reservation.as_ref().is_none_or(|reservation| reservation.allows(agent.id, end_time)) {
+                            placement = Some((agent, chunk, end_time));
+                            break;
+                        }
+                    }
+
+                    let Some((agent, chunk, end_time)) = placement else {
+                        // the agents are booked by other users, not short of capacity, so
+                        // the later tasks are still dispatched
+                        if reserved_away {
+                            continue;
+                        }
+                        if sched_conf.sched_policy == config::SchedPolicy::Strict {
+                            break;
+                        }
+                        // reserve an agent for the first task that can not be placed, and
+                        // backfill the later tasks around it
+                        if reservation.is_none() {
+                            match backfill::reserve(&db, &sched_conf.capacity, &waiting_task, &requirements, &agent_reservations, now).await {
+                                Ok(Some(reserved)) => {
+                                    info!("Task {:?} reserves physical agent {:?} from {}", reserved.task_id, reserved.agent_id, reserved.start_time);
+                                    reservation = Some(reserved);
+                                }
+                                Ok(None) => {}
+                                Err(err) => {
+                                    error!("Reserve physical agent for task {:?} failed: {}", waiting_task.id, err);
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    };
+
+                    // hold the task if its next chunk would exceed the quota of its user
+                    let qubit_seconds = (chunk.seconds * waiting_task.qubits as f64).ceil() as i64;
+                    if let Err(err) = service::quota::Quota::check(&db, &sched_conf.quota, &waiting_task.username, chunk.shots as i64, qubit_seconds).await {
+                        info!("Task {:?} is not dispatched: {}", waiting_task.id, err);
+                        continue;
+                    }
+
+                    // claim the capacity of the agent for the assignment of the chunk
+                    let assignment_id = uuid::Uuid::new_v4();
+                    match service::capacity::Capacity::reserve(
+                        &db,
+                        &sched_conf.capacity,
+                        assignment_id,
+                        &agent,
+                        waiting_task.id,
+                        waiting_task.qubits,
+                        end_time,
                     ).await {
-                        Ok(Some(agent)) => {
-                            service::physical_agent::PhysicalAgent::update_physical_agent_qubits_idle(
-                                &db,
-                                agent.id,
-                                 - waiting_task.qubits as i32,
-                            ).await.unwrap();
-
-                            let db = db.clone();
-
-                            tokio::spawn(async move {
-                                consume_task(&db, sched_conf.sched_min_depth as f32, sched_conf.sched_min_gran as f32, waiting_task, agent).await
-                            });
+                        Ok(true) => {}
+                        Ok(false) => {
+                            info!("Capacity of physical agent {:?} is taken, retry in the next iteration", agent.id);
+                            break;
                         }
-                        Ok(None) => { break; }
                         Err(err) => {
-                            info!("Error: {}", err);
+                            error!("Reserve qubits of physical agent {:?} failed: {}", agent.id, err);
                             break;
                         }
                     }
+
+                    // claim the task by marking it as running only if it is still waiting,
+                    // so that it is neither dispatched again nor expired while the chunk is
+                    // running, and a task cancelled since the scan is not dispatched
+                    let claimed = service::task_active::TaskActive::claim_task(&db, waiting_task.id).await;
+                    if !matches!(claimed, Ok(true)) {
+                        if let Err(err) = service::capacity::Capacity::release(&db, assignment_id).await {
+                            error!("Release qubits of physical agent {:?} failed: {}", agent.id, err);
+                        }
+                        match claimed {
+                            Err(err) => {
+                                error!("Mark task {:?} as running failed: {}", waiting_task.id, err);
+                                break;
+                            }
+                            _ => {
+                                info!("Task {:?} is no longer waiting, it is not dispatched", waiting_task.id);
+                                continue;
+                            }
+                        }
+                    }
+
+                    let db = db.clone();
+                    tokio::spawn(async move {
+                        consume_task(&db, chunk.shots, assignment_id, waiting_task, agent).await
+                    });
                 }
 
-                // every 1 seconds to check if there are waiting tasks
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                // wait for a task to be submitted, a chunk to finish or an agent to come
+                // online, and scan periodically in case an event is lost
+                dispatch::wait(scan_interval).await;
             }
         });
     });
@@ -164,8 +440,9 @@ fn main() {
             config: sched_conf.clone(),
         };
 
-        // Start the web server
-        let emulator_router = Router::new()
+        // Start the web server, the legacy routes are deprecated aliases of the
+        // versioned api
+        let legacy_router = Router::new()
             .route(
                 "/add_agent",
                 routing::post(router::physical_agent::add_physical_agent),
@@ -189,6 +466,69 @@ fn main() {
                 routing::get(router::task::get_task_with_id),
             )
             .route("/fresh_db", routing::post(router::fresh_db))
+            .layer(axum::middleware::map_response(router::deprecated));
+
+        let api_v1_router = Router::new()
+            .route("/tasks", routing::post(router::task::create_task))
+            .route("/tasks/sweep", routing::post(router::task::create_sweep))
+            .route("/tasks/:id", routing::get(router::task::fetch_task))
+            .route(
+                "/agents",
+                routing::post(router::physical_agent::create_agent)
+                    .get(router::physical_agent::list_agents),
+            )
+            .route(
+                "/agents/register",
+                routing::post(router::physical_agent::register_agent),
+            )
+            .route(
+                "/agents/:id/lease",
+                routing::post(router::physical_agent::renew_agent_lease),
+            )
+            .route(
+                "/agents/:id/deregister",
+                routing::post(router::physical_agent::deregister_agent),
+            )
+            .route(
+                "/agents/:id/drain",
+                routing::post(router::physical_agent::drain_agent),
+            )
+            .route(
+                "/drains/:id",
+                routing::get(router::physical_agent::fetch_drain),
+            )
+            .route(
+                "/agents/:id",
+                routing::get(router::physical_agent::fetch_agent)
+                    .patch(router::physical_agent::patch_agent)
+                    .delete(router::physical_agent::delete_agent),
+            )
+            .route(
+                "/circuits",
+                routing::post(router::circuit::create_circuit).get(router::circuit::list_circuits),
+            )
+            .route(
+                "/circuits/:name/:version",
+                routing::get(router::circuit::fetch_circuit)
+                    .patch(router::circuit::patch_circuit)
+                    .delete(router::circuit::delete_circuit),
+            )
+            .route("/usage", routing::get(router::usage::get_usage))
+            .route(
+                "/reservations",
+                routing::post(router::reservation::create_reservation)
+                    .get(router::reservation::list_reservations),
+            )
+            .route(
+                "/reservations/:id",
+                routing::delete(router::reservation::cancel_reservation),
+            )
+            .route("/admin/fresh-db", routing::post(router::fresh_database))
+            .route("/openapi.json", routing::get(router::openapi::openapi_json));
+
+        let emulator_router = Router::new()
+            .nest("/api/v1", api_v1_router)
+            .merge(legacy_router)
             .with_state(state);
 
         let listener = tokio::net::TcpListener::bind(format!(
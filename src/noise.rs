@@ -0,0 +1,159 @@
+//! The module that contains the noise model of the tasks. A task with a noise
+//! model is validated at submit time, saved with the task and only forwarded
+//! to the agents that advertise noise support in their
+//! [capability document](crate::capability::AgentCapability).
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// ## Noise Model
+/// The noise model applied by the agent when running the task, every field is
+/// optional but at least one must be given.
+/// - `preset`: The name of a noise model preset in the `noise_presets` of the
+///   configuration file, the other fields override the preset.
+/// - `depolarizing`: The depolarizing error probability of every gate.
+/// - `amplitude_damping`: The amplitude damping probability (gamma) of every
+///   gate.
+/// - `readout_error`: The probability that a measured bit is flipped.
+///
+/// All the probabilities must be in `[0, 1]`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct NoiseModel {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depolarizing: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amplitude_damping: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readout_error: Option<f64>,
+}
+
+impl NoiseModel {
+    /// Resolve the preset of the noise model with the given presets and
+    /// validate the result. The fields given in the task override the ones of
+    /// the preset, the name of the preset is kept for reference. If the preset
+    /// does not exist, the model is empty or a probability is out of range,
+    /// return an invalid request error.
+    pub fn resolve(self, presets: &HashMap<String, NoiseModel>) -> Result<NoiseModel> {
+        let model = match &self.preset {
+            Some(name) => {
+                let preset = presets.get(name).ok_or_else(|| {
+                    Error::InvalidRequest(format!("noise model preset {} not found", name))
+                })?;
+                NoiseModel {
+                    preset: self.preset.clone(),
+                    depolarizing: self.depolarizing.or(preset.depolarizing),
+                    amplitude_damping: self.amplitude_damping.or(preset.amplitude_damping),
+                    readout_error: self.readout_error.or(preset.readout_error),
+                }
+            }
+            None => self,
+        };
+
+        if model.depolarizing.is_none()
+            && model.amplitude_damping.is_none()
+            && model.readout_error.is_none()
+        {
+            return Err(Error::InvalidRequest(
+                "noise model must have at least one error".to_owned(),
+            ));
+        }
+        for (name, probability) in [
+            ("depolarizing", model.depolarizing),
+            ("amplitude_damping", model.amplitude_damping),
+            ("readout_error", model.readout_error),
+        ] {
+            if let Some(probability) = probability {
+                if !(0.0..=1.0).contains(&probability) {
+                    return Err(Error::InvalidRequest(format!(
+                        "noise model {} {} is not in [0, 1]",
+                        name, probability
+                    )));
+                }
+            }
+        }
+
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets() -> HashMap<String, NoiseModel> {
+        HashMap::from([(
+            "ibm".to_owned(),
+            NoiseModel {
+                preset: None,
+                depolarizing: Some(0.01),
+                amplitude_damping: None,
+                readout_error: Some(0.02),
+            },
+        )])
+    }
+
+    #[test]
+    fn preset_is_resolved_and_overridden() {
+        let model = NoiseModel {
+            preset: Some("ibm".to_owned()),
+            readout_error: Some(0.05),
+            ..Default::default()
+        }
+        .resolve(&presets())
+        .unwrap();
+        assert_eq!(
+            model,
+            NoiseModel {
+                preset: Some("ibm".to_owned()),
+                depolarizing: Some(0.01),
+                amplitude_damping: None,
+                readout_error: Some(0.05),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_preset_is_rejected() {
+        let resolved = NoiseModel {
+            preset: Some("google".to_owned()),
+            depolarizing: Some(0.01),
+            ..Default::default()
+        }
+        .resolve(&presets());
+        assert!(matches!(resolved, Err(Error::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn inline_model_is_validated() {
+        let model = NoiseModel {
+            amplitude_damping: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(model.clone().resolve(&HashMap::new()).unwrap(), model);
+
+        for invalid in [
+            NoiseModel::default(),
+            NoiseModel {
+                depolarizing: Some(-0.1),
+                ..Default::default()
+            },
+            NoiseModel {
+                readout_error: Some(1.5),
+                ..Default::default()
+            },
+            NoiseModel {
+                depolarizing: Some(f64::NAN),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                invalid.resolve(&HashMap::new()),
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! The module that binds the parameters of a parameterized circuit. A
+//! parameter is a free identifier in the arguments of a gate call, e.g.
+//! `theta` in `rx(theta) q[0];`. The parameters are substituted with the
+//! values given in the task before the source is stored, so that the agents
+//! only receive plain QASM.
+//!
+//! The formal parameters of a `gate` or `opaque` declaration, the gate bodies,
+//! the conditions of `if` statements and the built-in constants and functions
+//! (`pi`, `tau`, `euler`, `sin`, `cos`, `tan`, `exp`, `ln` and `sqrt`) are not
+//! parameters.
+
+use crate::error::{Error, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+/// The values of the parameters of a circuit by name.
+pub type Parameters = BTreeMap<String, f64>;
+
+/// The identifiers that can be used in the gate arguments without binding.
+const BUILTINS: [&str; 9] = [
+    "pi", "tau", "euler", "sin", "cos", "tan", "exp", "ln", "sqrt",
+];
+
+/// A token of the QASM source that matters for finding the parameters.
+enum Token<'a> {
+    Ident(&'a str, Range<usize>),
+    Punct(char),
+}
+
+/// Split the source into identifiers and punctuations, the comments, strings,
+/// numbers and whitespaces are skipped.
+fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let bytes = source.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if source[i..].starts_with("//") {
+            i = source[i..].find('\n').map_or(bytes.len(), |end| i + end);
+        } else if c == b'"' {
+            i = source[i + 1..]
+                .find('"')
+                .map_or(bytes.len(), |end| i + end + 2);
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(&source[start..i], start..i));
+        } else if c.is_ascii_digit() || c == b'.' {
+            // a real number may have an exponent like `1.5e-3`
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+                i += 1;
+                if i < bytes.len() && (bytes[i] == b'+' || bytes[i] == b'-') {
+                    i += 1;
+                }
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+        } else {
+            if !c.is_ascii_whitespace() {
+                tokens.push(Token::Punct(c as char));
+            }
+            i += source[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    tokens
+}
+
+/// Find the parameters in the gate arguments of the source, the name and the
+/// position of every occurrence is returned in order.
+fn find_parameters(source: &str) -> Vec<(&str, Range<usize>)> {
+    let mut parameters = vec![];
+    let mut brace_depth = 0usize;
+    let mut paren_depth = 0usize;
+    let mut collecting = false;
+    let mut statement: Option<&str> = None;
+    let mut previous: Option<&str> = None;
+
+    for token in tokenize(source) {
+        match token {
+            Token::Ident(name, range) => {
+                statement.get_or_insert(name);
+                if collecting && !BUILTINS.contains(&name) {
+                    parameters.push((name, range));
+                }
+                previous = Some(name);
+                continue;
+            }
+            Token::Punct('(') => {
+                if paren_depth == 0 {
+                    collecting = brace_depth == 0
+                        && !matches!(statement, Some("gate" | "opaque"))
+                        && previous != Some("if");
+                }
+                paren_depth += 1;
+            }
+            Token::Punct(')') => {
+                paren_depth = paren_depth.saturating_sub(1);
+                if paren_depth == 0 {
+                    collecting = false;
+                }
+            }
+            Token::Punct('{') => {
+                brace_depth += 1;
+                statement = None;
+            }
+            Token::Punct('}') => {
+                brace_depth = brace_depth.saturating_sub(1);
+                statement = None;
+            }
+            Token::Punct(';') => statement = None,
+            Token::Punct(_) => {
+                statement.get_or_insert("");
+            }
+        }
+        previous = None;
+    }
+    parameters
+}
+
+/// ## Bind Parameters
+/// Substitute the parameters of the source with the given values, a negative
+/// value is wrapped in parentheses. If a parameter of the source has no value
+/// or a value is not used by the source, return an invalid request error.
+pub fn bind_parameters(source: &str, values: &Parameters) -> Result<String> {
+    let parameters = find_parameters(source);
+
+    let names: BTreeSet<&str> = parameters.iter().map(|(name, _)| *name).collect();
+    let unbound: Vec<&str> = names
+        .iter()
+        .filter(|name| !values.contains_key(**name))
+        .copied()
+        .collect();
+    if !unbound.is_empty() {
+        return Err(Error::InvalidRequest(format!(
+            "unbound parameters: {}",
+            unbound.join(", ")
+        )));
+    }
+    let unused: Vec<&str> = values
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !names.contains(name))
+        .collect();
+    if !unused.is_empty() {
+        return Err(Error::InvalidRequest(format!(
+            "unused parameters: {}",
+            unused.join(", ")
+        )));
+    }
+
+    let mut bound = String::with_capacity(source.len());
+    let mut end = 0;
+    for (name, range) in parameters {
+        let value = values[name];
+        bound.push_str(&source[end..range.start]);
+        if value.is_sign_negative() {
+            bound.push_str(&format!("({})", value));
+        } else {
+            bound.push_str(&value.to_string());
+        }
+        end = range.end;
+    }
+    bound.push_str(&source[end..]);
+    Ok(bound)
+}
+
+/// ## Sweep Parameters
+/// Expand the parameter sets of a sweep over the source, every set is merged
+/// over the shared values, a value in the set overrides the shared one. If
+/// there is no set or a merged set can not be
+/// [bound](bind_parameters), return an invalid request error with the index
+/// of the set.
+pub fn sweep_parameters(
+    source: &str,
+    shared: &Parameters,
+    parameter_sets: Vec<Parameters>,
+) -> Result<Vec<Parameters>> {
+    if parameter_sets.is_empty() {
+        return Err(Error::InvalidRequest("parameter_sets is empty".to_owned()));
+    }
+    parameter_sets
+        .into_iter()
+        .enumerate()
+        .map(|(i, parameters)| {
+            let mut values = shared.clone();
+            values.extend(parameters);
+            match bind_parameters(source, &values) {
+                Ok(_) => Ok(values),
+                Err(Error::InvalidRequest(reason)) => Err(Error::InvalidRequest(format!(
+                    "parameter_sets[{}]: {}",
+                    i, reason
+                ))),
+                Err(err) => Err(err),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "OPENQASM 2.0;\nqreg q[1];\nrx(theta) q[0];\nrz(phi/2) q[0];\n";
+
+    fn parameters(values: &[(&str, f64)]) -> Parameters {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    #[test]
+    fn find_parameters_skips_declarations_conditions_and_builtins() {
+        let source = "OPENQASM 2.0;\n\
+            include \"qelib1.inc\";\n\
+            gate g(alpha) a { rx(alpha) a; }\n\
+            opaque o(beta) a;\n\
+            qreg q[1]; creg c[1];\n\
+            // rx(comment) q[0];\n\
+            u3(theta, sin(pi/2)*phi, 1.5e-3) q[0];\n\
+            if(c==1) g(theta) q[0];\n";
+        let names: Vec<&str> = find_parameters(source)
+            .into_iter()
+            .map(|(name, range)| {
+                assert_eq!(&source[range], name);
+                name
+            })
+            .collect();
+        assert_eq!(names, ["theta", "phi", "theta"]);
+    }
+
+    #[test]
+    fn bind_substitutes_inside_expressions() {
+        let source = "OPENQASM 2.0;\nqreg q[1];\nu3(2*theta, -theta, sqrt(phi)+pi) q[0];\n";
+        assert_eq!(
+            bind_parameters(source, &parameters(&[("theta", 0.25), ("phi", -1.0)])).unwrap(),
+            "OPENQASM 2.0;\nqreg q[1];\nu3(2*0.25, -0.25, sqrt((-1))+pi) q[0];\n"
+        );
+        // a source without parameters binds to itself
+        let plain = "OPENQASM 2.0;\nqreg q[1];\nrx(pi/2) q[0];\n";
+        assert_eq!(bind_parameters(plain, &Parameters::new()).unwrap(), plain);
+    }
+
+    #[test]
+    fn bind_rejects_unbound_and_unused_parameters() {
+        let reason = |result: Result<String>| match result {
+            Err(Error::InvalidRequest(reason)) => reason,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(
+            reason(bind_parameters(SOURCE, &Parameters::new())),
+            "unbound parameters: phi, theta"
+        );
+        assert_eq!(
+            reason(bind_parameters(SOURCE, &parameters(&[("theta", 0.1)]))),
+            "unbound parameters: phi"
+        );
+        assert_eq!(
+            reason(bind_parameters(
+                SOURCE,
+                &parameters(&[("theta", 0.1), ("phi", 0.2), ("gamma", 0.3)])
+            )),
+            "unused parameters: gamma"
+        );
+    }
+
+    #[test]
+    fn sweep_overrides_the_shared_values() {
+        let shared = parameters(&[("phi", 0.5)]);
+        let sets = vec![
+            parameters(&[("theta", 0.1)]),
+            parameters(&[("theta", -0.2), ("phi", 1.5)]),
+        ];
+        let expanded = sweep_parameters(SOURCE, &shared, sets).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                parameters(&[("theta", 0.1), ("phi", 0.5)]),
+                parameters(&[("theta", -0.2), ("phi", 1.5)]),
+            ]
+        );
+        assert_eq!(
+            bind_parameters(SOURCE, &expanded[1]).unwrap(),
+            "OPENQASM 2.0;\nqreg q[1];\nrx((-0.2)) q[0];\nrz(1.5/2) q[0];\n"
+        );
+    }
+
+    #[test]
+    fn sweep_rejects_a_bad_grid() {
+        let shared = parameters(&[("phi", 0.5)]);
+        let reason = |result: Result<Vec<Parameters>>| match result {
+            Err(Error::InvalidRequest(reason)) => reason,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(
+            reason(sweep_parameters(SOURCE, &shared, vec![])),
+            "parameter_sets is empty"
+        );
+        assert_eq!(
+            reason(sweep_parameters(
+                SOURCE,
+                &shared,
+                vec![parameters(&[("theta", 0.1)]), parameters(&[])],
+            )),
+            "parameter_sets[1]: unbound parameters: theta"
+        );
+        assert_eq!(
+            reason(sweep_parameters(
+                SOURCE,
+                &shared,
+                vec![parameters(&[("theta", 0.1), ("gamma", 1.0)])],
+            )),
+            "parameter_sets[0]: unused parameters: gamma"
+        );
+    }
+}
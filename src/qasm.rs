@@ -0,0 +1,680 @@
+//! The module that translates OpenQASM 3 programs to OpenQASM 2. The agents
+//! speak OpenQASM 2, so a program with the `OPENQASM 3` header is translated
+//! down at submit time if it only uses the features that OpenQASM 2 supports:
+//! - `qubit[n] q;` and `bit[n] c;` become `qreg q[n];` and `creg c[n];`.
+//! - `c = measure q;` becomes `measure q -> c;`.
+//! - `include "stdgates.inc";` becomes `include "qelib1.inc";`, the standard
+//!   gates missing in `qelib1.inc` are renamed (`p`, `phase`, `cp`, `cphase`
+//!   and `u`) or defined in the translated program (`sx`, `swap`, `cswap`,
+//!   `crx`, `cry` and `cu`).
+//! - The constants `π`, `τ`, `tau` and `euler` and the power operator `**`
+//!   are rewritten in the gate arguments.
+//! - The `input` declarations whose parameters are
+//!   [bound](crate::parameter) are removed.
+//!
+//! Any other feature, such as classical control flow, subroutines, gate
+//! modifiers or classical types, is reported as a [Diagnostic] with its
+//! position in the program.
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+/// ## Diagnostic
+/// A feature of the OpenQASM 3 program that can not be translated to
+/// OpenQASM 2.
+/// - `line`: The line of the statement, starting from 1.
+/// - `column`: The column of the statement, starting from 1.
+/// - `message`: What is not supported.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// The gates of `qelib1.inc` and the built-in gates of OpenQASM 2.
+const QELIB1_GATES: [&str; 25] = [
+    "U", "CX", "u3", "u2", "u1", "cx", "id", "x", "y", "z", "h", "s", "sdg", "t", "tdg", "rx",
+    "ry", "rz", "cz", "cy", "ch", "ccx", "crz", "cu1", "cu3",
+];
+
+/// The standard gates of OpenQASM 3 that have another name in `qelib1.inc`.
+const RENAMED_GATES: [(&str, &str); 5] = [
+    ("p", "u1"),
+    ("phase", "u1"),
+    ("cp", "cu1"),
+    ("cphase", "cu1"),
+    ("u", "u3"),
+];
+
+/// The standard gates of OpenQASM 3 that are missing in `qelib1.inc`, with
+/// their definitions in the gates of `qelib1.inc`.
+const DEFINED_GATES: [(&str, &str); 6] = [
+    ("sx", "gate sx a { sdg a; h a; sdg a; }"),
+    ("swap", "gate swap a,b { cx a,b; cx b,a; cx a,b; }"),
+    ("cswap", "gate cswap a,b,c { cx c,b; ccx a,b,c; cx c,b; }"),
+    (
+        "crx",
+        "gate crx(lambda) a,b { u1(pi/2) b; cx a,b; u3(-lambda/2,0,0) b; cx a,b; \
+         u3(lambda/2,-pi/2,0) b; }",
+    ),
+    (
+        "cry",
+        "gate cry(lambda) a,b { ry(lambda/2) b; cx a,b; ry(-lambda/2) b; cx a,b; }",
+    ),
+    (
+        "cu",
+        "gate cu(theta,phi,lambda,gamma) c,t { u1(gamma) c; u1((lambda+phi)/2) c; \
+         u1((lambda-phi)/2) t; cx c,t; u3(-theta/2,0,-(phi+lambda)/2) t; cx c,t; \
+         u3(theta/2,phi,0) t; }",
+    ),
+];
+
+/// The keywords of OpenQASM 3 that can not be translated, with the kind of
+/// the feature.
+const UNSUPPORTED_KEYWORDS: [(&str, &str); 35] = [
+    ("if", "classical control flow"),
+    ("else", "classical control flow"),
+    ("for", "classical control flow"),
+    ("while", "classical control flow"),
+    ("switch", "classical control flow"),
+    ("break", "classical control flow"),
+    ("continue", "classical control flow"),
+    ("end", "classical control flow"),
+    ("return", "classical control flow"),
+    ("def", "subroutine"),
+    ("extern", "subroutine"),
+    ("ctrl", "gate modifier"),
+    ("negctrl", "gate modifier"),
+    ("inv", "gate modifier"),
+    ("pow", "gate modifier"),
+    ("int", "classical type"),
+    ("uint", "classical type"),
+    ("float", "classical type"),
+    ("angle", "classical type"),
+    ("bool", "classical type"),
+    ("complex", "classical type"),
+    ("duration", "classical type"),
+    ("stretch", "classical type"),
+    ("const", "classical type"),
+    ("array", "classical type"),
+    ("let", "alias"),
+    ("output", "output declaration"),
+    ("box", "timing"),
+    ("delay", "timing"),
+    ("cal", "pulse calibration"),
+    ("defcal", "pulse calibration"),
+    ("defcalgrammar", "pulse calibration"),
+    ("gphase", "global phase"),
+    ("durationof", "timing"),
+    ("opaque", "opaque gate"),
+];
+
+/// Whether the program has the `OPENQASM 3` header.
+pub fn is_qasm3(source: &str) -> bool {
+    let source = blank_comments(source);
+    let mut words = source.split_whitespace();
+    words.next() == Some("OPENQASM") && words.next().is_some_and(|v| v.starts_with('3'))
+}
+
+/// Replace the comments with spaces, the newlines and the byte offsets of the
+/// other characters are kept.
+pub fn blank_comments(source: &str) -> String {
+    let mut blanked = String::with_capacity(source.len());
+    let mut chars = source.char_indices().peekable();
+    let mut in_string = false;
+    while let Some((i, c)) = chars.next() {
+        if c == '"' {
+            in_string = !in_string;
+        } else if !in_string && source[i..].starts_with("//") {
+            while let Some(&(_, c)) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                blanked.push_str(&" ".repeat(c.len_utf8()));
+                chars.next();
+            }
+            blanked.push(' ');
+            continue;
+        } else if !in_string && source[i..].starts_with("/*") {
+            let end = source[i + 2..]
+                .find("*/")
+                .map_or(source.len(), |end| i + end + 4);
+            for c in source[i..end].chars() {
+                match c {
+                    '\n' => blanked.push('\n'),
+                    c => blanked.push_str(&" ".repeat(c.len_utf8())),
+                }
+            }
+            while chars.peek().is_some_and(|&(j, _)| j < end) {
+                chars.next();
+            }
+            continue;
+        }
+        blanked.push(c);
+    }
+    blanked
+}
+
+/// Split the program into statements with their byte offsets. A statement
+/// ends with `;` or with the `}` that closes its block.
+pub fn split_statements(source: &str) -> Vec<(usize, &str)> {
+    let mut statements = vec![];
+    let (mut start, mut depth, mut in_string) = (0, 0usize, false);
+    for (i, c) in source.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '{' if !in_string => depth += 1,
+            '}' if !in_string => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    statements.push((start, &source[start..=i]));
+                    start = i + 1;
+                }
+            }
+            ';' if !in_string && depth == 0 => {
+                statements.push((start, &source[start..=i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push((start, &source[start..]));
+
+    statements
+        .into_iter()
+        .filter_map(|(offset, statement)| {
+            let trimmed = statement.trim_start();
+            (!trimmed.trim().is_empty())
+                .then(|| (offset + statement.len() - trimmed.len(), trimmed.trim_end()))
+        })
+        .collect()
+}
+
+/// The identifiers of the text in order.
+fn identifiers(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+/// The first identifier of the statement.
+pub fn first_word(statement: &str) -> &str {
+    let end = statement
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(statement.len());
+    &statement[..end]
+}
+
+/// Rewrite the OpenQASM 3 constants and operators in the gate arguments.
+fn translate_expression(text: &str) -> String {
+    let mut translated = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let word = first_word(rest);
+        if !word.is_empty() {
+            translated.push_str(match word {
+                "tau" => "(2*pi)",
+                "euler" => "2.718281828459045",
+                word => word,
+            });
+            rest = &rest[word.len()..];
+            continue;
+        }
+        let c = rest.chars().next().unwrap_or_default();
+        if rest.starts_with("**") {
+            translated.push('^');
+            rest = &rest[2..];
+        } else {
+            match c {
+                'π' => translated.push_str("pi"),
+                'τ' => translated.push_str("(2*pi)"),
+                c => translated.push(c),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    translated
+}
+
+/// The translator of one program, it collects the translated statements, the
+/// gates used and the diagnostics.
+struct Translator<'a> {
+    original: &'a str,
+    source: &'a str,
+    statements: Vec<String>,
+    declared_gates: BTreeSet<String>,
+    used_gates: BTreeSet<&'static str>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Translator<'a> {
+    /// Report a diagnostic at the byte offset of the source. The column is
+    /// counted in the original program, a comment before the statement may
+    /// have more characters than its blanked bytes.
+    fn report(&mut self, offset: usize, message: String) {
+        let before = &self.original[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rfind('\n')
+            .map_or(before.chars().count(), |i| before[i + 1..].chars().count())
+            + 1;
+        self.diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+        });
+    }
+
+    /// Translate a statement of the program at the byte offset.
+    fn translate_statement(&mut self, offset: usize, statement: &'a str) {
+        let body = statement.strip_suffix(';').unwrap_or(statement).trim_end();
+        let keyword = first_word(body);
+
+        if keyword.is_empty() {
+            let feature = match body.chars().next() {
+                Some('#') => "pragma",
+                Some('@') => "annotation",
+                Some('$') => "physical qubit",
+                _ => "statement",
+            };
+            return self.report(
+                offset,
+                format!("{} is not supported by OpenQASM 2", feature),
+            );
+        }
+        if !statement.ends_with(';') && !statement.ends_with('}') {
+            return self.report(offset, "statement is not terminated by `;`".to_owned());
+        }
+        if let Some((_, feature)) = UNSUPPORTED_KEYWORDS.iter().find(|(k, _)| *k == keyword) {
+            return self.report(
+                offset,
+                format!("{} `{}` is not supported by OpenQASM 2", feature, keyword),
+            );
+        }
+
+        match keyword {
+            "OPENQASM" => {}
+            "include" => match body[keyword.len()..].trim() {
+                "\"stdgates.inc\"" | "\"qelib1.inc\"" => {}
+                file => self.report(offset, format!("include of {} is not supported", file)),
+            },
+            "qubit" | "bit" => self.translate_declaration(offset, keyword, body),
+            "qreg" | "creg" | "reset" | "barrier" => {
+                self.statements.push(format!("{};", body));
+            }
+            "measure" => {
+                if body.contains("->") {
+                    self.statements.push(format!("{};", body));
+                } else {
+                    self.report(
+                        offset,
+                        "measure without a target bit is not supported by OpenQASM 2".to_owned(),
+                    );
+                }
+            }
+            "input" => self.translate_input(offset, body),
+            "gate" => self.translate_gate_definition(offset, body),
+            _ if body.contains('=') => match body.split_once('=') {
+                Some((target, measure)) if first_word(measure.trim()) == "measure" => {
+                    let qubits = measure.trim()["measure".len()..].trim();
+                    self.statements
+                        .push(format!("measure {} -> {};", qubits, target.trim()));
+                }
+                _ => self.report(
+                    offset,
+                    "classical assignment is not supported by OpenQASM 2".to_owned(),
+                ),
+            },
+            _ => {
+                if let Some(call) = self.translate_gate_call(offset, body) {
+                    self.statements.push(format!("{};", call));
+                }
+            }
+        }
+    }
+
+    /// Translate `qubit[n] q` and `bit[n] c` to the register declarations.
+    fn translate_declaration(&mut self, offset: usize, keyword: &str, body: &str) {
+        let register = if keyword == "qubit" { "qreg" } else { "creg" };
+        let rest = body[keyword.len()..].trim();
+        let (size, name) = match rest.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']') {
+                Some((size, name)) => (size.trim(), name.trim()),
+                None => return self.report(offset, format!("invalid {} declaration", keyword)),
+            },
+            None => ("1", rest),
+        };
+        if name.contains('=') {
+            return self.report(
+                offset,
+                format!("initialized {} is not supported by OpenQASM 2", keyword),
+            );
+        }
+        if size.parse::<u32>().is_err() || first_word(name) != name || name.is_empty() {
+            return self.report(
+                offset,
+                format!(
+                    "{} declaration with a non-constant size is not supported",
+                    keyword
+                ),
+            );
+        }
+        self.statements
+            .push(format!("{} {}[{}];", register, name, size));
+    }
+
+    /// Remove the `input` declaration whose parameter is bound, that is, the
+    /// parameter is not used anywhere else in the program.
+    fn translate_input(&mut self, offset: usize, body: &str) {
+        let name = identifiers(body).last().unwrap_or_default();
+        if identifiers(self.source)
+            .filter(|word| *word == name)
+            .count()
+            > 1
+        {
+            self.report(
+                offset,
+                format!(
+                    "input `{}` is not supported by OpenQASM 2, give its value in `parameters`",
+                    name
+                ),
+            );
+        }
+    }
+
+    /// Translate the gate definition, the gates in the body are translated
+    /// like the gate calls.
+    fn translate_gate_definition(&mut self, offset: usize, body: &'a str) {
+        let Some((header, block)) = body.split_once('{') else {
+            return self.report(offset, "invalid gate definition".to_owned());
+        };
+        let name = first_word(header["gate".len()..].trim_start());
+        self.declared_gates.insert(name.to_owned());
+
+        let block = block.trim_end().strip_suffix('}').unwrap_or(block);
+        let block_offset = offset + header.len() + 1;
+        let mut calls = vec![];
+        for (call_offset, call) in split_statements(block) {
+            let call = call.strip_suffix(';').unwrap_or(call).trim_end();
+            let call_offset = block_offset + call_offset;
+            let keyword = first_word(call);
+            if let Some((_, feature)) = UNSUPPORTED_KEYWORDS.iter().find(|(k, _)| *k == keyword) {
+                self.report(
+                    call_offset,
+                    format!("{} `{}` is not supported by OpenQASM 2", feature, keyword),
+                );
+            } else if keyword == "barrier" {
+                calls.push(format!("{};", call));
+            } else if let Some(call) = self.translate_gate_call(call_offset, call) {
+                calls.push(format!("{};", call));
+            }
+        }
+        self.statements
+            .push(format!("{}{{ {} }}", header, calls.join(" ")));
+    }
+
+    /// Translate the gate call, the gate is renamed or defined if it is
+    /// missing in `qelib1.inc`. Return `None` if the call can not be
+    /// translated.
+    fn translate_gate_call(&mut self, offset: usize, call: &str) -> Option<String> {
+        let name = first_word(call);
+        let arguments = &call[name.len()..];
+        if arguments.contains('@') {
+            self.report(
+                offset,
+                "gate modifier is not supported by OpenQASM 2".to_owned(),
+            );
+            return None;
+        }
+        if arguments.contains(':') || arguments.contains('$') {
+            self.report(
+                offset,
+                "range index or physical qubit is not supported by OpenQASM 2".to_owned(),
+            );
+            return None;
+        }
+
+        let name = if self.declared_gates.contains(name) || QELIB1_GATES.contains(&name) {
+            name
+        } else if let Some((_, renamed)) = RENAMED_GATES.iter().find(|(g, _)| *g == name) {
+            *renamed
+        } else if let Some((gate, _)) = DEFINED_GATES.iter().find(|(g, _)| *g == name) {
+            self.used_gates.insert(*gate);
+            *gate
+        } else {
+            self.report(offset, format!("gate `{}` is not defined", name));
+            return None;
+        };
+        Some(format!("{}{}", name, translate_expression(arguments)))
+    }
+}
+
+/// ## Translate
+/// Translate the OpenQASM 3 program to OpenQASM 2. If the program uses a
+/// feature that OpenQASM 2 does not support, return the diagnostics of all
+/// such features.
+pub fn translate(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let blanked = blank_comments(source);
+    let mut translator = Translator {
+        original: source,
+        source: &blanked,
+        statements: vec![],
+        declared_gates: BTreeSet::new(),
+        used_gates: BTreeSet::new(),
+        diagnostics: vec![],
+    };
+    for (offset, statement) in split_statements(&blanked) {
+        translator.translate_statement(offset, statement);
+    }
+    if !translator.diagnostics.is_empty() {
+        return Err(translator.diagnostics);
+    }
+
+    let mut program = vec![
+        "OPENQASM 2.0;".to_owned(),
+        "include \"qelib1.inc\";".to_owned(),
+    ];
+    program.extend(
+        DEFINED_GATES
+            .iter()
+            .filter(|(gate, _)| {
+                translator.used_gates.contains(gate) && !translator.declared_gates.contains(*gate)
+            })
+            .map(|(_, definition)| definition.to_string()),
+    );
+    program.extend(translator.statements);
+    Ok(program.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(diagnostics: &[Diagnostic]) -> Vec<(usize, usize)> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column))
+            .collect()
+    }
+
+    #[test]
+    fn translate_declarations_and_measurements() {
+        let source = "OPENQASM 3.0;\n\
+                      include \"stdgates.inc\";\n\
+                      qubit[2] q;\n\
+                      qubit r;\n\
+                      bit[2] c;\n\
+                      bit d;\n\
+                      h q[0];\n\
+                      cx q[0], q[1];\n\
+                      c = measure q;\n\
+                      d = measure r;\n\
+                      measure q[0] -> c[0];\n";
+        assert_eq!(
+            translate(source).unwrap(),
+            "OPENQASM 2.0;\n\
+             include \"qelib1.inc\";\n\
+             qreg q[2];\n\
+             qreg r[1];\n\
+             creg c[2];\n\
+             creg d[1];\n\
+             h q[0];\n\
+             cx q[0], q[1];\n\
+             measure q -> c;\n\
+             measure r -> d;\n\
+             measure q[0] -> c[0];\n"
+        );
+    }
+
+    #[test]
+    fn translate_renames_and_defines_gates() {
+        let source = "OPENQASM 3; qubit[3] q; p(π/2) q[0]; u(τ, 0, 2**3) q[1]; \
+                      sx q[0]; cswap q[0], q[1], q[2]; sx q[2]; cp(tau) q[0], q[1];";
+        let translated = translate(source).unwrap();
+        let lines: Vec<&str> = translated.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "OPENQASM 2.0;",
+                "include \"qelib1.inc\";",
+                DEFINED_GATES[0].1,
+                DEFINED_GATES[2].1,
+                "qreg q[3];",
+                "u1(pi/2) q[0];",
+                "u3((2*pi), 0, 2^3) q[1];",
+                "sx q[0];",
+                "cswap q[0], q[1], q[2];",
+                "sx q[2];",
+                "cu1((2*pi)) q[0], q[1];",
+            ]
+        );
+    }
+
+    #[test]
+    fn translate_keeps_the_declared_gates() {
+        let source = "OPENQASM 3; qubit[2] q; gate swap a, b { cx a, b; cx b, a; cx a, b; } \
+                      swap q[0], q[1];";
+        let translated = translate(source).unwrap();
+        assert!(!translated.contains(DEFINED_GATES[1].1));
+        assert_eq!(translated.matches("gate swap").count(), 1);
+        assert!(translated.ends_with("swap q[0], q[1];\n"));
+        // the program is valid OpenQASM 2 with every standard gate defined
+        for (gate, _) in DEFINED_GATES {
+            let source = format!(
+                "OPENQASM 3; qubit[4] q; {} q[0], q[1], q[2], q[3];",
+                match gate {
+                    "sx" => "sx".to_owned(),
+                    "cu" => "cu(1, 2, 3, 4)".to_owned(),
+                    "crx" | "cry" => format!("{}(0.5)", gate),
+                    gate => gate.to_owned(),
+                }
+            );
+            let translated = translate(&source).unwrap();
+            assert!(translated.contains(&format!("gate {}", gate)), "{}", gate);
+        }
+    }
+
+    #[test]
+    fn blank_comments_keeps_bytes_and_lines() {
+        let source = "h q; // π comment\n/* block\n τ */ x q; \"//not\" /* y */ z q;\n// end";
+        let blanked = blank_comments(source);
+        assert_eq!(blanked.len(), source.len());
+        assert_eq!(blanked.matches('\n').count(), source.matches('\n').count());
+        for (i, c) in source.char_indices() {
+            if c == '\n' {
+                assert_eq!(&blanked[i..=i], "\n");
+            }
+        }
+        assert_eq!(
+            blanked.split_whitespace().collect::<Vec<_>>().join(" "),
+            "h q; x q; \"//not\" z q;"
+        );
+        assert_eq!(
+            blank_comments("/* unterminated\n comment"),
+            "               \n        "
+        );
+    }
+
+    #[test]
+    fn split_statements_with_offsets() {
+        let source = "  h q;\ngate g a { x a; }\n measure q -> c ;  tail";
+        let statements = split_statements(source);
+        assert_eq!(
+            statements,
+            [
+                (2, "h q;"),
+                (7, "gate g a { x a; }"),
+                (26, "measure q -> c ;"),
+                (44, "tail"),
+            ]
+        );
+        for (offset, statement) in statements {
+            assert!(source[offset..].starts_with(statement));
+        }
+    }
+
+    #[test]
+    fn translate_reports_the_position_of_every_diagnostic() {
+        let source = "OPENQASM 3.0;\n\
+                      qubit[2] q;\n\
+                      bit[2] c;\n\
+                      /* π */ int i = 0;\n\
+                      \x20 ctrl @ x q[0], q[1]; h q[0];\n\
+                      if (c[0]) x q[0];\n\
+                      gate g a {\n  h a;\n  inv @ x a;\n  foo a;\n}\n\
+                      c[0] = 1;\n\
+                      measure q;\n\
+                      #pragma x\n\
+                      h q[0]";
+        let diagnostics = translate(source).unwrap_err();
+        assert_eq!(
+            positions(&diagnostics),
+            [
+                (4, 9),
+                (5, 3),
+                (6, 1),
+                (9, 3),
+                (10, 3),
+                (12, 1),
+                (13, 1),
+                (14, 1),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "classical type `int` is not supported by OpenQASM 2"
+        );
+        assert_eq!(diagnostics[4].message, "gate `foo` is not defined");
+    }
+
+    #[test]
+    fn translate_removes_only_bound_inputs() {
+        let bound = "OPENQASM 3; input float[64] theta; qubit q; rx(0.5) q;";
+        assert_eq!(
+            translate(bound).unwrap(),
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[1];\nrx(0.5) q;\n"
+        );
+
+        let unbound = "OPENQASM 3;\ninput angle theta;\nqubit q;\nrx(theta) q;";
+        let diagnostics = translate(unbound).unwrap_err();
+        assert_eq!(positions(&diagnostics), [(2, 1)]);
+        assert!(diagnostics[0].message.contains("`theta`"));
+
+        // the identifier is counted in the whole program, not in a comment
+        let commented = "OPENQASM 3; input angle theta; qubit q; // rx(theta) q;\nx q;";
+        assert!(translate(commented).is_ok());
+        let elsewhere = "OPENQASM 3; input angle theta; qubit theta;";
+        assert!(translate(elsewhere).is_err());
+        let prefix = "OPENQASM 3; input angle theta; qubit q; rx(theta2) q;";
+        assert!(translate(prefix).is_ok());
+    }
+
+    #[test]
+    fn qasm3_header() {
+        assert!(is_qasm3("// comment\nOPENQASM 3.0;"));
+        assert!(is_qasm3("/* a */ OPENQASM 3;"));
+        assert!(!is_qasm3("OPENQASM 2.0;"));
+        assert!(!is_qasm3("qubit q; OPENQASM 3;"));
+    }
+}
@@ -0,0 +1,256 @@
+//! The module that contains the circuit router. The circuit router manages the
+//! library of named and versioned QASM sources, so that a task can reference a
+//! stored [circuit](crate::entity::circuit::Model) by `name@version` instead of
+//! sending the code inline.
+
+use super::{extract_body, ServerState};
+use crate::entity;
+use crate::error::{Error, Result};
+use crate::service;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use log::info;
+use sea_orm::DbConn;
+use serde::Deserialize;
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+
+/// ## Circuit Message
+/// The circuit to add to the library. If a circuit with the same name exists,
+/// a new version is added.
+/// - `name`: The name of the circuit, it can only contain ASCII letters,
+///   digits, `-`, `_` and `.`.
+/// - `source`: The QASM source of the circuit.
+/// - `description`: The description of the circuit, optional.
+/// - `metadata`: Any JSON object attached to the circuit, optional. It can only
+///   be given in a JSON body.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct CircuitMessage {
+    name: String,
+    source: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<Value>,
+}
+
+/// ## Circuit Patch
+/// The description and the metadata of the circuit version to update, both
+/// are optional. The source of a version can not be changed.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct CircuitPatch {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<Value>,
+}
+
+/// ## Circuit Query
+/// - `name`: Only list the versions of this circuit, optional.
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CircuitQuery {
+    name: Option<String>,
+}
+
+/// Check that the circuit name is not empty and only contains ASCII letters,
+/// digits, `-`, `_` and `.`, so that it can be used in a `name@version`
+/// reference and in the url path.
+fn check_circuit_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(Error::InvalidRequest(format!(
+            "invalid circuit name {:?}, only ASCII letters, digits, '-', '_' and '.' are allowed",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// ## Resolve Circuit
+/// Get the circuit referenced by `name@version`, or the latest version of the
+/// circuit if the reference is only `name`. If the reference is malformed,
+/// return an invalid request error; if the circuit does not exist, return a
+/// not found error.
+pub async fn resolve_circuit(db: &DbConn, reference: &str) -> Result<entity::circuit::Model> {
+    let (name, version) = match reference.split_once('@') {
+        Some((name, version)) => (
+            name,
+            Some(version.parse::<i32>().map_err(|_| {
+                Error::InvalidRequest(format!("invalid circuit version in {:?}", reference))
+            })?),
+        ),
+        None => (reference, None),
+    };
+    check_circuit_name(name)?;
+
+    service::circuit::Circuit::get_circuit(db, name, version)
+        .await?
+        .ok_or_else(|| Error::not_found("circuit", reference))
+}
+
+/// ## Create Circuit
+/// Add a new version of the circuit to the library, the first version of a
+/// circuit is 1. The created circuit is returned with the status code 201.
+#[utoipa::path(
+    post,
+    path = "/api/v1/circuits",
+    tag = "circuits",
+    request_body(
+        content = CircuitMessage,
+        content_type = "application/json",
+        description = "The circuit to add, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 201, description = "Circuit version created", body = Circuit),
+        (status = 400, description = "Invalid circuit name", body = ErrorResponse),
+        (status = 409, description = "The version is added concurrently, retry", body = ErrorResponse),
+    )
+)]
+pub async fn create_circuit(
+    State(state): State<ServerState>,
+    request: Request,
+) -> Result<(StatusCode, Json<entity::circuit::Model>)> {
+    let message: CircuitMessage = extract_body(request).await?;
+    check_circuit_name(&message.name)?;
+
+    let circuit = service::circuit::Circuit::add_circuit(
+        &state.db,
+        entity::circuit::Model {
+            id: uuid::Uuid::new_v4(),
+            name: message.name,
+            version: 0,
+            source: message.source,
+            description: message.description,
+            metadata: message.metadata,
+            created_time: Utc::now().naive_utc(),
+            deleted_time: None,
+        },
+    )
+    .await?;
+    info!(
+        "Add circuit {}@{} successfully",
+        circuit.name, circuit.version
+    );
+    Ok((StatusCode::CREATED, Json(circuit)))
+}
+
+/// ## List Circuits
+/// List all the versions of the circuits in the library, ordered by name and
+/// version.
+#[utoipa::path(
+    get,
+    path = "/api/v1/circuits",
+    tag = "circuits",
+    params(CircuitQuery),
+    responses(
+        (status = 200, description = "The circuit versions", body = [Circuit]),
+    )
+)]
+pub async fn list_circuits(
+    State(state): State<ServerState>,
+    Query(query): Query<CircuitQuery>,
+) -> Result<Json<Vec<entity::circuit::Model>>> {
+    Ok(Json(
+        service::circuit::Circuit::get_circuits(&state.db, query.name.as_deref()).await?,
+    ))
+}
+
+/// ## Fetch Circuit
+/// Get the circuit version by the name and the version in the url path.
+#[utoipa::path(
+    get,
+    path = "/api/v1/circuits/{name}/{version}",
+    tag = "circuits",
+    params(
+        ("name" = String, Path, description = "The circuit name"),
+        ("version" = i32, Path, description = "The circuit version"),
+    ),
+    responses(
+        (status = 200, description = "The circuit version", body = Circuit),
+        (status = 404, description = "Circuit not found", body = ErrorResponse),
+    )
+)]
+pub async fn fetch_circuit(
+    State(state): State<ServerState>,
+    Path((name, version)): Path<(String, i32)>,
+) -> Result<Json<entity::circuit::Model>> {
+    let circuit = service::circuit::Circuit::get_circuit(&state.db, &name, Some(version))
+        .await?
+        .ok_or_else(|| Error::not_found("circuit", format!("{}@{}", name, version)))?;
+    Ok(Json(circuit))
+}
+
+/// ## Patch Circuit
+/// Update the description and the metadata of the circuit version by the name
+/// and the version in the url path.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/circuits/{name}/{version}",
+    tag = "circuits",
+    params(
+        ("name" = String, Path, description = "The circuit name"),
+        ("version" = i32, Path, description = "The circuit version"),
+    ),
+    request_body(
+        content = CircuitPatch,
+        content_type = "application/json",
+        description = "The fields to update, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 200, description = "The updated circuit version", body = Circuit),
+        (status = 404, description = "Circuit not found", body = ErrorResponse),
+    )
+)]
+pub async fn patch_circuit(
+    State(state): State<ServerState>,
+    Path((name, version)): Path<(String, i32)>,
+    request: Request,
+) -> Result<Json<entity::circuit::Model>> {
+    let message: CircuitPatch = extract_body(request).await?;
+    let circuit = service::circuit::Circuit::update_circuit(
+        &state.db,
+        &name,
+        version,
+        message.description,
+        message.metadata,
+    )
+    .await?;
+    info!("Update circuit {}@{} successfully", name, version);
+    Ok(Json(circuit))
+}
+
+/// ## Delete Circuit
+/// Remove the circuit version by the name and the version in the url path,
+/// the removed circuit is returned. The tasks that have run this version keep
+/// their copy of the source, and the version number is not reused.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/circuits/{name}/{version}",
+    tag = "circuits",
+    params(
+        ("name" = String, Path, description = "The circuit name"),
+        ("version" = i32, Path, description = "The circuit version"),
+    ),
+    responses(
+        (status = 200, description = "The removed circuit version", body = Circuit),
+        (status = 404, description = "Circuit not found", body = ErrorResponse),
+    )
+)]
+pub async fn delete_circuit(
+    State(state): State<ServerState>,
+    Path((name, version)): Path<(String, i32)>,
+) -> Result<Json<entity::circuit::Model>> {
+    let circuit = service::circuit::Circuit::remove_circuit(&state.db, &name, version).await?;
+    info!("Remove circuit {}@{} successfully", name, version);
+    Ok(Json(circuit))
+}
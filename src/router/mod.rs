@@ -1,13 +1,23 @@
-use axum::{extract::State, Json};
-use http::StatusCode;
-use log::{error, info};
+use crate::error::{Error, Result};
+use axum::{
+    extract::{Request, State},
+    response::Response,
+    Form, Json, RequestExt,
+};
+use http::{header, HeaderMap, HeaderValue};
+use log::info;
 use migration::{Migrator, MigratorTrait};
 use sea_orm::DbConn;
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 
+pub mod circuit;
+pub mod openapi;
 pub mod physical_agent;
 pub mod physical_agent_utils;
+pub mod reservation;
 pub mod task;
+pub mod usage;
 
 /// ## Server State
 /// The server state is a struct that holds the database connection and the
@@ -19,23 +29,153 @@ pub struct ServerState {
     pub config: super::config::QSchedulerConfig,
 }
 
-pub async fn fresh_db(State(state): State<ServerState>) -> (StatusCode, Json<Value>) {
-    match Migrator::fresh(&state.db).await {
-        Ok(_) => {
-            info!("fresh database success: drop all tables from the database, then reapply all migrations.");
-            (
-                StatusCode::OK,
-                Json(
-                    json!({ "result": "drop all tables from the database, then reapply all migrations." }),
-                ),
-            )
+/// ## Extract Body
+/// Deserialize the request body according to its content type. The content
+/// type can be either `application/json` or
+/// `application/x-www-form-urlencoded`, parameters such as `charset` are
+/// ignored. If the content type is missing or not supported, or the body can
+/// not be deserialized, return an error.
+pub async fn extract_body<T>(request: Request) -> Result<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    let content_type = match request.headers().get(header::CONTENT_TYPE) {
+        Some(content_type) => content_type
+            .to_str()
+            .map_err(|_| Error::UnsupportedContentType(Some(format!("{:?}", content_type))))?
+            .to_owned(),
+        None => return Err(Error::UnsupportedContentType(None)),
+    };
+
+    match content_type.split(';').next().unwrap_or_default().trim() {
+        "application/json" => {
+            let Json(message) = request
+                .extract::<Json<T>, _>()
+                .await
+                .map_err(|rejection| Error::InvalidRequest(rejection.body_text()))?;
+            Ok(message)
         }
-        Err(e) => {
-            error!("fresh database error: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": e.to_string() })),
-            )
+        "application/x-www-form-urlencoded" => {
+            let Form(message) = request
+                .extract::<Form<T>, _>()
+                .await
+                .map_err(|rejection| Error::InvalidRequest(rejection.body_text()))?;
+            Ok(message)
         }
+        _ => Err(Error::UnsupportedContentType(Some(content_type))),
+    }
+}
+
+/// ## Request User
+/// The user of the request from the `user_header` of the
+/// [admission config](crate::config::AdmissionConfig), which is set by a
+/// trusted authenticating proxy. Return `None` if no header is configured,
+/// then the user is given by the request body. If the header is configured
+/// but missing or empty, return an unauthorized error.
+pub fn request_user(
+    headers: &HeaderMap,
+    config: &crate::config::AdmissionConfig,
+) -> Result<Option<String>> {
+    let Some(name) = &config.user_header else {
+        return Ok(None);
+    };
+    headers
+        .get(name.as_str())
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .map(|user| Some(user.to_owned()))
+        .ok_or_else(|| Error::Unauthorized {
+            header: name.clone(),
+        })
+}
+
+/// ## Deprecated
+/// Mark the response of a legacy unversioned route as deprecated with the
+/// `Deprecation` header, the successor routes are under `/api/v1`.
+pub async fn deprecated(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert("deprecation", HeaderValue::from_static("true"));
+    response
+}
+
+/// ## Fresh DB
+/// Drop all tables from the database, then reapply all migrations.
+///
+/// Deprecated, please use [fresh_database] instead.
+pub async fn fresh_db(State(state): State<ServerState>) -> Result<Json<Value>> {
+    Migrator::fresh(&state.db).await?;
+    info!(
+        "fresh database success: drop all tables from the database, then reapply all migrations."
+    );
+    Ok(Json(
+        json!({ "result": "drop all tables from the database, then reapply all migrations." }),
+    ))
+}
+
+/// ## Fresh Database
+/// Please refer to the [fresh_db] function.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/fresh-db",
+    tag = "admin",
+    responses(
+        (status = 200, description = "All tables are dropped and migrations reapplied"),
+        (status = 500, description = "Database error", body = ErrorResponse),
+    )
+)]
+pub async fn fresh_database(state: State<ServerState>) -> Result<Json<Value>> {
+    fresh_db(state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AdmissionConfig;
+
+    fn config(user_header: Option<&str>) -> AdmissionConfig {
+        AdmissionConfig {
+            user_header: user_header.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    fn headers(user: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-user", HeaderValue::from_str(user).unwrap());
+        headers
+    }
+
+    #[test]
+    fn user_is_taken_from_the_configured_header() {
+        let config = config(Some("X-Forwarded-User"));
+        assert_eq!(
+            request_user(&headers(" alice "), &config).unwrap(),
+            Some("alice".to_owned())
+        );
+    }
+
+    #[test]
+    fn missing_or_empty_header_is_unauthorized() {
+        let config = config(Some("X-Forwarded-User"));
+        for headers in [HeaderMap::new(), headers(" ")] {
+            assert!(matches!(
+                request_user(&headers, &config),
+                Err(Error::Unauthorized { header }) if header == "X-Forwarded-User"
+            ));
+        }
+    }
+
+    #[test]
+    fn header_is_ignored_if_not_configured() {
+        assert_eq!(
+            request_user(&headers("alice"), &config(None)).unwrap(),
+            None
+        );
+        assert_eq!(
+            request_user(&HeaderMap::new(), &config(None)).unwrap(),
+            None
+        );
     }
 }
@@ -0,0 +1,136 @@
+//! The module that contains the OpenAPI 3 document of the versioned
+//! `/api/v1` API. The document is generated from the annotated handlers by
+//! [utoipa](https://github.com/juhaku/utoipa) and served at
+//! `/api/v1/openapi.json`, so that clients in other languages can be
+//! generated from it.
+
+use super::{circuit, physical_agent, physical_agent_utils, reservation, task, usage};
+use crate::entity;
+use crate::error::ErrorResponse;
+use axum::Json;
+use utoipa::OpenApi;
+
+/// ## API Doc
+/// The OpenAPI document of the `/api/v1` API. The legacy unversioned routes
+/// are deprecated aliases and are not part of the document.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "emulate-server",
+        description = "Quantum task scheduler for emulator agents"
+    ),
+    paths(
+        task::create_task,
+        task::create_sweep,
+        task::fetch_task,
+        physical_agent::create_agent,
+        physical_agent::list_agents,
+        physical_agent::fetch_agent,
+        physical_agent::patch_agent,
+        physical_agent::delete_agent,
+        physical_agent::register_agent,
+        physical_agent::renew_agent_lease,
+        physical_agent::deregister_agent,
+        physical_agent::drain_agent,
+        physical_agent::fetch_drain,
+        circuit::create_circuit,
+        circuit::list_circuits,
+        circuit::fetch_circuit,
+        circuit::patch_circuit,
+        circuit::delete_circuit,
+        usage::get_usage,
+        reservation::create_reservation,
+        reservation::list_reservations,
+        reservation::cancel_reservation,
+        super::fresh_database,
+    ),
+    components(schemas(
+        task::EmulateMessage,
+        task::SweepMessage,
+        task::TaskView,
+        physical_agent_utils::AgentInfo,
+        physical_agent_utils::AgentInfoPatch,
+        physical_agent_utils::AgentStatus,
+        physical_agent_utils::AgentView,
+        physical_agent_utils::AgentDetail,
+        physical_agent_utils::AgentUtilization,
+        physical_agent_utils::AgentChange,
+        crate::capability::AgentCapability,
+        crate::capability::TaskRequirements,
+        crate::noise::NoiseModel,
+        crate::qasm::Diagnostic,
+        circuit::CircuitMessage,
+        circuit::CircuitPatch,
+        usage::UsageReport,
+        reservation::ReservationMessage,
+        crate::service::usage::UserUsage,
+        entity::task_active::Model,
+        entity::task::Model,
+        entity::physical_agent::Model,
+        entity::circuit::Model,
+        entity::agent_drain::Model,
+        entity::task_assignment::Model,
+        entity::agent_reservation::Model,
+        entity::sea_orm_active_enums::TaskActiveStatus,
+        entity::sea_orm_active_enums::TaskStatus,
+        entity::sea_orm_active_enums::PhysicalAgentStatus,
+        entity::sea_orm_active_enums::DrainStatus,
+        entity::sea_orm_active_enums::AssignmentStatus,
+        ErrorResponse,
+    )),
+    tags(
+        (name = "tasks", description = "Submit and query quantum tasks"),
+        (name = "agents", description = "Manage the physical agents"),
+        (name = "circuits", description = "Manage the library of versioned circuits"),
+        (name = "usage", description = "Report the usage of the users"),
+        (name = "reservations", description = "Book agents for the sole use of a user"),
+        (name = "admin", description = "Administrative operations"),
+    )
+)]
+pub struct ApiDoc;
+
+/// ## OpenAPI JSON
+/// Return the OpenAPI document of the `/api/v1` API.
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// Collect the `$ref` values of the document.
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object {
+                    match (key.as_str(), value) {
+                        ("$ref", Value::String(reference)) => found.push(reference),
+                        _ => refs(value, found),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_ref_resolves_to_a_component() {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut found = vec![];
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let pointer = reference
+                .strip_prefix('#')
+                .unwrap_or_else(|| panic!("external reference {}", reference));
+            assert!(
+                document.pointer(pointer).is_some(),
+                "dangling reference {}",
+                reference
+            );
+        }
+    }
+}
@@ -1,106 +1,140 @@
 //! The module that contains the physical agent router. The physical agent
 //! router is used to add, get, and update the physical agent information.
 
+use crate::dispatch;
 use crate::entity;
 use crate::entity::sea_orm_active_enums;
+use crate::error::{Error, Result};
 use crate::service;
-use axum::extract::{Query, Request};
+use crate::service::task_assignment::AgentAssignments;
+use axum::extract::{Path, Query, Request};
 use axum::{extract::State, http::StatusCode, Json};
-use axum::{Form, RequestExt};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use dns_lookup::lookup_host;
-use http::header;
 use log::{error, info};
 use sea_orm::DbConn;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
 
-use super::physical_agent_utils::{AgentAddress, AgentInfo, AgentInfoUpdate, AgentStatus, Agents};
-use super::ServerState;
+use super::physical_agent_utils::{
+    AgentAddress, AgentChange, AgentDetail, AgentFilter, AgentInfo, AgentInfoPatch,
+    AgentInfoUpdate, AgentUpdate, AgentUtilization, AgentView, AgentWindow, Agents, DrainAction,
+    DrainOptions, DEFAULT_UTILIZATION_WINDOW,
+};
+use super::{extract_body, ServerState};
 
 /// ## Get Agent Info
 /// Get the agent information from the given path and return the Agents struct.
-/// If the file does not exist, return an empty Agents struct.
+/// If the file does not exist or can not be parsed, return an empty Agents
+/// struct.
 pub fn get_agent_info(path: &str) -> Agents {
     if !std::path::Path::new(path).exists() {
         error!("Agents info file not found");
-        Agents { agents: vec![] }
-    } else {
-        let agent_info = std::fs::read_to_string(path).unwrap();
-        let agent_info: Agents = serde_json::from_str(&agent_info).unwrap();
-        agent_info
+        return Agents { agents: vec![] };
+    }
+
+    match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|agent_info| {
+            serde_json::from_str::<Agents>(&agent_info).map_err(|err| err.to_string())
+        }) {
+        Ok(agents) => agents,
+        Err(err) => {
+            error!("Read agents info file {} failed: {}", path, err);
+            Agents { agents: vec![] }
+        }
     }
 }
 
-/// Internal function to add a physical agent to the database
-async fn _add_physical_agent(
-    state: ServerState,
-    Form(mut query_message): Form<AgentInfo>,
-) -> (StatusCode, Json<Value>) {
+/// ## Resolve IP
+/// Return the given ip if it is not empty, otherwise use the hostname to get
+/// the ip address. If the hostname is also missing or can not be resolved,
+/// return an error.
+fn resolve_ip(ip: &str, hostname: Option<&str>) -> Result<String> {
+    if !ip.is_empty() {
+        return Ok(ip.to_owned());
+    }
+
+    let hostname = hostname.ok_or_else(|| {
+        Error::InvalidRequest("either ip or hostname of the agent must be provided".to_owned())
+    })?;
+
+    info!("Using hostname to get the ip address");
+    let ips = lookup_host(hostname).map_err(|err| Error::HostResolution {
+        hostname: hostname.to_owned(),
+        reason: err.to_string(),
+    })?;
+
+    match ips.first() {
+        Some(ip) => {
+            info!("Get ip address successfully: {:?}", ips);
+            Ok(ip.to_string())
+        }
+        None => Err(Error::HostResolution {
+            hostname: hostname.to_owned(),
+            reason: "No ip address found".to_owned(),
+        }),
+    }
+}
+
+/// Build a running physical agent from the agent information. If the ip is
+/// empty, use the hostname to get the ip address. An agent without capability
+/// document has the [default](crate::capability::AgentCapability) one.
+fn agent_model(
+    agent: AgentInfo,
+    lease_expire_time: Option<NaiveDateTime>,
+) -> Result<entity::physical_agent::Model> {
     info!(
         "Init physical agent (qubits: {}, circuit_depth: {}) with {}:{:?} (hostname: {:?})",
-        query_message.qubit_count,
-        query_message.circuit_depth,
-        query_message.ip,
-        query_message.port,
-        query_message.hostname
+        agent.qubit_count, agent.circuit_depth, agent.ip, agent.port, agent.hostname
     );
-    let db = &state.db;
 
     // if the ip is empty, use the hostname to get the ip address
-    if query_message.ip == "" {
-        info!("Using hostname to get the ip address");
-        // not check hostname is none
-        match lookup_host(&query_message.hostname.unwrap()) {
-            Ok(ips) => {
-                if ips.len() == 0 {
-                    error!("Get ip address failed: No ip address found");
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": "No ip address found"})),
-                    );
-                }
-                info!("Get ip address successfully: {:?}", ips);
-                let ip = ips[0].to_string();
-                query_message.ip = ip;
-            }
-            Err(err) => {
-                error!("Get ip address failed: {}", err);
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": format!("{}", err)})),
-                );
-            }
-        }
-    }
+    let ip = resolve_ip(&agent.ip, agent.hostname.as_deref())?;
 
-    match service::physical_agent::PhysicalAgent::add_physical_agent(
-        db,
-        entity::physical_agent::Model {
-            id: uuid::Uuid::new_v4(),
-            status: sea_orm_active_enums::PhysicalAgentStatus::Running,
-            ip: query_message.ip.clone(),
-            port: query_message.port as i32,
-            qubit_count: query_message.qubit_count as i32,
-            qubit_idle: query_message.qubit_count as i32,
-            circuit_depth: query_message.circuit_depth as i32,
-        },
-    )
-    .await
-    {
-        Ok(agent) => {
-            info!(
-                "Add {}:{} physical agents added successfully",
-                query_message.ip, query_message.port
-            );
-            (StatusCode::OK, Json(json!({"agent": agent})))
-        }
-        Err(err) => {
-            error!("Add physical agents failed: {}", err);
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("{}", err)})),
-            )
-        }
-    }
+    let mut model = entity::physical_agent::Model {
+        id: uuid::Uuid::new_v4(),
+        status: sea_orm_active_enums::PhysicalAgentStatus::Running,
+        ip,
+        port: agent.port as i32,
+        qubit_count: agent.qubit_count as i32,
+        qubit_idle: agent.qubit_count as i32,
+        circuit_depth: agent.circuit_depth as i32,
+        lease_expire_time,
+        discovery: None,
+        simulator: String::new(),
+        gates: None,
+        noise: false,
+        max_shots: None,
+        memory_mb: None,
+        qasm3: false,
+        memory_idle_mb: None,
+        slots: None,
+        slots_idle: None,
+        labels: agent.labels.map(serde_json::to_value).transpose()?,
+        pool: agent.pool,
+        lost_time: None,
+    };
+    agent.capability.unwrap_or_default().apply_to(&mut model);
+    Ok(model)
+}
+
+/// The expire time of a lease that is granted or renewed now.
+fn lease_expire_time(lease_ttl: u64) -> NaiveDateTime {
+    (Utc::now() + TimeDelta::seconds(lease_ttl.min(i32::MAX as u64) as i64)).naive_utc()
+}
+
+/// Internal function to add a physical agent to the database
+async fn _add_physical_agent(
+    db: &DbConn,
+    agent: AgentInfo,
+) -> Result<entity::physical_agent::Model> {
+    let agent =
+        service::physical_agent::PhysicalAgent::add_physical_agent(db, agent_model(agent, None)?)
+            .await?;
+    dispatch::wake(db).await;
+    Ok(agent)
 }
 
 /// ## Add Physical Agent
@@ -110,36 +144,51 @@ async fn _add_physical_agent(
 /// return an error.
 ///
 /// If the ip is empty, use the hostname to get the ip address.
+///
+/// Deprecated, please use [create_agent] instead.
 pub async fn add_physical_agent(
     State(state): State<ServerState>,
     request: Request,
-) -> (StatusCode, Json<Value>) {
-    match request.headers().get(header::CONTENT_TYPE) {
-        Some(content_type) => match content_type.to_str().unwrap() {
-            "application/json" => {
-                let Json::<AgentInfo>(message) = request.extract().await.unwrap();
-                _add_physical_agent(state, Form(message)).await
-            }
-            "application/x-www-form-urlencoded" => {
-                let Form(message) = request.extract().await.unwrap();
-                _add_physical_agent(state, Form(message)).await
-            }
-            _ => {
-                error!("Add physical agents failed: Invalid content type");
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": "Invalid content type"})),
-                )
-            }
-        },
-        None => {
-            error!("Add physical agents failed: No content type");
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": "No content type"})),
-            )
-        }
-    }
+) -> Result<Json<Value>> {
+    let message: AgentInfo = extract_body(request).await?;
+    let agent = _add_physical_agent(&state.db, message).await?;
+    info!(
+        "Add {}:{} physical agents added successfully",
+        agent.ip, agent.port
+    );
+    Ok(Json(json!({"agent": agent})))
+}
+
+/// ## Create Agent
+/// Add a physical agent to the database, please refer to
+/// [add_physical_agent]. The created agent is returned with the status code
+/// 201.
+#[utoipa::path(
+    post,
+    path = "/api/v1/agents",
+    tag = "agents",
+    request_body(
+        content = AgentInfo,
+        content_type = "application/json",
+        description = "The agent to add, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 201, description = "Agent created", body = PhysicalAgent),
+        (status = 400, description = "Hostname can not be resolved", body = ErrorResponse),
+        (status = 409, description = "Agent already exists", body = ErrorResponse),
+    )
+)]
+pub async fn create_agent(
+    State(state): State<ServerState>,
+    request: Request,
+) -> Result<(StatusCode, Json<entity::physical_agent::Model>)> {
+    let message: AgentInfo = extract_body(request).await?;
+    let agent = _add_physical_agent(&state.db, message).await?;
+    info!(
+        "Add {}:{} physical agents added successfully",
+        agent.ip, agent.port
+    );
+    Ok((StatusCode::CREATED, Json(agent)))
 }
 
 /// ## Add Physical Agent From File
@@ -147,258 +196,676 @@ pub async fn add_physical_agent(
 /// contain the agent information in JSON format. This function is used by the
 /// consume task thread.
 ///
-/// If the ip is empty, use the hostname to get the ip address.
+/// If the ip is empty, use the hostname to get the ip address. An agent that
+/// can not be added is logged and skipped.
 pub async fn add_physical_agent_from_file(db: &DbConn, agents: Agents) {
-    for mut agent in agents.agents {
-        // if the ip is empty, use the hostname to get the ip address
-        if agent.ip == "" {
-            info!("Using hostname to get the ip address");
-            // not check hostname is none
-            match lookup_host(&agent.hostname.unwrap()) {
-                Ok(ips) => {
-                    if ips.len() == 0 {
-                        error!("Get ip address failed: No ip address found");
-                        continue;
-                    }
-                    info!("Get ip address successfully: {:?}", ips);
-                    let ip = ips[0].to_string();
-                    agent.ip = ip;
-                }
-                Err(err) => {
-                    error!("Get ip address failed: {}", err);
-                    continue;
-                }
-            }
-        }
-        match service::physical_agent::PhysicalAgent::add_physical_agent(
-            db,
-            entity::physical_agent::Model {
-                id: uuid::Uuid::new_v4(),
-                status: sea_orm_active_enums::PhysicalAgentStatus::Running,
-                ip: agent.ip,
-                port: agent.port as i32,
-                qubit_count: agent.qubit_count as i32,
-                qubit_idle: agent.qubit_count as i32,
-                circuit_depth: agent.circuit_depth as i32,
-            },
-        )
-        .await
-        {
+    for agent in agents.agents {
+        match _add_physical_agent(db, agent).await {
             Ok(a) => {
                 info!(
                     "Add {}:{} physical agent with qubit_count: {}, circuit_depth: {}",
                     a.ip, a.port, a.qubit_count, a.circuit_depth
                 );
             }
-            Err(err) => match err {
-                sea_orm::DbErr::Custom(e) => {
-                    error!("Add physical agent failed: {}", e);
-                }
-                _ => {
-                    error!("Add physical agent failed: {}", err);
-                }
-            },
+            Err(err) => {
+                error!("Add physical agent failed: {}", err);
+            }
         }
     }
 }
 
+/// Internal function to get the physical agents by the given address
+async fn _get_physical_agent_by_address(
+    db: &DbConn,
+    query_message: AgentAddress,
+) -> Result<Vec<entity::physical_agent::Model>> {
+    info!("Get physical agent by address: {:?}", query_message);
+
+    let ip = resolve_ip(&query_message.ip, query_message.hostname.as_deref())?;
+
+    match query_message.port {
+        None => service::physical_agent::PhysicalAgent::get_physical_agent_by_ip(db, ip).await,
+        Some(port) => Ok(
+            service::physical_agent::PhysicalAgent::get_physical_agent_by_address(
+                db,
+                ip,
+                port as i32,
+            )
+            .await?
+            .into_iter()
+            .collect(),
+        ),
+    }
+}
+
 /// ## Get Physical Agent By Address
 /// Get the physical agent by the given ip and port. If the port is not
 /// provided, return all the agents with the given ip. If the ip is empty, use
 /// the hostname to get the ip address.
+///
+/// Deprecated, please use [list_agents] instead.
 pub async fn get_physical_agent_by_address(
     State(state): State<ServerState>,
-    Query(mut query_message): Query<AgentAddress>,
-) -> (StatusCode, Json<Value>) {
-    info!("Get physical agent by address: {:?}", query_message);
+    Query(query_message): Query<AgentAddress>,
+) -> Result<Json<Value>> {
+    let port = query_message.port;
+    let host = match &query_message.hostname {
+        Some(hostname) if query_message.ip.is_empty() => hostname.clone(),
+        _ => query_message.ip.clone(),
+    };
+    let address = match port {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    let mut agents = _get_physical_agent_by_address(&state.db, query_message).await?;
+    if agents.is_empty() {
+        return Err(Error::not_found("physical agent", address));
+    }
+    info!("Get physical agent by address successfully");
+
+    match port {
+        None => Ok(Json(json!({
+            "agents": agents,
+        }))),
+        Some(_) => Ok(Json(json!({
+            "agent": agents.remove(0),
+        }))),
+    }
+}
 
-    let db = &state.db;
-
-    if query_message.ip == "" {
-        info!("Using hostname to get the ip address");
-        // not check hostname is none
-        match lookup_host(&query_message.hostname.unwrap()) {
-            Ok(ips) => {
-                if ips.len() == 0 {
-                    error!("Get ip address failed: No ip address found");
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": "No ip address found"})),
-                    );
+/// Parse the statuses of the agents separated by commas, e.g.
+/// `running,draining`.
+fn parse_statuses(statuses: &str) -> Result<Vec<sea_orm_active_enums::PhysicalAgentStatus>> {
+    statuses
+        .split(',')
+        .map(|status| match status.trim() {
+            "running" => Ok(sea_orm_active_enums::PhysicalAgentStatus::Running),
+            "down" => Ok(sea_orm_active_enums::PhysicalAgentStatus::Down),
+            "draining" => Ok(sea_orm_active_enums::PhysicalAgentStatus::Draining),
+            status => Err(Error::InvalidRequest(format!(
+                "Unknown agent status {:?}, expected running, down or draining",
+                status
+            ))),
+        })
+        .collect()
+}
+
+/// The start of the recent window of the agent utilization.
+fn window_start(window: u64) -> NaiveDateTime {
+    (Utc::now() - TimeDelta::seconds(window.min(i32::MAX as u64) as i64)).naive_utc()
+}
+
+/// ## List Agents
+/// List the physical agents with their live utilization: the running
+/// assignments, the share of the capacity in use, and the failure rate and the
+/// served shots in the recent window. The agents can be filtered by the
+/// address, like [get_physical_agent_by_address], and by the statuses. All
+/// the agents are listed if no filter is given.
+#[utoipa::path(
+    get,
+    path = "/api/v1/agents",
+    tag = "agents",
+    params(AgentFilter),
+    responses(
+        (status = 200, description = "The matched agents", body = [AgentView]),
+        (status = 400, description = "Unknown status or hostname can not be resolved", body = ErrorResponse),
+    )
+)]
+pub async fn list_agents(
+    State(state): State<ServerState>,
+    Query(filter): Query<AgentFilter>,
+) -> Result<Json<Vec<AgentView>>> {
+    let statuses = match &filter.status {
+        Some(statuses) => parse_statuses(statuses)?,
+        None => vec![],
+    };
+    let agents = match filter.address() {
+        Some(address) => _get_physical_agent_by_address(&state.db, address)
+            .await?
+            .into_iter()
+            .filter(|agent| statuses.is_empty() || statuses.contains(&agent.status))
+            .collect(),
+        None => {
+            service::physical_agent::PhysicalAgent::get_physical_agents(&state.db, &statuses)
+                .await?
+        }
+    };
+
+    let window = filter.window.unwrap_or(DEFAULT_UTILIZATION_WINDOW);
+    let mut assignments: HashMap<Uuid, AgentAssignments> =
+        service::task_assignment::TaskAssignment::get_assignments_by_agents(
+            &state.db,
+            None,
+            window_start(window),
+        )
+        .await?
+        .into_iter()
+        .map(|assignments| (assignments.agent_id, assignments))
+        .collect();
+    let mut reservations: HashMap<Uuid, Vec<entity::capacity_reservation::Model>> = HashMap::new();
+    for reservation in service::capacity::Capacity::get_reservations(&state.db).await? {
+        reservations
+            .entry(reservation.agent_id)
+            .or_default()
+            .push(reservation);
+    }
+    Ok(Json(
+        agents
+            .into_iter()
+            .map(|agent| {
+                let assignments = assignments.remove(&agent.id).unwrap_or_default();
+                let reservations = reservations.remove(&agent.id).unwrap_or_default();
+                AgentView {
+                    utilization: AgentUtilization::new(&agent, &reservations, &assignments, window),
+                    agent,
                 }
-                info!("Get ip address successfully: {:?}", ips);
-                let ip = ips[0].to_string();
-                query_message.ip = ip;
-            }
-            Err(err) => {
-                error!("Get ip address failed: {}", err);
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": format!("{}", err)})),
-                );
-            }
+            })
+            .collect(),
+    ))
+}
+
+/// ## Fetch Agent
+/// Get the physical agent by the agent id in the url path, with its
+/// utilization like [list_agents] and its assignment history, the latest
+/// first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/agents/{id}",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id"), AgentWindow),
+    responses(
+        (status = 200, description = "The agent", body = AgentDetail),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+    )
+)]
+pub async fn fetch_agent(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+    Query(query): Query<AgentWindow>,
+) -> Result<Json<AgentDetail>> {
+    let agent = service::physical_agent::PhysicalAgent::get_physical_agent(&state.db, agent_id)
+        .await?
+        .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+
+    let window = query.window.unwrap_or(DEFAULT_UTILIZATION_WINDOW);
+    let assignments = service::task_assignment::TaskAssignment::get_assignments_by_agents(
+        &state.db,
+        Some(agent_id),
+        window_start(window),
+    )
+    .await?
+    .pop()
+    .unwrap_or_default();
+    let reservations =
+        service::capacity::Capacity::get_agent_reservations(&state.db, agent_id).await?;
+    let history =
+        service::task_assignment::TaskAssignment::get_assignment_by_agent(&state.db, agent_id)
+            .await?;
+    Ok(Json(AgentDetail {
+        utilization: AgentUtilization::new(&agent, &reservations, &assignments, window),
+        agent,
+        assignments: history,
+    }))
+}
+
+/// ## Register Agent
+/// Register the agent that calls this api on boot. The agent information is
+/// passed in the request body like [create_agent]. If the agent with the same
+/// address exists, e.g. the agent is restarted, its capacity is updated and
+/// it is set to running. The agent gets a lease of `agent_lease_ttl` seconds,
+/// it should [renew](renew_agent_lease) the lease before it expires,
+/// otherwise it is marked as down.
+#[utoipa::path(
+    post,
+    path = "/api/v1/agents/register",
+    tag = "agents",
+    request_body(
+        content = AgentInfo,
+        content_type = "application/json",
+        description = "The agent to register, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 200, description = "The registered agent with its lease", body = PhysicalAgent),
+        (status = 400, description = "Hostname can not be resolved", body = ErrorResponse),
+    )
+)]
+pub async fn register_agent(
+    State(state): State<ServerState>,
+    request: Request,
+) -> Result<Json<entity::physical_agent::Model>> {
+    let message: AgentInfo = extract_body(request).await?;
+    let agent = agent_model(
+        message,
+        Some(lease_expire_time(state.config.agent_lease_ttl)),
+    )?;
+    let agent =
+        service::physical_agent::PhysicalAgent::register_physical_agent(&state.db, agent).await?;
+    dispatch::wake(&state.db).await;
+    info!(
+        "Register physical agent {:?} ({}:{}) successfully",
+        agent.id, agent.ip, agent.port
+    );
+    Ok(Json(agent))
+}
+
+/// ## Renew Agent Lease
+/// Renew the lease of the registered agent by the agent id in the url path,
+/// the lease expires `agent_lease_ttl` seconds later. An agent that was
+/// marked as down because its lease expired is set to running again.
+#[utoipa::path(
+    post,
+    path = "/api/v1/agents/{id}/lease",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id")),
+    responses(
+        (status = 200, description = "The agent with the renewed lease", body = PhysicalAgent),
+        (status = 404, description = "Agent not found, it should register again", body = ErrorResponse),
+    )
+)]
+pub async fn renew_agent_lease(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+) -> Result<Json<entity::physical_agent::Model>> {
+    let agent = service::physical_agent::PhysicalAgent::renew_physical_agent_lease(
+        &state.db,
+        agent_id,
+        lease_expire_time(state.config.agent_lease_ttl),
+    )
+    .await?;
+    // the agent may be back from down
+    dispatch::wake(&state.db).await;
+    Ok(Json(agent))
+}
+
+/// ## Deregister Agent
+/// Deregister the agent by the agent id in the url path on a clean shutdown.
+/// The agent is removed at once if it is idle or down. Otherwise it is
+/// [drained](drain_agent) so that no new task is dispatched to it, and the
+/// drain job is returned, the agent is removed when its running chunks are
+/// finished. If they are not finished in `drain.timeout` seconds, the agent
+/// is kept with its previous status.
+#[utoipa::path(
+    post,
+    path = "/api/v1/agents/{id}/deregister",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id")),
+    responses(
+        (status = 200, description = "The removed agent", body = PhysicalAgent),
+        (status = 202, description = "The drain job that removes the agent when it is drained", body = AgentDrain),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+        (status = 409, description = "Agent is busy and already draining", body = ErrorResponse),
+    )
+)]
+pub async fn deregister_agent(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+) -> Result<(StatusCode, Json<AgentChange>)> {
+    info!("Deregister physical agent: {:?}", agent_id);
+    let timeout = state.config.drain.timeout;
+    change_after_drain(&state.db, agent_id, None, &DrainOptions::default(), timeout).await
+}
+
+/// ## Expire Agent Leases
+/// Mark the registered agents whose lease has expired as down, and
+/// [release](release_lost_agent) the reservations of the chunks running on
+/// them. This function is called by the consume thread before dispatching the
+/// waiting tasks, any error is logged.
+pub async fn expire_agent_leases(db: &DbConn) {
+    let expired = match service::physical_agent::PhysicalAgent::expire_physical_agent_leases(
+        db,
+        Utc::now().naive_utc(),
+    )
+    .await
+    {
+        Ok(expired) => expired,
+        Err(err) => {
+            error!("Expire physical agent leases failed: {}", err);
+            return;
+        }
+    };
+
+    for agent_id in expired {
+        info!("Physical agent {:?} is down as its lease expired", agent_id);
+        if let Err(err) = release_lost_agent(db, agent_id).await {
+            error!(
+                "Release the reservations of physical agent {:?} failed: {}",
+                agent_id, err
+            );
         }
     }
+}
 
-    if query_message.port.is_none() {
-        match service::physical_agent::PhysicalAgent::get_physical_agent_by_ip(
+/// Release the reservations of the physical agent that is lost, the chunks
+/// running on it will not return. Their assignments are marked as failed and
+/// their tasks are waiting again, like the [requeued](super::task::requeue_tasks)
+/// ones. A chunk that still returns finds its reservation released and is
+/// discarded, and a chunk that returned meanwhile has released its
+/// reservation, so its task is left as is.
+async fn release_lost_agent(db: &DbConn, agent_id: Uuid) -> Result<()> {
+    for reservation in service::capacity::Capacity::get_agent_reservations(db, agent_id).await? {
+        if !service::capacity::Capacity::release(db, reservation.id).await? {
+            continue;
+        }
+        service::task_assignment::TaskAssignment::fail_running_assignments(db, reservation.task_id)
+            .await?;
+        service::task_active::TaskActive::update_task_status(
             db,
-            query_message.ip.clone(),
+            reservation.task_id,
+            sea_orm_active_enums::TaskActiveStatus::Waiting,
         )
-        .await
-        {
-            Ok(agents) => match agents.len() {
-                0 => {
-                    error!("Get physical agent by address failed: No agent found");
-                    return (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": "No agent found"})),
-                    );
-                }
-                _ => {
-                    info!("Get physical agent by address successfully");
-                    (
-                        StatusCode::OK,
-                        Json(json!({
-                            "agents": agents,
-                        })),
-                    )
-                }
-            },
-            Err(err) => {
-                error!("Get physical agent by address failed: {}", err);
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": format!("{}", err)})),
-                )
-            }
-        }
-    } else {
-        match service::physical_agent::PhysicalAgent::get_physical_agent_by_address(
+        .await?;
+        info!(
+            "Task {:?} on lost physical agent {:?} is requeued",
+            reservation.task_id, agent_id
+        );
+    }
+    Ok(())
+}
+
+/// The deadline of a drain that starts now.
+fn drain_deadline(timeout: u64) -> NaiveDateTime {
+    (Utc::now() + TimeDelta::seconds(timeout.min(i32::MAX as u64) as i64)).naive_utc()
+}
+
+/// Mark the physical agent as draining, so that no new chunk is dispatched to
+/// it, and add the drain job that is finished when the running chunks are,
+/// with the change of the agent to apply then. A down agent gets no new
+/// chunk either, it is not marked and its job is finished as `drained` at
+/// once. An agent that is already draining is a conflict, its drain job
+/// would restore the `draining` status when it times out.
+async fn start_drain(
+    db: &DbConn,
+    agent: &entity::physical_agent::Model,
+    timeout: u64,
+    action: Option<&DrainAction>,
+) -> Result<entity::agent_drain::Model> {
+    let draining = || {
+        Error::Conflict(format!(
+            "Physical agent {} is already draining, wait for its drain job or force the change",
+            agent.id
+        ))
+    };
+    if agent.status == sea_orm_active_enums::PhysicalAgentStatus::Draining {
+        return Err(draining());
+    }
+    let down = agent.status == sea_orm_active_enums::PhysicalAgentStatus::Down;
+    // a concurrent drain that marks the agent first wins
+    if !down
+        && !service::physical_agent::PhysicalAgent::update_physical_agent_status_from(
             db,
-            query_message.ip.clone(),
-            query_message.port.unwrap() as i32,
+            agent.id,
+            agent.status.clone(),
+            sea_orm_active_enums::PhysicalAgentStatus::Draining,
         )
-        .await
+        .await?
+    {
+        return Err(draining());
+    }
+    let action = action.map(serde_json::to_value).transpose()?;
+    let drain =
+        service::drain::Drain::add_drain(db, agent.id, drain_deadline(timeout), action).await?;
+    info!("Drain physical agent {:?} by job {:?}", agent.id, drain.id);
+    if down {
+        let finished = service::drain::Drain::finish_drain(
+            db,
+            drain.id,
+            sea_orm_active_enums::DrainStatus::Drained,
+        )
+        .await?;
+        return Ok(finished.unwrap_or(drain));
+    }
+    Ok(drain)
+}
+
+/// Check the running drain job. It is finished as `drained` if the agent has
+/// no running chunk, as `cancelled` if the agent is no longer draining, e.g.
+/// it is set to running or removed, and as `timed_out` if its deadline has
+/// passed. Otherwise it is returned as is.
+///
+/// The request or the replica that finishes the job applies its action: the
+/// change of the agent if it is drained, or the previous status of the agent
+/// if the drain times out. An error of the action is logged, the job stays
+/// finished.
+async fn poll_drain(
+    db: &DbConn,
+    drain: entity::agent_drain::Model,
+) -> Result<entity::agent_drain::Model> {
+    if drain.status != sea_orm_active_enums::DrainStatus::Draining {
+        return Ok(drain);
+    }
+
+    let status =
+        match service::physical_agent::PhysicalAgent::get_physical_agent(db, drain.agent_id).await?
         {
-            Ok(agent) => match agent {
-                Some(agent) => {
-                    info!("Get physical agent by address successfully");
-                    (
-                        StatusCode::OK,
-                        Json(json!({
-                            "agent": agent,
-                        })),
-                    )
+            Some(agent) if agent.status == sea_orm_active_enums::PhysicalAgentStatus::Draining => {
+                if service::physical_agent::PhysicalAgent::check_physical_agent_idle(db, agent.id)
+                    .await?
+                {
+                    sea_orm_active_enums::DrainStatus::Drained
+                } else if drain.deadline < Utc::now().naive_utc() {
+                    sea_orm_active_enums::DrainStatus::TimedOut
+                } else {
+                    return Ok(drain);
                 }
-                None => {
-                    error!("Get physical agent by address failed: No agent found");
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": "No agent found"})),
+            }
+            _ => sea_orm_active_enums::DrainStatus::Cancelled,
+        };
+    let Some(finished) = service::drain::Drain::finish_drain(db, drain.id, status).await? else {
+        // finished by another request or replica, which applies the action
+        return service::drain::Drain::get_drain(db, drain.id)
+            .await?
+            .ok_or_else(|| Error::not_found("drain job", drain.id));
+    };
+    info!(
+        "Drain job {:?} is finished as {:?}",
+        finished.id, finished.status
+    );
+
+    if let Some(action) = &finished.action {
+        let applied = async {
+            let action: DrainAction = serde_json::from_value(action.clone())?;
+            match finished.status {
+                sea_orm_active_enums::DrainStatus::Drained => {
+                    apply_drain_action(db, finished.agent_id, action).await?;
+                }
+                sea_orm_active_enums::DrainStatus::TimedOut => {
+                    service::physical_agent::PhysicalAgent::update_physical_agent_status(
+                        db,
+                        finished.agent_id,
+                        action.previous_status,
                     )
+                    .await?;
+                    dispatch::wake(db).await;
                 }
-            },
-            Err(err) => {
-                error!("Get physical agent by address failed: {}", err);
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": format!("{}", err)})),
-                )
+                _ => {}
             }
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(err) = applied {
+            error!(
+                "Apply the action of drain job {:?} failed: {}",
+                finished.id, err
+            );
         }
     }
+    Ok(finished)
 }
 
-/// Internal function to update the physical agent with the given id
+/// ## Finish Drains
+/// Poll all the running drain jobs, so that the change of a drained agent
+/// is applied, and a timed out agent gets its previous status, even if no
+/// one polls the job. This function is called by the consume thread before
+/// dispatching the waiting tasks, any error is logged.
+pub async fn finish_drains(db: &DbConn) {
+    let drains = match service::drain::Drain::get_draining_drains(db).await {
+        Ok(drains) => drains,
+        Err(err) => {
+            error!("Get running drain jobs failed: {}", err);
+            return;
+        }
+    };
+    for drain in drains {
+        let drain_id = drain.id;
+        if let Err(err) = poll_drain(db, drain).await {
+            error!("Poll drain job {:?} failed: {}", drain_id, err);
+        }
+    }
+}
+
+/// Apply the update of the action to the physical agent, the agent keeps its
+/// previous status if the update has no status. An action without update
+/// removes the agent. Return the updated or removed agent.
+async fn apply_drain_action(
+    db: &DbConn,
+    agent_id: Uuid,
+    action: DrainAction,
+) -> Result<entity::physical_agent::Model> {
+    let Some(update) = action.update else {
+        return _remove_physical_agent(db, agent_id).await;
+    };
+    let agent = service::physical_agent::PhysicalAgent::update_physical_agent(
+        db,
+        agent_id,
+        update.ip,
+        update.port,
+        update.qubit_count,
+        update.circuit_depth,
+        Some(update.status.unwrap_or(action.previous_status)),
+        update.capability,
+        update.labels,
+        update.pool,
+    )
+    .await?;
+    dispatch::wake(db).await;
+    info!("Update physical agent {:?} successfully", agent_id);
+    Ok(agent)
+}
+
+/// Apply the update, or the removal if there is no update, to the physical
+/// agent after it is drained. The change is applied at once if it is forced,
+/// or the agent is down or idle, and the changed agent is returned with the
+/// status code 200. Otherwise the agent is drained, and the drain job that
+/// applies the change when it is [finished](poll_drain) is returned with the
+/// status code 202. A busy agent that is already draining is a conflict.
+async fn change_after_drain(
+    db: &DbConn,
+    agent_id: Uuid,
+    update: Option<AgentUpdate>,
+    options: &DrainOptions,
+    default_timeout: u64,
+) -> Result<(StatusCode, Json<AgentChange>)> {
+    let agent = service::physical_agent::PhysicalAgent::get_physical_agent(db, agent_id)
+        .await?
+        .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+    let action = DrainAction {
+        update,
+        previous_status: agent.status.clone(),
+    };
+
+    let at_once = options.force
+        || agent.status == sea_orm_active_enums::PhysicalAgentStatus::Down
+        || service::physical_agent::PhysicalAgent::check_physical_agent_idle(db, agent_id).await?;
+    if at_once {
+        info!("Change physical agent {:?} without draining", agent_id);
+        let agent = apply_drain_action(db, agent_id, action).await?;
+        return Ok((StatusCode::OK, Json(AgentChange::Applied(agent))));
+    }
+
+    let timeout = options.timeout.unwrap_or(default_timeout);
+    let drain = start_drain(db, &agent, timeout, Some(&action)).await?;
+    Ok((StatusCode::ACCEPTED, Json(AgentChange::Draining(drain))))
+}
+
+/// ## Drain Agent
+/// Drain the physical agent by the agent id in the url path. The agent is
+/// marked as `draining`, so that no new chunk is dispatched to it while the
+/// running chunks are finished. The request returns at once with the status
+/// code 202 and the drain job, which can be [polled](fetch_drain) until it is
+/// `drained`, or `timed_out` after `timeout` seconds. The agent stays
+/// draining after the job is finished, until it is updated to `running` or
+/// removed. A down agent is not marked, its job is `drained` at once, and an
+/// agent that is already draining is a conflict.
+#[utoipa::path(
+    post,
+    path = "/api/v1/agents/{id}/drain",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id"), DrainOptions),
+    responses(
+        (status = 202, description = "The drain job", body = AgentDrain),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+        (status = 409, description = "Agent is already draining", body = ErrorResponse),
+    )
+)]
+pub async fn drain_agent(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+    Query(options): Query<DrainOptions>,
+) -> Result<(StatusCode, Json<entity::agent_drain::Model>)> {
+    let agent = service::physical_agent::PhysicalAgent::get_physical_agent(&state.db, agent_id)
+        .await?
+        .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+    let timeout = options.timeout.unwrap_or(state.config.drain.timeout);
+    let drain = start_drain(&state.db, &agent, timeout, None).await?;
+    Ok((StatusCode::ACCEPTED, Json(drain)))
+}
+
+/// ## Fetch Drain
+/// Get the drain job by the job id in the url path, its status is updated if
+/// the drain is finished, and its action is applied then.
+#[utoipa::path(
+    get,
+    path = "/api/v1/drains/{id}",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The drain job id")),
+    responses(
+        (status = 200, description = "The drain job", body = AgentDrain),
+        (status = 404, description = "Drain job not found", body = ErrorResponse),
+    )
+)]
+pub async fn fetch_drain(
+    State(state): State<ServerState>,
+    Path(drain_id): Path<Uuid>,
+) -> Result<Json<entity::agent_drain::Model>> {
+    let drain = service::drain::Drain::get_drain(&state.db, drain_id)
+        .await?
+        .ok_or_else(|| Error::not_found("drain job", drain_id))?;
+    Ok(Json(poll_drain(&state.db, drain).await?))
+}
+
+/// Internal function to update the physical agent with the given id, after
+/// the agent is [drained](change_after_drain).
 async fn _update_physical_agent(
-    state: ServerState,
-    Form(query_message): Form<AgentInfoUpdate>,
-) -> (StatusCode, Json<Value>) {
+    db: &DbConn,
+    query_message: AgentInfoUpdate,
+    options: &DrainOptions,
+    default_timeout: u64,
+) -> Result<(StatusCode, Json<AgentChange>)> {
     info!(
         "Update physical agent {:?} with address {:?}:{:?}, qubit_count {:?}, circuit_depth {:?}, status {:?}",
         query_message.id, query_message.ip, query_message.port, query_message.qubit_count, query_message.circuit_depth, query_message.status
     );
-
-    let db = &state.db;
-
-    match service::physical_agent::PhysicalAgent::update_physical_agent_status(
+    let agent_id = query_message.id;
+    change_after_drain(
         db,
-        query_message.id,
-        sea_orm_active_enums::PhysicalAgentStatus::Down,
+        agent_id,
+        Some(query_message.into()),
+        options,
+        default_timeout,
     )
     .await
-    {
-        Ok((_, ori_status)) => {
-            info!("Update physical agent status to down before update agent information");
-            while !service::physical_agent::PhysicalAgent::check_physical_agent_idle(
-                db,
-                query_message.id,
-            )
-            .await
-            .unwrap()
-            {
-                info!(
-                    "Waiting for the physical agent {} to be idle",
-                    query_message.id
-                );
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            }
+}
 
-            // update the agent information after the agent is idle
-            match service::physical_agent::PhysicalAgent::update_physical_agent(
-                db,
-                query_message.id,
-                query_message.ip,
-                query_message.port.map(|x| x as i32),
-                query_message.qubit_count.map(|x| x as i32),
-                query_message.circuit_depth.map(|x| x as i32),
-                query_message.status.clone().map(|x| match x {
-                    AgentStatus::Running => sea_orm_active_enums::PhysicalAgentStatus::Running,
-                    AgentStatus::Down => sea_orm_active_enums::PhysicalAgentStatus::Down,
-                }),
-            )
-            .await
-            {
-                Ok(agent) => {
-                    info!("Update physical agent successfully");
-
-                    // if we do not update the agent information by this api, we restore the agent
-                    // status to the original status
-                    if query_message.status.is_none() {
-                        service::physical_agent::PhysicalAgent::update_physical_agent_status(
-                            db,
-                            query_message.id,
-                            ori_status,
-                        )
-                        .await
-                        .unwrap();
-                    }
-
-                    (
-                        StatusCode::OK,
-                        Json(json!({
-                            "agent": agent,
-                        })),
-                    )
-                }
-                Err(err) => {
-                    error!("Update physical agent failed: {}", err);
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({"Error": format!("{}", err)})),
-                    )
-                }
-            }
-        }
-        Err(err) => {
-            error!("Update physical agent status to down failed: {}", err);
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("{}", err)})),
-            );
-        }
-    }
+/// The legacy response of the agent change, `{"agent": ...}` with the changed
+/// agent or `{"drain": ...}` with the drain job.
+fn legacy_change(
+    (status, Json(change)): (StatusCode, Json<AgentChange>),
+) -> (StatusCode, Json<Value>) {
+    let body = match change {
+        AgentChange::Applied(agent) => json!({ "agent": agent }),
+        AgentChange::Draining(drain) => json!({ "drain": drain }),
+    };
+    (status, Json(body))
 }
 
 /// ## Update Physical Agent
@@ -406,60 +873,494 @@ async fn _update_physical_agent(
 /// in the request body. The request body can be either JSON or form-urlencoded.
 /// Except for the ID, all other fields are optional. Please refer to the
 /// [AgentInfoUpdate] struct for more information.
+///
+/// Deprecated, please use [patch_agent] instead.
 pub async fn update_physical_agent(
     State(state): State<ServerState>,
     request: Request,
-) -> (StatusCode, Json<Value>) {
-    match request.headers().get(header::CONTENT_TYPE) {
-        Some(content_type) => match content_type.to_str().unwrap() {
-            "application/json" => {
-                let Json::<AgentInfoUpdate>(message) = request.extract().await.unwrap();
-                _update_physical_agent(state, Form(message)).await
-            }
-            "application/x-www-form-urlencoded" => {
-                let Form(message) = request.extract().await.unwrap();
-                _update_physical_agent(state, Form(message)).await
-            }
-            _ => {
-                error!("Update physical agent failed: Invalid content type");
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": "Invalid content type"})),
-                )
-            }
-        },
-        None => {
-            error!("Update physical agent failed: No content type");
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": "No content type"})),
-            )
-        }
-    }
+) -> Result<(StatusCode, Json<Value>)> {
+    let message: AgentInfoUpdate = extract_body(request).await?;
+    let timeout = state.config.drain.timeout;
+    let change =
+        _update_physical_agent(&state.db, message, &DrainOptions::default(), timeout).await?;
+    Ok(legacy_change(change))
+}
+
+/// ## Patch Agent
+/// Update the physical agent by the agent id in the url path, please refer to
+/// [update_physical_agent]. The update is applied at once if the agent is
+/// idle or down, or `force` is set in the query. Otherwise the agent is
+/// [drained](drain_agent) and the drain job is returned, the update is
+/// applied when the agent is drained. If the drain times out, the agent gets
+/// its previous status and the update is not applied.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/agents/{id}",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id"), DrainOptions),
+    request_body(
+        content = AgentInfoPatch,
+        content_type = "application/json",
+        description = "The fields to update, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 200, description = "The updated agent", body = PhysicalAgent),
+        (status = 202, description = "The drain job that updates the agent when it is drained", body = AgentDrain),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+        (status = 409, description = "Agent is busy and already draining", body = ErrorResponse),
+    )
+)]
+pub async fn patch_agent(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+    Query(options): Query<DrainOptions>,
+    request: Request,
+) -> Result<(StatusCode, Json<AgentChange>)> {
+    let message: AgentInfoPatch = extract_body(request).await?;
+    let timeout = state.config.drain.timeout;
+    _update_physical_agent(&state.db, message.with_id(agent_id), &options, timeout).await
 }
 
 /// ## Remove Physical Agent
 /// Remove the physical agent with the given id.
+///
+/// Deprecated, please use [delete_agent] instead.
 pub async fn remove_physical_agent(
     State(state): State<ServerState>,
     Query(query_message): Query<AgentInfoUpdate>,
-) -> (StatusCode, Json<Value>) {
-    info!("Remove physical agent: {:?}", query_message.id);
+) -> Result<(StatusCode, Json<Value>)> {
+    let timeout = state.config.drain.timeout;
+    let change = change_after_drain(
+        &state.db,
+        query_message.id,
+        None,
+        &DrainOptions::default(),
+        timeout,
+    )
+    .await?;
+    Ok(legacy_change(change))
+}
 
-    let db = &state.db;
+/// Internal function to remove the physical agent with the given id
+async fn _remove_physical_agent(
+    db: &DbConn,
+    agent_id: Uuid,
+) -> Result<entity::physical_agent::Model> {
+    info!("Remove physical agent: {:?}", agent_id);
+    let agent = service::physical_agent::PhysicalAgent::remove_physical_agent(db, agent_id).await?;
+    info!("Remove physical agent {:?} successfully", agent);
+    Ok(agent)
+}
 
-    match service::physical_agent::PhysicalAgent::remove_physical_agent(db, query_message.id).await
-    {
-        Ok(agent) => {
-            info!("Remove physical agent {:?} successfully", agent);
-            (StatusCode::OK, Json(json!({"agent": agent})))
+/// ## Delete Agent
+/// Remove the physical agent by the agent id in the url path. The agent is
+/// removed at once if it is idle or down, or `force` is set in the query.
+/// Otherwise the agent is [drained](drain_agent) and the drain job is
+/// returned, the agent is removed when it is drained. If the drain times out,
+/// the agent is kept with its previous status.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/agents/{id}",
+    tag = "agents",
+    params(("id" = Uuid, Path, description = "The agent id"), DrainOptions),
+    responses(
+        (status = 200, description = "The removed agent", body = PhysicalAgent),
+        (status = 202, description = "The drain job that removes the agent when it is drained", body = AgentDrain),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+        (status = 409, description = "Agent is busy and already draining", body = ErrorResponse),
+    )
+)]
+pub async fn delete_agent(
+    State(state): State<ServerState>,
+    Path(agent_id): Path<Uuid>,
+    Query(options): Query<DrainOptions>,
+) -> Result<(StatusCode, Json<AgentChange>)> {
+    let timeout = state.config.drain.timeout;
+    change_after_drain(&state.db, agent_id, None, &options, timeout).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CapacityConfig;
+    use entity::sea_orm_active_enums::{DrainStatus, PhysicalAgentStatus};
+    use sea_orm::{ActiveModelTrait, ConnectOptions, ConnectionTrait, Database, Schema};
+
+    /// The in-memory database with the tables of the agents, the drain jobs,
+    /// the capacity reservations, the active tasks and their assignments.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        for statement in [
+            schema.create_table_from_entity(entity::physical_agent::Entity),
+            schema.create_table_from_entity(entity::agent_drain::Entity),
+            schema.create_table_from_entity(entity::capacity_reservation::Entity),
+            schema.create_table_from_entity(entity::task_active::Entity),
+            schema.create_table_from_entity(entity::task_assignment::Entity),
+        ] {
+            db.execute(backend.build(&statement)).await.unwrap();
         }
-        Err(err) => {
-            error!("Remove physical agent failed: {}", err);
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("{}", err)})),
-            )
+        db
+    }
+
+    async fn add_agent(db: &DbConn) -> entity::physical_agent::Model {
+        let agent = AgentInfo {
+            ip: "10.0.0.1".to_owned(),
+            hostname: None,
+            port: 8080,
+            qubit_count: 20,
+            circuit_depth: 100,
+            capability: None,
+            labels: None,
+            pool: None,
+        };
+        entity::physical_agent::ActiveModel::from(agent_model(agent, None).unwrap())
+            .insert(db)
+            .await
+            .unwrap()
+    }
+
+    /// Reserve 4 qubits of the agent for a running chunk, and return the id
+    /// of the assignment.
+    async fn reserve(db: &DbConn, agent: &entity::physical_agent::Model) -> Uuid {
+        let assignment_id = Uuid::new_v4();
+        let reserved = service::capacity::Capacity::reserve(
+            db,
+            &CapacityConfig::default(),
+            assignment_id,
+            agent,
+            Uuid::new_v4(),
+            4,
+            Utc::now().naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert!(reserved);
+        assignment_id
+    }
+
+    /// Add a task that runs a chunk on the agent, with its reservation and
+    /// its running assignment, and return the task.
+    async fn add_running_task(
+        db: &DbConn,
+        agent: &entity::physical_agent::Model,
+    ) -> entity::task_active::Model {
+        let now = Utc::now().naive_utc();
+        let task = entity::task_active::ActiveModel::from(entity::task_active::Model {
+            id: Uuid::new_v4(),
+            source: "OPENQASM 2.0;".to_owned(),
+            result: None,
+            qubits: 4,
+            shots: 100,
+            exec_shots: 0,
+            v_exec_shots: 0,
+            depth: 1,
+            status: sea_orm_active_enums::TaskActiveStatus::Running,
+            created_time: now,
+            updated_time: now,
+            username: "alice".to_owned(),
+            deadline: None,
+            requirements: None,
+            noise: None,
+            source_hash: None,
+            circuit: None,
+            parameters: None,
+            parent_id: None,
+            cutting: None,
+        })
+        .insert(db)
+        .await
+        .unwrap();
+        let assignment_id = Uuid::new_v4();
+        assert!(service::capacity::Capacity::reserve(
+            db,
+            &CapacityConfig::default(),
+            assignment_id,
+            agent,
+            task.id,
+            4,
+            now,
+        )
+        .await
+        .unwrap());
+        service::task_assignment::TaskAssignment::add_assignment(
+            db,
+            entity::task_assignment::Model {
+                id: assignment_id,
+                agent_id: agent.id,
+                task_id: task.id,
+                shots: Some(100),
+                status: sea_orm_active_enums::AssignmentStatus::Running,
+                created_time: Some(now),
+            },
+        )
+        .await
+        .unwrap();
+        task
+    }
+
+    /// Set the lease of the agent to expire the given seconds from now.
+    async fn set_lease(db: &DbConn, agent_id: Uuid, seconds: i64) {
+        service::physical_agent::PhysicalAgent::renew_physical_agent_lease(
+            db,
+            agent_id,
+            (Utc::now() + TimeDelta::seconds(seconds)).naive_utc(),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn get_agent(db: &DbConn, agent_id: Uuid) -> Option<entity::physical_agent::Model> {
+        service::physical_agent::PhysicalAgent::get_physical_agent(db, agent_id)
+            .await
+            .unwrap()
+    }
+
+    fn drain_job(change: (StatusCode, Json<AgentChange>)) -> entity::agent_drain::Model {
+        match change {
+            (StatusCode::ACCEPTED, Json(AgentChange::Draining(drain))) => drain,
+            (status, Json(change)) => panic!("unexpected {} {:?}", status, change),
         }
     }
+
+    async fn get_drain(db: &DbConn, drain_id: Uuid) -> entity::agent_drain::Model {
+        service::drain::Drain::get_drain(db, drain_id)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn drain_completes_and_applies_the_update() {
+        let db = database().await;
+        let agent = add_agent(&db).await;
+        let assignment_id = reserve(&db, &agent).await;
+        let update = AgentUpdate {
+            qubit_count: Some(24),
+            ..Default::default()
+        };
+        let change = change_after_drain(&db, agent.id, Some(update), &DrainOptions::default(), 60)
+            .await
+            .unwrap();
+        let drain = drain_job(change);
+        assert_eq!(
+            get_agent(&db, agent.id).await.unwrap().status,
+            PhysicalAgentStatus::Draining
+        );
+
+        // the chunk is still running
+        finish_drains(&db).await;
+        assert_eq!(get_drain(&db, drain.id).await.status, DrainStatus::Draining);
+
+        service::capacity::Capacity::release(&db, assignment_id)
+            .await
+            .unwrap();
+        finish_drains(&db).await;
+        assert_eq!(get_drain(&db, drain.id).await.status, DrainStatus::Drained);
+        let updated = get_agent(&db, agent.id).await.unwrap();
+        assert_eq!(updated.status, PhysicalAgentStatus::Running);
+        assert_eq!((updated.qubit_count, updated.qubit_idle), (24, 24));
+    }
+
+    #[tokio::test]
+    async fn drain_times_out_and_restores_the_status() {
+        let db = database().await;
+        let agent = add_agent(&db).await;
+        reserve(&db, &agent).await;
+        let options = DrainOptions {
+            timeout: Some(0),
+            force: false,
+        };
+        let drain = drain_job(
+            change_after_drain(&db, agent.id, None, &options, 60)
+                .await
+                .unwrap(),
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        finish_drains(&db).await;
+        assert_eq!(get_drain(&db, drain.id).await.status, DrainStatus::TimedOut);
+        // the agent is kept, back in the pool
+        let kept = get_agent(&db, agent.id).await.unwrap();
+        assert_eq!(kept.status, PhysicalAgentStatus::Running);
+    }
+
+    #[tokio::test]
+    async fn drain_removes_the_agent_when_drained() {
+        let db = database().await;
+        let agent = add_agent(&db).await;
+        let assignment_id = reserve(&db, &agent).await;
+        let drain = drain_job(
+            change_after_drain(&db, agent.id, None, &DrainOptions::default(), 60)
+                .await
+                .unwrap(),
+        );
+
+        service::capacity::Capacity::release(&db, assignment_id)
+            .await
+            .unwrap();
+        let drained = poll_drain(&db, get_drain(&db, drain.id).await)
+            .await
+            .unwrap();
+        assert_eq!(drained.status, DrainStatus::Drained);
+        assert!(get_agent(&db, agent.id).await.is_none());
+        // the job is finished once, polling it again changes nothing
+        let polled = poll_drain(&db, drained.clone()).await.unwrap();
+        assert_eq!(polled, drained);
+    }
+
+    #[tokio::test]
+    async fn second_drain_of_a_draining_agent_is_a_conflict() {
+        let db = database().await;
+        let agent = add_agent(&db).await;
+        reserve(&db, &agent).await;
+        let drain = start_drain(&db, &agent, 60, None).await.unwrap();
+
+        let draining = get_agent(&db, agent.id).await.unwrap();
+        assert!(matches!(
+            start_drain(&db, &draining, 60, None).await,
+            Err(Error::Conflict(_))
+        ));
+        // a drain with the stale status loses against the first one
+        assert!(matches!(
+            start_drain(&db, &agent, 60, None).await,
+            Err(Error::Conflict(_))
+        ));
+        assert!(matches!(
+            change_after_drain(&db, agent.id, None, &DrainOptions::default(), 60).await,
+            Err(Error::Conflict(_))
+        ));
+
+        // a forced change still applies, and cancels the running job
+        let update = AgentUpdate {
+            status: Some(PhysicalAgentStatus::Running),
+            ..Default::default()
+        };
+        let options = DrainOptions {
+            timeout: None,
+            force: true,
+        };
+        let (status, _) = change_after_drain(&db, agent.id, Some(update), &options, 60)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        finish_drains(&db).await;
+        assert_eq!(
+            get_drain(&db, drain.id).await.status,
+            DrainStatus::Cancelled
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_lease_marks_the_agent_down_and_requeues_its_tasks() {
+        let db = database().await;
+        let expired = add_agent(&db).await;
+        set_lease(&db, expired.id, -1).await;
+        let task = add_running_task(&db, &expired).await;
+        let leased = add_agent(&db).await;
+        set_lease(&db, leased.id, 60).await;
+        let running = add_running_task(&db, &leased).await;
+        // an agent without lease never expires
+        let unleased = add_agent(&db).await;
+
+        expire_agent_leases(&db).await;
+
+        let agent = get_agent(&db, expired.id).await.unwrap();
+        assert_eq!(agent.status, PhysicalAgentStatus::Down);
+        assert_eq!(agent.qubit_idle, agent.qubit_count);
+        assert!(
+            service::capacity::Capacity::get_agent_reservations(&db, expired.id)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let assignments =
+            service::task_assignment::TaskAssignment::get_assignment_by_task(&db, task.id)
+                .await
+                .unwrap();
+        assert_eq!(assignments.len(), 1);
+        assert_eq!(
+            assignments[0].status,
+            sea_orm_active_enums::AssignmentStatus::Failed
+        );
+        let requeued = service::task_active::TaskActive::get_task(&db, task.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            requeued.status,
+            sea_orm_active_enums::TaskActiveStatus::Waiting
+        );
+
+        for agent in [&leased, &unleased] {
+            assert_eq!(
+                get_agent(&db, agent.id).await.unwrap().status,
+                PhysicalAgentStatus::Running
+            );
+        }
+        assert_eq!(
+            service::capacity::Capacity::get_agent_reservations(&db, leased.id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let running = service::task_active::TaskActive::get_task(&db, running.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            running.status,
+            sea_orm_active_enums::TaskActiveStatus::Running
+        );
+    }
+
+    #[tokio::test]
+    async fn renew_extends_the_lease_and_revives_an_expired_agent() {
+        let db = database().await;
+        let agent = add_agent(&db).await;
+        set_lease(&db, agent.id, 1).await;
+        let lease = lease_expire_time(60);
+        let renewed = service::physical_agent::PhysicalAgent::renew_physical_agent_lease(
+            &db, agent.id, lease,
+        )
+        .await
+        .unwrap();
+        assert_eq!(renewed.lease_expire_time, Some(lease));
+        assert_eq!(renewed.status, PhysicalAgentStatus::Running);
+
+        set_lease(&db, agent.id, -1).await;
+        expire_agent_leases(&db).await;
+        assert_eq!(
+            get_agent(&db, agent.id).await.unwrap().status,
+            PhysicalAgentStatus::Down
+        );
+        let renewed = service::physical_agent::PhysicalAgent::renew_physical_agent_lease(
+            &db, agent.id, lease,
+        )
+        .await
+        .unwrap();
+        assert_eq!(renewed.lease_expire_time, Some(lease));
+        assert_eq!(renewed.status, PhysicalAgentStatus::Running);
+
+        // an agent set down by the operator is not revived
+        service::physical_agent::PhysicalAgent::update_physical_agent_status_from(
+            &db,
+            agent.id,
+            PhysicalAgentStatus::Running,
+            PhysicalAgentStatus::Down,
+        )
+        .await
+        .unwrap();
+        let renewed = service::physical_agent::PhysicalAgent::renew_physical_agent_lease(
+            &db, agent.id, lease,
+        )
+        .await
+        .unwrap();
+        assert_eq!(renewed.status, PhysicalAgentStatus::Down);
+    }
 }
@@ -1,29 +1,49 @@
 //! The module that contains some struct definitions for the physical agent
 //! router. It is used to deserialize the post request body from the user.
 //! - `AgentStatus`: The enum that represents the status of the agent. It can be
-//!  either `running` or `down`.
+//!   either `running` or `down`.
 //! - `AgentInfo`: The struct that represents the information of the agent. The
-//! user can add a new agent with the given information.
+//!   user can add a new agent with the given information.
 //! - `AgentInfoUpdate`: The struct that represents the information of the agent
-//! that the user wants to update. The user can update the agent with the given
-//! information.
+//!   that the user wants to update. The user can update the agent with the
+//!   given information.
+//! - `AgentInfoPatch`: The struct that represents the information of the
+//!   agent that the user wants to update by the agent id in the url path.
 //! - `AgentAddress`: The struct that represents the address of the agent. The
-//! user can get the agent information by the address.
+//!   user can get the agent information by the address.
+//! - `DrainOptions`: The struct that represents how to drain the agent before
+//!   it is updated or removed.
+//! - `AgentUpdate`, `DrainAction`: The structs that represent the change of
+//!   the agent that waits for its drain job.
+//! - `AgentChange`: The enum that represents the result of the change, the
+//!   changed agent or the drain job.
+//! - `AgentFilter`: The struct that represents the query of the agent list,
+//!   by address, status and the window of the utilization.
+//! - `AgentWindow`: The struct that represents the query of the agent detail.
+//! - `AgentUtilization`: The struct that represents the live utilization of
+//!   the agent and its assignments in the recent window.
+//! - `AgentView`, `AgentDetail`: The structs that represent the agent with its
+//!   utilization, and its assignment history for the detail.
 //! - `Agents`: The struct that represents the list of agents. This struct is
-//! used to deserialize the agents from the file. This struct is used to add the
-//! agents for consume task thread at the beginning.
+//!   used to deserialize the agents from the file. This struct is used to add
+//!   the agents for consume task thread at the beginning.
 //! - `empty_string_as_none`: The function that converts an empty string to
-//! `None` when deserializing the optional field.
+//!   `None` when deserializing the optional field.
 
+use crate::capability::AgentCapability;
+use crate::entity;
+use crate::entity::sea_orm_active_enums::PhysicalAgentStatus;
+use crate::service::task_assignment::AgentAssignments;
 use serde::{de, Deserialize, Deserializer, Serialize};
-use std::{fmt, str::FromStr};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 /// ## Agent Status
 /// The struct that represents the status of the agent. It can be either
 /// `running` or `down`. This struct is used for the user to update the status
 /// of the agent.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub enum AgentStatus {
     #[serde(rename = "running")]
     Running,
@@ -63,7 +83,13 @@ impl FromStr for AgentStatus {
 /// - `port`: The port number of the agent.
 /// - `qubit_count`: The number of qubits the agent has.
 /// - `circuit_depth`: The circuit depth of the agent can run.
-#[derive(Deserialize, Debug)]
+/// - `capability`: The [capability document](AgentCapability) of the agent,
+///   optional. It can only be given in a JSON body.
+/// - `labels`: The key/value labels of the agent, e.g. `{"team": "chem"}`,
+///   optional. It can only be given in a JSON body. The tasks select the
+///   agents by them.
+/// - `pool`: The named pool of agents the agent belongs to, optional.
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct AgentInfo {
     pub ip: String,
     #[serde(default, deserialize_with = "empty_string_as_none")]
@@ -71,6 +97,12 @@ pub struct AgentInfo {
     pub port: u32,
     pub qubit_count: u32,
     pub circuit_depth: u32,
+    #[serde(default)]
+    pub capability: Option<AgentCapability>,
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub pool: Option<String>,
 }
 
 /// ## Agent Info Update
@@ -83,6 +115,11 @@ pub struct AgentInfo {
 /// - `circuit_depth`: The circuit depth of the agent can run, optional.
 /// - `status`: The status of the agent, optional. The `status` field is an enum
 ///   of `AgentStatus` which can be either `running` or `down`.
+/// - `capability`: The new capability document of the agent, optional. It
+///   replaces the whole document.
+/// - `labels`: The new labels of the agent, optional. They replace all the
+///   labels.
+/// - `pool`: The new pool of the agent, optional.
 #[derive(Deserialize, Debug, Clone)]
 pub struct AgentInfoUpdate {
     pub id: Uuid,
@@ -96,20 +133,290 @@ pub struct AgentInfoUpdate {
     pub circuit_depth: Option<u32>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub status: Option<AgentStatus>,
+    #[serde(default)]
+    pub capability: Option<AgentCapability>,
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub pool: Option<String>,
+}
+
+/// ## Agent Info Patch
+/// The struct that represents the information of the agent that the user wants
+/// to update, the agent is identified by the id in the url path. Please refer
+/// to [AgentInfoUpdate] for the meaning of the fields, all of them are
+/// optional.
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct AgentInfoPatch {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub ip: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub port: Option<u32>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub qubit_count: Option<u32>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub circuit_depth: Option<u32>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub status: Option<AgentStatus>,
+    #[serde(default)]
+    pub capability: Option<AgentCapability>,
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub pool: Option<String>,
+}
+
+impl AgentInfoPatch {
+    /// Attach the agent id to the patch, so that it can be handled as an
+    /// [AgentInfoUpdate].
+    pub fn with_id(self, id: Uuid) -> AgentInfoUpdate {
+        AgentInfoUpdate {
+            id,
+            ip: self.ip,
+            port: self.port,
+            qubit_count: self.qubit_count,
+            circuit_depth: self.circuit_depth,
+            status: self.status,
+            capability: self.capability,
+            labels: self.labels,
+            pool: self.pool,
+        }
+    }
 }
 
 /// ## Agent Address
 /// The struct that represents the address of the agent. The user can get the
 /// agent information by the address.
-/// - `ip`: The IP address of the agent.
+/// - `ip`: The IP address of the agent, use the hostname if it is empty.
+/// - `hostname`: The host name of the agent, optional.
 /// - `port`: The port number of the agent, optional.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct AgentAddress {
+    #[serde(default)]
+    pub ip: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub hostname: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub port: Option<u32>,
+}
+
+/// ## Drain Options
+/// The struct that represents how to drain the agent before it is updated or
+/// removed, or when it is drained by itself.
+/// - `timeout`: The seconds to wait for the running chunks of the agent,
+///   optional. The `drain.timeout` of the configuration by default.
+/// - `force`: Apply the update or the removal at once without draining the
+///   agent, false by default. The running chunks are finished as usual.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DrainOptions {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// ## Agent Update
+/// The fields of an [AgentInfoUpdate] that are stored with the drain job,
+/// and applied to the agent when it is drained.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AgentUpdate {
+    pub ip: Option<String>,
+    pub port: Option<i32>,
+    pub qubit_count: Option<i32>,
+    pub circuit_depth: Option<i32>,
+    pub status: Option<PhysicalAgentStatus>,
+    pub capability: Option<AgentCapability>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub pool: Option<String>,
+}
+
+impl From<AgentInfoUpdate> for AgentUpdate {
+    fn from(update: AgentInfoUpdate) -> Self {
+        AgentUpdate {
+            ip: update.ip,
+            port: update.port.map(|x| x as i32),
+            qubit_count: update.qubit_count.map(|x| x as i32),
+            circuit_depth: update.circuit_depth.map(|x| x as i32),
+            status: update.status.map(|status| match status {
+                AgentStatus::Running => PhysicalAgentStatus::Running,
+                AgentStatus::Down => PhysicalAgentStatus::Down,
+            }),
+            capability: update.capability,
+            labels: update.labels,
+            pool: update.pool,
+        }
+    }
+}
+
+/// ## Drain Action
+/// The change of the agent that waits for its drain job, it is stored in the
+/// `action` field of the job.
+/// - `update`: The update of the agent, or `None` to remove the agent.
+/// - `previous_status`: The status of the agent before the drain. It is
+///   restored if the drain times out, and kept by an update without status.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DrainAction {
+    pub update: Option<AgentUpdate>,
+    pub previous_status: PhysicalAgentStatus,
+}
+
+/// ## Agent Change
+/// The result of an update or a removal of the agent. The changed agent if
+/// the change is applied at once, because the agent is idle, down or the
+/// change is forced, or the drain job that applies the change when the
+/// agent is drained.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(untagged)]
+pub enum AgentChange {
+    #[schema(value_type = PhysicalAgent)]
+    Applied(entity::physical_agent::Model),
+    #[schema(value_type = AgentDrain)]
+    Draining(entity::agent_drain::Model),
+}
+
+/// The seconds of the recent window of the agent utilization, one day by
+/// default.
+pub const DEFAULT_UTILIZATION_WINDOW: u64 = 86400;
+
+/// ## Agent Filter
+/// The query of the agent list, all the fields are optional and all the agents
+/// are listed if none is given.
+/// - `ip`: The IP address of the agents, use the hostname if it is empty.
+/// - `hostname`: The host name of the agents.
+/// - `port`: The port number of the agent, only used with the address.
+/// - `status`: The statuses of the agents separated by commas, e.g.
+///   `running,draining`.
+/// - `window`: The seconds of the recent window of the failure rate and the
+///   served shots, one day by default.
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AgentFilter {
+    #[serde(default)]
     pub ip: String,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub hostname: Option<String>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub port: Option<u32>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub status: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub window: Option<u64>,
+}
+
+impl AgentFilter {
+    /// The address of the agents to list, `None` if neither the ip nor the
+    /// hostname is given.
+    pub fn address(&self) -> Option<AgentAddress> {
+        if self.ip.is_empty() && self.hostname.is_none() {
+            return None;
+        }
+        Some(AgentAddress {
+            ip: self.ip.clone(),
+            hostname: self.hostname.clone(),
+            port: self.port,
+        })
+    }
+}
+
+/// ## Agent Window
+/// The query of the agent detail.
+/// - `window`: The seconds of the recent window of the failure rate and the
+///   served shots, one day by default.
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AgentWindow {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub window: Option<u64>,
+}
+
+/// ## Agent Utilization
+/// The live utilization of the agent, and its assignments in the recent
+/// window.
+/// - `running_assignments`: The number of the running assignments.
+/// - `qubit_utilization`: The share of the qubits of the agent that are held
+///   by the live reservations of the running assignments. It can be over 1
+///   under the `memory` capacity model, which does not pack by qubits.
+/// - `memory_utilization`: The share of the memory of the agent that is held
+///   by the live reservations, `null` if the memory is unknown.
+/// - `slot_utilization`: The share of the slots of the agent that are held by
+///   the live reservations, `null` if the slots are unlimited.
+/// - `succeeded_assignments`: The assignments succeeded in the window.
+/// - `failed_assignments`: The assignments failed in the window.
+/// - `failure_rate`: The share of the failed assignments among the finished
+///   ones in the window, `null` if none is finished.
+/// - `shots`: The shots served by the succeeded assignments in the window.
+/// - `window`: The seconds of the window.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct AgentUtilization {
+    pub running_assignments: i64,
+    pub qubit_utilization: f64,
+    pub memory_utilization: Option<f64>,
+    pub slot_utilization: Option<f64>,
+    pub succeeded_assignments: i64,
+    pub failed_assignments: i64,
+    pub failure_rate: Option<f64>,
+    pub shots: i64,
+    pub window: u64,
+}
+
+impl AgentUtilization {
+    /// The utilization of the agent with its live reservations, and its
+    /// assignments in the window.
+    pub fn new(
+        agent: &entity::physical_agent::Model,
+        reservations: &[entity::capacity_reservation::Model],
+        assignments: &AgentAssignments,
+        window: u64,
+    ) -> Self {
+        let share = |used: i64, total: i64| match total {
+            0 => 0.0,
+            total => used as f64 / total as f64,
+        };
+        let qubits = reservations
+            .iter()
+            .map(|reservation| reservation.qubits as i64);
+        let memory_mb = reservations.iter().map(|reservation| reservation.memory_mb);
+        let finished = assignments.succeeded + assignments.failed;
+        AgentUtilization {
+            running_assignments: assignments.running,
+            qubit_utilization: share(qubits.sum(), agent.qubit_count as i64),
+            memory_utilization: agent.memory_mb.map(|total| share(memory_mb.sum(), total)),
+            slot_utilization: agent
+                .slots
+                .map(|slots| share(reservations.len() as i64, slots as i64)),
+            succeeded_assignments: assignments.succeeded,
+            failed_assignments: assignments.failed,
+            failure_rate: (finished > 0).then(|| assignments.failed as f64 / finished as f64),
+            shots: assignments.shots,
+            window,
+        }
+    }
+}
+
+/// ## Agent View
+/// The agent in the agent list, the fields of the
+/// [agent](crate::entity::physical_agent::Model) with its utilization.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct AgentView {
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub agent: entity::physical_agent::Model,
+    pub utilization: AgentUtilization,
+}
+
+/// ## Agent Detail
+/// The agent by id, the [agent view](AgentView) with the assignment history
+/// of the agent, the latest first.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct AgentDetail {
+    #[serde(flatten)]
+    #[schema(inline)]
+    pub agent: entity::physical_agent::Model,
+    pub utilization: AgentUtilization,
+    #[schema(value_type = Vec<TaskAssignment>)]
+    pub assignments: Vec<entity::task_assignment::Model>,
 }
 
 /// The function that converts an empty string to `None` when deserializing the
@@ -135,3 +442,88 @@ where
 pub struct Agents {
     pub agents: Vec<AgentInfo>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(memory_mb: Option<i64>, slots: Option<i32>) -> entity::physical_agent::Model {
+        entity::physical_agent::Model {
+            id: Uuid::new_v4(),
+            status: PhysicalAgentStatus::Running,
+            ip: "10.0.0.1".to_owned(),
+            port: 8080,
+            qubit_count: 20,
+            // the idle qubits are not used, only the live reservations are
+            qubit_idle: 0,
+            circuit_depth: 100,
+            lease_expire_time: None,
+            discovery: None,
+            simulator: String::new(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb,
+            qasm3: false,
+            memory_idle_mb: memory_mb,
+            slots,
+            slots_idle: slots,
+            labels: None,
+            pool: None,
+            lost_time: None,
+        }
+    }
+
+    fn reservation(
+        agent: &entity::physical_agent::Model,
+        qubits: i32,
+        memory_mb: i64,
+    ) -> entity::capacity_reservation::Model {
+        entity::capacity_reservation::Model {
+            id: Uuid::new_v4(),
+            agent_id: agent.id,
+            task_id: Uuid::new_v4(),
+            qubits,
+            memory_mb,
+            created_time: chrono::Utc::now().naive_utc(),
+            expected_end_time: None,
+        }
+    }
+
+    #[test]
+    fn idle_agent_has_no_utilization_nor_failure_rate() {
+        let agent = agent(None, None);
+        let utilization = AgentUtilization::new(&agent, &[], &AgentAssignments::default(), 60);
+        assert_eq!(utilization.qubit_utilization, 0.0);
+        assert_eq!(utilization.memory_utilization, None);
+        assert_eq!(utilization.slot_utilization, None);
+        assert_eq!(utilization.failure_rate, None);
+        assert_eq!(utilization.window, 60);
+    }
+
+    #[test]
+    fn utilization_is_held_by_the_live_reservations() {
+        let agent = agent(Some(4096), Some(4));
+        let reservations = [reservation(&agent, 5, 1024), reservation(&agent, 10, 2048)];
+        let assignments = AgentAssignments {
+            agent_id: agent.id,
+            running: 2,
+            succeeded: 3,
+            failed: 1,
+            shots: 300,
+        };
+        let utilization = AgentUtilization::new(&agent, &reservations, &assignments, 60);
+        assert_eq!(utilization.running_assignments, 2);
+        assert_eq!(utilization.qubit_utilization, 0.75);
+        assert_eq!(utilization.memory_utilization, Some(0.75));
+        assert_eq!(utilization.slot_utilization, Some(0.5));
+        assert_eq!(utilization.failure_rate, Some(0.25));
+        assert_eq!(utilization.shots, 300);
+
+        // the memory model packs by memory, the qubits can be overcommitted
+        let reservations = [reservation(&agent, 20, 1024), reservation(&agent, 20, 1024)];
+        let utilization = AgentUtilization::new(&agent, &reservations, &assignments, 60);
+        assert_eq!(utilization.qubit_utilization, 2.0);
+        assert_eq!(utilization.memory_utilization, Some(0.5));
+    }
+}
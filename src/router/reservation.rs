@@ -0,0 +1,196 @@
+//! The module that contains the reservation router. A team can book
+//! [agents](crate::entity::agent_reservation::Model) for the sole use of one
+//! user over a time window, e.g. for benchmarking. During the window the
+//! consume loop only dispatches the tasks of the user to the booked agents,
+//! and the agents are released when the window ends or the reservation is
+//! cancelled.
+
+use super::{extract_body, ServerState};
+use crate::dispatch;
+use crate::entity;
+use crate::error::{Error, Result};
+use crate::service;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::info;
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// ## Reservation Message
+/// The reservation to add. Exactly one of `agents` and `pool` must be given,
+/// and exactly one of `end_time` and `duration`.
+/// - `username`: The user who gets the sole use of the agents.
+/// - `agents`: The ids of the booked agents. It can only be given in a JSON
+///   body.
+/// - `pool`: The pool whose agents are booked, the agents that join the pool
+///   during the window are booked too.
+/// - `start_time`: The start of the window (RFC 3339), now by default.
+/// - `end_time`: The end of the window (RFC 3339).
+/// - `duration`: The seconds of the window.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ReservationMessage {
+    username: String,
+    #[serde(default)]
+    agents: Option<Vec<Uuid>>,
+    #[serde(default)]
+    pool: Option<String>,
+    #[serde(default)]
+    start_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    end_time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    duration: Option<u64>,
+}
+
+/// ## Reservation Query
+/// The query of the reservation list.
+/// - `username`: Only list the reservations of this user, optional.
+/// - `all`: Also list the reservations that are cancelled or ended, false by
+///   default.
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReservationQuery {
+    username: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+/// ## Create Reservation
+/// Book the agents, or the agents of a pool, for the user over the time
+/// window. The agents booked by another user in an overlapping window are a
+/// conflict. The created reservation is returned with the status code 201.
+#[utoipa::path(
+    post,
+    path = "/api/v1/reservations",
+    tag = "reservations",
+    request_body(
+        content = ReservationMessage,
+        content_type = "application/json",
+        description = "The reservation to add, `application/x-www-form-urlencoded` is also accepted for a pool"
+    ),
+    responses(
+        (status = 201, description = "Reservation created", body = AgentReservation),
+        (status = 400, description = "Invalid window, or no agent is booked", body = ErrorResponse),
+        (status = 404, description = "Agent not found", body = ErrorResponse),
+        (status = 409, description = "An agent is reserved by another user in the window", body = ErrorResponse),
+    )
+)]
+pub async fn create_reservation(
+    State(state): State<ServerState>,
+    request: Request,
+) -> Result<(StatusCode, Json<entity::agent_reservation::Model>)> {
+    let message: ReservationMessage = extract_body(request).await?;
+    if message.username.trim().is_empty() {
+        return Err(Error::InvalidRequest("username is empty".to_owned()));
+    }
+    if message.agents.is_some() == message.pool.is_some() {
+        return Err(Error::InvalidRequest(
+            "exactly one of agents and pool must be given".to_owned(),
+        ));
+    }
+    for agent_id in message.agents.iter().flatten() {
+        service::physical_agent::PhysicalAgent::get_physical_agent(&state.db, *agent_id)
+            .await?
+            .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+    }
+
+    let now = Utc::now();
+    let start_time = message.start_time.unwrap_or(now);
+    let end_time = match (message.end_time, message.duration) {
+        (Some(end_time), None) => end_time,
+        (None, Some(duration)) => i64::try_from(duration)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .and_then(|duration| start_time.checked_add_signed(duration))
+            .ok_or_else(|| Error::InvalidRequest(format!("duration {} is too large", duration)))?,
+        _ => {
+            return Err(Error::InvalidRequest(
+                "exactly one of end_time and duration must be given".to_owned(),
+            ))
+        }
+    };
+    if end_time <= start_time || end_time <= now {
+        return Err(Error::InvalidRequest(format!(
+            "the window from {} to {} is empty or over",
+            start_time, end_time
+        )));
+    }
+
+    let reservation = service::agent_reservation::AgentReservation::add_reservation(
+        &state.db,
+        entity::agent_reservation::Model {
+            id: Uuid::new_v4(),
+            username: message.username,
+            agents: message.agents.map(serde_json::to_value).transpose()?,
+            pool: message.pool,
+            start_time: start_time.naive_utc(),
+            end_time: end_time.naive_utc(),
+            created_time: now.naive_utc(),
+            cancelled_time: None,
+        },
+    )
+    .await?;
+    info!(
+        "Reserve physical agents for {} from {} to {} by {:?}",
+        reservation.username, reservation.start_time, reservation.end_time, reservation.id
+    );
+    Ok((StatusCode::CREATED, Json(reservation)))
+}
+
+/// ## List Reservations
+/// List the reservations that are not cancelled or ended, or all of them,
+/// ordered by their start time.
+#[utoipa::path(
+    get,
+    path = "/api/v1/reservations",
+    tag = "reservations",
+    params(ReservationQuery),
+    responses(
+        (status = 200, description = "The reservations", body = [AgentReservation]),
+    )
+)]
+pub async fn list_reservations(
+    State(state): State<ServerState>,
+    Query(query): Query<ReservationQuery>,
+) -> Result<Json<Vec<entity::agent_reservation::Model>>> {
+    let since = (!query.all).then(|| Utc::now().naive_utc());
+    Ok(Json(
+        service::agent_reservation::AgentReservation::get_reservations(
+            &state.db,
+            query.username.as_deref(),
+            since,
+        )
+        .await?,
+    ))
+}
+
+/// ## Cancel Reservation
+/// Cancel the reservation by the id in the url path, the booked agents are
+/// released at once. The cancelled reservation is returned.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/reservations/{id}",
+    tag = "reservations",
+    params(("id" = Uuid, Path, description = "The reservation id")),
+    responses(
+        (status = 200, description = "The cancelled reservation", body = AgentReservation),
+        (status = 404, description = "Reservation not found", body = ErrorResponse),
+        (status = 409, description = "The reservation is already cancelled or ended", body = ErrorResponse),
+    )
+)]
+pub async fn cancel_reservation(
+    State(state): State<ServerState>,
+    Path(reservation_id): Path<Uuid>,
+) -> Result<Json<entity::agent_reservation::Model>> {
+    let reservation =
+        service::agent_reservation::AgentReservation::cancel_reservation(&state.db, reservation_id)
+            .await?;
+    info!("Cancel reservation {:?} successfully", reservation_id);
+    dispatch::wake(&state.db).await;
+    Ok(Json(reservation))
+}
@@ -1,22 +1,35 @@
-//! The modult that contains the task router. The task router is responsible for
+//! The module that contains the task router. The task router is responsible for
 //! handling the task submit and get task status requests. The task submit
 //! request is used to submit a task to the scheduler. The get task status
 //! request is used to get the task status by task id.
 
-use super::ServerState;
+use super::{extract_body, request_user, ServerState};
+use crate::capability::{agent_gates, supports_circuit, TaskRequirements};
+use crate::chunking;
+use crate::config::{CacheConfig, CapacityConfig, ChunkingConfig};
+use crate::cutting;
+use crate::dispatch;
 use crate::entity;
 use crate::entity::sea_orm_active_enums;
+use crate::error::{Error, Result};
+use crate::noise::NoiseModel;
+use crate::parameter::{bind_parameters, sweep_parameters, Parameters};
+use crate::qasm;
 use crate::service;
+use crate::transpile;
 use axum::{
     extract::{Path, Query, Request, State},
-    http::{header, StatusCode},
-    Form, Json, RequestExt,
+    http::StatusCode,
+    Json,
 };
+use chrono::{DateTime, TimeDelta, Utc};
 use log::{error, info};
-use reqwest::Response;
-use sea_orm::DbConn;
-use serde::Deserialize;
+use sea_orm::{ConnectionTrait, DbConn, TransactionTrait};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, map::Entry, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 /// ## Emulate message
@@ -24,15 +37,94 @@ use uuid::Uuid;
 /// user can submit a task to the server by sending a POST request with the
 /// emulate message.
 /// - `code`: The code is the quantum assembly code that the user wants to run.
+///   An OpenQASM 3 program is [translated](crate::qasm) to OpenQASM 2.
+/// - `circuit`: The reference `name@version` of a stored
+///   [circuit](crate::router::circuit) to run instead of `code`, the latest
+///   version is used if the version is omitted. Exactly one of `code` and
+///   `circuit` must be given, the task records the circuit version it runs.
+/// - `parameters`: The values of the [parameters](crate::parameter) of the
+///   code or the circuit by name, optional. It can only be given in a JSON
+///   body. Every parameter must have a value and every value must be used,
+///   the bound source is stored with the task. It can only be omitted if
+///   the source has no parameters, or is an OpenQASM 3 program.
 /// - `qubits`: The number of qubits that the user wants to run.
 /// - `depth`: The depth of the circuit that the user wants to run.
-/// - `shots`: The number of shots that the user wants to run.
-#[derive(Deserialize, Debug)]
+/// - `shots`: The number of shots that the user wants to run, from 1 to
+///   2147483647.
+/// - `username`: The user that submits the task, optional. It is used by the
+///   admission control to limit the concurrent tasks of one user and to
+///   charge the usage to the user, the default user is `anonymous`. It is
+///   ignored if the user is taken from the `user_header` of the
+///   [admission config](crate::config::AdmissionConfig).
+/// - `deadline`: The time (RFC 3339) after which the task expires, optional.
+/// - `ttl`: The seconds after the submission after which the task expires,
+///   optional. If both `deadline` and `ttl` are given, the earlier one is
+///   used. An expired task is moved to the task list with the `Expired`
+///   status and the partial result of the executed shots.
+/// - `requirements`: The [requirements](TaskRequirements) of the task on the
+///   agent, optional. It can only be given in a JSON body. It also holds the
+///   pool, node selector and anti-affinity constraints on the agent labels.
+/// - `noise`: The [noise model](NoiseModel) of the task, optional. It can only
+///   be given in a JSON body. A task with a noise model is only placed on the
+///   agents that support noise.
+/// - `cache`: Whether to reuse the result of an identical task of the same
+///   user if the cache is enabled in the configuration, optional. By default,
+///   only the result of a deterministic task (a single outcome) is reused;
+///   `true` reuses any result, or attaches to an identical in-flight task;
+///   `false` always runs the task.
+/// - `cutting`: Whether to [cut](crate::cutting) the circuit into fragments
+///   that run as child tasks if it is too wide for every agent, optional and
+///   false by default. The task then has no agent of its own, its result is
+///   reconstructed from the results of the child tasks.
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct EmulateMessage {
-    code: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    circuit: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Option<HashMap<String, f64>>)]
+    parameters: Option<Parameters>,
     qubits: usize,
     depth: usize,
     shots: usize,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    deadline: Option<DateTime<Utc>>,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    requirements: Option<TaskRequirements>,
+    #[serde(default)]
+    noise: Option<NoiseModel>,
+    #[serde(default)]
+    cache: Option<bool>,
+    #[serde(default)]
+    cutting: Option<bool>,
+}
+
+/// The options of the emulate message that apply when the task is added to
+/// the queue.
+#[derive(Clone, Copy, Debug)]
+struct TaskOptions {
+    cache: Option<bool>,
+    cutting: bool,
+}
+
+/// ## Sweep Message
+/// The sweep message is used to submit many tasks from one parameterized
+/// template in one call, it can only be given in a JSON body.
+/// - The fields of the [emulate message](EmulateMessage), the `parameters`
+///   are shared by all the parameter sets.
+/// - `parameter_sets`: The list of parameter values, one task is created for
+///   each set. A value in the set overrides the shared one.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct SweepMessage {
+    #[serde(flatten)]
+    task: EmulateMessage,
+    #[schema(value_type = Vec<HashMap<String, f64>>)]
+    parameter_sets: Vec<Parameters>,
 }
 
 /// ## Task ID
@@ -44,14 +136,35 @@ pub struct TaskID {
     task_id: Uuid,
 }
 
+/// ## Task View
+/// The task returned by the task query. A task that is waiting or running is
+/// an [active task](crate::entity::task_active::Model), a task that is
+/// succeeded, failed or expired is a
+/// [finished task](crate::entity::task::Model).
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(untagged)]
+pub enum TaskView {
+    #[schema(value_type = TaskActive)]
+    Active(entity::task_active::Model),
+    #[schema(value_type = Task)]
+    Finished(entity::task::Model),
+}
+
 /// ## Merge simulation results
 /// Merge the previous result with the new result. This function will merge the
 /// content of `Memory` field in the result. For the same key (same state), the
 /// values will be added together. If the key does not exist in the previous
-/// result, it will be added to the previous result.
-fn merge_and_add(v1: &mut Value, v2: &Value) {
-    let v1_memory_map = v1.get_mut("Memory").unwrap().as_object_mut().unwrap();
-    let v2_memory_map = v2.get("Memory").unwrap().as_object().unwrap();
+/// result, it will be added to the previous result. If either result has no
+/// `Memory` object, return an error.
+fn merge_and_add(v1: &mut Value, v2: &Value) -> Result<()> {
+    let v1_memory_map = v1
+        .get_mut("Memory")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| Error::Internal("previous result has no Memory field".to_owned()))?;
+    let v2_memory_map = v2
+        .get("Memory")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::Agent("agent result has no Memory field".to_owned()))?;
 
     for (k, v2_value) in v2_memory_map {
         match v1_memory_map.entry(k.clone()) {
@@ -66,12 +179,15 @@ fn merge_and_add(v1: &mut Value, v2: &Value) {
             }
         }
     }
+
+    Ok(())
 }
 
 /// ## Invoke the agent
 /// According to the agent address, invoke the agent's submit API with the
-/// `qasm` and `shots` parameters. The agent will run the task and return the
-/// result. The result is like:
+/// `qasm` and `shots` parameters, and the `noise` parameter (the JSON of the
+/// [noise model](NoiseModel)) if the task has one. The agent will run the task
+/// and return the result. The result is like:
 /// ```json
 /// {
 ///    "Memory": {
@@ -80,221 +196,1046 @@ fn merge_and_add(v1: &mut Value, v2: &Value) {
 ///       "10": 1000,
 ///       "11": 1000
 /// }
-async fn invoke_agent(address: &str, qasm: &str, shots: i32) -> Result<Response, reqwest::Error> {
-    let body = [("qasm", qasm.to_string()), ("shots", shots.to_string())];
+/// ```
+/// If the agent can not be reached, responds with an error status or the body
+/// is not JSON, return an [agent error](Error::Agent).
+async fn invoke_agent(
+    address: &str,
+    qasm: &str,
+    shots: i32,
+    noise: Option<&Value>,
+) -> Result<Value> {
+    let mut body = vec![("qasm", qasm.to_string()), ("shots", shots.to_string())];
+    if let Some(noise) = noise {
+        body.push(("noise", noise.to_string()));
+    }
 
-    reqwest::Client::new()
+    Ok(reqwest::Client::new()
         .post(address)
         .form(&body)
         .send()
+        .await?
+        .error_for_status()?
+        .json::<Value>()
+        .await?)
+}
+
+/// Get the deadline of the task from the `deadline` and `ttl` of the emulate
+/// message, the earlier one is used. If the deadline is not in the future,
+/// return an invalid request error.
+fn task_deadline(emulate_message: &EmulateMessage) -> Result<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+    let ttl_deadline = match emulate_message.ttl {
+        Some(ttl) => Some(
+            i64::try_from(ttl)
+                .ok()
+                .and_then(TimeDelta::try_seconds)
+                .and_then(|ttl| now.checked_add_signed(ttl))
+                .ok_or_else(|| Error::InvalidRequest(format!("ttl {} is too large", ttl)))?,
+        ),
+        None => None,
+    };
+    let deadline = emulate_message
+        .deadline
+        .into_iter()
+        .chain(ttl_deadline)
+        .min();
+
+    match deadline {
+        Some(deadline) if deadline <= now => Err(Error::InvalidRequest(format!(
+            "deadline {} is not in the future",
+            deadline
+        ))),
+        _ => Ok(deadline),
+    }
+}
+
+/// Normalize the QASM source for hashing, the comments, the empty lines and
+/// the redundant whitespaces are removed.
+fn normalize_source(source: &str) -> String {
+    source
+        .lines()
+        .map(|line| {
+            line.split("//")
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The SHA-256 hash of the normalized source and the execution parameters
+/// (qubits, depth, shots, requirements and noise model) of the task. Two
+/// tasks with the same hash are identical.
+fn source_hash(task: &entity::task_active::Model) -> String {
+    let parameters = json!({
+        "qubits": task.qubits,
+        "depth": task.depth,
+        "shots": task.shots,
+        "requirements": task.requirements,
+        "noise": task.noise,
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(normalize_source(&task.source));
+    hasher.update(parameters.to_string());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether the result of the task has a single outcome, so that running the
+/// task again gives the same result.
+fn is_deterministic(result: &str) -> bool {
+    serde_json::from_str::<Value>(result)
+        .ok()
+        .and_then(|result| {
+            result
+                .get("Memory")?
+                .as_object()
+                .map(|memory| memory.len() == 1)
+        })
+        .unwrap_or(false)
+}
+
+/// Find the task of the user identical to the new task. A succeeded task is
+/// returned if its result is deterministic or `reuse_any` is true. Otherwise
+/// an in-flight task is returned if `reuse_any` is true, as its result is not
+/// known to be deterministic yet. Return `None` if there is no identical task.
+async fn find_identical_task(
+    db: &DbConn,
+    config: &CacheConfig,
+    username: &str,
+    source_hash: &str,
+    reuse_any: bool,
+) -> Result<Option<TaskView>> {
+    let since = config
+        .max_age
+        .and_then(|max_age| TimeDelta::try_seconds(max_age.min(i64::MAX as u64) as i64))
+        .and_then(|max_age| Utc::now().naive_utc().checked_sub_signed(max_age));
+    if let Some(task) =
+        service::task::Task::get_succeeded_task_by_hash(db, username, source_hash, since).await?
+    {
+        if reuse_any || is_deterministic(&task.result) {
+            info!("Reuse the result of the identical task {:?}", task.id);
+            return Ok(Some(TaskView::Finished(task)));
+        }
+    }
+
+    if !reuse_any {
+        return Ok(None);
+    }
+    Ok(
+        service::task_active::TaskActive::get_task_by_hash(db, username, source_hash)
+            .await?
+            .map(TaskView::Active),
+    )
+}
+
+/// Convert the count of the emulate message to the column type. The count
+/// must be at least `min` and fit in the column, otherwise it is an invalid
+/// request.
+fn checked_count(name: &str, value: usize, min: usize) -> Result<i32> {
+    i32::try_from(value)
+        .ok()
+        .filter(|_| value >= min)
+        .ok_or_else(|| {
+            Error::InvalidRequest(format!(
+                "{} must be from {} to {}, got {}",
+                name,
+                min,
+                i32::MAX,
+                value
+            ))
+        })
+}
+
+/// Build the task of the emulate message without adding it to the queue, the
+/// source is not bound to the parameters yet. Return the task template and
+/// the options of the message.
+async fn new_task(
+    state: &ServerState,
+    emulate_message: EmulateMessage,
+) -> Result<(entity::task_active::Model, TaskOptions)> {
+    let qubits = checked_count("qubits", emulate_message.qubits, 0)?;
+    let depth = checked_count("depth", emulate_message.depth, 0)?;
+    let shots = checked_count("shots", emulate_message.shots, 1)?;
+    let deadline = task_deadline(&emulate_message)?;
+    let options = TaskOptions {
+        cache: emulate_message.cache,
+        cutting: emulate_message.cutting.unwrap_or(false),
+    };
+
+    // a task with noise model can only run on the agents that support noise
+    let noise = emulate_message
+        .noise
+        .map(|noise| noise.resolve(&state.config.noise_presets))
+        .transpose()?;
+    let mut requirements = emulate_message.requirements;
+    if noise.is_some() {
+        requirements.get_or_insert_with(Default::default).noise = true;
+    }
+
+    // the source is either inline or from the circuit library
+    let (source, circuit) = match (
+        emulate_message.code.filter(|code| !code.is_empty()),
+        emulate_message
+            .circuit
+            .filter(|circuit| !circuit.is_empty()),
+    ) {
+        (Some(code), None) => (code, None),
+        (None, Some(reference)) => {
+            let circuit = super::circuit::resolve_circuit(&state.db, &reference).await?;
+            let reference = format!("{}@{}", circuit.name, circuit.version);
+            (circuit.source, Some(reference))
+        }
+        _ => {
+            return Err(Error::InvalidRequest(
+                "exactly one of code and circuit must be given".to_owned(),
+            ))
+        }
+    };
+
+    let min_vexec_shots = service::task_active::TaskActive::get_min_vexec_shots(&state.db).await?;
+
+    let task = entity::task_active::Model {
+        id: uuid::Uuid::new_v4(),
+        source,
+        result: None,
+        qubits,
+        depth,
+        shots,
+        exec_shots: 0,
+        v_exec_shots: min_vexec_shots,
+        status: sea_orm_active_enums::TaskActiveStatus::Waiting,
+        created_time: Utc::now().naive_utc(),
+        updated_time: Utc::now().naive_utc(),
+        username: emulate_message
+            .username
+            .filter(|username| !username.is_empty())
+            .unwrap_or_else(|| "anonymous".to_owned()),
+        deadline: deadline.map(|deadline| deadline.naive_utc()),
+        requirements: requirements
+            .map(|requirements| serde_json::to_value(requirements.normalized()))
+            .transpose()?,
+        noise: noise.map(serde_json::to_value).transpose()?,
+        source_hash: None,
+        circuit,
+        parameters: None,
+        parent_id: None,
+        cutting: None,
+    };
+    Ok((task, options))
+}
+
+/// Bind the parameters to the source of the task template, the bound values
+/// are recorded with the task. An OpenQASM 2 source with parameters must be
+/// given their values. An OpenQASM 3 program is
+/// [translated](qasm::translate) to OpenQASM 2; if it can not be translated,
+/// it is kept as is and only placed on the agents that run OpenQASM 3, or
+/// rejected with the diagnostics if there is no such agent. The depth of an
+/// OpenQASM 2 program is [recomputed](transpile::circuit_depth) from the
+/// optimized circuit for scheduling. The task gets a new id and the hash of
+/// its source.
+async fn bind_task(
+    state: &ServerState,
+    template: &entity::task_active::Model,
+    parameters: Option<Parameters>,
+) -> Result<entity::task_active::Model> {
+    let mut task = template.clone();
+    task.id = uuid::Uuid::new_v4();
+    match parameters {
+        Some(parameters) => {
+            task.source = bind_parameters(&template.source, &parameters)?;
+            task.parameters = Some(serde_json::to_value(parameters)?);
+        }
+        // the variables of an OpenQASM 3 program are declared by the program
+        None if !qasm::is_qasm3(&template.source) => {
+            bind_parameters(&template.source, &Parameters::new())?;
+        }
+        None => {}
+    }
+
+    if qasm::is_qasm3(&task.source) {
+        match qasm::translate(&task.source) {
+            Ok(source) => task.source = source,
+            Err(diagnostics) => {
+                let mut requirements = TaskRequirements::from_json(task.requirements.as_ref())?;
+                requirements.qasm3 = true;
+                let agents = service::physical_agent::PhysicalAgent::get_physical_agent_available(
+                    &state.db,
+                    &state.config.capacity,
+                    task.qubits,
+                    task.depth,
+                    &requirements,
+                )
+                .await?;
+                if agents.is_empty() {
+                    return Err(Error::UnsupportedQasm(diagnostics));
+                }
+                info!("Run the OpenQASM 3 program on the agents that support it");
+                task.requirements = Some(serde_json::to_value(requirements.normalized())?);
+            }
+        }
+    }
+
+    // schedule the task by the depth of the optimized circuit
+    if let Some(depth) = transpile::circuit_depth(&task.source) {
+        task.depth = depth.max(1) as i32;
+    }
+
+    task.source_hash = Some(source_hash(&task));
+    Ok(task)
+}
+
+/// Estimate the qubit-seconds to run all the shots of the tasks from the
+/// latest completed assignments of all the agents. The estimate is 0 without
+/// any assignment, so that only a used up quota is rejected then.
+async fn estimate_qubit_seconds<'a>(
+    state: &ServerState,
+    tasks: impl IntoIterator<Item = &'a entity::task_active::Model>,
+) -> Result<i64> {
+    let history =
+        service::usage::Usage::get_latest_usages(&state.db, state.config.chunking.history).await?;
+    Ok(tasks
+        .into_iter()
+        .map(|task| {
+            chunking::estimate_seconds(&history, task.qubits, task.depth, task.shots)
+                .map_or(0.0, |seconds| seconds * task.qubits as f64)
+        })
+        .sum::<f64>()
+        .ceil()
+        .min(i64::MAX as f64) as i64)
+}
+
+/// Check the admission limits and the user quota for the task that will run
+/// the given shots and qubit-seconds.
+async fn admit_task(
+    state: &ServerState,
+    task: &entity::task_active::Model,
+    shots: i64,
+    qubit_seconds: i64,
+) -> Result<()> {
+    let admitted = async {
+        service::admission::Admission::check(&state.db, &state.config.admission, task).await?;
+        service::quota::Quota::check(
+            &state.db,
+            &state.config.quota,
+            &task.username,
+            shots,
+            qubit_seconds,
+        )
         .await
+    }
+    .await;
+    if let Err(err) = admitted {
+        error!("Task of user {} is rejected: {}", task.username, err);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// How a task is added to the queue, it is decided by [prepare_task] before
+/// anything is added.
+enum Enqueue {
+    /// The identical task whose result is reused.
+    Reused(TaskView),
+    /// The task to add.
+    Task(entity::task_active::Model),
+    /// The cut task and its child tasks to add.
+    Cut(entity::task_active::Model, Vec<entity::task_active::Model>),
+}
+
+/// Decide how the task is added to the queue: the identical task is reused
+/// if there is one, the task that opts in to cutting is [cut](cut_task) if no
+/// agent is wide enough, and the admission limits and the user quota are
+/// checked before the task is added. They are also checked before the task
+/// is attached to an identical in-flight task, whose usage is charged to the
+/// same user.
+async fn prepare_task(
+    state: &ServerState,
+    task: entity::task_active::Model,
+    options: TaskOptions,
+) -> Result<Enqueue> {
+    let cache = options.cache;
+    // reuse the result of an identical task
+    if state.config.cache.enabled && cache != Some(false) {
+        if let Some(identical) = find_identical_task(
+            &state.db,
+            &state.config.cache,
+            &task.username,
+            task.source_hash.as_deref().unwrap_or_default(),
+            cache == Some(true),
+        )
+        .await?
+        {
+            if let TaskView::Active(active) = &identical {
+                let qubit_seconds = estimate_qubit_seconds(state, [&task]).await?;
+                admit_task(state, &task, task.shots as i64, qubit_seconds).await?;
+                info!("Attach to the identical in-flight task {:?}", active.id);
+            }
+            return Ok(Enqueue::Reused(identical));
+        }
+    }
+
+    let requirements = TaskRequirements::from_json(task.requirements.as_ref())?;
+    let agents = service::physical_agent::PhysicalAgent::get_physical_agent_available(
+        &state.db,
+        &state.config.capacity,
+        task.qubits,
+        task.depth,
+        &requirements,
+    )
+    .await?;
+    if options.cutting && agents.is_empty() {
+        return cut_task(state, task).await;
+    }
+    // the task is only placed on the agents whose gates its circuit can be
+    // decomposed into, it would wait forever if there is none
+    if !agents.is_empty()
+        && !agents
+            .iter()
+            .any(|agent| supports_circuit(agent, &task.source))
+    {
+        return Err(Error::InvalidRequest(
+            "the circuit can not be decomposed into the gates of any agent".to_owned(),
+        ));
+    }
+
+    // check the admission limits and the user quota before adding the task to
+    // the queue
+    let qubit_seconds = estimate_qubit_seconds(state, [&task]).await?;
+    admit_task(state, &task, task.shots as i64, qubit_seconds).await?;
+    Ok(Enqueue::Task(task))
+}
+
+/// Add the [prepared](prepare_task) task to the database with the given
+/// connection. Return the status code 201 with the new task, or 200 with the
+/// identical task whose result is reused.
+async fn add_prepared_task<C: ConnectionTrait + TransactionTrait>(
+    db: &C,
+    capacity: &CapacityConfig,
+    enqueue: Enqueue,
+) -> Result<(StatusCode, TaskView)> {
+    let task = match enqueue {
+        Enqueue::Reused(task) => return Ok((StatusCode::OK, task)),
+        Enqueue::Task(task) => {
+            service::task_active::TaskActive::add_task(db, capacity, task).await?
+        }
+        Enqueue::Cut(task, children) => {
+            service::task_active::TaskActive::add_cut_task(db, capacity, task, children).await?
+        }
+    };
+    info!(
+        "Task {:?} (qubits: {:?}, depth: {:?}, shots: {:?}) added successfully",
+        task.id, task.qubits, task.depth, task.shots
+    );
+    Ok((StatusCode::CREATED, TaskView::Active(task)))
 }
 
-/// Internal task submit function
+/// Add the task to the queue after checking the admission limits and the
+/// user quota. Return the status code 201 with the new task, or 200 with the
+/// identical task whose result is reused. If the task opts in to cutting and
+/// no agent is wide enough, it is [cut](cut_task) instead.
+async fn enqueue_task(
+    state: &ServerState,
+    task: entity::task_active::Model,
+    options: TaskOptions,
+) -> Result<(StatusCode, TaskView)> {
+    let enqueue = prepare_task(state, task, options).await?;
+    let added = add_prepared_task(&state.db, &state.config.capacity, enqueue).await?;
+    if added.0 == StatusCode::CREATED {
+        dispatch::wake(&state.db).await;
+    }
+    Ok(added)
+}
+
+/// Cut the task that is too wide for every agent into fragments of at most
+/// the qubits of the largest agent that meets its requirements, the quota of
+/// the user must cover the shots of all the child tasks. The task is added
+/// as running with its child tasks in one transaction, and is finished when
+/// its child tasks are.
+async fn cut_task(state: &ServerState, mut task: entity::task_active::Model) -> Result<Enqueue> {
+    let requirements = TaskRequirements::from_json(task.requirements.as_ref())?;
+    let agents = service::physical_agent::PhysicalAgent::get_physical_agent_available(
+        &state.db,
+        &state.config.capacity,
+        1,
+        1,
+        &requirements,
+    )
+    .await?;
+    let capacity =
+        agents
+            .iter()
+            .map(|agent| agent.qubit_count)
+            .max()
+            .ok_or(Error::NoAvailableAgent {
+                qubits: task.qubits,
+                depth: task.depth,
+            })?;
+    let (plan, fragments) = cutting::cut_circuit(
+        &task.source,
+        capacity as usize,
+        state.config.cutting.max_cuts,
+    )?;
+
+    let children: Vec<entity::task_active::Model> = fragments
+        .into_iter()
+        .map(|fragment| entity::task_active::Model {
+            id: fragment.id,
+            source: fragment.source,
+            qubits: fragment.qubits as i32,
+            depth: fragment.depth.max(1) as i32,
+            source_hash: None,
+            circuit: None,
+            parameters: None,
+            parent_id: Some(task.id),
+            cutting: None,
+            ..task.clone()
+        })
+        .collect();
+    let unsupported = children.iter().find(|child| {
+        !agents.iter().any(|agent| {
+            agent.qubit_count >= child.qubits && supports_circuit(agent, &child.source)
+        })
+    });
+    if let Some(child) = unsupported {
+        return Err(Error::InvalidRequest(format!(
+            "the fragment of {} qubits can not be decomposed into the gates of any agent",
+            child.qubits
+        )));
+    }
+    let qubit_seconds = estimate_qubit_seconds(state, &children).await?;
+    admit_task(
+        state,
+        &task,
+        task.shots as i64 * children.len() as i64,
+        qubit_seconds,
+    )
+    .await?;
+
+    info!(
+        "Task {:?} is cut at {} gates into {} fragments of {} child tasks",
+        task.id,
+        plan.angles.len(),
+        plan.fragments.len(),
+        children.len()
+    );
+    task.status = sea_orm_active_enums::TaskActiveStatus::Running;
+    task.cutting = Some(serde_json::to_value(&plan)?);
+    Ok(Enqueue::Cut(task, children))
+}
+
+/// Internal task submit function. Return the status code 201 with the new
+/// task, or 200 with the identical task whose result is reused.
 async fn _submit(
     state: ServerState,
-    Form(emulate_message): Form<EmulateMessage>,
-) -> (StatusCode, Json<Value>) {
+    mut emulate_message: EmulateMessage,
+) -> Result<(StatusCode, TaskView)> {
     info!("Consume task in submit request");
 
-    let min_vexec_shots = service::task_active::TaskActive::get_min_vexec_shots(&state.db)
-        .await
-        .unwrap();
+    let parameters = emulate_message.parameters.take();
+    let (template, options) = new_task(&state, emulate_message).await?;
+    let task = bind_task(&state, &template, parameters).await?;
+    enqueue_task(&state, task, options).await
+}
+
+/// Internal task sweep function. All the parameter sets are
+/// [expanded](sweep_parameters) and bound, and the quota of the user must
+/// cover the shots of all the tasks. The tasks are added in one transaction,
+/// so either all of them or none is added. Return the tasks in the order of
+/// the parameter sets.
+async fn _sweep(state: ServerState, mut sweep_message: SweepMessage) -> Result<Vec<TaskView>> {
+    info!(
+        "Consume sweep of {} tasks in submit request",
+        sweep_message.parameter_sets.len()
+    );
+    let shared = sweep_message.task.parameters.take().unwrap_or_default();
+    let (template, options) = new_task(&state, sweep_message.task).await?;
+    let parameter_sets = sweep_parameters(&template.source, &shared, sweep_message.parameter_sets)?;
+    let mut tasks = Vec::with_capacity(parameter_sets.len());
+    for parameters in parameter_sets {
+        tasks.push(bind_task(&state, &template, Some(parameters)).await?);
+    }
 
-    // add this task to the database
-    match service::task_active::TaskActive::add_task(
+    service::quota::Quota::check(
         &state.db,
-        entity::task_active::Model {
-            id: uuid::Uuid::new_v4(),
-            source: emulate_message.code.clone(),
-            result: None,
-            qubits: emulate_message.qubits as i32,
-            depth: emulate_message.depth as i32,
-            shots: emulate_message.shots as i32,
-            exec_shots: 0,
-            v_exec_shots: min_vexec_shots as i32,
-            status: sea_orm_active_enums::TaskActiveStatus::Waiting,
-            created_time: chrono::Utc::now().naive_utc(),
-            updated_time: chrono::Utc::now().naive_utc(),
-        },
+        &state.config.quota,
+        &template.username,
+        tasks.iter().map(|task| task.shots as i64).sum(),
+        estimate_qubit_seconds(&state, &tasks).await?,
     )
-    .await
-    {
-        Ok(task) => {
+    .await?;
+
+    let mut prepared = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        prepared.push(prepare_task(&state, task, options).await?);
+    }
+    let txn = state.db.begin().await?;
+    let mut views = Vec::with_capacity(prepared.len());
+    for enqueue in prepared {
+        let (_, view) = add_prepared_task(&txn, &state.config.capacity, enqueue).await?;
+        views.push(view);
+    }
+    txn.commit().await?;
+    dispatch::wake(&state.db).await;
+    Ok(views)
+}
+
+/// Move the task from the active task list to the task list with the given
+/// result and status. If it is a child task of a cut task, the cut task may
+/// be [completed](complete_cut_task).
+async fn finish_task(
+    db: &DbConn,
+    task_id: Uuid,
+    result: &Value,
+    status: sea_orm_active_enums::TaskStatus,
+) -> Result<()> {
+    let txn = db.begin().await?;
+    let task = move_task(&txn, task_id, result, status).await?;
+    txn.commit().await?;
+    if let Some(parent_id) = task.parent_id {
+        complete_cut_task(db, parent_id, &task).await?;
+    }
+    Ok(())
+}
+
+/// Finish the cut task after its child task is finished. The cut task is
+/// locked meanwhile, so it is finished once when its child tasks finish at
+/// the same time. If the child task is not succeeded, the cut task is
+/// finished with the same status, and the other child tasks are cancelled
+/// with it and their reservations released. When all the child tasks are
+/// succeeded, the result is [reconstructed](cutting::reconstruct) from their
+/// results.
+async fn complete_cut_task(
+    db: &DbConn,
+    parent_id: Uuid,
+    child: &entity::task::Model,
+) -> Result<()> {
+    let txn = db.begin().await?;
+    let Some(parent) = service::task_active::TaskActive::lock_task(&txn, parent_id).await? else {
+        return Ok(());
+    };
+    if child.status != sea_orm_active_enums::TaskStatus::Succeeded {
+        let result = json!({"Error": format!("child task {} is {:?}", child.id, child.status)});
+        move_task(&txn, parent.id, &result, child.status.clone()).await?;
+
+        // the result of the other child tasks is of no use any more, a running
+        // chunk of them is discarded as its reservation is released
+        let cancelled = json!({"Error": format!("cut task {} is {:?}", parent.id, child.status)});
+        for sibling in
+            service::task_active::TaskActive::get_tasks_by_parent(&txn, parent.id).await?
+        {
+            service::capacity::Capacity::release_task(&txn, sibling.id).await?;
+            move_task(&txn, sibling.id, &cancelled, child.status.clone()).await?;
             info!(
-                "Task {:?} (qubits: {:?}, depth: {:?}, shots: {:?}) added successfully",
-                task.id, task.qubits, task.depth, task.shots
+                "Child task {:?} of cut task {:?} is cancelled",
+                sibling.id, parent.id
             );
-            (StatusCode::OK, Json(json!({"task": task})))
+        }
+        txn.commit().await?;
+        return Ok(());
+    }
+
+    let plan: cutting::CutPlan = serde_json::from_value(
+        parent
+            .cutting
+            .clone()
+            .ok_or_else(|| Error::Internal(format!("task {} has no cut plan", parent.id)))?,
+    )?;
+    let children = service::task::Task::get_tasks_by_parent(&txn, parent.id).await?;
+    if children.len() < plan.task_count() {
+        return Ok(());
+    }
+
+    let results = children
+        .iter()
+        .map(|child| Ok((child.id, serde_json::from_str::<Value>(&child.result)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+    match cutting::reconstruct(&plan, &results, parent.shots) {
+        Ok(result) => {
+            info!("Cut task {:?} is reconstructed", parent.id);
+            move_task(
+                &txn,
+                parent.id,
+                &result,
+                sea_orm_active_enums::TaskStatus::Succeeded,
+            )
+            .await?;
         }
         Err(err) => {
-            error!("Add task failed: {}", err);
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("{}", err)})),
+            error!("Reconstruct cut task {:?} failed: {}", parent.id, err);
+            move_task(
+                &txn,
+                parent.id,
+                &json!({"Error": format!("{}", err)}),
+                sea_orm_active_enums::TaskStatus::Failed,
             )
+            .await?;
         }
     }
+    Ok(txn.commit().await?)
 }
 
-/// ## Consume Task
-/// The consume task function is responsible for submitting the task to the
-///  agent. The main steps are:
-/// - Calculate the number of shots to run the task according to the task's
-/// depth and scheduler configuration. The formula is:
-///  `shots = task.depth / sched_min_depth * sched_min_gran`. That is, the
-/// deeper the circuit, the less shots will be executed in one run.
-/// - Add the [assignment](crate::entity::task_assignment::Model) to the
-///   database.
-/// - Submit the task to the agent by [invoking](invoke_agent) the agent's
-///   submit API.
-/// - Update the agent's qubit_idle field in the database.
-/// - Depending on the result of the task, update the task's result and status
-/// in the database.
-///   - If the task is run for the first time, update the task's result and
-///     status to Waiting.
-///   - If the task is finished, remove the task from the active task list and
-///     add it to the task list.
-///   - If the task is not finished, [merge](merge_and_add) previous result with
-///     the new result and update the task's result and status to Waiting.
-///   - If the invocation fails, remove the task from the active task list and
-///     add it to the task list with the error message.
-pub async fn consume_task(
+/// Move the task from the active task list to the task list with the given
+/// result and status.
+async fn move_task<C: ConnectionTrait>(
+    db: &C,
+    task_id: Uuid,
+    result: &Value,
+    status: sea_orm_active_enums::TaskStatus,
+) -> Result<entity::task::Model> {
+    let task = service::task_active::TaskActive::remove_active_task(db, task_id).await?;
+    service::task::Task::add_task(
+        db,
+        entity::task::Model {
+            id: task.id,
+            source: task.source,
+            result: serde_json::to_string_pretty(result)?,
+            qubits: task.qubits,
+            depth: task.depth,
+            shots: task.shots,
+            status,
+            created_time: task.created_time,
+            updated_time: task.updated_time,
+            username: task.username,
+            noise: task.noise,
+            source_hash: task.source_hash,
+            circuit: task.circuit,
+            parameters: task.parameters,
+            parent_id: task.parent_id,
+            cutting: task.cutting,
+        },
+    )
+    .await
+}
+
+/// ## Chunk
+/// The next chunk of a task on an agent.
+/// - `shots`: The shots of the chunk.
+/// - `seconds`: The estimated seconds to run the chunk on the agent.
+pub struct Chunk {
+    pub shots: i32,
+    pub seconds: f64,
+}
+
+/// ## Next Chunk
+/// Size the next chunk of the task on the agent. The shots come from the
+/// [throughput](chunking) of the agent learned from its completed
+/// assignments, so that the chunk runs for the configured time slice. If the
+/// agent has no completed assignment, the formula is: `shots =
+/// sched_min_depth / task.depth * sched_min_gran`. The shots are at least one
+/// and at most the configured ceiling, the max shots of the agent and the
+/// remaining shots of the task.
+pub async fn next_chunk(
     db: &DbConn,
     sched_min_depth: f32,
     sched_min_gran: f32,
-    task: entity::task_active::Model,
-    agent: entity::physical_agent::Model,
-) {
-    // get exec shots according to the min depth and gran
-    let mut exec_shots = (sched_min_depth / task.depth as f32 * sched_min_gran) as i32;
-    if task.exec_shots + exec_shots > task.shots {
-        exec_shots = task.shots - task.exec_shots;
+    chunking: &ChunkingConfig,
+    task: &entity::task_active::Model,
+    agent: &entity::physical_agent::Model,
+) -> Result<Chunk> {
+    let history = service::usage::Usage::get_agent_usages(db, agent.id, chunking.history).await?;
+    let mut shots = chunking::chunk_shots(
+        chunking,
+        &history,
+        task.qubits,
+        task.depth,
+        sched_min_depth / task.depth as f32 * sched_min_gran,
+    );
+    if let Some(max_shots) = agent.max_shots {
+        shots = shots.min(max_shots);
+    }
+    if task.exec_shots + shots > task.shots {
+        shots = task.shots - task.exec_shots;
     }
+    Ok(Chunk {
+        shots,
+        seconds: chunking::chunk_seconds(chunking, &history, task.qubits, task.depth, shots),
+    })
+}
 
+/// Internal consume task function, run one chunk of the task on the agent and
+/// record the result.
+async fn _consume_task(
+    db: &DbConn,
+    exec_shots: i32,
+    assignment_id: Uuid,
+    task: entity::task_active::Model,
+    agent: &entity::physical_agent::Model,
+) -> Result<()> {
     info!("Consume task {:?} with {:?} shots", task.id, exec_shots);
 
     // init add assignment
     let assign = service::task_assignment::TaskAssignment::add_assignment(
         db,
         entity::task_assignment::Model {
-            id: uuid::Uuid::new_v4(),
+            id: assignment_id,
             task_id: task.id,
             agent_id: agent.id,
             shots: Some(exec_shots),
             status: sea_orm_active_enums::AssignmentStatus::Running,
+            created_time: Some(Utc::now().naive_utc()),
         },
     )
-    .await
-    .unwrap();
+    .await?;
 
-    // run the task
-    let result = invoke_agent(
-        &format!("http://{}:{}/submit", agent.ip, agent.port),
-        &task.source,
-        exec_shots,
-    )
-    .await;
+    // run the task, and merge the result with the previous one
+    let started_time = Utc::now().naive_utc();
+    let result: Result<Value> = async {
+        // fit the circuit to the gates of the agent
+        let gates = agent_gates(agent)?;
+        let source = transpile::transpile(&task.source, gates.as_deref())?;
 
-    service::physical_agent::PhysicalAgent::update_physical_agent_qubits_idle(
-        db,
-        agent.id,
-        task.qubits as i32,
-    )
-    .await
-    .unwrap();
+        let result = invoke_agent(
+            &format!("http://{}:{}/submit", agent.ip, agent.port),
+            &source,
+            exec_shots,
+            task.noise.as_ref(),
+        )
+        .await?;
 
-    if result.is_ok() {
-        let task_result = if task.result.is_none() {
+        match &task.result {
             // if the task is run for the first time
-            result.unwrap().json::<Value>().await.unwrap()
-        } else {
-            let mut task_result: Value =
-                serde_json::from_str::<Value>(&task.result.unwrap()).unwrap();
-            merge_and_add(
-                &mut task_result,
-                &result.unwrap().json::<Value>().await.unwrap(),
-            );
-            task_result
-        };
+            None => Ok(result),
+            Some(previous) => {
+                let mut task_result = serde_json::from_str::<Value>(previous)?;
+                merge_and_add(&mut task_result, &result)?;
+                Ok(task_result)
+            }
+        }
+    }
+    .await;
 
-        // if the task is finisched
-        if task.exec_shots + exec_shots >= task.shots {
-            service::task_active::TaskActive::remove_active_task(db, task.id)
-                .await
-                .unwrap();
-            service::task::Task::add_task(
+    // the reservation is the lease of the assignment: if it is released by a
+    // new leader, the task is requeued and the chunk must be discarded
+    if !service::capacity::Capacity::release(db, assignment_id).await? {
+        info!(
+            "Reservation {:?} of task {:?} is released by another replica, discard the chunk",
+            assignment_id, task.id
+        );
+        service::task_assignment::TaskAssignment::update_assignment_status(
+            db,
+            assign.id,
+            sea_orm_active_enums::AssignmentStatus::Failed,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    match result {
+        Ok(task_result) => {
+            // if the task is finisched
+            if task.exec_shots + exec_shots >= task.shots {
+                finish_task(
+                    db,
+                    task.id,
+                    &task_result,
+                    sea_orm_active_enums::TaskStatus::Succeeded,
+                )
+                .await?;
+            } else {
+                // if the task is not finisched
+                service::task_active::TaskActive::update_task_result(
+                    db,
+                    task.id,
+                    task.exec_shots + exec_shots,
+                    task.v_exec_shots + exec_shots,
+                    Some(serde_json::to_string_pretty(&task_result)?),
+                    sea_orm_active_enums::TaskActiveStatus::Waiting,
+                )
+                .await?;
+            }
+
+            // update the assignment status
+            service::task_assignment::TaskAssignment::update_assignment_status(
                 db,
-                entity::task::Model {
-                    id: task.id,
-                    source: task.source,
-                    result: serde_json::to_string_pretty(&task_result).unwrap(),
+                assign.id,
+                sea_orm_active_enums::AssignmentStatus::Succeeded,
+            )
+            .await?;
+
+            // charge the completed assignment to the user
+            let finished_time = Utc::now().naive_utc();
+            service::usage::Usage::add_usage(
+                db,
+                entity::usage_record::Model {
+                    id: uuid::Uuid::new_v4(),
+                    assignment_id: assign.id,
+                    task_id: task.id,
+                    agent_id: agent.id,
+                    username: task.username.clone(),
+                    shots: exec_shots,
                     qubits: task.qubits,
                     depth: task.depth,
-                    shots: task.shots,
-                    status: sea_orm_active_enums::TaskStatus::Succeeded,
-                    created_time: task.created_time,
-                    updated_time: task.updated_time,
+                    wall_time_ms: (finished_time - started_time).num_milliseconds(),
+                    started_time,
+                    finished_time,
                 },
             )
-            .await
-            .unwrap();
-        } else {
-            // if the task is not finisched
-            service::task_active::TaskActive::update_task_result(
+            .await?;
+        }
+        Err(err) => {
+            // if the task is failed
+            error!(
+                "Run task {:?} on agent {:?} failed: {}",
+                task.id, agent.id, err
+            );
+            service::task_assignment::TaskAssignment::update_assignment_status(
+                db,
+                assign.id,
+                sea_orm_active_enums::AssignmentStatus::Failed,
+            )
+            .await?;
+
+            // remove the task from the active task list, and add it to the task list
+            finish_task(
                 db,
                 task.id,
-                task.exec_shots + exec_shots,
-                task.v_exec_shots + exec_shots,
-                Some(serde_json::to_string_pretty(&task_result).unwrap()),
-                sea_orm_active_enums::TaskActiveStatus::Waiting,
+                &json!({"Error": format!("{}", err)}),
+                sea_orm_active_enums::TaskStatus::Failed,
             )
-            .await
-            .unwrap();
+            .await?;
         }
+    }
 
-        // update the assignment status
-        service::task_assignment::TaskAssignment::update_assignment_status(
-            db,
-            assign.id,
-            sea_orm_active_enums::AssignmentStatus::Succeeded,
-        )
-        .await
-        .unwrap();
-    } else {
-        // if the task is failed
-        service::task_assignment::TaskAssignment::update_assignment_status(
-            db,
-            assign.id,
-            sea_orm_active_enums::AssignmentStatus::Failed,
-        )
-        .await
-        .unwrap();
+    Ok(())
+}
 
-        // remove the task from the active task list, and add it to the task list
-        let task = service::task_active::TaskActive::remove_active_task(db, task.id)
-            .await
-            .unwrap();
+/// ## Consume Task
+/// The consume task function is responsible for submitting the
+/// [chunk](next_chunk) of the task to the agent. The main steps are:
+/// - Add the [assignment](crate::entity::task_assignment::Model) to the
+///   database, with the id of the [reservation](service::capacity::Capacity)
+///   of the agent's capacity made by the consume loop.
+/// - [Transpile](transpile::transpile) the circuit to the gates of the agent,
+///   the consume loop only places the task on the agents whose gates its
+///   circuit [can be decomposed](supports_circuit) into.
+/// - Submit the task to the agent by [invoking](invoke_agent) the agent's
+///   submit API.
+/// - Depending on the result of the task, update the task's result and status
+///   in the database.
+///   - If the task is run for the first time, update the task's result and
+///     status to Waiting.
+///   - If the task is finished, remove the task from the active task list and
+///     add it to the task list.
+///   - If the task is not finished, [merge](merge_and_add) previous result with
+///     the new result and update the task's result and status to Waiting.
+///   - If the invocation fails, remove the task from the active task list and
+///     add it to the task list with the error message.
+/// - If the assignment succeeds, add a record with the shots and the wall
+///   time to the [usage ledger](crate::entity::usage_record::Model).
+/// - Before the result is recorded,
+///   [release](service::capacity::Capacity::release) the reservation of the
+///   agent's capacity. If it is already released by a new leader that
+///   [requeued](requeue_tasks) the task, the result of the chunk is
+///   discarded. Then [wake](dispatch::wake) the consume loop to dispatch on
+///   the freed capacity.
+///
+/// Any error is logged instead of panicking, and the agent's capacity is
+/// always released, so the consume thread keeps running.
+pub async fn consume_task(
+    db: &DbConn,
+    exec_shots: i32,
+    assignment_id: Uuid,
+    task: entity::task_active::Model,
+    agent: entity::physical_agent::Model,
+) {
+    let task_id = task.id;
+
+    if let Err(err) = _consume_task(db, exec_shots, assignment_id, task, &agent).await {
+        error!("Consume task {:?} failed: {}", task_id, err);
+    }
+
+    if let Err(err) = service::capacity::Capacity::release(db, assignment_id).await {
+        error!(
+            "Release the reservation {:?} of physical agent {:?} failed: {}",
+            assignment_id, agent.id, err
+        );
+    }
+
+    // the next chunk or another task can run on the freed qubits
+    dispatch::wake(db).await;
+}
+
+/// ## Requeue tasks
+/// Requeue the tasks that are marked as running when this replica takes over
+/// the consume loop, e.g. after the previous leader crashed or lost its lock,
+/// or after a restart. Their chunks are not tracked by this replica, so the
+/// reservations of the tasks are released, their running assignments are
+/// marked as failed and the tasks are waiting again. A chunk that still runs
+/// on a replica that is gone finds its reservation released and is
+/// discarded. Any error is logged.
+pub async fn requeue_tasks(db: &DbConn) {
+    let running_tasks = match service::task_active::TaskActive::get_running_tasks(db).await {
+        Ok(running_tasks) => running_tasks,
+        Err(err) => {
+            error!("Get running tasks failed: {}", err);
+            return;
+        }
+    };
+
+    for task in running_tasks {
+        let requeued: Result<()> = async {
+            service::capacity::Capacity::release_task(db, task.id).await?;
+            service::task_assignment::TaskAssignment::fail_running_assignments(db, task.id).await?;
+            service::task_active::TaskActive::update_task_status(
+                db,
+                task.id,
+                sea_orm_active_enums::TaskActiveStatus::Waiting,
+            )
+            .await?;
+            Ok(())
+        }
+        .await;
+        match requeued {
+            Ok(()) => info!("Task {:?} is requeued", task.id),
+            Err(err) => error!("Requeue task {:?} failed: {}", task.id, err),
+        }
+    }
+}
 
-        service::task::Task::add_task(
+/// ## Expire tasks
+/// Move the waiting tasks whose deadline has passed to the task list with the
+/// `Expired` status. The partial result of the executed shots is kept, a task
+/// that has not been run has an empty `Memory`. A task that is running
+/// expires after the running chunk is finished. This function is called by
+/// the consume thread before dispatching the waiting tasks, any error is
+/// logged.
+pub async fn expire_tasks(db: &DbConn) {
+    let expired_tasks =
+        match service::task_active::TaskActive::get_expired_tasks(db, Utc::now().naive_utc()).await
+        {
+            Ok(expired_tasks) => expired_tasks,
+            Err(err) => {
+                error!("Get expired tasks failed: {}", err);
+                return;
+            }
+        };
+
+    for task in expired_tasks {
+        let result = match &task.result {
+            Some(result) => {
+                serde_json::from_str::<Value>(result).unwrap_or_else(|_| json!({"Memory": {}}))
+            }
+            None => json!({"Memory": {}}),
+        };
+        info!(
+            "Task {:?} expired after {} of {} shots",
+            task.id, task.exec_shots, task.shots
+        );
+        if let Err(err) = finish_task(
             db,
-            entity::task::Model {
-                id: task.id,
-                source: task.source,
-                result: serde_json::to_string_pretty(
-                    &json!({"Error": format!("{}", result.unwrap_err())}),
-                )
-                .unwrap(),
-                qubits: task.qubits,
-                depth: task.depth,
-                shots: task.shots,
-                status: sea_orm_active_enums::TaskStatus::Failed,
-                created_time: task.created_time,
-                updated_time: task.updated_time,
-            },
+            task.id,
+            &result,
+            sea_orm_active_enums::TaskStatus::Expired,
         )
         .await
-        .unwrap();
+        {
+            error!("Expire task {:?} failed: {}", task.id, err);
+        }
     }
 }
 
@@ -303,96 +1244,116 @@ pub async fn consume_task(
 /// if the qubits and depth are less than the agent's qubit_count and circuit
 /// depth. Then, retrieve the virtual execution shots from the task_active
 /// table. If these conditions are not met, return an error message.
-pub async fn submit(
+///
+/// If the cache is enabled, the result of an identical task may be returned
+/// instead, please refer to [EmulateMessage].
+///
+/// Deprecated, please use [create_task] instead.
+pub async fn submit(State(state): State<ServerState>, request: Request) -> Result<Json<Value>> {
+    let user = request_user(request.headers(), &state.config.admission)?;
+    let mut emulate_message: EmulateMessage = extract_body(request).await?;
+    emulate_message.username = user.or(emulate_message.username);
+    let (_, task) = _submit(state, emulate_message).await?;
+    Ok(Json(json!({"task": task})))
+}
+
+/// ## Create task
+/// Create a new task, please ref to the [submit] function. The created task is
+/// returned with the status code 201. If the cache is enabled, the identical
+/// succeeded or in-flight task is returned with the status code 200.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks",
+    tag = "tasks",
+    request_body(
+        content = EmulateMessage,
+        content_type = "application/json",
+        description = "The task to run, `application/x-www-form-urlencoded` is also accepted"
+    ),
+    responses(
+        (status = 201, description = "Task created", body = TaskActive),
+        (status = 200, description = "The identical succeeded or in-flight task", body = TaskView),
+        (status = 400, description = "Invalid shots, deadline, ttl, noise model, circuit reference or parameters, or unsupported OpenQASM 3 features", body = ErrorResponse),
+        (status = 401, description = "The user header of the admission config is missing", body = ErrorResponse),
+        (status = 404, description = "Referenced circuit not found", body = ErrorResponse),
+        (status = 413, description = "Task exceeds a per task limit", body = ErrorResponse),
+        (status = 415, description = "Content type not supported", body = ErrorResponse),
+        (status = 422, description = "No agent can run the task or meets its requirements", body = ErrorResponse),
+        (status = 429, description = "Queue limit or user quota reached, retry after `Retry-After` seconds", body = ErrorResponse),
+    )
+)]
+pub async fn create_task(
     State(state): State<ServerState>,
     request: Request,
-) -> (StatusCode, Json<Value>) {
-    match request.headers().get(header::CONTENT_TYPE) {
-        // If the content type is correct, consume the task
-        Some(content_type) => match content_type.to_str().unwrap() {
-            "application/x-www-form-urlencoded" => {
-                let Form(emulate_message) = request.extract().await.unwrap();
-                _submit(state, Form(emulate_message)).await
-            }
-            "application/json" => {
-                let Json::<EmulateMessage>(emulate_message) = request.extract().await.unwrap();
-                _submit(state, Form(emulate_message)).await
-            }
-            _ => {
-                error!(
-                    "Submit request failed: content type {:?} not support",
-                    content_type
-                );
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({"Error": format!("content type {:?} not support", content_type)})),
-                )
-            }
-        },
-        _ => {
-            error!("Submit request failed: content type not specified");
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({"Error": format!("content type not specified")})),
-            )
-        }
-    }
+) -> Result<(StatusCode, Json<TaskView>)> {
+    let user = request_user(request.headers(), &state.config.admission)?;
+    let mut emulate_message: EmulateMessage = extract_body(request).await?;
+    emulate_message.username = user.or(emulate_message.username);
+    let (status, task) = _submit(state, emulate_message).await?;
+    Ok((status, Json(task)))
+}
+
+/// ## Create sweep
+/// Create one task for each parameter set of the
+/// [sweep message](SweepMessage) from the same template, please ref to the
+/// [submit] function. The tasks are returned with the status code 201 in the
+/// order of the parameter sets, an identical task may be returned instead of
+/// a new one if the cache is enabled. The tasks are added in one
+/// transaction, if a parameter set does not match the template or a task is
+/// rejected, no task is created.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks/sweep",
+    tag = "tasks",
+    request_body(content = SweepMessage, content_type = "application/json"),
+    responses(
+        (status = 201, description = "Tasks created", body = [TaskView]),
+        (status = 400, description = "Invalid parameters or empty parameter sets", body = ErrorResponse),
+        (status = 401, description = "The user header of the admission config is missing", body = ErrorResponse),
+        (status = 404, description = "Referenced circuit not found", body = ErrorResponse),
+        (status = 413, description = "Task exceeds a per task limit", body = ErrorResponse),
+        (status = 422, description = "No agent can run the tasks or meets their requirements", body = ErrorResponse),
+        (status = 429, description = "Queue limit or user quota reached, retry after `Retry-After` seconds", body = ErrorResponse),
+    )
+)]
+pub async fn create_sweep(
+    State(state): State<ServerState>,
+    request: Request,
+) -> Result<(StatusCode, Json<Vec<TaskView>>)> {
+    let user = request_user(request.headers(), &state.config.admission)?;
+    let mut sweep_message: SweepMessage = extract_body(request).await?;
+    sweep_message.task.username = user.or(sweep_message.task.username);
+    let tasks = _sweep(state, sweep_message).await?;
+    Ok((StatusCode::CREATED, Json(tasks)))
 }
 
 /// Internal get task function
-async fn _get_task(db: &DbConn, task_id: Uuid) -> (StatusCode, Json<Value>) {
+async fn _get_task(db: &DbConn, task_id: Uuid) -> Result<TaskView> {
     info!("Get task status by task id: {:?}", task_id);
-    let db = db;
-    match service::task_active::TaskActive::get_task(db, task_id).await {
-        Ok(task) => match task {
-            Some(task) => {
-                info!("Task {:?} is running", task.id);
-                (StatusCode::OK, Json(json!({"task": task})))
-            }
-            None => match service::task::Task::get_task(db, task_id).await {
-                Ok(task) => match task {
-                    Some(task) => match task.status {
-                        sea_orm_active_enums::TaskStatus::Failed => {
-                            info!("Task {:?} is failed", task.id);
-                            (StatusCode::OK, Json(json!({"task": task})))
-                        }
-                        sea_orm_active_enums::TaskStatus::Succeeded => {
-                            info!("Task {:?} is succeeded", task.id);
-                            (StatusCode::OK, Json(json!({"task": task})))
-                        }
-                    },
-                    None => {
-                        info!("Task with id {:?} not found", task_id);
-                        (
-                            StatusCode::BAD_REQUEST,
-                            Json(json!({
-                                "task_id": task_id,
-                                "Error": "Task not found"
-                            })),
-                        )
-                    }
-                },
-                Err(err) => {
-                    error!("Get task {:?} status failed: {}", task_id, err);
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Json(json!({
-                            "task_id": task_id,
-                            "Error": format!("{}", err)
-                        })),
-                    )
+
+    if let Some(task) = service::task_active::TaskActive::get_task(db, task_id).await? {
+        info!("Task {:?} is running", task.id);
+        return Ok(TaskView::Active(task));
+    }
+
+    match service::task::Task::get_task(db, task_id).await? {
+        Some(task) => {
+            match task.status {
+                sea_orm_active_enums::TaskStatus::Failed => {
+                    info!("Task {:?} is failed", task.id);
                 }
-            },
-        },
-        Err(err) => {
-            error!("Get task {:?} status failed: {}", task_id, err);
-            (
-                StatusCode::BAD_REQUEST,
-                Json(json!({
-                    "task_id": task_id,
-                    "Error": format!("{}", err)
-                })),
-            )
+                sea_orm_active_enums::TaskStatus::Succeeded => {
+                    info!("Task {:?} is succeeded", task.id);
+                }
+                sea_orm_active_enums::TaskStatus::Expired => {
+                    info!("Task {:?} is expired", task.id);
+                }
+            }
+            Ok(TaskView::Finished(task))
+        }
+        None => {
+            info!("Task with id {:?} not found", task_id);
+            Err(Error::not_found("task", task_id))
         }
     }
 }
@@ -402,22 +1363,254 @@ async fn _get_task(db: &DbConn, task_id: Uuid) -> (StatusCode, Json<Value>) {
 /// [task_active](crate::entity::task_active::Model) table. If the task is
 /// running/waiting, return the task status. If the task is not in the
 /// task_active table, check if the task is in the
-/// [task](crate::entity::task::Model) table. If the task is Failed/Succeeded,
-/// return the task status. If the task is not in the task table, return an
-/// error message.
+/// [task](crate::entity::task::Model) table. If the task is
+/// Failed/Succeeded/Expired, return the task status. If the task is not in the
+/// task table, return a not found error.
+///
+/// Deprecated, please use [fetch_task] instead.
 pub async fn get_task(
     State(state): State<ServerState>,
     // query only support following format, Query<Uuid> is wrong
     Query(query_message): Query<TaskID>,
-) -> (StatusCode, Json<Value>) {
-    _get_task(&state.db, query_message.task_id).await
+) -> Result<Json<Value>> {
+    let task = _get_task(&state.db, query_message.task_id).await?;
+    Ok(Json(json!({"task": task})))
 }
 
 /// ## Get task by url path
 /// Please ref to the [get_task] function
+///
+/// Deprecated, please use [fetch_task] instead.
 pub async fn get_task_with_id(
     State(state): State<ServerState>,
     Path(task_id): Path<Uuid>,
-) -> (StatusCode, Json<Value>) {
-    _get_task(&state.db, task_id).await
+) -> Result<Json<Value>> {
+    let task = _get_task(&state.db, task_id).await?;
+    Ok(Json(json!({"task": task})))
+}
+
+/// ## Fetch task
+/// Get the task by the task id in the url path, please ref to the [get_task]
+/// function. The task is returned without the `task` wrapper.
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}",
+    tag = "tasks",
+    params(("id" = Uuid, Path, description = "The task id")),
+    responses(
+        (status = 200, description = "The active or finished task", body = TaskView),
+        (status = 404, description = "Task not found", body = ErrorResponse),
+    )
+)]
+pub async fn fetch_task(
+    State(state): State<ServerState>,
+    Path(task_id): Path<Uuid>,
+) -> Result<Json<TaskView>> {
+    Ok(Json(_get_task(&state.db, task_id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{ActiveModelTrait, ConnectOptions, Database, EntityTrait, Schema, Set};
+
+    /// The in-memory database with the tables of the active and finished
+    /// tasks, the capacity reservations and the usage ledger.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        for statement in [
+            schema.create_table_from_entity(entity::task_active::Entity),
+            schema.create_table_from_entity(entity::task::Entity),
+            schema.create_table_from_entity(entity::capacity_reservation::Entity),
+            schema.create_table_from_entity(entity::usage_record::Entity),
+        ] {
+            db.execute(backend.build(&statement)).await.unwrap();
+        }
+        db
+    }
+
+    async fn add_task(db: &DbConn, parent_id: Option<Uuid>) -> entity::task_active::Model {
+        let now = Utc::now().naive_utc();
+        entity::task_active::ActiveModel::from(entity::task_active::Model {
+            id: Uuid::new_v4(),
+            source: "OPENQASM 2.0;".to_owned(),
+            result: None,
+            qubits: 2,
+            shots: 100,
+            exec_shots: 0,
+            v_exec_shots: 0,
+            depth: 1,
+            status: sea_orm_active_enums::TaskActiveStatus::Waiting,
+            created_time: now,
+            updated_time: now,
+            username: "alice".to_owned(),
+            deadline: None,
+            requirements: None,
+            noise: None,
+            source_hash: None,
+            circuit: None,
+            parameters: None,
+            parent_id,
+            cutting: None,
+        })
+        .insert(db)
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn checked_count_rejects_out_of_range_shots() {
+        assert_eq!(checked_count("shots", 1, 1).unwrap(), 1);
+        assert_eq!(
+            checked_count("shots", i32::MAX as usize, 1).unwrap(),
+            i32::MAX
+        );
+        assert_eq!(checked_count("qubits", 0, 0).unwrap(), 0);
+        for shots in [0, i32::MAX as usize + 1, usize::MAX] {
+            assert!(matches!(
+                checked_count("shots", shots, 1),
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn qubit_seconds_are_estimated_from_the_ledger() {
+        let state = ServerState {
+            db: database().await,
+            config: crate::config::QSchedulerConfig::default(),
+        };
+        let task = add_task(&state.db, None).await;
+        // no assignment yet, only a used up quota is rejected
+        assert_eq!(estimate_qubit_seconds(&state, [&task]).await.unwrap(), 0);
+
+        // 100 shots of the 2 qubit circuit of depth 1 take 4 seconds
+        let now = Utc::now().naive_utc();
+        service::usage::Usage::add_usage(
+            &state.db,
+            entity::usage_record::Model {
+                id: Uuid::new_v4(),
+                assignment_id: Uuid::new_v4(),
+                task_id: Uuid::new_v4(),
+                agent_id: Uuid::new_v4(),
+                username: "bob".to_owned(),
+                shots: 100,
+                qubits: 2,
+                depth: 1,
+                wall_time_ms: 4000,
+                started_time: now,
+                finished_time: now,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(estimate_qubit_seconds(&state, [&task]).await.unwrap(), 8);
+        assert_eq!(
+            estimate_qubit_seconds(&state, [&task, &task])
+                .await
+                .unwrap(),
+            16
+        );
+    }
+
+    #[tokio::test]
+    async fn cut_task_counts_once_for_its_user() {
+        let db = database().await;
+        let parent = add_task(&db, None).await;
+        for _ in 0..5 {
+            add_task(&db, Some(parent.id)).await;
+        }
+        let count = service::task_active::TaskActive::count_user_tasks(&db, "alice")
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_child_fails_the_cut_task_and_cancels_the_siblings() {
+        let db = database().await;
+        let parent = add_task(&db, None).await;
+        let failed = add_task(&db, Some(parent.id)).await;
+        let siblings = [
+            add_task(&db, Some(parent.id)).await,
+            add_task(&db, Some(parent.id)).await,
+        ];
+
+        finish_task(
+            &db,
+            failed.id,
+            &json!({"Error": "agent error"}),
+            sea_orm_active_enums::TaskStatus::Failed,
+        )
+        .await
+        .unwrap();
+
+        assert!(entity::task_active::Entity::find()
+            .all(&db)
+            .await
+            .unwrap()
+            .is_empty());
+        for task in [&parent, &failed, &siblings[0], &siblings[1]] {
+            let finished = service::task::Task::get_task(&db, task.id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(finished.status, sea_orm_active_enums::TaskStatus::Failed);
+        }
+        let sibling = service::task::Task::get_task(&db, siblings[0].id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(sibling.result.contains(&parent.id.to_string()));
+
+        // a chunk of a cancelled sibling that finishes later does not finish
+        // the cut task again
+        let finished = finish_task(
+            &db,
+            siblings[1].id,
+            &json!({"Memory": {"00": 100}}),
+            sea_orm_active_enums::TaskStatus::Succeeded,
+        )
+        .await;
+        assert!(matches!(finished, Err(Error::NotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn expired_tasks_are_finished_as_expired() {
+        let db = database().await;
+        let past = Some(Utc::now().naive_utc() - chrono::TimeDelta::seconds(1));
+        let mut tasks = Vec::new();
+        for (status, deadline) in [
+            (sea_orm_active_enums::TaskActiveStatus::Waiting, past),
+            (sea_orm_active_enums::TaskActiveStatus::Running, past),
+            (
+                sea_orm_active_enums::TaskActiveStatus::Waiting,
+                Some(Utc::now().naive_utc() + chrono::TimeDelta::seconds(60)),
+            ),
+        ] {
+            let mut task: entity::task_active::ActiveModel = add_task(&db, None).await.into();
+            task.status = Set(status);
+            task.deadline = Set(deadline);
+            tasks.push(task.update(&db).await.unwrap());
+        }
+
+        expire_tasks(&db).await;
+
+        let expired = service::task::Task::get_task(&db, tasks[0].id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(expired.status, sea_orm_active_enums::TaskStatus::Expired);
+        for task in &tasks[1..] {
+            assert!(service::task_active::TaskActive::get_task(&db, task.id)
+                .await
+                .unwrap()
+                .is_some());
+        }
+    }
 }
@@ -0,0 +1,79 @@
+//! The module that contains the usage router. The usage router reports the
+//! consumption of the users over a date range, which is aggregated from the
+//! [usage ledger](crate::entity::usage_record::Model).
+
+use super::ServerState;
+use crate::error::{Error, Result};
+use crate::service;
+use crate::service::usage::UserUsage;
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{Datelike, NaiveDate, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// ## Usage Query
+/// The query of the usage report, the days are in UTC.
+/// - `from`: The first day of the range, the first day of the current month by
+///   default.
+/// - `to`: The last day of the range (inclusive), today by default.
+/// - `username`: Only report the usage of this user, optional.
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    username: Option<String>,
+}
+
+/// ## Usage Report
+/// The usage of every user whose assignments finished in the date range.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UsageReport {
+    from: NaiveDate,
+    to: NaiveDate,
+    users: Vec<UserUsage>,
+}
+
+/// ## Get usage
+/// Report the executed shots, wall time and qubit-seconds of every user over
+/// the date range, both ends are inclusive.
+#[utoipa::path(
+    get,
+    path = "/api/v1/usage",
+    tag = "usage",
+    params(UsageQuery),
+    responses(
+        (status = 200, description = "The usage of the users", body = UsageReport),
+        (status = 400, description = "Invalid date range", body = ErrorResponse),
+    )
+)]
+pub async fn get_usage(
+    State(state): State<ServerState>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageReport>> {
+    let today = Utc::now().date_naive();
+    let from = query
+        .from
+        .unwrap_or_else(|| today.with_day(1).unwrap_or(today));
+    let to = query.to.unwrap_or(today);
+    if from > to {
+        return Err(Error::InvalidRequest(format!(
+            "from {} is later than to {}",
+            from, to
+        )));
+    }
+    info!("Get usage from {} to {}", from, to);
+
+    let users = service::usage::Usage::get_usage_by_user(
+        &state.db,
+        from.and_time(Default::default()),
+        to.succ_opt().unwrap_or(to).and_time(Default::default()),
+        query.username.as_deref(),
+    )
+    .await?;
+    Ok(Json(UsageReport { from, to, users }))
+}
@@ -0,0 +1,288 @@
+use crate::config::AdmissionConfig;
+use crate::entity::*;
+use crate::error::{Error, Result};
+use sea_orm::DbConn;
+
+pub struct Admission;
+
+impl Admission {
+    /// Check whether the task can be added to the waiting queue according to
+    /// the admission limits. The per task limits (shots and code size) return
+    /// a task too large error, the queue limits (waiting tasks, pending shots
+    /// and concurrent tasks of the user) return a too many requests error
+    /// with the configured retry after seconds.
+    ///
+    /// The queue limits are soft limits, concurrent submissions may exceed
+    /// them by the number of concurrent requests.
+    pub async fn check(
+        db: &DbConn,
+        config: &AdmissionConfig,
+        task: &task_active::Model,
+    ) -> Result<()> {
+        if let Some(max) = config.max_task_shots {
+            if task.shots as usize > max {
+                return Err(Error::TaskTooLarge {
+                    limit: "max_task_shots",
+                    max: max as u64,
+                    actual: task.shots as u64,
+                });
+            }
+        }
+
+        if let Some(max) = config.max_code_size {
+            if task.source.len() > max {
+                return Err(Error::TaskTooLarge {
+                    limit: "max_code_size",
+                    max: max as u64,
+                    actual: task.source.len() as u64,
+                });
+            }
+        }
+
+        if let Some(max) = config.max_waiting_tasks {
+            if super::task_active::TaskActive::count_waiting_tasks(db).await? >= max {
+                return Err(Error::TooManyRequests {
+                    limit: "max_waiting_tasks",
+                    retry_after: config.retry_after,
+                });
+            }
+        }
+
+        if let Some(max) = config.max_pending_shots {
+            // the task could never be admitted, retrying will not help
+            if task.shots as i64 > max {
+                return Err(Error::TaskTooLarge {
+                    limit: "max_pending_shots",
+                    max: max as u64,
+                    actual: task.shots as u64,
+                });
+            }
+            let pending_shots = super::task_active::TaskActive::sum_pending_shots(db).await?;
+            if pending_shots + task.shots as i64 > max {
+                return Err(Error::TooManyRequests {
+                    limit: "max_pending_shots",
+                    retry_after: config.retry_after,
+                });
+            }
+        }
+
+        if let Some(max) = config.max_user_tasks {
+            if super::task_active::TaskActive::count_user_tasks(db, &task.username).await? >= max {
+                return Err(Error::TooManyRequests {
+                    limit: "max_user_tasks",
+                    retry_after: config.retry_after,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use sea_orm::{ActiveModelTrait, ConnectOptions, ConnectionTrait, Database, Schema};
+    use sea_orm_active_enums::TaskActiveStatus;
+
+    /// The in-memory database with the table of the active tasks.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        db.execute(backend.build(&schema.create_table_from_entity(task_active::Entity)))
+            .await
+            .unwrap();
+        db
+    }
+
+    fn task(username: &str, shots: i32, parent_id: Option<uuid::Uuid>) -> task_active::Model {
+        let now = chrono::Utc::now().naive_utc();
+        task_active::Model {
+            id: uuid::Uuid::new_v4(),
+            source: "OPENQASM 2.0;".to_owned(),
+            result: None,
+            qubits: 2,
+            shots,
+            exec_shots: 0,
+            v_exec_shots: 0,
+            depth: 1,
+            status: TaskActiveStatus::Waiting,
+            created_time: now,
+            updated_time: now,
+            username: username.to_owned(),
+            deadline: None,
+            requirements: None,
+            noise: None,
+            source_hash: None,
+            circuit: None,
+            parameters: None,
+            parent_id,
+            cutting: None,
+        }
+    }
+
+    async fn add(db: &DbConn, task: task_active::Model) -> task_active::Model {
+        task_active::ActiveModel::from(task)
+            .insert(db)
+            .await
+            .unwrap()
+    }
+
+    fn config() -> AdmissionConfig {
+        AdmissionConfig {
+            retry_after: 7,
+            ..Default::default()
+        }
+    }
+
+    fn limit(result: Result<()>) -> &'static str {
+        match result {
+            Err(Error::TooManyRequests { limit, retry_after }) => {
+                assert_eq!(retry_after, 7);
+                limit
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_length_is_limited() {
+        let db = database().await;
+        let config = AdmissionConfig {
+            max_waiting_tasks: Some(2),
+            ..config()
+        };
+        add(&db, task("alice", 10, None)).await;
+        assert!(Admission::check(&db, &config, &task("bob", 10, None))
+            .await
+            .is_ok());
+        // a running task is not waiting
+        add(
+            &db,
+            task_active::Model {
+                status: TaskActiveStatus::Running,
+                ..task("alice", 10, None)
+            },
+        )
+        .await;
+        assert!(Admission::check(&db, &config, &task("bob", 10, None))
+            .await
+            .is_ok());
+        add(&db, task("alice", 10, None)).await;
+        assert_eq!(
+            limit(Admission::check(&db, &config, &task("bob", 10, None)).await),
+            "max_waiting_tasks"
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_tasks_of_a_user_are_limited() {
+        let db = database().await;
+        let config = AdmissionConfig {
+            max_user_tasks: Some(2),
+            ..config()
+        };
+        add(&db, task("alice", 10, None)).await;
+        // a cut task counts once, whatever the number of its child tasks
+        let cut = add(&db, task("alice", 10, None)).await;
+        for _ in 0..3 {
+            add(&db, task("alice", 10, Some(cut.id))).await;
+        }
+        assert_eq!(
+            limit(Admission::check(&db, &config, &task("alice", 10, None)).await),
+            "max_user_tasks"
+        );
+        assert!(Admission::check(&db, &config, &task("bob", 10, None))
+            .await
+            .is_ok());
+
+        let config = AdmissionConfig {
+            max_user_tasks: Some(3),
+            ..config
+        };
+        assert!(Admission::check(&db, &config, &task("alice", 10, None))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn pending_shots_are_limited() {
+        let db = database().await;
+        let config = AdmissionConfig {
+            max_pending_shots: Some(1000),
+            ..config()
+        };
+        // only the shots that are not executed yet are pending
+        add(
+            &db,
+            task_active::Model {
+                exec_shots: 300,
+                ..task("alice", 800, None)
+            },
+        )
+        .await;
+        assert!(Admission::check(&db, &config, &task("bob", 500, None))
+            .await
+            .is_ok());
+        assert_eq!(
+            limit(Admission::check(&db, &config, &task("bob", 501, None)).await),
+            "max_pending_shots"
+        );
+        // a task that could never be admitted is too large rather than retried
+        assert!(matches!(
+            Admission::check(&db, &config, &task("bob", 1001, None)).await,
+            Err(Error::TaskTooLarge {
+                limit: "max_pending_shots",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn per_task_limits_are_too_large() {
+        let db = database().await;
+        let config = AdmissionConfig {
+            max_task_shots: Some(100),
+            max_code_size: Some(20),
+            ..config()
+        };
+        assert!(Admission::check(&db, &config, &task("alice", 100, None))
+            .await
+            .is_ok());
+        assert!(matches!(
+            Admission::check(&db, &config, &task("alice", 101, None)).await,
+            Err(Error::TaskTooLarge {
+                limit: "max_task_shots",
+                ..
+            })
+        ));
+        let long = task_active::Model {
+            source: "OPENQASM 2.0; qreg q[2];".to_owned(),
+            ..task("alice", 10, None)
+        };
+        assert!(matches!(
+            Admission::check(&db, &config, &long).await,
+            Err(Error::TaskTooLarge {
+                limit: "max_code_size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn too_many_requests_sets_retry_after() {
+        let response = Error::TooManyRequests {
+            limit: "max_waiting_tasks",
+            retry_after: 7,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+    }
+}
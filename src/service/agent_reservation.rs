@@ -0,0 +1,281 @@
+use crate::entity::*;
+use crate::error::{Error, Result};
+use chrono::NaiveDateTime;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DbConn, EntityTrait, QueryFilter,
+    QueryOrder, TransactionTrait,
+};
+
+/// The exclusive reservations of the physical agents. A reservation books
+/// the listed agents, or the agents of a pool, for one user over a time
+/// window: while it is active, only the tasks of the user are dispatched to
+/// the agents. A reservation is released when its window ends or when it is
+/// cancelled.
+pub struct AgentReservation;
+
+impl AgentReservation {
+    /// Add a new reservation. The agents booked by another user in an
+    /// overlapping window are a conflict, and so is a reservation that books
+    /// no agent. The reservations are added one at a time, so two
+    /// overlapping ones can not both pass the check.
+    pub async fn add_reservation(
+        db: &DbConn,
+        data: agent_reservation::Model,
+    ) -> Result<agent_reservation::Model> {
+        let txn = db.begin().await?;
+        txn.execute_unprepared("LOCK TABLE agent_reservation IN SHARE ROW EXCLUSIVE MODE")
+            .await?;
+
+        let agents: Vec<physical_agent::Model> = physical_agent::Entity::find()
+            .all(&txn)
+            .await?
+            .into_iter()
+            .filter(|agent| covers(&data, agent))
+            .collect();
+        if agents.is_empty() {
+            return Err(Error::InvalidRequest(
+                "The reservation books no physical agent".to_owned(),
+            ));
+        }
+
+        let overlapping = agent_reservation::Entity::find()
+            .filter(agent_reservation::Column::CancelledTime.is_null())
+            .filter(agent_reservation::Column::Username.ne(data.username.as_str()))
+            .filter(agent_reservation::Column::StartTime.lt(data.end_time))
+            .filter(agent_reservation::Column::EndTime.gt(data.start_time))
+            .all(&txn)
+            .await?;
+        for reservation in overlapping {
+            if let Some(agent) = agents.iter().find(|agent| covers(&reservation, agent)) {
+                return Err(Error::Conflict(format!(
+                    "Physical agent {} is reserved by {} from {} to {}",
+                    agent.id, reservation.username, reservation.start_time, reservation.end_time
+                )));
+            }
+        }
+
+        let reservation = agent_reservation::ActiveModel {
+            id: ActiveValue::set(data.id),
+            username: ActiveValue::set(data.username),
+            agents: ActiveValue::set(data.agents),
+            pool: ActiveValue::set(data.pool),
+            start_time: ActiveValue::set(data.start_time),
+            end_time: ActiveValue::set(data.end_time),
+            created_time: ActiveValue::set(data.created_time),
+            cancelled_time: ActiveValue::set(None),
+        }
+        .insert(&txn)
+        .await?;
+        txn.commit().await?;
+        Ok(reservation)
+    }
+
+    /// Get the reservations ordered by their start time. If the username is
+    /// given, only the reservations of this user are returned. If `since` is
+    /// given, the reservations that are cancelled or ended before it are not
+    /// returned.
+    pub async fn get_reservations(
+        db: &DbConn,
+        username: Option<&str>,
+        since: Option<NaiveDateTime>,
+    ) -> Result<Vec<agent_reservation::Model>> {
+        let mut query = agent_reservation::Entity::find();
+        if let Some(username) = username {
+            query = query.filter(agent_reservation::Column::Username.eq(username));
+        }
+        if let Some(since) = since {
+            query = query
+                .filter(agent_reservation::Column::CancelledTime.is_null())
+                .filter(agent_reservation::Column::EndTime.gt(since));
+        }
+        Ok(query
+            .order_by_asc(agent_reservation::Column::StartTime)
+            .all(db)
+            .await?)
+    }
+
+    /// Get the reservations that are active at the given time, that is, not
+    /// cancelled and the time is in their window.
+    pub async fn get_active_reservations(
+        db: &DbConn,
+        now: NaiveDateTime,
+    ) -> Result<Vec<agent_reservation::Model>> {
+        Ok(agent_reservation::Entity::find()
+            .filter(agent_reservation::Column::CancelledTime.is_null())
+            .filter(agent_reservation::Column::StartTime.lte(now))
+            .filter(agent_reservation::Column::EndTime.gt(now))
+            .all(db)
+            .await?)
+    }
+
+    /// Cancel the reservation by the given ID, the booked agents are released
+    /// at once. A reservation that is already cancelled or ended can not be
+    /// cancelled.
+    pub async fn cancel_reservation(
+        db: &DbConn,
+        reservation_id: uuid::Uuid,
+    ) -> Result<agent_reservation::Model> {
+        let reservation = agent_reservation::Entity::find_by_id(reservation_id)
+            .one(db)
+            .await?
+            .ok_or_else(|| Error::not_found("agent reservation", reservation_id))?;
+        let now = chrono::Utc::now().naive_utc();
+        if reservation.cancelled_time.is_some() || reservation.end_time <= now {
+            return Err(Error::Conflict(format!(
+                "Agent reservation {} is already released",
+                reservation_id
+            )));
+        }
+
+        let mut reservation: agent_reservation::ActiveModel = reservation.into();
+        reservation.cancelled_time = ActiveValue::set(Some(now));
+        Ok(reservation.update(db).await?)
+    }
+}
+
+/// Whether the reservation books the physical agent, by its id or its pool.
+pub fn covers(reservation: &agent_reservation::Model, agent: &physical_agent::Model) -> bool {
+    let listed = reservation
+        .agents
+        .clone()
+        .and_then(|agents| serde_json::from_value::<Vec<uuid::Uuid>>(agents).ok())
+        .is_some_and(|agents| agents.contains(&agent.id));
+    let pooled = reservation.pool.is_some() && reservation.pool == agent.pool;
+    listed || pooled
+}
+
+/// Whether a task of the user can be dispatched to the physical agent, that
+/// is, none of the given active reservations of the other users books it.
+pub fn allows(
+    reservations: &[agent_reservation::Model],
+    agent: &physical_agent::Model,
+    username: &str,
+) -> bool {
+    reservations
+        .iter()
+        .all(|reservation| reservation.username == username || !covers(reservation, agent))
+}
+
+/// Split the physical agents into the agents that a task of the user can be
+/// dispatched to, and whether the active reservations of the other users
+/// book all of them. A task whose agents are all booked away waits for the
+/// reservations to end, it is not short of capacity.
+pub fn allowed_agents(
+    reservations: &[agent_reservation::Model],
+    agents: Vec<physical_agent::Model>,
+    username: &str,
+) -> (Vec<physical_agent::Model>, bool) {
+    let count = agents.len();
+    let allowed: Vec<physical_agent::Model> = agents
+        .into_iter()
+        .filter(|agent| allows(reservations, agent, username))
+        .collect();
+    let reserved_away = count > 0 && allowed.is_empty();
+    (allowed, reserved_away)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::sea_orm_active_enums::PhysicalAgentStatus;
+    use uuid::Uuid;
+
+    fn agent(pool: Option<&str>) -> physical_agent::Model {
+        physical_agent::Model {
+            id: Uuid::new_v4(),
+            status: PhysicalAgentStatus::Running,
+            ip: "10.0.0.1".to_owned(),
+            port: 8080,
+            qubit_count: 20,
+            qubit_idle: 20,
+            circuit_depth: 100,
+            lease_expire_time: None,
+            discovery: None,
+            simulator: String::new(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb: None,
+            qasm3: false,
+            memory_idle_mb: None,
+            slots: None,
+            slots_idle: None,
+            labels: None,
+            pool: pool.map(str::to_owned),
+            lost_time: None,
+        }
+    }
+
+    fn reservation(
+        username: &str,
+        agents: Option<&[&physical_agent::Model]>,
+        pool: Option<&str>,
+    ) -> agent_reservation::Model {
+        let now = chrono::Utc::now().naive_utc();
+        agent_reservation::Model {
+            id: Uuid::new_v4(),
+            username: username.to_owned(),
+            agents: agents.map(|agents| {
+                serde_json::json!(agents.iter().map(|agent| agent.id).collect::<Vec<_>>())
+            }),
+            pool: pool.map(str::to_owned),
+            start_time: now,
+            end_time: now + chrono::TimeDelta::hours(1),
+            created_time: now,
+            cancelled_time: None,
+        }
+    }
+
+    #[test]
+    fn covers_the_listed_and_the_pooled_agents() {
+        let listed = agent(None);
+        let pooled = agent(Some("gpu"));
+        let other = agent(Some("cpu"));
+        let by_id = reservation("alice", Some(&[&listed]), None);
+        assert!(covers(&by_id, &listed));
+        assert!(!covers(&by_id, &pooled));
+        let by_pool = reservation("alice", None, Some("gpu"));
+        assert!(covers(&by_pool, &pooled));
+        assert!(!covers(&by_pool, &other));
+        // an agent without pool is not booked by a reservation without pool
+        assert!(!covers(&reservation("alice", None, None), &listed));
+    }
+
+    #[test]
+    fn allows_the_owner_and_the_unbooked_agents() {
+        let booked = agent(Some("gpu"));
+        let free = agent(None);
+        let reservations = [reservation("alice", None, Some("gpu"))];
+        assert!(allows(&reservations, &booked, "alice"));
+        assert!(!allows(&reservations, &booked, "bob"));
+        assert!(allows(&reservations, &free, "bob"));
+        assert!(allows(&[], &booked, "bob"));
+    }
+
+    #[test]
+    fn allowed_agents_tells_a_booked_queue_from_a_full_one() {
+        let booked = agent(Some("gpu"));
+        let free = agent(None);
+        let reservations = [reservation("alice", None, Some("gpu"))];
+
+        // every fitting agent is booked by another user: the consume loop
+        // skips the task rather than stopping the queue
+        let (agents, reserved_away) = allowed_agents(&reservations, vec![booked.clone()], "bob");
+        assert!(agents.is_empty());
+        assert!(reserved_away);
+
+        let (agents, reserved_away) =
+            allowed_agents(&reservations, vec![booked.clone(), free.clone()], "bob");
+        assert_eq!(agents, vec![free]);
+        assert!(!reserved_away);
+
+        // no agent fits at all: a real capacity shortage
+        let (agents, reserved_away) = allowed_agents(&reservations, vec![], "bob");
+        assert!(agents.is_empty());
+        assert!(!reserved_away);
+
+        let (agents, reserved_away) = allowed_agents(&reservations, vec![booked.clone()], "alice");
+        assert_eq!(agents, vec![booked]);
+        assert!(!reserved_away);
+    }
+}
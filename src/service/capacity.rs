@@ -0,0 +1,523 @@
+use crate::capability::memory_estimate_mb;
+use crate::config::CapacityConfig;
+use crate::entity::*;
+use crate::error::Result;
+use crate::service::physical_agent::capacity_condition;
+use migration::Expr;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DbConn, EntityTrait, QueryFilter,
+    QuerySelect, TransactionTrait,
+};
+use sea_orm_active_enums::{AssignmentStatus, PhysicalAgentStatus};
+
+/// The capacity reservations of the physical agents. A reservation holds the
+/// qubits, the memory and one slot of an agent for one task assignment, and
+/// is keyed by the id of the assignment. The `qubit_idle` of an agent is
+/// always its `qubit_count` minus the qubits of its live reservations, and
+/// likewise for the idle memory and slots, and can be
+/// [rebuilt](Capacity::rebuild) from them at any time.
+pub struct Capacity;
+
+impl Capacity {
+    /// Reserve the capacity of the running physical agent for the assignment
+    /// of the task: the qubits, the [memory estimate](memory_estimate_mb) of
+    /// the task on the simulator of the agent and one slot. The idle capacity
+    /// is claimed with one conditional update on the
+    /// [capacity model](crate::config::CapacityModel), so the agent is never
+    /// over-committed by a concurrent reservation or update. Return `false`
+    /// if the agent is not running or has not enough idle capacity any more.
+    /// The reservation is expected to be released at the given time, when
+    /// the chunk of the assignment is estimated to finish.
+    pub async fn reserve(
+        db: &DbConn,
+        capacity: &CapacityConfig,
+        assignment_id: uuid::Uuid,
+        agent: &physical_agent::Model,
+        task_id: uuid::Uuid,
+        qubits: i32,
+        expected_end_time: chrono::NaiveDateTime,
+    ) -> Result<bool> {
+        let memory_mb = memory_estimate_mb(&agent.simulator, qubits, capacity.overhead_mb);
+        let txn = db.begin().await?;
+        let claimed = physical_agent::Entity::update_many()
+            .col_expr(
+                physical_agent::Column::QubitIdle,
+                Expr::col(physical_agent::Column::QubitIdle).sub(qubits),
+            )
+            .col_expr(
+                physical_agent::Column::MemoryIdleMb,
+                Expr::col(physical_agent::Column::MemoryIdleMb).sub(memory_mb),
+            )
+            .col_expr(
+                physical_agent::Column::SlotsIdle,
+                Expr::col(physical_agent::Column::SlotsIdle).sub(1),
+            )
+            .filter(physical_agent::Column::Id.eq(agent.id))
+            .filter(physical_agent::Column::Status.eq(PhysicalAgentStatus::Running))
+            .filter(capacity_condition(capacity, qubits, true))
+            .exec(&txn)
+            .await?
+            .rows_affected;
+        if claimed == 0 {
+            txn.rollback().await?;
+            return Ok(false);
+        }
+
+        capacity_reservation::ActiveModel {
+            id: ActiveValue::set(assignment_id),
+            agent_id: ActiveValue::set(agent.id),
+            task_id: ActiveValue::set(task_id),
+            qubits: ActiveValue::set(qubits),
+            memory_mb: ActiveValue::set(memory_mb),
+            created_time: ActiveValue::set(chrono::Utc::now().naive_utc()),
+            expected_end_time: ActiveValue::set(Some(expected_end_time)),
+        }
+        .insert(&txn)
+        .await?;
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    /// Release the reservation of the assignment and give its qubits back to
+    /// the agent. Releasing a reservation that does not exist, or that is
+    /// already released, does nothing. Return whether the reservation is
+    /// released by this call, so the reservation is the lease of its
+    /// assignment: the result of a chunk whose reservation was released by
+    /// another replica, e.g. a new leader, must be discarded.
+    pub async fn release<C: ConnectionTrait + TransactionTrait>(
+        db: &C,
+        assignment_id: uuid::Uuid,
+    ) -> Result<bool> {
+        let txn = db.begin().await?;
+        let Some(reservation) = capacity_reservation::Entity::find_by_id(assignment_id)
+            .one(&txn)
+            .await?
+        else {
+            txn.rollback().await?;
+            return Ok(false);
+        };
+        let released = capacity_reservation::Entity::delete_by_id(assignment_id)
+            .exec(&txn)
+            .await?
+            .rows_affected;
+        if released == 0 {
+            txn.rollback().await?;
+            return Ok(false);
+        }
+
+        physical_agent::Entity::update_many()
+            .col_expr(
+                physical_agent::Column::QubitIdle,
+                Expr::col(physical_agent::Column::QubitIdle).add(reservation.qubits),
+            )
+            .col_expr(
+                physical_agent::Column::MemoryIdleMb,
+                Expr::col(physical_agent::Column::MemoryIdleMb).add(reservation.memory_mb),
+            )
+            .col_expr(
+                physical_agent::Column::SlotsIdle,
+                Expr::col(physical_agent::Column::SlotsIdle).add(1),
+            )
+            .filter(physical_agent::Column::Id.eq(reservation.agent_id))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    /// Release all the reservations of the task.
+    pub async fn release_task<C: ConnectionTrait + TransactionTrait>(
+        db: &C,
+        task_id: uuid::Uuid,
+    ) -> Result<()> {
+        let reservations = capacity_reservation::Entity::find()
+            .filter(capacity_reservation::Column::TaskId.eq(task_id))
+            .all(db)
+            .await?;
+        for reservation in reservations {
+            Self::release(db, reservation.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Get the live reservations of all the physical agents.
+    pub async fn get_reservations(db: &DbConn) -> Result<Vec<capacity_reservation::Model>> {
+        Ok(capacity_reservation::Entity::find().all(db).await?)
+    }
+
+    /// Get the live reservations of the physical agent.
+    pub async fn get_agent_reservations(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+    ) -> Result<Vec<capacity_reservation::Model>> {
+        Ok(capacity_reservation::Entity::find()
+            .filter(capacity_reservation::Column::AgentId.eq(agent_id))
+            .all(db)
+            .await?)
+    }
+
+    /// Rebuild the idle qubits of the physical agent from its live
+    /// reservations. The agent is locked while its reservations are summed,
+    /// so a concurrent reservation or release is applied after the rebuild.
+    pub async fn rebuild(db: &DbConn, agent_id: uuid::Uuid) -> Result<()> {
+        let txn = db.begin().await?;
+        Self::rebuild_agent(&txn, agent_id).await?;
+        Ok(txn.commit().await?)
+    }
+
+    /// Rebuild the idle qubits of all the physical agents from their live
+    /// reservations, after the [stale](Capacity::release_stale) ones are
+    /// released.
+    pub async fn rebuild_all(db: &DbConn) -> Result<()> {
+        let released = Self::release_stale(db, chrono::Utc::now().naive_utc()).await?;
+        if released > 0 {
+            log::info!("Release {} stale capacity reservations", released);
+        }
+        let agents = physical_agent::Entity::find().all(db).await?;
+        for agent in agents {
+            Self::rebuild(db, agent.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Release the reservations that are left by a process that is gone: the
+    /// ones whose assignment is not running, or is never added, and the ones
+    /// whose chunk is overdue at the given time. Their assignments are marked
+    /// as failed. Return the number of the released reservations.
+    pub async fn release_stale(db: &DbConn, now: chrono::NaiveDateTime) -> Result<u64> {
+        let mut released = 0;
+        for reservation in capacity_reservation::Entity::find().all(db).await? {
+            let running = task_assignment::Entity::find_by_id(reservation.id)
+                .one(db)
+                .await?
+                .is_some_and(|assignment| assignment.status == AssignmentStatus::Running);
+            let overdue = reservation
+                .expected_end_time
+                .is_some_and(|expected_end_time| expected_end_time < now);
+            if running && !overdue {
+                continue;
+            }
+            if !Self::release(db, reservation.id).await? {
+                continue;
+            }
+            task_assignment::Entity::update_many()
+                .col_expr(
+                    task_assignment::Column::Status,
+                    Expr::value(AssignmentStatus::Failed),
+                )
+                .filter(task_assignment::Column::Id.eq(reservation.id))
+                .filter(task_assignment::Column::Status.eq(AssignmentStatus::Running))
+                .exec(db)
+                .await?;
+            released += 1;
+        }
+        Ok(released)
+    }
+
+    /// Rebuild the idle qubits of the physical agent in the given transaction,
+    /// which is used to change the qubit count and rebuild at once.
+    pub async fn rebuild_agent<C: ConnectionTrait>(db: &C, agent_id: uuid::Uuid) -> Result<()> {
+        let Some(agent) = physical_agent::Entity::find_by_id(agent_id)
+            .lock_exclusive()
+            .one(db)
+            .await?
+        else {
+            return Ok(());
+        };
+        let reservations = capacity_reservation::Entity::find()
+            .filter(capacity_reservation::Column::AgentId.eq(agent_id))
+            .all(db)
+            .await?;
+        let qubits: i32 = reservations
+            .iter()
+            .map(|reservation| reservation.qubits)
+            .sum();
+        let memory_mb: i64 = reservations
+            .iter()
+            .map(|reservation| reservation.memory_mb)
+            .sum();
+
+        physical_agent::Entity::update_many()
+            .col_expr(
+                physical_agent::Column::QubitIdle,
+                Expr::value(agent.qubit_count - qubits),
+            )
+            .col_expr(
+                physical_agent::Column::MemoryIdleMb,
+                Expr::value(agent.memory_mb.map(|memory| memory - memory_mb)),
+            )
+            .col_expr(
+                physical_agent::Column::SlotsIdle,
+                Expr::value(agent.slots.map(|slots| slots - reservations.len() as i32)),
+            )
+            .filter(physical_agent::Column::Id.eq(agent_id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CapacityModel;
+    use chrono::{NaiveDateTime, TimeDelta};
+    use sea_orm::{ConnectOptions, Database, Schema};
+    use uuid::Uuid;
+
+    /// The in-memory database with the tables of the agents, the capacity
+    /// reservations and the assignments.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        for statement in [
+            schema.create_table_from_entity(physical_agent::Entity),
+            schema.create_table_from_entity(capacity_reservation::Entity),
+            schema.create_table_from_entity(task_assignment::Entity),
+        ] {
+            db.execute(backend.build(&statement)).await.unwrap();
+        }
+        db
+    }
+
+    fn now() -> NaiveDateTime {
+        chrono::Utc::now().naive_utc()
+    }
+
+    async fn add_agent(
+        db: &DbConn,
+        qubit_count: i32,
+        memory_mb: Option<i64>,
+        slots: Option<i32>,
+    ) -> physical_agent::Model {
+        physical_agent::ActiveModel::from(physical_agent::Model {
+            id: Uuid::new_v4(),
+            status: PhysicalAgentStatus::Running,
+            ip: "10.0.0.1".to_owned(),
+            port: 8080,
+            qubit_count,
+            qubit_idle: qubit_count,
+            circuit_depth: 100,
+            lease_expire_time: None,
+            discovery: None,
+            simulator: String::new(),
+            gates: None,
+            noise: false,
+            max_shots: None,
+            memory_mb,
+            qasm3: false,
+            memory_idle_mb: memory_mb,
+            slots,
+            slots_idle: slots,
+            labels: None,
+            pool: None,
+            lost_time: None,
+        })
+        .insert(db)
+        .await
+        .unwrap()
+    }
+
+    async fn get_agent(db: &DbConn, agent_id: Uuid) -> physical_agent::Model {
+        physical_agent::Entity::find_by_id(agent_id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn add_assignment(db: &DbConn, assignment_id: Uuid, status: AssignmentStatus) {
+        task_assignment::ActiveModel::from(task_assignment::Model {
+            id: assignment_id,
+            agent_id: Uuid::new_v4(),
+            task_id: Uuid::new_v4(),
+            shots: Some(100),
+            status,
+            created_time: Some(now()),
+        })
+        .insert(db)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn racing_reserves_do_not_over_commit() {
+        let db = database().await;
+        let capacity = CapacityConfig::default();
+        let agent = add_agent(&db, 4, None, None).await;
+        let end_time = now() + TimeDelta::seconds(10);
+
+        // both dispatches read the agent with 4 idle qubits
+        let (first, second) = tokio::join!(
+            Capacity::reserve(
+                &db,
+                &capacity,
+                Uuid::new_v4(),
+                &agent,
+                Uuid::new_v4(),
+                4,
+                end_time
+            ),
+            Capacity::reserve(
+                &db,
+                &capacity,
+                Uuid::new_v4(),
+                &agent,
+                Uuid::new_v4(),
+                4,
+                end_time
+            ),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first != second);
+        assert_eq!(get_agent(&db, agent.id).await.qubit_idle, 0);
+        let reservations = Capacity::get_agent_reservations(&db, agent.id)
+            .await
+            .unwrap();
+        assert_eq!(reservations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn double_release_restores_the_capacity_once() {
+        let db = database().await;
+        let capacity = CapacityConfig {
+            model: CapacityModel::Memory,
+            overhead_mb: 64,
+        };
+        let agent = add_agent(&db, 20, Some(1024), Some(2)).await;
+        let assignment_id = Uuid::new_v4();
+        let reserved = Capacity::reserve(
+            &db,
+            &capacity,
+            assignment_id,
+            &agent,
+            Uuid::new_v4(),
+            4,
+            now() + TimeDelta::seconds(10),
+        )
+        .await
+        .unwrap();
+        assert!(reserved);
+        let held = get_agent(&db, agent.id).await;
+        assert_eq!(
+            (held.qubit_idle, held.memory_idle_mb, held.slots_idle),
+            (16, Some(1024 - memory_estimate_mb("", 4, 64)), Some(1))
+        );
+
+        assert!(Capacity::release(&db, assignment_id).await.unwrap());
+        assert!(!Capacity::release(&db, assignment_id).await.unwrap());
+        let released = get_agent(&db, agent.id).await;
+        assert_eq!(
+            (
+                released.qubit_idle,
+                released.memory_idle_mb,
+                released.slots_idle
+            ),
+            (20, Some(1024), Some(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn release_stale_only_releases_the_expired_leases() {
+        let db = database().await;
+        let capacity = CapacityConfig::default();
+        let agent = add_agent(&db, 20, None, None).await;
+        let reserve = |assignment_id, end_time| {
+            let (db, capacity, agent) = (&db, &capacity, &agent);
+            async move {
+                let reserved = Capacity::reserve(
+                    db,
+                    capacity,
+                    assignment_id,
+                    agent,
+                    Uuid::new_v4(),
+                    2,
+                    end_time,
+                )
+                .await
+                .unwrap();
+                assert!(reserved);
+            }
+        };
+
+        let live = Uuid::new_v4();
+        reserve(live, now() + TimeDelta::seconds(60)).await;
+        add_assignment(&db, live, AssignmentStatus::Running).await;
+        let overdue = Uuid::new_v4();
+        reserve(overdue, now() - TimeDelta::seconds(60)).await;
+        add_assignment(&db, overdue, AssignmentStatus::Running).await;
+        let finished = Uuid::new_v4();
+        reserve(finished, now() + TimeDelta::seconds(60)).await;
+        add_assignment(&db, finished, AssignmentStatus::Succeeded).await;
+        let orphan = Uuid::new_v4();
+        reserve(orphan, now() + TimeDelta::seconds(60)).await;
+
+        assert_eq!(Capacity::release_stale(&db, now()).await.unwrap(), 3);
+        let reservations = Capacity::get_agent_reservations(&db, agent.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            reservations
+                .iter()
+                .map(|reservation| reservation.id)
+                .collect::<Vec<_>>(),
+            [live]
+        );
+        assert_eq!(get_agent(&db, agent.id).await.qubit_idle, 18);
+        let status = |assignment_id| {
+            let db = &db;
+            async move {
+                task_assignment::Entity::find_by_id(assignment_id)
+                    .one(db)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .status
+            }
+        };
+        assert_eq!(status(live).await, AssignmentStatus::Running);
+        assert_eq!(status(overdue).await, AssignmentStatus::Failed);
+        assert_eq!(status(finished).await, AssignmentStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn rebuild_agent_recomputes_the_idle_capacity() {
+        let db = database().await;
+        let agent = add_agent(&db, 20, Some(1024), Some(4)).await;
+        for (qubits, memory_mb) in [(3, 100), (5, 200)] {
+            capacity_reservation::ActiveModel::from(capacity_reservation::Model {
+                id: Uuid::new_v4(),
+                agent_id: agent.id,
+                task_id: Uuid::new_v4(),
+                qubits,
+                memory_mb,
+                created_time: now(),
+                expected_end_time: None,
+            })
+            .insert(&db)
+            .await
+            .unwrap();
+        }
+        // the idle capacity drifted, e.g. by a release that was lost
+        let mut drifted: physical_agent::ActiveModel = agent.clone().into();
+        drifted.qubit_idle = ActiveValue::set(1);
+        drifted.memory_idle_mb = ActiveValue::set(Some(7));
+        drifted.slots_idle = ActiveValue::set(Some(0));
+        drifted.update(&db).await.unwrap();
+
+        Capacity::rebuild_agent(&db, agent.id).await.unwrap();
+        let rebuilt = get_agent(&db, agent.id).await;
+        assert_eq!(
+            (
+                rebuilt.qubit_idle,
+                rebuilt.memory_idle_mb,
+                rebuilt.slots_idle
+            ),
+            (12, Some(724), Some(2))
+        );
+    }
+}
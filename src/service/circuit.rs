@@ -0,0 +1,204 @@
+use crate::entity::*;
+use crate::error::{Error, Result};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DbConn, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set, SqlErr,
+};
+
+pub struct Circuit;
+
+impl Circuit {
+    /// Add a new version of the circuit, the version is one more than the
+    /// latest version of the circuit with the same name, removed or not, or 1
+    /// for a new circuit, so a version number always names the same source.
+    /// If the same version is added concurrently, it will return a conflict
+    /// error.
+    pub async fn add_circuit(db: &DbConn, data: circuit::Model) -> Result<circuit::Model> {
+        let latest: Option<Option<i32>> = circuit::Entity::find()
+            .select_only()
+            .column_as(circuit::Column::Version.max(), "version")
+            .filter(circuit::Column::Name.eq(data.name.as_str()))
+            .into_tuple()
+            .one(db)
+            .await?;
+        let version = latest.flatten().unwrap_or(0) + 1;
+
+        circuit::ActiveModel {
+            id: ActiveValue::set(data.id.to_owned()),
+            name: ActiveValue::set(data.name.to_owned()),
+            version: ActiveValue::set(version),
+            source: ActiveValue::set(data.source.to_owned()),
+            description: ActiveValue::set(data.description.to_owned()),
+            metadata: ActiveValue::set(data.metadata.to_owned()),
+            created_time: ActiveValue::set(data.created_time.to_owned()),
+            deleted_time: ActiveValue::set(None),
+        }
+        .insert(db)
+        .await
+        .map_err(|err| match err.sql_err() {
+            Some(SqlErr::UniqueConstraintViolation(_)) => {
+                Error::Conflict(format!("Circuit {}@{} already exists", data.name, version))
+            }
+            _ => err.into(),
+        })
+    }
+
+    /// Get the circuit with the given name and version, or the latest version
+    /// of the circuit if the version is not given. The removed versions are
+    /// not returned.
+    pub async fn get_circuit(
+        db: &DbConn,
+        name: &str,
+        version: Option<i32>,
+    ) -> Result<Option<circuit::Model>> {
+        let mut query = circuit::Entity::find()
+            .filter(circuit::Column::Name.eq(name))
+            .filter(circuit::Column::DeletedTime.is_null());
+        if let Some(version) = version {
+            query = query.filter(circuit::Column::Version.eq(version));
+        }
+        Ok(query
+            .order_by_desc(circuit::Column::Version)
+            .one(db)
+            .await?)
+    }
+
+    /// Get all the circuits ordered by name and version, except the removed
+    /// versions. If the name is given, only the versions of this circuit are
+    /// returned.
+    pub async fn get_circuits(db: &DbConn, name: Option<&str>) -> Result<Vec<circuit::Model>> {
+        let mut query = circuit::Entity::find().filter(circuit::Column::DeletedTime.is_null());
+        if let Some(name) = name {
+            query = query.filter(circuit::Column::Name.eq(name));
+        }
+        Ok(query
+            .order_by_asc(circuit::Column::Name)
+            .order_by_asc(circuit::Column::Version)
+            .all(db)
+            .await?)
+    }
+
+    /// Update the description and the metadata of the circuit version. The
+    /// source of a version can not be changed, add a new version instead.
+    pub async fn update_circuit(
+        db: &DbConn,
+        name: &str,
+        version: i32,
+        description: Option<String>,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<circuit::Model> {
+        let circuit = Self::get_circuit(db, name, Some(version))
+            .await?
+            .ok_or_else(|| Error::not_found("circuit", format!("{}@{}", name, version)))?;
+        let mut circuit: circuit::ActiveModel = circuit.into();
+        if let Some(description) = description {
+            circuit.description = Set(Some(description));
+        }
+        if let Some(metadata) = metadata {
+            circuit.metadata = Set(Some(metadata));
+        }
+        Ok(circuit.update(db).await?)
+    }
+
+    /// Remove the circuit version. The row is kept and marked as deleted, so
+    /// that its version is not given to another source. The tasks that have
+    /// run this version keep their copy of the source.
+    pub async fn remove_circuit(db: &DbConn, name: &str, version: i32) -> Result<circuit::Model> {
+        let circuit = Self::get_circuit(db, name, Some(version))
+            .await?
+            .ok_or_else(|| Error::not_found("circuit", format!("{}@{}", name, version)))?;
+        let mut circuit: circuit::ActiveModel = circuit.into();
+        circuit.deleted_time = Set(Some(chrono::Utc::now().naive_utc()));
+        Ok(circuit.update(db).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{ConnectOptions, ConnectionTrait, Database, Schema};
+
+    /// The in-memory database with the table of the circuits.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        db.execute(backend.build(&schema.create_table_from_entity(circuit::Entity)))
+            .await
+            .unwrap();
+        db
+    }
+
+    async fn add(db: &DbConn, name: &str, source: &str) -> circuit::Model {
+        Circuit::add_circuit(
+            db,
+            circuit::Model {
+                id: uuid::Uuid::new_v4(),
+                name: name.to_owned(),
+                version: 0,
+                source: source.to_owned(),
+                description: None,
+                metadata: None,
+                created_time: chrono::Utc::now().naive_utc(),
+                deleted_time: None,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn versions_count_up_per_name() {
+        let db = database().await;
+        assert_eq!(add(&db, "ghz", "a").await.version, 1);
+        assert_eq!(add(&db, "ghz", "b").await.version, 2);
+        assert_eq!(add(&db, "qft", "c").await.version, 1);
+
+        let latest = Circuit::get_circuit(&db, "ghz", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((latest.version, latest.source.as_str()), (2, "b"));
+        let first = Circuit::get_circuit(&db, "ghz", Some(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.source, "a");
+    }
+
+    #[tokio::test]
+    async fn removed_version_is_hidden_and_never_reused() {
+        let db = database().await;
+        add(&db, "ghz", "a").await;
+        add(&db, "ghz", "b").await;
+
+        let removed = Circuit::remove_circuit(&db, "ghz", 2).await.unwrap();
+        assert!(removed.deleted_time.is_some());
+        assert!(Circuit::get_circuit(&db, "ghz", Some(2))
+            .await
+            .unwrap()
+            .is_none());
+        let latest = Circuit::get_circuit(&db, "ghz", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.version, 1);
+        assert_eq!(
+            Circuit::get_circuits(&db, Some("ghz")).await.unwrap().len(),
+            1
+        );
+        assert!(matches!(
+            Circuit::remove_circuit(&db, "ghz", 2).await,
+            Err(Error::NotFound { .. })
+        ));
+
+        // the next version does not take the number of the removed one
+        let next = add(&db, "ghz", "c").await;
+        assert_eq!(next.version, 3);
+        let reference = Circuit::get_circuit(&db, "ghz", Some(2)).await.unwrap();
+        assert!(reference.is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::entity::*;
+use crate::error::Result;
+use migration::Expr;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DbConn, EntityTrait, QueryFilter, QueryOrder,
+};
+use sea_orm_active_enums::DrainStatus;
+
+pub struct Drain;
+
+impl Drain {
+    /// Add a new drain job of the physical agent, which is draining until
+    /// the given deadline. The action is the change of the agent that is
+    /// applied when the job is drained.
+    pub async fn add_drain(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        deadline: chrono::NaiveDateTime,
+        action: Option<serde_json::Value>,
+    ) -> Result<agent_drain::Model> {
+        Ok(agent_drain::ActiveModel {
+            id: ActiveValue::set(uuid::Uuid::new_v4()),
+            agent_id: ActiveValue::set(agent_id),
+            status: ActiveValue::set(DrainStatus::Draining),
+            created_time: ActiveValue::set(chrono::Utc::now().naive_utc()),
+            deadline: ActiveValue::set(deadline),
+            finished_time: ActiveValue::set(None),
+            action: ActiveValue::set(action),
+        }
+        .insert(db)
+        .await?)
+    }
+
+    /// Get the drain job by the given ID. If the job does not exist, it will
+    /// return `None`.
+    pub async fn get_drain(
+        db: &DbConn,
+        drain_id: uuid::Uuid,
+    ) -> Result<Option<agent_drain::Model>> {
+        Ok(agent_drain::Entity::find_by_id(drain_id).one(db).await?)
+    }
+
+    /// Get the drain jobs that are still draining, the oldest first. This
+    /// function is used by the consume thread to finish the drained jobs.
+    pub async fn get_draining_drains(db: &DbConn) -> Result<Vec<agent_drain::Model>> {
+        Ok(agent_drain::Entity::find()
+            .filter(agent_drain::Column::Status.eq(DrainStatus::Draining))
+            .order_by_asc(agent_drain::Column::CreatedTime)
+            .all(db)
+            .await?)
+    }
+
+    /// Finish the drain job with the given status if it is still draining.
+    /// Return the finished job, or `None` if the job is already finished by
+    /// another request or replica, so that its action is applied once.
+    pub async fn finish_drain(
+        db: &DbConn,
+        drain_id: uuid::Uuid,
+        status: DrainStatus,
+    ) -> Result<Option<agent_drain::Model>> {
+        let finished = agent_drain::Entity::update_many()
+            .col_expr(agent_drain::Column::Status, Expr::value(status))
+            .col_expr(
+                agent_drain::Column::FinishedTime,
+                Expr::value(chrono::Utc::now().naive_utc()),
+            )
+            .filter(agent_drain::Column::Id.eq(drain_id))
+            .filter(agent_drain::Column::Status.eq(DrainStatus::Draining))
+            .exec(db)
+            .await?
+            .rows_affected;
+        match finished {
+            0 => Ok(None),
+            _ => Self::get_drain(db, drain_id).await,
+        }
+    }
+}
@@ -1,4 +1,11 @@
+pub mod admission;
+pub mod agent_reservation;
+pub mod capacity;
+pub mod circuit;
+pub mod drain;
 pub mod physical_agent;
+pub mod quota;
 pub mod task;
 pub mod task_active;
 pub mod task_assignment;
+pub mod usage;
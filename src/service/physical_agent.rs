@@ -1,10 +1,14 @@
+use crate::capability::{memory_estimate_mb, AgentCapability, TaskRequirements};
+use crate::config::{CapacityConfig, CapacityModel};
 use crate::entity::*;
-use migration::Expr;
+use crate::error::{Error, Result};
+use migration::{extension::postgres::PgExpr, Expr};
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, Condition, DbConn, EntityTrait, QueryFilter, QueryOrder, Set,
-    UpdateResult,
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DbConn, EntityTrait, QueryFilter,
+    QueryOrder, Set, TransactionTrait,
 };
 use sea_orm_active_enums::PhysicalAgentStatus;
+use std::collections::BTreeMap;
 
 pub struct PhysicalAgent;
 
@@ -14,48 +18,151 @@ impl PhysicalAgent {
     pub async fn add_physical_agent(
         db: &DbConn,
         data: physical_agent::Model,
-    ) -> Result<physical_agent::Model, sea_orm::prelude::DbErr> {
+    ) -> Result<physical_agent::Model> {
         match physical_agent::Entity::find()
             .filter(physical_agent::Column::Ip.eq(data.ip.to_owned()))
             .filter(physical_agent::Column::Port.eq(data.port))
             .one(db)
-            .await
+            .await?
         {
-            Ok(Some(_)) => {
-                return Err(sea_orm::prelude::DbErr::Custom(format!(
-                    "Physical agent {}:{} already exists",
-                    data.ip, data.port
-                )));
+            Some(_) => Err(Error::Conflict(format!(
+                "Physical agent {}:{} already exists",
+                data.ip, data.port
+            ))),
+            None => Ok(physical_agent::ActiveModel {
+                id: Set(data.id.to_owned()),
+                status: Set(data.status.to_owned()),
+                ip: Set(data.ip.to_owned()),
+                port: Set(data.port.to_owned()),
+                qubit_count: Set(data.qubit_count.to_owned()),
+                qubit_idle: Set(data.qubit_idle.to_owned()),
+                circuit_depth: Set(data.circuit_depth.to_owned()),
+                lease_expire_time: Set(data.lease_expire_time.to_owned()),
+                discovery: Set(data.discovery.to_owned()),
+                simulator: Set(data.simulator.to_owned()),
+                gates: Set(data.gates.to_owned()),
+                noise: Set(data.noise),
+                max_shots: Set(data.max_shots),
+                memory_mb: Set(data.memory_mb),
+                qasm3: Set(data.qasm3),
+                memory_idle_mb: Set(data.memory_idle_mb),
+                slots: Set(data.slots),
+                slots_idle: Set(data.slots_idle),
+                labels: Set(data.labels.to_owned()),
+                pool: Set(data.pool.to_owned()),
+                lost_time: Set(data.lost_time),
             }
-            Ok(None) => {
-                physical_agent::ActiveModel {
-                    id: Set(data.id.to_owned()),
-                    status: Set(data.status.to_owned()),
-                    ip: Set(data.ip.to_owned()),
-                    port: Set(data.port.to_owned()),
-                    qubit_count: Set(data.qubit_count.to_owned()),
-                    qubit_idle: Set(data.qubit_idle.to_owned()),
-                    circuit_depth: Set(data.circuit_depth.to_owned()),
-                }
-                .insert(db)
-                .await
+            .insert(db)
+            .await?),
+        }
+    }
+
+    /// Register a physical agent with a lease. If an agent with the same
+    /// address exists, its capacity, capability, labels and pool are updated, its status is set to
+    /// `Running` and its lease is renewed, the qubits reserved by the running
+    /// tasks are kept. Otherwise, the agent is added.
+    pub async fn register_physical_agent(
+        db: &DbConn,
+        data: physical_agent::Model,
+    ) -> Result<physical_agent::Model> {
+        match physical_agent::Entity::find()
+            .filter(physical_agent::Column::Ip.eq(data.ip.to_owned()))
+            .filter(physical_agent::Column::Port.eq(data.port))
+            .one(db)
+            .await?
+        {
+            Some(agent) => {
+                let agent_id = agent.id;
+                let mut agent: physical_agent::ActiveModel = agent.into();
+                agent.status = Set(PhysicalAgentStatus::Running);
+                agent.qubit_count = Set(data.qubit_count);
+                agent.circuit_depth = Set(data.circuit_depth);
+                agent.lease_expire_time = Set(data.lease_expire_time);
+                agent.simulator = Set(data.simulator);
+                agent.gates = Set(data.gates);
+                agent.noise = Set(data.noise);
+                agent.max_shots = Set(data.max_shots);
+                agent.memory_mb = Set(data.memory_mb);
+                agent.qasm3 = Set(data.qasm3);
+                agent.slots = Set(data.slots);
+                agent.labels = Set(data.labels);
+                agent.pool = Set(data.pool);
+                Self::update_with_capacity(db, agent_id, agent).await
             }
-            Err(err) => {
-                return Err(err);
+            None => Self::add_physical_agent(db, data).await,
+        }
+    }
+
+    /// Renew the lease of the physical agent. If the agent was marked as
+    /// `Down` because its lease expired, it is set to `Running` again. If the
+    /// agent does not exist, it will return a not found error.
+    pub async fn renew_physical_agent_lease(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        lease_expire_time: chrono::NaiveDateTime,
+    ) -> Result<physical_agent::Model> {
+        let agent = physical_agent::Entity::find_by_id(agent_id)
+            .one(db)
+            .await?
+            .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+        let lease_expired = agent.status == PhysicalAgentStatus::Down
+            && agent
+                .lease_expire_time
+                .is_some_and(|expire_time| expire_time < chrono::Utc::now().naive_utc());
+        let mut agent: physical_agent::ActiveModel = agent.into();
+        if lease_expired {
+            agent.status = Set(PhysicalAgentStatus::Running);
+        }
+        agent.lease_expire_time = Set(Some(lease_expire_time));
+        Ok(agent.update(db).await?)
+    }
+
+    /// Mark the running physical agents whose lease expired before the given
+    /// time as `Down`, the agents without lease never expire. Each agent is
+    /// marked only if it is still running with the expired lease, so an agent
+    /// that renews its lease meanwhile is left as is. Return the IDs of the
+    /// expired agents.
+    pub async fn expire_physical_agent_leases(
+        db: &DbConn,
+        now: chrono::NaiveDateTime,
+    ) -> Result<Vec<uuid::Uuid>> {
+        let agents = physical_agent::Entity::find()
+            .filter(physical_agent::Column::Status.eq(PhysicalAgentStatus::Running))
+            .filter(physical_agent::Column::LeaseExpireTime.lt(now))
+            .all(db)
+            .await?;
+        let mut expired = Vec::new();
+        for agent in agents {
+            let marked = physical_agent::Entity::update_many()
+                .col_expr(
+                    physical_agent::Column::Status,
+                    Expr::value(PhysicalAgentStatus::Down),
+                )
+                .filter(physical_agent::Column::Id.eq(agent.id))
+                .filter(physical_agent::Column::Status.eq(PhysicalAgentStatus::Running))
+                .filter(physical_agent::Column::LeaseExpireTime.lt(now))
+                .exec(db)
+                .await?
+                .rows_affected;
+            if marked == 1 {
+                expired.push(agent.id);
             }
         }
+        Ok(expired)
     }
 
     /// Given the number of qubits and the depth of the circuit, return the most
     /// available physical agent. If there is no available agent, it will return
     /// `None`. The most available means the agent has the most idle qubits and
-    /// the depth and qubits are enough for the task.
+    /// the depth and qubits are enough for the task. The capability of the
+    /// agent must meet the requirements of the task.
     pub async fn get_most_available_physical_agent(
         db: &DbConn,
         task_qubits: u32,
         task_depth: u32,
-    ) -> Result<Option<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        match physical_agent::Entity::find()
+        requirements: &TaskRequirements,
+    ) -> Result<Option<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find()
             .filter(
                 Condition::all()
                     .add(
@@ -63,45 +170,45 @@ impl PhysicalAgent {
                             .eq(sea_orm_active_enums::PhysicalAgentStatus::Running),
                     )
                     .add(physical_agent::Column::QubitIdle.gte(task_qubits as i32))
-                    .add(physical_agent::Column::CircuitDepth.gte(task_depth as i32)),
+                    .add(physical_agent::Column::CircuitDepth.gte(task_depth as i32))
+                    .add(requirements_condition(requirements)),
             )
             .order_by_desc(physical_agent::Column::QubitIdle)
             .one(db)
-            .await
-        {
-            Ok(Some(agent)) => Ok(Some(agent)),
-            Ok(None) => Ok(None),
-            Err(err) => Err(err),
-        }
+            .await?)
     }
 
     /// Given the number of qubits and the depth of the circuit, return the
-    /// least available physical agent. If there is no available agent, it
-    /// will return `None`. The least available means the agent has the
-    /// least idle qubits and the depth and qubits are enough for the task.
-    pub async fn get_least_available_physical_agent(
+    /// idle physical agents from the least available one. The least available
+    /// means the agent has the least idle qubits, or the least idle memory in
+    /// the `memory` [capacity model](CapacityModel), and the depth and the
+    /// idle capacity are enough for the task. The capability of the agent
+    /// must meet the requirements of the task.
+    pub async fn get_idle_physical_agents(
         db: &DbConn,
+        capacity: &CapacityConfig,
         task_qubits: u32,
         task_depth: u32,
-    ) -> Result<Option<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        match physical_agent::Entity::find()
-            .filter(
-                Condition::all()
-                    .add(
-                        physical_agent::Column::Status
-                            .eq(sea_orm_active_enums::PhysicalAgentStatus::Running),
-                    )
-                    .add(physical_agent::Column::QubitIdle.gte(task_qubits as i32))
-                    .add(physical_agent::Column::CircuitDepth.gte(task_depth as i32)),
-            )
-            .order_by_asc(physical_agent::Column::QubitIdle)
-            .one(db)
-            .await
-        {
-            Ok(Some(agent)) => Ok(Some(agent)),
-            Ok(None) => Ok(None),
-            Err(err) => Err(err),
+        requirements: &TaskRequirements,
+    ) -> Result<Vec<physical_agent::Model>> {
+        let mut query = physical_agent::Entity::find().filter(
+            Condition::all()
+                .add(
+                    physical_agent::Column::Status
+                        .eq(sea_orm_active_enums::PhysicalAgentStatus::Running),
+                )
+                .add(physical_agent::Column::QubitCount.gte(task_qubits as i32))
+                .add(capacity_condition(capacity, task_qubits as i32, true))
+                .add(physical_agent::Column::CircuitDepth.gte(task_depth as i32))
+                .add(requirements_condition(requirements)),
+        );
+        if capacity.model == CapacityModel::Memory {
+            query = query.order_by_asc(physical_agent::Column::MemoryIdleMb);
         }
+        Ok(query
+            .order_by_asc(physical_agent::Column::QubitIdle)
+            .all(db)
+            .await?)
     }
 
     /// Get the physical agent by the given ID. If the agent does not exist,
@@ -109,23 +216,22 @@ impl PhysicalAgent {
     pub async fn get_physical_agent(
         db: &DbConn,
         agent_id: uuid::Uuid,
-    ) -> Result<Option<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        physical_agent::Entity::find_by_id(agent_id).one(db).await
+    ) -> Result<Option<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find_by_id(agent_id).one(db).await?)
     }
 
-    /// Check whether the physical agent is idle by the given ID. This function
-    /// is used to check whether the agent has finished the task before update
-    /// the physical agent information.
-    pub async fn check_physical_agent_idle(
-        db: &DbConn,
-        agent_id: uuid::Uuid,
-    ) -> Result<bool, sea_orm::prelude::DbErr> {
+    /// Check whether the physical agent is idle by the given ID, that is, it
+    /// has no live [capacity reservation](super::capacity::Capacity). This
+    /// function is used to check whether the agent has finished the task
+    /// before update the physical agent information.
+    pub async fn check_physical_agent_idle(db: &DbConn, agent_id: uuid::Uuid) -> Result<bool> {
         match physical_agent::Entity::find_by_id(agent_id).one(db).await? {
-            Some(agent) => Ok(agent.qubit_idle == agent.qubit_count),
-            None => Err(sea_orm::prelude::DbErr::RecordNotFound(format!(
-                "Physical agent {} not found",
-                agent_id
-            ))),
+            Some(_) => Ok(
+                super::capacity::Capacity::get_agent_reservations(db, agent_id)
+                    .await?
+                    .is_empty(),
+            ),
+            None => Err(Error::not_found("physical agent", agent_id)),
         }
     }
 
@@ -135,12 +241,12 @@ impl PhysicalAgent {
         db: &DbConn,
         agent_ip: String,
         port: i32,
-    ) -> Result<Option<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        physical_agent::Entity::find()
+    ) -> Result<Option<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find()
             .filter(physical_agent::Column::Ip.eq(agent_ip))
             .filter(physical_agent::Column::Port.eq(port))
             .one(db)
-            .await
+            .await?)
     }
 
     /// Get the physical agent by the given IP address. If the agent does not
@@ -148,23 +254,57 @@ impl PhysicalAgent {
     pub async fn get_physical_agent_by_ip(
         db: &DbConn,
         agent_ip: String,
-    ) -> Result<Vec<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        physical_agent::Entity::find()
+    ) -> Result<Vec<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find()
             .filter(physical_agent::Column::Ip.eq(agent_ip))
             .all(db)
-            .await
+            .await?)
+    }
+
+    /// Get the physical agents with any of the given statuses, or all the
+    /// agents if no status is given, ordered by their address.
+    pub async fn get_physical_agents(
+        db: &DbConn,
+        statuses: &[PhysicalAgentStatus],
+    ) -> Result<Vec<physical_agent::Model>> {
+        let mut query = physical_agent::Entity::find();
+        if !statuses.is_empty() {
+            query = query.filter(physical_agent::Column::Status.is_in(statuses.to_vec()));
+        }
+        Ok(query
+            .order_by_asc(physical_agent::Column::Ip)
+            .order_by_asc(physical_agent::Column::Port)
+            .all(db)
+            .await?)
+    }
+
+    /// Get the physical agents that are added by the discovery of the given
+    /// service.
+    pub async fn get_physical_agent_by_discovery(
+        db: &DbConn,
+        service: &str,
+    ) -> Result<Vec<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find()
+            .filter(physical_agent::Column::Discovery.eq(service))
+            .all(db)
+            .await?)
     }
 
     /// Given the number of qubits and the depth of the circuit, return the
     /// available physical agents. The available means the agent has the
     /// enough qubits and the depth and qubits are enough for the task. This
     /// function is used to check whether a task can be executed by the agents.
-    pub async fn get_physical_agent_available(
-        db: &DbConn,
+    /// The capability of the agent must meet the requirements of the task,
+    /// and its memory must fit the task in the `memory`
+    /// [capacity model](CapacityModel).
+    pub async fn get_physical_agent_available<C: ConnectionTrait>(
+        db: &C,
+        capacity: &CapacityConfig,
         task_qubits: i32,
         task_depth: i32,
-    ) -> Result<Vec<physical_agent::Model>, sea_orm::prelude::DbErr> {
-        physical_agent::Entity::find()
+        requirements: &TaskRequirements,
+    ) -> Result<Vec<physical_agent::Model>> {
+        Ok(physical_agent::Entity::find()
             .filter(
                 Condition::all()
                     .add(
@@ -172,31 +312,17 @@ impl PhysicalAgent {
                             .eq(sea_orm_active_enums::PhysicalAgentStatus::Running),
                     )
                     .add(physical_agent::Column::QubitCount.gte(task_qubits))
-                    .add(physical_agent::Column::CircuitDepth.gte(task_depth)),
+                    .add(capacity_condition(capacity, task_qubits, false))
+                    .add(physical_agent::Column::CircuitDepth.gte(task_depth))
+                    .add(requirements_condition(requirements)),
             )
             .all(db)
-            .await
-    }
-
-    /// Update the idle qubits of the physical agent. This function is used by
-    /// consume task thread to update the idle qubits of the agent before/after
-    /// running a task.
-    pub async fn update_physical_agent_qubits_idle(
-        db: &DbConn,
-        agent_id: uuid::Uuid,
-        qubits_idle: i32,
-    ) -> Result<UpdateResult, sea_orm::prelude::DbErr> {
-        physical_agent::Entity::update_many()
-            .filter(physical_agent::Column::Id.eq(agent_id))
-            .col_expr(
-                physical_agent::Column::QubitIdle,
-                Expr::col(physical_agent::Column::QubitIdle).add(qubits_idle),
-            )
-            .exec(db)
-            .await
+            .await?)
     }
 
-    /// Update the physical agent with the given information.
+    /// Update the physical agent with the given information. The given labels
+    /// replace all the labels of the agent.
+    #[allow(clippy::too_many_arguments)]
     pub async fn update_physical_agent(
         db: &DbConn,
         agent_id: uuid::Uuid,
@@ -205,67 +331,331 @@ impl PhysicalAgent {
         agent_qubit_count: Option<i32>,
         agent_circuit_depth: Option<i32>,
         agent_status: Option<sea_orm_active_enums::PhysicalAgentStatus>,
-    ) -> Result<physical_agent::Model, sea_orm::prelude::DbErr> {
+        agent_capability: Option<AgentCapability>,
+        agent_labels: Option<BTreeMap<String, String>>,
+        agent_pool: Option<String>,
+    ) -> Result<physical_agent::Model> {
         match physical_agent::Entity::find_by_id(agent_id).one(db).await? {
             Some(agent) => {
                 let mut agent: physical_agent::ActiveModel = agent.into();
-                if agent_ip.is_some() {
-                    agent.ip = Set(agent_ip.unwrap());
+                if let Some(ip) = agent_ip {
+                    agent.ip = Set(ip);
+                }
+                if let Some(port) = agent_port {
+                    agent.port = Set(port);
+                }
+                if let Some(qubit_count) = agent_qubit_count {
+                    agent.qubit_count = Set(qubit_count);
                 }
-                if agent_port.is_some() {
-                    agent.port = Set(agent_port.unwrap());
+                if let Some(circuit_depth) = agent_circuit_depth {
+                    agent.circuit_depth = Set(circuit_depth);
                 }
-                if agent_qubit_count.is_some() {
-                    agent.qubit_count = Set(agent_qubit_count.unwrap());
-                    agent.qubit_idle = Set(agent_qubit_count.unwrap());
+                // the status set by the operator is not overridden by the discovery
+                if let Some(status) = agent_status {
+                    agent.status = Set(status);
+                    agent.lost_time = Set(None);
                 }
-                if agent_circuit_depth.is_some() {
-                    agent.circuit_depth = Set(agent_circuit_depth.unwrap());
+                if let Some(capability) = agent_capability.map(AgentCapability::normalized) {
+                    agent.simulator = Set(capability.simulator);
+                    agent.gates = Set(capability.gates.map(Into::into));
+                    agent.noise = Set(capability.noise);
+                    agent.max_shots = Set(capability.max_shots.map(|x| x as i32));
+                    agent.memory_mb = Set(capability.memory_mb.map(|x| x as i64));
+                    agent.qasm3 = Set(capability.qasm3);
+                    agent.slots = Set(capability.slots.map(|x| x as i32));
                 }
-                if agent_status.is_some() {
-                    agent.status = Set(agent_status.unwrap());
+                if let Some(labels) = agent_labels {
+                    agent.labels = Set(Some(serde_json::to_value(labels)?));
                 }
-                agent.update(db).await
+                if let Some(pool) = agent_pool {
+                    agent.pool = Set(Some(pool));
+                }
+                Self::update_with_capacity(db, agent_id, agent).await
             }
-            None => Err(sea_orm::prelude::DbErr::RecordNotFound(format!(
-                "Physical agent {} not found",
-                agent_id
-            ))),
+            None => Err(Error::not_found("physical agent", agent_id)),
         }
     }
 
+    /// Update the physical agent and [rebuild](super::capacity::Capacity::rebuild_agent) its
+    /// idle qubits from the live reservations in one transaction, so the
+    /// qubits reserved by the running tasks are kept when the qubit count
+    /// changes.
+    async fn update_with_capacity(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        agent: physical_agent::ActiveModel,
+    ) -> Result<physical_agent::Model> {
+        let txn = db.begin().await?;
+        agent.update(&txn).await?;
+        super::capacity::Capacity::rebuild_agent(&txn, agent_id).await?;
+        let agent = physical_agent::Entity::find_by_id(agent_id)
+            .one(&txn)
+            .await?
+            .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
+        txn.commit().await?;
+        Ok(agent)
+    }
+
     /// Update the physical agent status, this function is used before update
     /// physical agent information.
     pub async fn update_physical_agent_status(
         db: &DbConn,
         agent_id: uuid::Uuid,
         agent_status: sea_orm_active_enums::PhysicalAgentStatus,
-    ) -> Result<(physical_agent::Model, PhysicalAgentStatus), sea_orm::prelude::DbErr> {
+    ) -> Result<(physical_agent::Model, PhysicalAgentStatus)> {
         match physical_agent::Entity::find_by_id(agent_id).one(db).await? {
             Some(agent) => {
                 let mut agent_act: physical_agent::ActiveModel = agent.clone().into();
                 agent_act.status = Set(agent_status);
                 Ok((agent_act.update(db).await?, agent.status))
             }
-            None => Err(sea_orm::prelude::DbErr::RecordNotFound(format!(
-                "Physical agent {} not found",
-                agent_id
-            ))),
+            None => Err(Error::not_found("physical agent", agent_id)),
         }
     }
 
-    /// Remove the physical agent by the given ID.
+    /// Mark the discovered physical agent as down because its replica is gone,
+    /// unless it is already down. Return whether the agent is marked, an agent
+    /// that the operator has set down is left as is, so it is not
+    /// [revived](PhysicalAgent::revive_physical_agent) by the discovery.
+    pub async fn mark_physical_agent_lost(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        now: chrono::NaiveDateTime,
+    ) -> Result<bool> {
+        let marked = physical_agent::Entity::update_many()
+            .col_expr(
+                physical_agent::Column::Status,
+                Expr::value(PhysicalAgentStatus::Down),
+            )
+            .col_expr(physical_agent::Column::LostTime, Expr::value(Some(now)))
+            .filter(physical_agent::Column::Id.eq(agent_id))
+            .filter(physical_agent::Column::Status.ne(PhysicalAgentStatus::Down))
+            .exec(db)
+            .await?
+            .rows_affected;
+        Ok(marked == 1)
+    }
+
+    /// Set the discovered physical agent running again when its replica is
+    /// back, only if it was [marked](PhysicalAgent::mark_physical_agent_lost)
+    /// down by the discovery. Return whether the agent is revived.
+    pub async fn revive_physical_agent(db: &DbConn, agent_id: uuid::Uuid) -> Result<bool> {
+        let revived = physical_agent::Entity::update_many()
+            .col_expr(
+                physical_agent::Column::Status,
+                Expr::value(PhysicalAgentStatus::Running),
+            )
+            .col_expr(
+                physical_agent::Column::LostTime,
+                Expr::value(Option::<chrono::NaiveDateTime>::None),
+            )
+            .filter(physical_agent::Column::Id.eq(agent_id))
+            .filter(physical_agent::Column::Status.eq(PhysicalAgentStatus::Down))
+            .filter(physical_agent::Column::LostTime.is_not_null())
+            .exec(db)
+            .await?
+            .rows_affected;
+        Ok(revived == 1)
+    }
+
+    /// Update the status of the physical agent only if it still has the given
+    /// status. Return whether the status is updated.
+    pub async fn update_physical_agent_status_from(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        from: PhysicalAgentStatus,
+        to: PhysicalAgentStatus,
+    ) -> Result<bool> {
+        let updated = physical_agent::Entity::update_many()
+            .col_expr(physical_agent::Column::Status, Expr::value(to))
+            .filter(physical_agent::Column::Id.eq(agent_id))
+            .filter(physical_agent::Column::Status.eq(from))
+            .exec(db)
+            .await?
+            .rows_affected;
+        Ok(updated == 1)
+    }
+
+    /// Remove the physical agent by the given ID. If the agent does not exist,
+    /// it will return a not found error.
     pub async fn remove_physical_agent(
         db: &DbConn,
         agent_id: uuid::Uuid,
-    ) -> Result<physical_agent::Model, sea_orm::prelude::DbErr> {
+    ) -> Result<physical_agent::Model> {
         let agent = physical_agent::Entity::find_by_id(agent_id)
             .one(db)
             .await?
-            .unwrap();
+            .ok_or_else(|| Error::not_found("physical agent", agent_id))?;
         physical_agent::Entity::delete_by_id(agent_id)
             .exec(db)
             .await?;
         Ok(agent)
     }
 }
+
+/// The condition that the physical agent has the capacity for a task of the
+/// given qubits, out of its idle capacity if `idle` is set, or out of its
+/// total capacity otherwise. In the `qubits` [capacity model](CapacityModel)
+/// the idle qubits must be enough. In the `memory` model the memory must fit
+/// the [estimate](memory_estimate_mb) of the task on the simulator of the
+/// agent, an agent without memory is checked by qubits instead, and an idle
+/// slot is needed if the agent has slots.
+pub fn capacity_condition(capacity: &CapacityConfig, qubits: i32, idle: bool) -> Condition {
+    let qubit_condition = match idle {
+        true => Condition::all().add(physical_agent::Column::QubitIdle.gte(qubits)),
+        false => Condition::all(),
+    };
+    if capacity.model == CapacityModel::Qubits {
+        return qubit_condition;
+    }
+
+    let memory = match idle {
+        true => physical_agent::Column::MemoryIdleMb,
+        false => physical_agent::Column::MemoryMb,
+    };
+    let estimate = |simulator| memory_estimate_mb(simulator, qubits, capacity.overhead_mb);
+    let simulators = ["density_matrix", "stabilizer"];
+    let mut fits = Condition::any().add(
+        Condition::all()
+            .add(physical_agent::Column::Simulator.is_not_in(simulators))
+            .add(memory.gte(estimate("statevector"))),
+    );
+    for simulator in simulators {
+        fits = fits.add(
+            Condition::all()
+                .add(physical_agent::Column::Simulator.eq(simulator))
+                .add(memory.gte(estimate(simulator))),
+        );
+    }
+    let mut condition = Condition::all().add(
+        Condition::any().add(fits).add(
+            Condition::all()
+                .add(physical_agent::Column::MemoryMb.is_null())
+                .add(qubit_condition),
+        ),
+    );
+    if idle {
+        condition = condition.add(
+            Condition::any()
+                .add(physical_agent::Column::SlotsIdle.is_null())
+                .add(physical_agent::Column::SlotsIdle.gte(1)),
+        );
+    }
+    condition
+}
+
+/// The condition that the capability of the physical agent meets the given
+/// requirements of the task. An agent without gate list supports all the
+/// gates, an agent whose memory is unknown is not excluded by the memory
+/// requirement. The labels of the agent must contain the node selector of the
+/// task and none of its anti-affinity labels.
+fn requirements_condition(requirements: &TaskRequirements) -> Condition {
+    let mut condition = Condition::all();
+    if let Some(simulator) = &requirements.simulator {
+        condition = condition.add(physical_agent::Column::Simulator.eq(simulator.as_str()));
+    }
+    if !requirements.gates.is_empty() {
+        condition = condition.add(
+            Condition::any()
+                .add(physical_agent::Column::Gates.is_null())
+                .add(
+                    Expr::col(physical_agent::Column::Gates)
+                        .contains(serde_json::json!(requirements.gates)),
+                ),
+        );
+    }
+    if requirements.noise {
+        condition = condition.add(physical_agent::Column::Noise.eq(true));
+    }
+    if requirements.qasm3 {
+        condition = condition.add(physical_agent::Column::Qasm3.eq(true));
+    }
+    if let Some(memory_mb) = requirements.memory_mb {
+        condition = condition.add(
+            Condition::any()
+                .add(physical_agent::Column::MemoryMb.is_null())
+                .add(physical_agent::Column::MemoryMb.gte(memory_mb as i64)),
+        );
+    }
+    if let Some(pool) = &requirements.pool {
+        condition = condition.add(physical_agent::Column::Pool.eq(pool.as_str()));
+    }
+    if !requirements.node_selector.is_empty() {
+        condition = condition.add(
+            Expr::col(physical_agent::Column::Labels)
+                .contains(serde_json::json!(requirements.node_selector)),
+        );
+    }
+    for (key, value) in &requirements.anti_affinity {
+        condition = condition.add(
+            Condition::any()
+                .add(physical_agent::Column::Labels.is_null())
+                .add(
+                    Expr::col(physical_agent::Column::Labels)
+                        .contains(serde_json::json!({ key: value }))
+                        .not(),
+                ),
+        );
+    }
+    condition
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{DbBackend, QueryTrait};
+
+    /// The `WHERE` clause of the agent query for the requirements on
+    /// Postgres, the JSONB containment is not supported by the in-memory
+    /// database.
+    fn where_clause(requirements: TaskRequirements) -> String {
+        let sql = physical_agent::Entity::find()
+            .filter(requirements_condition(&requirements))
+            .build(DbBackend::Postgres)
+            .to_string();
+        sql.split_once(" WHERE ").unwrap().1.to_owned()
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn no_requirements_match_every_agent() {
+        assert_eq!(where_clause(TaskRequirements::default()), "TRUE");
+    }
+
+    #[test]
+    fn pool_and_node_selector_must_match() {
+        let requirements = TaskRequirements {
+            pool: Some("gpu".to_owned()),
+            node_selector: labels(&[("team", "chem"), ("zone", "a")]),
+            ..Default::default()
+        };
+        // all the labels of the node selector are contained at once
+        assert_eq!(
+            where_clause(requirements),
+            r#""physical_agent"."pool" = 'gpu' AND "labels" @> E'{\"team\":\"chem\",\"zone\":\"a\"}'"#
+        );
+    }
+
+    #[test]
+    fn anti_affinity_excludes_each_label() {
+        let requirements = TaskRequirements {
+            anti_affinity: labels(&[("spot", "true"), ("zone", "a")]),
+            ..Default::default()
+        };
+        // an agent is avoided if it has any of the labels, an agent without
+        // labels has none of them
+        assert_eq!(
+            where_clause(requirements),
+            concat!(
+                r#"("physical_agent"."labels" IS NULL OR (NOT "labels" @> E'{\"spot\":\"true\"}'))"#,
+                r#" AND ("physical_agent"."labels" IS NULL OR (NOT "labels" @> E'{\"zone\":\"a\"}'))"#,
+            )
+        );
+    }
+}
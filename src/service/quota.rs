@@ -0,0 +1,337 @@
+use crate::config::QuotaConfig;
+use crate::error::{Error, Result};
+use chrono::{Datelike, Months, NaiveDate, NaiveDateTime, Utc};
+use sea_orm::DbConn;
+
+pub struct Quota;
+
+impl Quota {
+    /// Check whether the user has quota left to run `shots` more shots, which
+    /// are estimated to use `qubit_seconds` more qubit-seconds. The submit
+    /// request passes the shots and the estimate of the new task, the consume
+    /// thread passes the ones of the next chunk before dispatching it. If a
+    /// daily or monthly quota is exceeded, return a quota exceeded error with
+    /// the seconds until the quota is reset. A task with more shots or
+    /// qubit-seconds than a quota returns a task too large error.
+    pub async fn check(
+        db: &DbConn,
+        config: &QuotaConfig,
+        username: &str,
+        shots: i64,
+        qubit_seconds: i64,
+    ) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        Self::check_at(db, config, username, shots, qubit_seconds, now).await
+    }
+
+    /// [Check](Quota::check) the quota of the user at the given time.
+    async fn check_at(
+        db: &DbConn,
+        config: &QuotaConfig,
+        username: &str,
+        shots: i64,
+        qubit_seconds: i64,
+        now: NaiveDateTime,
+    ) -> Result<()> {
+        let quota = config.user_quota(username);
+        let today = now.date();
+        let month = NaiveDate::from_ymd_opt(today.year(), today.month(), 1).unwrap_or(today);
+        let periods = [
+            (
+                today,
+                today.succ_opt().unwrap_or(today),
+                ("daily_shots", quota.daily_shots),
+                ("daily_qubit_seconds", quota.daily_qubit_seconds),
+            ),
+            (
+                month,
+                month.checked_add_months(Months::new(1)).unwrap_or(month),
+                ("monthly_shots", quota.monthly_shots),
+                ("monthly_qubit_seconds", quota.monthly_qubit_seconds),
+            ),
+        ];
+
+        for (start, end, (shots_name, max_shots), (qubit_seconds_name, max_qubit_seconds)) in
+            periods
+        {
+            if max_shots.is_none() && max_qubit_seconds.is_none() {
+                continue;
+            }
+            let (used_shots, used_qubit_seconds) = super::usage::Usage::get_user_usage_since(
+                db,
+                username,
+                start.and_time(Default::default()),
+            )
+            .await?;
+            let retry_after = seconds_until(now, end);
+
+            if let Some(max) = max_shots {
+                // the task could never be run in one period
+                if shots > max {
+                    return Err(Error::TaskTooLarge {
+                        limit: shots_name,
+                        max: max as u64,
+                        actual: shots as u64,
+                    });
+                }
+                if used_shots >= max || used_shots + shots > max {
+                    return Err(Error::QuotaExceeded {
+                        quota: shots_name,
+                        used: used_shots,
+                        max,
+                        retry_after,
+                    });
+                }
+            }
+            if let Some(max) = max_qubit_seconds {
+                if qubit_seconds > max {
+                    return Err(Error::TaskTooLarge {
+                        limit: qubit_seconds_name,
+                        max: max as u64,
+                        actual: qubit_seconds as u64,
+                    });
+                }
+                if used_qubit_seconds >= max || used_qubit_seconds + qubit_seconds > max {
+                    return Err(Error::QuotaExceeded {
+                        quota: qubit_seconds_name,
+                        used: used_qubit_seconds,
+                        max,
+                        retry_after,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The seconds from now until the start of the given day, at least 1.
+fn seconds_until(now: NaiveDateTime, day: NaiveDate) -> u64 {
+    (day.and_time(Default::default()) - now)
+        .num_seconds()
+        .max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserQuota;
+    use crate::entity::usage_record;
+    use crate::service::usage::Usage;
+    use axum::response::IntoResponse;
+    use sea_orm::{ConnectOptions, ConnectionTrait, Database, Schema};
+
+    /// The in-memory database with the table of the usage ledger.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        db.execute(backend.build(&schema.create_table_from_entity(usage_record::Entity)))
+            .await
+            .unwrap();
+        db
+    }
+
+    fn at(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    /// Record an assignment of the user that finished at the given time.
+    async fn record(
+        db: &DbConn,
+        username: &str,
+        shots: i32,
+        qubits: i32,
+        seconds: i64,
+        time: &str,
+    ) {
+        Usage::add_usage(
+            db,
+            usage_record::Model {
+                id: uuid::Uuid::new_v4(),
+                assignment_id: uuid::Uuid::new_v4(),
+                task_id: uuid::Uuid::new_v4(),
+                agent_id: uuid::Uuid::new_v4(),
+                username: username.to_owned(),
+                shots,
+                qubits,
+                depth: 10,
+                wall_time_ms: seconds * 1000,
+                started_time: at(time) - chrono::TimeDelta::seconds(seconds),
+                finished_time: at(time),
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    fn config(quota: UserQuota) -> QuotaConfig {
+        QuotaConfig {
+            default: quota,
+            users: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn daily_quota_counts_from_midnight() {
+        let db = database().await;
+        let config = config(UserQuota {
+            daily_shots: Some(1000),
+            ..Default::default()
+        });
+        // the day before does not count, the start of the day does
+        record(&db, "alice", 900, 2, 1, "2024-03-09 23:59:59").await;
+        record(&db, "alice", 600, 2, 1, "2024-03-10 00:00:00").await;
+        record(&db, "bob", 900, 2, 1, "2024-03-10 08:00:00").await;
+        let now = at("2024-03-10 22:00:00");
+
+        assert!(Quota::check_at(&db, &config, "alice", 400, 0, now)
+            .await
+            .is_ok());
+        match Quota::check_at(&db, &config, "alice", 401, 0, now).await {
+            Err(Error::QuotaExceeded {
+                quota,
+                used,
+                max,
+                retry_after,
+            }) => {
+                assert_eq!((quota, used, max), ("daily_shots", 600, 1000));
+                // the quota is reset at the next midnight
+                assert_eq!(retry_after, 2 * 3600);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        // a task that never fits in a day is too large
+        assert!(matches!(
+            Quota::check_at(&db, &config, "alice", 1001, 0, now).await,
+            Err(Error::TaskTooLarge {
+                limit: "daily_shots",
+                ..
+            })
+        ));
+        // the usage of the day before is reset
+        let tomorrow = at("2024-03-11 00:00:00");
+        assert!(Quota::check_at(&db, &config, "alice", 1000, 0, tomorrow)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn monthly_quota_counts_from_the_first_day() {
+        let db = database().await;
+        let config = config(UserQuota {
+            monthly_qubit_seconds: Some(100),
+            ..Default::default()
+        });
+        // 4 qubits for 20 seconds are 80 qubit-seconds
+        record(&db, "alice", 100, 4, 20, "2024-01-31 23:59:59").await;
+        record(&db, "alice", 100, 4, 20, "2024-02-01 00:00:00").await;
+        let now = at("2024-02-10 12:00:00");
+
+        assert!(Quota::check_at(&db, &config, "alice", 0, 20, now)
+            .await
+            .is_ok());
+        match Quota::check_at(&db, &config, "alice", 0, 21, now).await {
+            Err(Error::QuotaExceeded {
+                quota,
+                used,
+                retry_after,
+                ..
+            }) => {
+                assert_eq!((quota, used), ("monthly_qubit_seconds", 80));
+                // the quota is reset at the first day of the next month
+                assert_eq!(retry_after, (19 * 24 + 12) * 3600);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        assert!(matches!(
+            Quota::check_at(&db, &config, "alice", 0, 101, now).await,
+            Err(Error::TaskTooLarge {
+                limit: "monthly_qubit_seconds",
+                ..
+            })
+        ));
+        assert!(
+            Quota::check_at(&db, &config, "alice", 0, 100, at("2024-03-01 00:00:00"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn quota_of_the_user_overrides_the_default() {
+        let db = database().await;
+        record(&db, "alice", 500, 2, 1, "2024-03-10 08:00:00").await;
+        let mut config = config(UserQuota {
+            daily_shots: Some(100),
+            ..Default::default()
+        });
+        config
+            .users
+            .insert("alice".to_owned(), UserQuota::default());
+        let now = at("2024-03-10 09:00:00");
+        assert!(Quota::check_at(&db, &config, "alice", 100, 0, now)
+            .await
+            .is_ok());
+        assert!(Quota::check_at(&db, &config, "bob", 101, 0, now)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn quota_exceeded_sets_retry_after() {
+        let response = Error::QuotaExceeded {
+            quota: "daily_shots",
+            used: 10,
+            max: 10,
+            retry_after: 3600,
+        }
+        .into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "3600");
+    }
+
+    #[tokio::test]
+    async fn usage_ledger_is_aggregated_by_user() {
+        let db = database().await;
+        record(&db, "alice", 100, 2, 3, "2024-03-10 08:00:00").await;
+        record(&db, "alice", 200, 4, 5, "2024-03-10 09:00:00").await;
+        record(&db, "bob", 50, 1, 1, "2024-03-10 09:30:00").await;
+        // outside of the range
+        record(&db, "alice", 999, 9, 9, "2024-03-11 00:00:00").await;
+
+        let from = at("2024-03-10 00:00:00");
+        let to = at("2024-03-11 00:00:00");
+        let usages = Usage::get_usage_by_user(&db, from, to, None).await.unwrap();
+        let summary: Vec<_> = usages
+            .iter()
+            .map(|usage| {
+                (
+                    usage.username.as_str(),
+                    usage.assignments,
+                    usage.shots,
+                    usage.wall_time_seconds,
+                    usage.qubit_seconds,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [("alice", 2, 300, 8.0, 26.0), ("bob", 1, 50, 1.0, 1.0)]
+        );
+        let bob = Usage::get_usage_by_user(&db, from, to, Some("bob"))
+            .await
+            .unwrap();
+        assert_eq!(bob.len(), 1);
+        assert_eq!(
+            Usage::get_user_usage_since(&db, "alice", from)
+                .await
+                .unwrap(),
+            (1299, 26 + 81)
+        );
+    }
+}
@@ -1,15 +1,17 @@
 use crate::entity::*;
-use sea_orm::{ActiveModelTrait, ActiveValue, DbConn, EntityTrait};
+use crate::error::Result;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DbConn, EntityTrait, QueryFilter,
+    QueryOrder,
+};
 
 pub struct Task;
 
 impl Task {
-    /// Add a new task to the database after the task is succeeded or failed.
-    pub async fn add_task(
-        db: &DbConn,
-        data: task::Model,
-    ) -> Result<task::Model, sea_orm::prelude::DbErr> {
-        task::ActiveModel {
+    /// Add a new task to the database after the task is succeeded, failed or
+    /// expired.
+    pub async fn add_task<C: ConnectionTrait>(db: &C, data: task::Model) -> Result<task::Model> {
+        Ok(task::ActiveModel {
             id: ActiveValue::set(data.id.to_owned()),
             source: ActiveValue::set(data.source.to_owned()),
             result: ActiveValue::set(data.result.to_owned()),
@@ -19,16 +21,53 @@ impl Task {
             status: ActiveValue::set(data.status.to_owned()),
             created_time: ActiveValue::set(data.created_time.to_owned()),
             updated_time: ActiveValue::set(data.updated_time.to_owned()),
+            username: ActiveValue::set(data.username.to_owned()),
+            noise: ActiveValue::set(data.noise.to_owned()),
+            source_hash: ActiveValue::set(data.source_hash.to_owned()),
+            circuit: ActiveValue::set(data.circuit.to_owned()),
+            parameters: ActiveValue::set(data.parameters.to_owned()),
+            parent_id: ActiveValue::set(data.parent_id.to_owned()),
+            cutting: ActiveValue::set(data.cutting.to_owned()),
         }
         .insert(db)
-        .await
+        .await?)
     }
 
     /// Get the task with the given task id.
-    pub async fn get_task(
+    pub async fn get_task(db: &DbConn, task_id: uuid::Uuid) -> Result<Option<task::Model>> {
+        Ok(task::Entity::find_by_id(task_id).one(db).await?)
+    }
+
+    /// Get the finished child tasks of the cut task with the given ID.
+    pub async fn get_tasks_by_parent<C: ConnectionTrait>(
+        db: &C,
+        parent_id: uuid::Uuid,
+    ) -> Result<Vec<task::Model>> {
+        Ok(task::Entity::find()
+            .filter(task::Column::ParentId.eq(parent_id))
+            .all(db)
+            .await?)
+    }
+
+    /// Get the latest succeeded task of the user with the given source hash
+    /// that is finished after the given time. This function is used to reuse
+    /// the cached result of an identical task.
+    pub async fn get_succeeded_task_by_hash(
         db: &DbConn,
-        task_id: uuid::Uuid,
-    ) -> Result<Option<task::Model>, sea_orm::prelude::DbErr> {
-        task::Entity::find_by_id(task_id).one(db).await
+        username: &str,
+        source_hash: &str,
+        since: Option<chrono::NaiveDateTime>,
+    ) -> Result<Option<task::Model>> {
+        let mut query = task::Entity::find()
+            .filter(task::Column::Username.eq(username))
+            .filter(task::Column::SourceHash.eq(source_hash))
+            .filter(task::Column::Status.eq(sea_orm_active_enums::TaskStatus::Succeeded));
+        if let Some(since) = since {
+            query = query.filter(task::Column::UpdatedTime.gte(since));
+        }
+        Ok(query
+            .order_by_desc(task::Column::UpdatedTime)
+            .one(db)
+            .await?)
     }
 }
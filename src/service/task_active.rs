@@ -1,85 +1,286 @@
+use crate::capability::TaskRequirements;
+use crate::config::CapacityConfig;
 use crate::entity::*;
+use crate::error::{Error, Result};
+use migration::Expr;
 use sea_orm::{
-    ActiveModelTrait, ActiveValue, ColumnTrait, DbConn, EntityTrait, QueryFilter, QueryOrder,
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DbConn, EntityTrait,
+    PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
 };
 
 pub struct TaskActive;
 
 impl TaskActive {
-    /// Add a new task to the database. If there is no available physical agent,
-    /// it will return an error.
-    pub async fn add_task(
-        db: &DbConn,
+    /// Add a new task to the database. If there is no physical agent that is
+    /// big enough and meets the requirements of the task, it will return an
+    /// error.
+    pub async fn add_task<C: ConnectionTrait>(
+        db: &C,
+        capacity: &CapacityConfig,
+        data: task_active::Model,
+    ) -> Result<task_active::Model> {
+        Self::check_available_agent(db, capacity, &data).await?;
+        Self::insert_task(db, data).await
+    }
+
+    /// Add the cut task and its child tasks in one transaction. The cut task
+    /// is added as running, so it is never dispatched, only the child tasks
+    /// are. If there is no physical agent for a child task, nothing is added
+    /// and it will return an error.
+    pub async fn add_cut_task<C: ConnectionTrait + TransactionTrait>(
+        db: &C,
+        capacity: &CapacityConfig,
         data: task_active::Model,
-    ) -> Result<task_active::Model, sea_orm::prelude::DbErr> {
-        match super::physical_agent::PhysicalAgent::get_physical_agent_available(
+        children: Vec<task_active::Model>,
+    ) -> Result<task_active::Model> {
+        let txn = db.begin().await?;
+        for child in children {
+            Self::check_available_agent(&txn, capacity, &child).await?;
+            Self::insert_task(&txn, child).await?;
+        }
+        let task = Self::insert_task(&txn, data).await?;
+        txn.commit().await?;
+        Ok(task)
+    }
+
+    /// Check that there is a physical agent that is big enough and meets the
+    /// requirements of the task.
+    async fn check_available_agent<C: ConnectionTrait>(
+        db: &C,
+        capacity: &CapacityConfig,
+        data: &task_active::Model,
+    ) -> Result<()> {
+        let requirements = TaskRequirements::from_json(data.requirements.as_ref())?;
+        let agents = super::physical_agent::PhysicalAgent::get_physical_agent_available(
             db,
+            capacity,
             data.qubits,
             data.depth,
+            &requirements,
         )
-        .await
-        {
-            Ok(agents) => {
-                if agents.is_empty() {
-                    Err(sea_orm::prelude::DbErr::Custom(
-                        "No available physical agent".to_owned(),
-                    ))
-                } else {
-                    task_active::ActiveModel {
-                        id: ActiveValue::set(data.id.to_owned()),
-                        source: ActiveValue::set(data.source.to_owned()),
-                        result: ActiveValue::set(data.result.to_owned()),
-                        qubits: ActiveValue::set(data.qubits.to_owned()),
-                        depth: ActiveValue::set(data.depth.to_owned()),
-                        shots: ActiveValue::set(data.shots.to_owned()),
-                        exec_shots: ActiveValue::set(data.exec_shots.to_owned()),
-                        v_exec_shots: ActiveValue::set(data.v_exec_shots.to_owned()),
-                        status: ActiveValue::set(data.status.to_owned()),
-                        created_time: ActiveValue::set(data.created_time.to_owned()),
-                        updated_time: ActiveValue::set(data.updated_time.to_owned()),
-                    }
-                    .insert(db)
-                    .await
-                }
-            }
-            Err(err) => Err(err),
+        .await?;
+
+        if agents.is_empty() {
+            return Err(Error::NoAvailableAgent {
+                qubits: data.qubits,
+                depth: data.depth,
+            });
+        }
+        Ok(())
+    }
+
+    async fn insert_task<C: ConnectionTrait>(
+        db: &C,
+        data: task_active::Model,
+    ) -> Result<task_active::Model> {
+        Ok(task_active::ActiveModel {
+            id: ActiveValue::set(data.id.to_owned()),
+            source: ActiveValue::set(data.source.to_owned()),
+            result: ActiveValue::set(data.result.to_owned()),
+            qubits: ActiveValue::set(data.qubits.to_owned()),
+            depth: ActiveValue::set(data.depth.to_owned()),
+            shots: ActiveValue::set(data.shots.to_owned()),
+            exec_shots: ActiveValue::set(data.exec_shots.to_owned()),
+            v_exec_shots: ActiveValue::set(data.v_exec_shots.to_owned()),
+            status: ActiveValue::set(data.status.to_owned()),
+            created_time: ActiveValue::set(data.created_time.to_owned()),
+            updated_time: ActiveValue::set(data.updated_time.to_owned()),
+            username: ActiveValue::set(data.username.to_owned()),
+            deadline: ActiveValue::set(data.deadline.to_owned()),
+            requirements: ActiveValue::set(data.requirements.to_owned()),
+            noise: ActiveValue::set(data.noise.to_owned()),
+            source_hash: ActiveValue::set(data.source_hash.to_owned()),
+            circuit: ActiveValue::set(data.circuit.to_owned()),
+            parameters: ActiveValue::set(data.parameters.to_owned()),
+            parent_id: ActiveValue::set(data.parent_id.to_owned()),
+            cutting: ActiveValue::set(data.cutting.to_owned()),
         }
+        .insert(db)
+        .await?)
     }
 
     /// Get all the tasks that are waiting to be executed. The tasks are ordered
     /// by the number of virtual executed shots in ascending order.
-    pub async fn get_asc_tasks(
-        db: &DbConn,
-    ) -> Result<Vec<task_active::Model>, sea_orm::prelude::DbErr> {
-        task_active::Entity::find()
+    pub async fn get_asc_tasks(db: &DbConn) -> Result<Vec<task_active::Model>> {
+        Ok(task_active::Entity::find()
             .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
             .order_by_asc(task_active::Column::VExecShots)
             .all(db)
-            .await
+            .await?)
     }
 
-    /// Get the task with the given ID.
-    pub async fn get_task(
+    /// Get all the tasks that are waiting to be executed in the earliest
+    /// deadline first order. The tasks without deadline are the last, ties
+    /// are ordered by the number of virtual executed shots in ascending order.
+    pub async fn get_edf_tasks(db: &DbConn) -> Result<Vec<task_active::Model>> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
+            .order_by_asc(Expr::col(task_active::Column::Deadline).is_null())
+            .order_by_asc(task_active::Column::Deadline)
+            .order_by_asc(task_active::Column::VExecShots)
+            .all(db)
+            .await?)
+    }
+
+    /// Get the waiting tasks whose deadline is earlier than the given time.
+    /// The running tasks are not returned, they expire after the running
+    /// chunk is finished.
+    pub async fn get_expired_tasks(
+        db: &DbConn,
+        now: chrono::NaiveDateTime,
+    ) -> Result<Vec<task_active::Model>> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
+            .filter(task_active::Column::Deadline.lt(now))
+            .all(db)
+            .await?)
+    }
+
+    /// Get the tasks that are marked as running. The cut tasks are not
+    /// returned, they are running until their child tasks are finished.
+    pub async fn get_running_tasks(db: &DbConn) -> Result<Vec<task_active::Model>> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Running))
+            .filter(task_active::Column::Cutting.is_null())
+            .all(db)
+            .await?)
+    }
+
+    /// Update the status of the task with the given ID. This function is used
+    /// to requeue the tasks left running by a former leader.
+    pub async fn update_task_status(
+        db: &DbConn,
+        task_id: uuid::Uuid,
+        status: sea_orm_active_enums::TaskActiveStatus,
+    ) -> Result<task_active::Model> {
+        let mut task: task_active::ActiveModel = task_active::Entity::find_by_id(task_id)
+            .one(db)
+            .await?
+            .ok_or_else(|| Error::not_found("task", task_id))?
+            .into();
+        task.status = ActiveValue::set(status);
+        task.updated_time = ActiveValue::set(chrono::Utc::now().naive_utc());
+        Ok(task.update(db).await?)
+    }
+
+    /// Claim the waiting task with the given ID for a dispatch, that is, mark
+    /// it as running only if it is still waiting. It returns false if the
+    /// task is gone or claimed, cancelled or expired since it was read, so it
+    /// is never dispatched twice.
+    pub async fn claim_task(db: &DbConn, task_id: uuid::Uuid) -> Result<bool> {
+        let claimed = task_active::Entity::update_many()
+            .col_expr(
+                task_active::Column::Status,
+                Expr::value(sea_orm_active_enums::TaskActiveStatus::Running),
+            )
+            .col_expr(
+                task_active::Column::UpdatedTime,
+                Expr::value(chrono::Utc::now().naive_utc()),
+            )
+            .filter(task_active::Column::Id.eq(task_id))
+            .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
+            .exec(db)
+            .await?
+            .rows_affected;
+        Ok(claimed == 1)
+    }
+
+    /// Get the oldest active task of the user with the given source hash. This
+    /// function is used to attach a new submission to an in-flight identical
+    /// task.
+    pub async fn get_task_by_hash(
         db: &DbConn,
+        username: &str,
+        source_hash: &str,
+    ) -> Result<Option<task_active::Model>> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Username.eq(username))
+            .filter(task_active::Column::SourceHash.eq(source_hash))
+            .order_by_asc(task_active::Column::CreatedTime)
+            .one(db)
+            .await?)
+    }
+
+    /// Get the task with the given ID.
+    pub async fn get_task(db: &DbConn, task_id: uuid::Uuid) -> Result<Option<task_active::Model>> {
+        Ok(task_active::Entity::find_by_id(task_id).one(db).await?)
+    }
+
+    /// Get and lock the task with the given ID in the given transaction, so
+    /// that the cut task is finished once when its child tasks finish at the
+    /// same time.
+    pub async fn lock_task<C: ConnectionTrait>(
+        db: &C,
         task_id: uuid::Uuid,
-    ) -> Result<Option<task_active::Model>, sea_orm::prelude::DbErr> {
-        task_active::Entity::find_by_id(task_id).one(db).await
+    ) -> Result<Option<task_active::Model>> {
+        Ok(task_active::Entity::find_by_id(task_id)
+            .lock_exclusive()
+            .one(db)
+            .await?)
+    }
+
+    /// Get the active child tasks of the cut task with the given ID.
+    pub async fn get_tasks_by_parent<C: ConnectionTrait>(
+        db: &C,
+        parent_id: uuid::Uuid,
+    ) -> Result<Vec<task_active::Model>> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::ParentId.eq(parent_id))
+            .all(db)
+            .await?)
     }
 
     /// Get the minimum number of virtual executed shots of the tasks that are
     /// waiting to be executed. This function is used to update the vexec_shots
     /// for the new task.
-    pub async fn get_min_vexec_shots(db: &DbConn) -> Result<i32, sea_orm::prelude::DbErr> {
-        match task_active::Entity::find()
+    pub async fn get_min_vexec_shots(db: &DbConn) -> Result<i32> {
+        Ok(task_active::Entity::find()
             .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
             .order_by_asc(task_active::Column::VExecShots)
             .one(db)
-            .await
-        {
-            Ok(Some(task)) => Ok(task.v_exec_shots),
-            Ok(None) => Ok(0),
-            Err(err) => Err(err),
-        }
+            .await?
+            .map_or(0, |task| task.v_exec_shots))
+    }
+
+    /// Count the tasks that are waiting to be executed. This function is used
+    /// by the admission control to limit the length of the waiting queue.
+    pub async fn count_waiting_tasks(db: &DbConn) -> Result<u64> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Status.eq(sea_orm_active_enums::TaskActiveStatus::Waiting))
+            .count(db)
+            .await?)
+    }
+
+    /// Count the active tasks (waiting or running) of the given user. This
+    /// function is used by the admission control to limit the concurrent tasks
+    /// of one user. The child tasks are not counted, a cut task counts once.
+    pub async fn count_user_tasks(db: &DbConn, username: &str) -> Result<u64> {
+        Ok(task_active::Entity::find()
+            .filter(task_active::Column::Username.eq(username))
+            .filter(task_active::Column::ParentId.is_null())
+            .count(db)
+            .await?)
+    }
+
+    /// Get the total number of shots that are not executed yet of all the
+    /// active tasks. This function is used by the admission control to limit
+    /// the pending shots.
+    pub async fn sum_pending_shots(db: &DbConn) -> Result<i64> {
+        let pending_shots: Option<Option<i64>> = task_active::Entity::find()
+            .select_only()
+            .column_as(
+                Expr::expr(
+                    Expr::col(task_active::Column::Shots)
+                        .sub(Expr::col(task_active::Column::ExecShots)),
+                )
+                .sum()
+                .cast_as(migration::Alias::new("bigint")),
+                "pending_shots",
+            )
+            .into_tuple()
+            .one(db)
+            .await?;
+        Ok(pending_shots.flatten().unwrap_or(0))
     }
 
     /// Update the task result with the given information.
@@ -90,31 +291,189 @@ impl TaskActive {
         vexec_shots: i32,
         result: Option<String>,
         status: sea_orm_active_enums::TaskActiveStatus,
-    ) -> Result<task_active::Model, sea_orm::prelude::DbErr> {
+    ) -> Result<task_active::Model> {
         let mut task: task_active::ActiveModel = task_active::Entity::find_by_id(task_id)
             .one(db)
             .await?
-            .unwrap()
+            .ok_or_else(|| Error::not_found("task", task_id))?
             .into();
         task.result = ActiveValue::set(result);
         task.exec_shots = ActiveValue::set(exec_shots);
         task.v_exec_shots = ActiveValue::set(vexec_shots);
         task.status = ActiveValue::set(status);
         task.updated_time = ActiveValue::set(chrono::Utc::now().naive_utc());
-        task.update(db).await
+        Ok(task.update(db).await?)
     }
 
     /// Remove the task with the given ID. After removing it, the task will be
-    /// added to the history task table.
-    pub async fn remove_active_task(
-        db: &DbConn,
+    /// added to the history task table. If the task is removed by another
+    /// request meanwhile, it will return a not found error, so a task is
+    /// finished once.
+    pub async fn remove_active_task<C: ConnectionTrait>(
+        db: &C,
         task_id: uuid::Uuid,
-    ) -> Result<task_active::Model, sea_orm::prelude::DbErr> {
+    ) -> Result<task_active::Model> {
         let task = task_active::Entity::find_by_id(task_id)
             .one(db)
             .await?
-            .unwrap();
-        task_active::Entity::delete_by_id(task_id).exec(db).await?;
+            .ok_or_else(|| Error::not_found("task", task_id))?;
+        let removed = task_active::Entity::delete_by_id(task_id)
+            .exec(db)
+            .await?
+            .rows_affected;
+        if removed == 0 {
+            return Err(Error::not_found("task", task_id));
+        }
         Ok(task)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::{ConnectOptions, Database, Schema};
+
+    /// The in-memory database with the table of the active tasks.
+    async fn database() -> DbConn {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        // every connection has its own in-memory database
+        options.max_connections(1).min_connections(1);
+        let db = Database::connect(options).await.unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+        db.execute(backend.build(&schema.create_table_from_entity(task_active::Entity)))
+            .await
+            .unwrap();
+        db
+    }
+
+    fn task(status: sea_orm_active_enums::TaskActiveStatus) -> task_active::Model {
+        let now = chrono::Utc::now().naive_utc();
+        task_active::Model {
+            id: uuid::Uuid::new_v4(),
+            source: "OPENQASM 2.0;".to_owned(),
+            result: None,
+            qubits: 2,
+            shots: 100,
+            exec_shots: 0,
+            v_exec_shots: 0,
+            depth: 1,
+            status,
+            created_time: now,
+            updated_time: now,
+            username: "alice".to_owned(),
+            deadline: None,
+            requirements: None,
+            noise: None,
+            source_hash: None,
+            circuit: None,
+            parameters: None,
+            parent_id: None,
+            cutting: None,
+        }
+    }
+
+    async fn add(db: &DbConn, task: task_active::Model) -> task_active::Model {
+        task_active::ActiveModel::from(task)
+            .insert(db)
+            .await
+            .unwrap()
+    }
+
+    async fn add_task(
+        db: &DbConn,
+        status: sea_orm_active_enums::TaskActiveStatus,
+    ) -> task_active::Model {
+        add(db, task(status)).await
+    }
+
+    /// Add a waiting task with the given deadline in seconds from the given
+    /// time and virtual executed shots.
+    async fn add_waiting(
+        db: &DbConn,
+        now: chrono::NaiveDateTime,
+        deadline: Option<i64>,
+        v_exec_shots: i32,
+    ) -> uuid::Uuid {
+        let task = task_active::Model {
+            deadline: deadline.map(|seconds| now + chrono::TimeDelta::seconds(seconds)),
+            v_exec_shots,
+            ..task(sea_orm_active_enums::TaskActiveStatus::Waiting)
+        };
+        add(db, task).await.id
+    }
+
+    #[tokio::test]
+    async fn claim_task_only_claims_a_waiting_task_once() {
+        let db = database().await;
+        let task = add_task(&db, sea_orm_active_enums::TaskActiveStatus::Waiting).await;
+        assert!(TaskActive::claim_task(&db, task.id).await.unwrap());
+        // a second dispatch of the same snapshot loses the claim
+        assert!(!TaskActive::claim_task(&db, task.id).await.unwrap());
+        let claimed = TaskActive::get_task(&db, task.id).await.unwrap().unwrap();
+        assert_eq!(
+            claimed.status,
+            sea_orm_active_enums::TaskActiveStatus::Running
+        );
+
+        let running = add_task(&db, sea_orm_active_enums::TaskActiveStatus::Running).await;
+        assert!(!TaskActive::claim_task(&db, running.id).await.unwrap());
+        assert!(!TaskActive::claim_task(&db, uuid::Uuid::new_v4())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn edf_orders_by_deadline_then_virtual_shots() {
+        let db = database().await;
+        let now = chrono::Utc::now().naive_utc();
+        let none_fewer = add_waiting(&db, now, None, 10).await;
+        let late = add_waiting(&db, now, Some(600), 0).await;
+        let none_more = add_waiting(&db, now, None, 50).await;
+        let early_more = add_waiting(&db, now, Some(60), 40).await;
+        let early_fewer = add_waiting(&db, now, Some(60), 20).await;
+        add(
+            &db,
+            task_active::Model {
+                deadline: Some(now),
+                ..task(sea_orm_active_enums::TaskActiveStatus::Running)
+            },
+        )
+        .await;
+
+        let order: Vec<uuid::Uuid> = TaskActive::get_edf_tasks(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|task| task.id)
+            .collect();
+        // the tasks without deadline are the last, the running task is left out
+        assert_eq!(
+            order,
+            [early_fewer, early_more, late, none_fewer, none_more]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_waiting_tasks_past_their_deadline_expire() {
+        let db = database().await;
+        let now = chrono::Utc::now().naive_utc();
+        let expired = add_waiting(&db, now, Some(-1), 0).await;
+        add_waiting(&db, now, Some(60), 0).await;
+        add_waiting(&db, now, None, 0).await;
+        add(
+            &db,
+            task_active::Model {
+                deadline: Some(now - chrono::TimeDelta::seconds(1)),
+                ..task(sea_orm_active_enums::TaskActiveStatus::Running)
+            },
+        )
+        .await;
+
+        let tasks = TaskActive::get_expired_tasks(&db, now).await.unwrap();
+        assert_eq!(
+            tasks.into_iter().map(|task| task.id).collect::<Vec<_>>(),
+            [expired]
+        );
+    }
+}
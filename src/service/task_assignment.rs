@@ -1,8 +1,32 @@
 use crate::entity::*;
-use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, DbConn, EntityTrait, QueryFilter};
+use crate::error::{Error, Result};
+use chrono::NaiveDateTime;
+use migration::{Alias, Expr, Order};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, DbConn, EntityTrait, FromQueryResult,
+    QueryFilter, QueryOrder, QuerySelect, QueryTrait,
+};
+use sea_orm_active_enums::AssignmentStatus;
 
 pub struct TaskAssignment;
 
+/// ## Agent Assignments
+/// The assignments of one physical agent, aggregated from the task
+/// assignments.
+/// - `agent_id`: The physical agent.
+/// - `running`: The number of running assignments.
+/// - `succeeded`: The number of succeeded assignments since the given time.
+/// - `failed`: The number of failed assignments since the given time.
+/// - `shots`: The shots of the succeeded assignments since the given time.
+#[derive(FromQueryResult, Debug, Default)]
+pub struct AgentAssignments {
+    pub agent_id: uuid::Uuid,
+    pub running: i64,
+    pub succeeded: i64,
+    pub failed: i64,
+    pub shots: i64,
+}
+
 impl TaskAssignment {
     /// Add a new task assignment to the database. This table is used to record
     /// the assignment of tasks to agents. The task assignment includes the task
@@ -10,16 +34,17 @@ impl TaskAssignment {
     pub async fn add_assignment(
         db: &DbConn,
         data: task_assignment::Model,
-    ) -> Result<task_assignment::Model, sea_orm::prelude::DbErr> {
-        task_assignment::ActiveModel {
+    ) -> Result<task_assignment::Model> {
+        Ok(task_assignment::ActiveModel {
             id: ActiveValue::set(data.id.to_owned()),
             task_id: ActiveValue::set(data.task_id.to_owned()),
             agent_id: ActiveValue::set(data.agent_id.to_owned()),
             shots: ActiveValue::set(data.shots.to_owned()),
             status: ActiveValue::set(data.status.to_owned()),
+            created_time: ActiveValue::set(data.created_time.to_owned()),
         }
         .insert(db)
-        .await
+        .await?)
     }
 
     /// Update the status of the task assignment. The status can be `Runnig`,
@@ -28,36 +53,104 @@ impl TaskAssignment {
         db: &DbConn,
         assign_id: uuid::Uuid,
         status: sea_orm_active_enums::AssignmentStatus,
-    ) -> Result<task_assignment::Model, sea_orm::prelude::DbErr> {
+    ) -> Result<task_assignment::Model> {
         let mut assignment: task_assignment::ActiveModel =
             task_assignment::Entity::find_by_id(assign_id)
                 .one(db)
                 .await?
-                .unwrap()
+                .ok_or_else(|| Error::not_found("task assignment", assign_id))?
                 .into();
         assignment.status = ActiveValue::set(status);
-        assignment.update(db).await
+        Ok(assignment.update(db).await?)
+    }
+
+    /// Mark the running assignments of the task as failed. This function is
+    /// used to requeue the task whose chunk was dispatched by a replica that
+    /// is gone.
+    pub async fn fail_running_assignments(db: &DbConn, task_id: uuid::Uuid) -> Result<u64> {
+        Ok(task_assignment::Entity::update_many()
+            .col_expr(
+                task_assignment::Column::Status,
+                Expr::value(AssignmentStatus::Failed),
+            )
+            .filter(task_assignment::Column::TaskId.eq(task_id))
+            .filter(task_assignment::Column::Status.eq(AssignmentStatus::Running))
+            .exec(db)
+            .await?
+            .rows_affected)
     }
 
     /// Get the task assignment with the given task id.
     pub async fn get_assignment_by_task(
         db: &DbConn,
         task_id: uuid::Uuid,
-    ) -> Result<Vec<task_assignment::Model>, sea_orm::prelude::DbErr> {
-        task_assignment::Entity::find()
+    ) -> Result<Vec<task_assignment::Model>> {
+        Ok(task_assignment::Entity::find()
             .filter(task_assignment::Column::TaskId.eq(task_id))
             .all(db)
-            .await
+            .await?)
     }
 
-    /// Get the task assignment with the given agent id.
+    /// Get the task assignment with the given agent id, the latest first. The
+    /// assignments made before their creation time was recorded come last.
     pub async fn get_assignment_by_agent(
         db: &DbConn,
         agent_id: uuid::Uuid,
-    ) -> Result<Vec<task_assignment::Model>, sea_orm::prelude::DbErr> {
-        task_assignment::Entity::find()
+    ) -> Result<Vec<task_assignment::Model>> {
+        Ok(task_assignment::Entity::find()
             .filter(task_assignment::Column::AgentId.eq(agent_id))
+            .order_by(
+                Expr::col(task_assignment::Column::CreatedTime).is_null(),
+                Order::Asc,
+            )
+            .order_by_desc(task_assignment::Column::CreatedTime)
+            .all(db)
+            .await?)
+    }
+
+    /// Get the assignments of every physical agent, or of the given agent
+    /// only: the running ones, and the ones created since the given time by
+    /// their status. The agents without such assignments are not returned.
+    pub async fn get_assignments_by_agents(
+        db: &DbConn,
+        agent_id: Option<uuid::Uuid>,
+        since: NaiveDateTime,
+    ) -> Result<Vec<AgentAssignments>> {
+        let count = |status: AssignmentStatus| {
+            Expr::expr(Expr::case(task_assignment::Column::Status.eq(status), 1).finally(0))
+                .sum()
+                .cast_as(Alias::new("bigint"))
+        };
+        let recent = task_assignment::Column::CreatedTime.gte(since);
+        Ok(task_assignment::Entity::find()
+            .select_only()
+            .column(task_assignment::Column::AgentId)
+            .column_as(count(AssignmentStatus::Running), "running")
+            .column_as(count(AssignmentStatus::Succeeded), "succeeded")
+            .column_as(count(AssignmentStatus::Failed), "failed")
+            .column_as(
+                Expr::expr(
+                    Expr::case(
+                        task_assignment::Column::Status.eq(AssignmentStatus::Succeeded),
+                        Expr::col(task_assignment::Column::Shots).if_null(0),
+                    )
+                    .finally(0),
+                )
+                .sum()
+                .cast_as(Alias::new("bigint")),
+                "shots",
+            )
+            .filter(
+                Condition::any()
+                    .add(task_assignment::Column::Status.eq(AssignmentStatus::Running))
+                    .add(recent),
+            )
+            .apply_if(agent_id, |query, agent_id| {
+                query.filter(task_assignment::Column::AgentId.eq(agent_id))
+            })
+            .group_by(task_assignment::Column::AgentId)
+            .into_model::<AgentAssignments>()
             .all(db)
-            .await
+            .await?)
     }
 }
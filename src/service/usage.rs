@@ -0,0 +1,163 @@
+use crate::entity::*;
+use crate::error::Result;
+use chrono::NaiveDateTime;
+use migration::{Alias, Expr};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DbConn, EntityTrait, FromQueryResult, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub struct Usage;
+
+/// ## User Usage
+/// The usage of one user over a time range, aggregated from the
+/// [usage ledger](crate::entity::usage_record::Model).
+/// - `username`: The user of the tasks.
+/// - `assignments`: The number of completed assignments.
+/// - `shots`: The executed shots.
+/// - `wall_time_seconds`: The wall time of the assignments in seconds.
+/// - `qubit_seconds`: The sum of qubits times wall time of the assignments.
+#[derive(FromQueryResult, Serialize, Debug, ToSchema)]
+pub struct UserUsage {
+    pub username: String,
+    pub assignments: i64,
+    pub shots: i64,
+    pub wall_time_seconds: f64,
+    pub qubit_seconds: f64,
+}
+
+impl Usage {
+    /// Add a new record to the usage ledger. A record is added for every
+    /// completed task assignment.
+    pub async fn add_usage(db: &DbConn, data: usage_record::Model) -> Result<usage_record::Model> {
+        Ok(usage_record::ActiveModel {
+            id: ActiveValue::set(data.id),
+            assignment_id: ActiveValue::set(data.assignment_id),
+            task_id: ActiveValue::set(data.task_id),
+            agent_id: ActiveValue::set(data.agent_id),
+            username: ActiveValue::set(data.username),
+            shots: ActiveValue::set(data.shots),
+            qubits: ActiveValue::set(data.qubits),
+            depth: ActiveValue::set(data.depth),
+            wall_time_ms: ActiveValue::set(data.wall_time_ms),
+            started_time: ActiveValue::set(data.started_time),
+            finished_time: ActiveValue::set(data.finished_time),
+        }
+        .insert(db)
+        .await?)
+    }
+
+    /// Get the executed shots and the used qubit-seconds of the user since
+    /// the given time. This function is used to check the quota of the user.
+    pub async fn get_user_usage_since(
+        db: &DbConn,
+        username: &str,
+        since: NaiveDateTime,
+    ) -> Result<(i64, i64)> {
+        let usage: Option<(Option<i64>, Option<i64>)> = usage_record::Entity::find()
+            .select_only()
+            .column_as(
+                Expr::col(usage_record::Column::Shots)
+                    .sum()
+                    .cast_as(Alias::new("bigint")),
+                "shots",
+            )
+            .column_as(
+                Expr::expr(
+                    Expr::col(usage_record::Column::Qubits)
+                        .mul(Expr::col(usage_record::Column::WallTimeMs)),
+                )
+                .sum()
+                .cast_as(Alias::new("bigint")),
+                "qubit_ms",
+            )
+            .filter(usage_record::Column::Username.eq(username))
+            .filter(usage_record::Column::FinishedTime.gte(since))
+            .into_tuple()
+            .one(db)
+            .await?;
+        let (shots, qubit_ms) = usage.unwrap_or_default();
+        Ok((shots.unwrap_or(0), qubit_ms.unwrap_or(0) / 1000))
+    }
+
+    /// Get the usage of every user whose assignments finished in the time
+    /// range `[from, to)`. If the username is given, only the usage of this
+    /// user is returned.
+    pub async fn get_usage_by_user(
+        db: &DbConn,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        username: Option<&str>,
+    ) -> Result<Vec<UserUsage>> {
+        let mut query = usage_record::Entity::find()
+            .select_only()
+            .column(usage_record::Column::Username)
+            .column_as(Expr::col(usage_record::Column::Id).count(), "assignments")
+            .column_as(
+                Expr::col(usage_record::Column::Shots)
+                    .sum()
+                    .cast_as(Alias::new("bigint")),
+                "shots",
+            )
+            .column_as(
+                Expr::expr(
+                    Expr::col(usage_record::Column::WallTimeMs)
+                        .sum()
+                        .cast_as(Alias::new("double precision")),
+                )
+                .div(1000.0),
+                "wall_time_seconds",
+            )
+            .column_as(
+                Expr::expr(
+                    Expr::expr(
+                        Expr::col(usage_record::Column::Qubits)
+                            .mul(Expr::col(usage_record::Column::WallTimeMs)),
+                    )
+                    .sum()
+                    .cast_as(Alias::new("double precision")),
+                )
+                .div(1000.0),
+                "qubit_seconds",
+            )
+            .filter(usage_record::Column::FinishedTime.gte(from))
+            .filter(usage_record::Column::FinishedTime.lt(to));
+        if let Some(username) = username {
+            query = query.filter(usage_record::Column::Username.eq(username));
+        }
+        Ok(query
+            .group_by(usage_record::Column::Username)
+            .order_by_asc(usage_record::Column::Username)
+            .into_model::<UserUsage>()
+            .all(db)
+            .await?)
+    }
+
+    /// Get the latest records of all the agents, at most `limit`. This
+    /// function is used to estimate the qubit-seconds of a new task before it
+    /// is placed on an agent.
+    pub async fn get_latest_usages(db: &DbConn, limit: u64) -> Result<Vec<usage_record::Model>> {
+        Ok(usage_record::Entity::find()
+            .order_by_desc(usage_record::Column::FinishedTime)
+            .limit(limit)
+            .all(db)
+            .await?)
+    }
+
+    /// Get the latest records of the agent, at most `limit`. This function is
+    /// used to learn the throughput of the agent.
+    pub async fn get_agent_usages(
+        db: &DbConn,
+        agent_id: uuid::Uuid,
+        limit: u64,
+    ) -> Result<Vec<usage_record::Model>> {
+        Ok(usage_record::Entity::find()
+            .filter(usage_record::Column::AgentId.eq(agent_id))
+            .order_by_desc(usage_record::Column::FinishedTime)
+            .limit(limit)
+            .all(db)
+            .await?)
+    }
+}
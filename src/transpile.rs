@@ -0,0 +1,1626 @@
+//! The module that transpiles the OpenQASM 2 circuits for the agents. The
+//! transpiler parses the circuit, inlines the user defined gates and the
+//! register broadcasts, then:
+//! - [decomposes](Circuit::decompose) every gate that is not in the gate set
+//!   of the target agent into the gates of the set, down to `u3` and `cx`,
+//!   which are further rewritten to `u`, `rz`/`ry`, `rz`/`sx` or `rz`/`rx`
+//!   and `cz` if the agent only supports those;
+//! - [optimizes](Circuit::optimize) the circuit by cancelling the adjacent
+//!   inverse gates, e.g. `h q; h q;` or `s q; sdg q;`, and merging the
+//!   adjacent rotations around the same axis and the adjacent `u3` gates;
+//! - computes the [depth](Circuit::depth) of the result.
+//!
+//! The global phase is not kept, it does not change the measured
+//! distribution. A circuit that can not be parsed, e.g. an OpenQASM 3 program
+//! that is sent as is to the agents that support it, is not transpiled. A
+//! gate definition can only call the gates defined before it, and the size of
+//! the parsed circuit is limited, so a recursive or exponentially nested
+//! definition is an error rather than an unbounded expansion.
+
+use crate::error::{Error, Result};
+use crate::qasm::{blank_comments, first_word, is_qasm3, split_statements};
+use log::info;
+use std::collections::{BTreeSet, HashMap};
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Neg};
+
+/// The tolerance of the angles and the matrix entries.
+const EPSILON: f64 = 1e-9;
+
+/// The maximum number of the qubits, and of the classical bits, of all the
+/// registers of a circuit.
+const MAX_BITS: usize = 1024;
+
+/// The maximum number of the operations of a circuit after the user defined
+/// gates and the register broadcasts are inlined.
+const MAX_OPERATIONS: usize = 200_000;
+
+/// The maximum nesting of the user defined gates.
+const MAX_NESTING: usize = 64;
+
+/// The gates of `qelib1.inc` with the number of parameters and qubits.
+const GATES: [(&str, usize, usize); 30] = [
+    ("u3", 3, 1),
+    ("u2", 2, 1),
+    ("u1", 1, 1),
+    ("u", 3, 1),
+    ("id", 0, 1),
+    ("x", 0, 1),
+    ("y", 0, 1),
+    ("z", 0, 1),
+    ("h", 0, 1),
+    ("s", 0, 1),
+    ("sdg", 0, 1),
+    ("t", 0, 1),
+    ("tdg", 0, 1),
+    ("sx", 0, 1),
+    ("sxdg", 0, 1),
+    ("rx", 1, 1),
+    ("ry", 1, 1),
+    ("rz", 1, 1),
+    ("cx", 0, 2),
+    ("cy", 0, 2),
+    ("cz", 0, 2),
+    ("ch", 0, 2),
+    ("swap", 0, 2),
+    ("crx", 1, 2),
+    ("cry", 1, 2),
+    ("crz", 1, 2),
+    ("cu1", 1, 2),
+    ("cu3", 3, 2),
+    ("rzz", 1, 2),
+    ("ccx", 0, 3),
+];
+
+/// The gates that are their own inverse.
+const SELF_INVERSE_GATES: [&str; 11] = [
+    "x", "y", "z", "h", "cx", "cy", "cz", "ch", "swap", "ccx", "cswap",
+];
+
+/// The pairs of gates that are the inverse of each other.
+const INVERSE_GATES: [(&str, &str); 3] = [("s", "sdg"), ("t", "tdg"), ("sx", "sxdg")];
+
+/// The rotations whose angles are added when they are adjacent, with the
+/// period of the angle.
+const ROTATION_GATES: [(&str, f64); 7] = [
+    ("rx", 2.0 * PI),
+    ("ry", 2.0 * PI),
+    ("rz", 2.0 * PI),
+    ("u1", 2.0 * PI),
+    ("cu1", 2.0 * PI),
+    ("rzz", 2.0 * PI),
+    ("crz", 4.0 * PI),
+];
+
+/// A gate applied to the qubits, optionally conditioned on the value of a
+/// classical register.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gate {
+    pub name: String,
+    pub params: Vec<f64>,
+    pub qubits: Vec<usize>,
+    pub condition: Option<(usize, u64)>,
+}
+
+impl Gate {
+    /// A gate with the same condition as this one.
+    pub fn derive(&self, name: &str, params: Vec<f64>, qubits: Vec<usize>) -> Gate {
+        Gate {
+            name: name.to_owned(),
+            params,
+            qubits,
+            condition: self.condition,
+        }
+    }
+}
+
+/// An operation of the circuit on the qubits by index.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Gate(Gate),
+    Measure(usize, usize),
+    Reset(usize),
+    Barrier(Vec<usize>),
+}
+
+impl Operation {
+    pub fn qubits(&self) -> Vec<usize> {
+        match self {
+            Operation::Gate(gate) => gate.qubits.clone(),
+            Operation::Measure(qubit, _) | Operation::Reset(qubit) => vec![*qubit],
+            Operation::Barrier(qubits) => qubits.clone(),
+        }
+    }
+}
+
+/// A gate defined by the `gate` statement of the circuit. Its body can only
+/// call the gates defined before it, so a definition is never recursive.
+struct GateDefinition {
+    index: usize,
+    params: Vec<String>,
+    qubits: Vec<String>,
+    body: Vec<String>,
+    /// The number of the operations the definition is inlined into.
+    size: usize,
+}
+
+/// ## Circuit
+/// The circuit parsed from an OpenQASM 2 program, the qubits and the
+/// classical bits of all the registers are numbered in the order of the
+/// declarations.
+pub struct Circuit {
+    pub qregs: Vec<(String, usize)>,
+    pub cregs: Vec<(String, usize)>,
+    pub operations: Vec<Operation>,
+}
+
+impl Circuit {
+    /// ## Parse
+    /// Parse the OpenQASM 2 program. If the program uses an unknown gate, an
+    /// include file other than `qelib1.inc` or an `opaque` gate, or it is
+    /// larger than the limits of [MAX_BITS], [MAX_OPERATIONS] and
+    /// [MAX_NESTING], return the reason why it can not be parsed.
+    pub fn parse(source: &str) -> std::result::Result<Circuit, String> {
+        let source = blank_comments(source);
+        let mut circuit = Circuit {
+            qregs: vec![],
+            cregs: vec![],
+            operations: vec![],
+        };
+        let mut definitions = HashMap::<String, GateDefinition>::new();
+
+        for (_, statement) in split_statements(&source) {
+            let statement = statement.trim();
+            let body = statement.strip_suffix(';').unwrap_or(statement).trim();
+            if body.is_empty() {
+                continue;
+            }
+            let keyword = first_word(body);
+            let rest = body[keyword.len()..].trim();
+            match keyword {
+                "OPENQASM" => {}
+                "include" if rest == "\"qelib1.inc\"" => {}
+                "qreg" => circuit.qregs.push(parse_register(&circuit.qregs, rest)?),
+                "creg" => circuit.cregs.push(parse_register(&circuit.cregs, rest)?),
+                "gate" => {
+                    let (name, mut definition) = parse_definition(definitions.len(), rest)?;
+                    definition.size = definition
+                        .body
+                        .iter()
+                        .map(|statement| {
+                            definitions
+                                .get(gate_name(first_word(statement)))
+                                .map_or(1, |inner| inner.size)
+                        })
+                        .fold(0, usize::saturating_add);
+                    if definitions.contains_key(&name) {
+                        return Err(format!("gate {} is defined twice", name));
+                    }
+                    definitions.insert(name, definition);
+                }
+                "measure" => {
+                    let (qubits, clbits) = rest
+                        .split_once("->")
+                        .ok_or_else(|| format!("invalid measure {}", rest))?;
+                    let qubits = circuit.qubit_argument(qubits.trim())?;
+                    let clbits = circuit.clbit_argument(clbits.trim())?;
+                    if qubits.len() != clbits.len() {
+                        return Err(format!("register sizes differ in measure {}", rest));
+                    }
+                    circuit.operations.extend(
+                        qubits
+                            .into_iter()
+                            .zip(clbits)
+                            .map(|(qubit, clbit)| Operation::Measure(qubit, clbit)),
+                    );
+                }
+                "reset" => {
+                    for qubit in circuit.qubit_argument(rest)? {
+                        circuit.operations.push(Operation::Reset(qubit));
+                    }
+                }
+                "barrier" => {
+                    let mut qubits = vec![];
+                    for argument in rest.split(',') {
+                        qubits.extend(circuit.qubit_argument(argument.trim())?);
+                    }
+                    circuit.operations.push(Operation::Barrier(qubits));
+                }
+                "if" => {
+                    let (condition, call) = rest
+                        .strip_prefix('(')
+                        .and_then(|rest| rest.split_once(')'))
+                        .ok_or_else(|| format!("invalid condition {}", rest))?;
+                    let (register, value) = condition
+                        .split_once("==")
+                        .ok_or_else(|| format!("invalid condition {}", condition))?;
+                    let register = circuit
+                        .cregs
+                        .iter()
+                        .position(|(name, _)| name == register.trim())
+                        .ok_or_else(|| format!("unknown register {}", register.trim()))?;
+                    let value = value
+                        .trim()
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid condition value {}", value.trim()))?;
+                    circuit.apply_call(&definitions, call.trim(), Some((register, value)))?;
+                }
+                _ => circuit.apply_call(&definitions, body, None)?,
+            }
+            if circuit.operations.len() > MAX_OPERATIONS {
+                return Err(format!("more than {} operations", MAX_OPERATIONS));
+            }
+        }
+        Ok(circuit)
+    }
+
+    /// The qubits of the argument `q[i]` or the whole register `q`.
+    fn qubit_argument(&self, argument: &str) -> std::result::Result<Vec<usize>, String> {
+        register_argument(&self.qregs, argument)
+    }
+
+    /// The classical bits of the argument `c[i]` or the whole register `c`.
+    fn clbit_argument(&self, argument: &str) -> std::result::Result<Vec<usize>, String> {
+        register_argument(&self.cregs, argument)
+    }
+
+    /// Apply the gate call at the top level of the program, the whole
+    /// register arguments are broadcast.
+    fn apply_call(
+        &mut self,
+        definitions: &HashMap<String, GateDefinition>,
+        call: &str,
+        condition: Option<(usize, u64)>,
+    ) -> std::result::Result<(), String> {
+        let (name, params, arguments) = split_call(call)?;
+        let params = params
+            .iter()
+            .map(|param| evaluate(param, &HashMap::new()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let arguments = arguments
+            .iter()
+            .map(|argument| self.qubit_argument(argument))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let size = arguments.iter().map(Vec::len).max().unwrap_or(1);
+        for i in 0..size {
+            let qubits = arguments
+                .iter()
+                .map(|qubits| match qubits.len() {
+                    1 => Ok(qubits[0]),
+                    len if len == size => Ok(qubits[i]),
+                    _ => Err(format!("register sizes differ in {}", call)),
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let call = Call {
+                name,
+                params: params.clone(),
+                qubits,
+                condition,
+            };
+            self.add_gate(definitions, call, usize::MAX, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Add the gate to the circuit, a user defined gate is inlined. Only the
+    /// definitions before the `scope` index are visible, that is all of them
+    /// at the top level and the earlier ones in the body of a definition, so
+    /// a gate can not call itself and a definition can shadow a gate of
+    /// `qelib1.inc` that it calls.
+    fn add_gate(
+        &mut self,
+        definitions: &HashMap<String, GateDefinition>,
+        call: Call,
+        scope: usize,
+        nesting: usize,
+    ) -> std::result::Result<(), String> {
+        let Call {
+            name,
+            params,
+            qubits,
+            condition,
+        } = call;
+        let name = gate_name(name);
+        let definition = definitions
+            .get(name)
+            .filter(|definition| definition.index < scope);
+        let size = definition.map_or(1, |definition| definition.size);
+        if size > MAX_OPERATIONS - self.operations.len().min(MAX_OPERATIONS) {
+            return Err(format!("more than {} operations", MAX_OPERATIONS));
+        }
+
+        if let Some(definition) = definition {
+            if nesting >= MAX_NESTING {
+                return Err(format!("gates are nested deeper than {}", MAX_NESTING));
+            }
+            if definition.params.len() != params.len() || definition.qubits.len() != qubits.len() {
+                return Err(format!("wrong number of arguments of gate {}", name));
+            }
+            let env: HashMap<String, f64> = definition.params.iter().cloned().zip(params).collect();
+            let mapping: HashMap<&str, usize> = definition
+                .qubits
+                .iter()
+                .map(String::as_str)
+                .zip(qubits)
+                .collect();
+            let map_qubit = |argument: &str| {
+                mapping
+                    .get(argument.trim())
+                    .copied()
+                    .ok_or_else(|| format!("unknown qubit {} in gate {}", argument, name))
+            };
+
+            for statement in &definition.body {
+                if first_word(statement) == "barrier" {
+                    let qubits = statement["barrier".len()..]
+                        .split(',')
+                        .map(map_qubit)
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+                    self.operations.push(Operation::Barrier(qubits));
+                    continue;
+                }
+                let (inner, inner_params, arguments) = split_call(statement)?;
+                let inner_params = inner_params
+                    .iter()
+                    .map(|param| evaluate(param, &env))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let inner_qubits = arguments
+                    .iter()
+                    .map(|argument| map_qubit(argument))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let inner = Call {
+                    name: inner,
+                    params: inner_params,
+                    qubits: inner_qubits,
+                    condition,
+                };
+                self.add_gate(definitions, inner, definition.index, nesting + 1)?;
+            }
+            return Ok(());
+        }
+
+        let (_, param_count, qubit_count) = GATES
+            .iter()
+            .chain([("cswap", 0, 3)].iter())
+            .find(|(gate, _, _)| *gate == name)
+            .ok_or_else(|| format!("unknown gate {}", name))?;
+        if *param_count != params.len() || *qubit_count != qubits.len() {
+            return Err(format!("wrong number of arguments of gate {}", name));
+        }
+        if qubits.iter().collect::<BTreeSet<_>>().len() != qubits.len() {
+            return Err(format!("duplicate qubits of gate {}", name));
+        }
+        self.operations.push(Operation::Gate(Gate {
+            name: name.to_owned(),
+            params,
+            qubits,
+            condition,
+        }));
+        Ok(())
+    }
+
+    /// ## Decompose
+    /// Decompose every gate that is not in the basis into the gates of the
+    /// basis. If a gate can not be decomposed, return its name.
+    pub fn decompose(&mut self, basis: &BTreeSet<String>) -> std::result::Result<(), String> {
+        let mut operations = Vec::with_capacity(self.operations.len());
+        for operation in std::mem::take(&mut self.operations) {
+            match operation {
+                Operation::Gate(gate) => lower(gate, basis, &mut operations)?,
+                operation => operations.push(operation),
+            }
+        }
+        self.operations = operations;
+        Ok(())
+    }
+
+    /// ## Optimize
+    /// Remove the `id` gates, cancel the adjacent inverse gates and merge the
+    /// adjacent rotations on the same qubits. Two gates are adjacent if no
+    /// other operation is applied to their qubits between them, the
+    /// conditioned gates are never changed.
+    pub fn optimize(&mut self) {
+        let mut operations: Vec<Option<Operation>> = Vec::with_capacity(self.operations.len());
+        // the indexes of the kept operations on every qubit
+        let mut stacks: Vec<Vec<usize>> = vec![vec![]; self.qubit_count()];
+
+        for operation in std::mem::take(&mut self.operations) {
+            if let Operation::Gate(gate) = &operation {
+                if gate.condition.is_none() {
+                    if gate.name == "id" {
+                        continue;
+                    }
+                    let previous = stacks[gate.qubits[0]].last().copied().filter(|&i| {
+                        gate.qubits
+                            .iter()
+                            .all(|&qubit| stacks[qubit].last() == Some(&i))
+                    });
+                    if let Some(i) = previous {
+                        if let Some(Operation::Gate(previous)) = &mut operations[i] {
+                            let combined = previous.condition.is_none()
+                                && previous.qubits.len() == gate.qubits.len()
+                                && combine(previous, gate);
+                            if combined {
+                                if is_identity(previous) {
+                                    for &qubit in &previous.qubits {
+                                        stacks[qubit].pop();
+                                    }
+                                    operations[i] = None;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+            for qubit in operation.qubits() {
+                stacks[qubit].push(operations.len());
+            }
+            operations.push(Some(operation));
+        }
+        self.operations = operations.into_iter().flatten().collect();
+    }
+
+    /// ## Depth
+    /// The number of layers of the circuit, the barriers only align their
+    /// qubits.
+    pub fn depth(&self) -> usize {
+        let mut depths = vec![0; self.qubit_count()];
+        for operation in &self.operations {
+            let qubits = operation.qubits();
+            let depth = qubits.iter().map(|&qubit| depths[qubit]).max().unwrap_or(0);
+            let depth = match operation {
+                Operation::Barrier(_) => depth,
+                _ => depth + 1,
+            };
+            for qubit in qubits {
+                depths[qubit] = depth;
+            }
+        }
+        depths.into_iter().max().unwrap_or(0)
+    }
+
+    /// ## To QASM
+    /// Write the circuit as an OpenQASM 2 program.
+    pub fn to_qasm(&self) -> String {
+        let qubits = register_names(&self.qregs);
+        let clbits = register_names(&self.cregs);
+        let mut lines = vec![
+            "OPENQASM 2.0;".to_owned(),
+            "include \"qelib1.inc\";".to_owned(),
+        ];
+        lines.extend(
+            self.qregs
+                .iter()
+                .map(|(name, size)| format!("qreg {}[{}];", name, size)),
+        );
+        lines.extend(
+            self.cregs
+                .iter()
+                .map(|(name, size)| format!("creg {}[{}];", name, size)),
+        );
+        for operation in &self.operations {
+            lines.push(match operation {
+                Operation::Gate(gate) => {
+                    let condition = match gate.condition {
+                        Some((register, value)) => {
+                            format!("if({}=={}) ", self.cregs[register].0, value)
+                        }
+                        None => String::new(),
+                    };
+                    let params = match gate.params.is_empty() {
+                        true => String::new(),
+                        false => format!(
+                            "({})",
+                            gate.params
+                                .iter()
+                                .map(f64::to_string)
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        ),
+                    };
+                    let arguments = gate
+                        .qubits
+                        .iter()
+                        .map(|&qubit| qubits[qubit].as_str())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    format!("{}{}{} {};", condition, gate.name, params, arguments)
+                }
+                Operation::Measure(qubit, clbit) => {
+                    format!("measure {} -> {};", qubits[*qubit], clbits[*clbit])
+                }
+                Operation::Reset(qubit) => format!("reset {};", qubits[*qubit]),
+                Operation::Barrier(barrier) => format!(
+                    "barrier {};",
+                    barrier
+                        .iter()
+                        .map(|&qubit| qubits[qubit].as_str())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            });
+        }
+        lines.join("\n") + "\n"
+    }
+
+    /// The number of qubits of all the registers.
+    pub fn qubit_count(&self) -> usize {
+        self.qregs.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Parse the register declaration `name[size]` after the given registers,
+/// all of them have at most [MAX_BITS] bits.
+fn parse_register(
+    registers: &[(String, usize)],
+    declaration: &str,
+) -> std::result::Result<(String, usize), String> {
+    let (name, size) = declaration
+        .strip_suffix(']')
+        .and_then(|declaration| declaration.split_once('['))
+        .and_then(|(name, size)| Some((name.trim().to_owned(), size.trim().parse::<usize>().ok()?)))
+        .ok_or_else(|| format!("invalid register {}", declaration))?;
+    let bits: usize = registers.iter().map(|(_, size)| size).sum();
+    if size > MAX_BITS - bits {
+        return Err(format!("registers have more than {} bits", MAX_BITS));
+    }
+    Ok((name, size))
+}
+
+/// The name of the gate of `qelib1.inc` that the builtin gate is.
+fn gate_name(name: &str) -> &str {
+    match name {
+        "U" => "u3",
+        "CX" => "cx",
+        "p" => "u1",
+        name => name,
+    }
+}
+
+/// The gate call to add to the circuit.
+struct Call<'a> {
+    name: &'a str,
+    params: Vec<f64>,
+    qubits: Vec<usize>,
+    condition: Option<(usize, u64)>,
+}
+
+/// Parse the gate definition `name(params) qubits { body }`, which is the
+/// definition of the given index in the program.
+fn parse_definition(
+    index: usize,
+    definition: &str,
+) -> std::result::Result<(String, GateDefinition), String> {
+    let (header, body) = definition
+        .split_once('{')
+        .ok_or_else(|| format!("invalid gate definition {}", definition))?;
+    let (name, params, qubits) = split_call(header.trim())?;
+    let body = body.trim_end().strip_suffix('}').unwrap_or(body);
+    Ok((
+        name.to_owned(),
+        GateDefinition {
+            index,
+            params: params.into_iter().map(str::to_owned).collect(),
+            qubits: qubits.into_iter().map(str::to_owned).collect(),
+            body: body
+                .split(';')
+                .map(str::trim)
+                .filter(|statement| !statement.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            size: 0,
+        },
+    ))
+}
+
+/// Split the gate call `name(params) arguments` into the name, the parameter
+/// expressions and the arguments.
+fn split_call(call: &str) -> std::result::Result<(&str, Vec<&str>, Vec<&str>), String> {
+    let name = first_word(call);
+    if name.is_empty() {
+        return Err(format!("invalid statement {}", call));
+    }
+    let rest = call[name.len()..].trim_start();
+    let (params, arguments) = match rest.strip_prefix('(') {
+        Some(rest) => {
+            let mut depth = 1;
+            let end = rest
+                .find(|c| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                })
+                .ok_or_else(|| format!("unbalanced parentheses in {}", call))?;
+            let mut params = vec![];
+            let (mut start, mut depth) = (0, 0);
+            for (i, c) in rest[..end].char_indices() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    ',' if depth == 0 => {
+                        params.push(rest[start..i].trim());
+                        start = i + 1;
+                    }
+                    _ => {}
+                }
+            }
+            params.push(rest[start..end].trim());
+            params.retain(|param| !param.is_empty());
+            (params, &rest[end + 1..])
+        }
+        None => (vec![], rest),
+    };
+    Ok((
+        name,
+        params,
+        arguments
+            .split(',')
+            .map(str::trim)
+            .filter(|argument| !argument.is_empty())
+            .collect(),
+    ))
+}
+
+/// The bits of the argument `r[i]` or the whole register `r` by index.
+fn register_argument(
+    registers: &[(String, usize)],
+    argument: &str,
+) -> std::result::Result<Vec<usize>, String> {
+    let (name, index) = match argument.strip_suffix(']') {
+        Some(argument) => {
+            let (name, index) = argument
+                .split_once('[')
+                .ok_or_else(|| format!("invalid argument {}", argument))?;
+            let index = index
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid index in {}", argument))?;
+            (name.trim(), Some(index))
+        }
+        None => (argument, None),
+    };
+
+    let mut offset = 0;
+    for (register, size) in registers {
+        if register == name {
+            return match index {
+                Some(index) if index < *size => Ok(vec![offset + index]),
+                Some(index) => Err(format!("index {} out of range of {}", index, name)),
+                None => Ok((offset..offset + size).collect()),
+            };
+        }
+        offset += size;
+    }
+    Err(format!("unknown register {}", name))
+}
+
+/// The names `r[i]` of all the bits of the registers by index.
+fn register_names(registers: &[(String, usize)]) -> Vec<String> {
+    registers
+        .iter()
+        .flat_map(|(name, size)| (0..*size).map(move |i| format!("{}[{}]", name, i)))
+        .collect()
+}
+
+/// Evaluate the parameter expression with the values of the gate parameters.
+fn evaluate(expression: &str, env: &HashMap<String, f64>) -> std::result::Result<f64, String> {
+    let mut parser = Expression {
+        text: expression.as_bytes(),
+        position: 0,
+        env,
+    };
+    let value = parser.sum()?;
+    parser.skip_whitespace();
+    match parser.position == parser.text.len() {
+        true => Ok(value),
+        false => Err(format!("invalid expression {}", expression)),
+    }
+}
+
+/// The recursive descent parser of the parameter expressions.
+struct Expression<'a> {
+    text: &'a [u8],
+    position: usize,
+    env: &'a HashMap<String, f64>,
+}
+
+impl Expression<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.position).copied()
+    }
+
+    fn error(&self) -> String {
+        format!("invalid expression {}", String::from_utf8_lossy(self.text))
+    }
+
+    fn sum(&mut self) -> std::result::Result<f64, String> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.position += 1;
+                    value += self.product()?;
+                }
+                Some(b'-') => {
+                    self.position += 1;
+                    value -= self.product()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn product(&mut self) -> std::result::Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            match self.peek() {
+                Some(b'*') => {
+                    self.position += 1;
+                    value *= self.unary()?;
+                }
+                Some(b'/') => {
+                    self.position += 1;
+                    value /= self.unary()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> std::result::Result<f64, String> {
+        match self.peek() {
+            Some(b'-') => {
+                self.position += 1;
+                Ok(-self.unary()?)
+            }
+            Some(b'+') => {
+                self.position += 1;
+                self.unary()
+            }
+            _ => {
+                let base = self.primary()?;
+                match self.peek() {
+                    Some(b'^') => {
+                        self.position += 1;
+                        Ok(base.powf(self.unary()?))
+                    }
+                    _ => Ok(base),
+                }
+            }
+        }
+    }
+
+    fn primary(&mut self) -> std::result::Result<f64, String> {
+        match self.peek() {
+            Some(b'(') => {
+                self.position += 1;
+                let value = self.sum()?;
+                match self.peek() {
+                    Some(b')') => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error()),
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => {
+                let start = self.position;
+                while self
+                    .text
+                    .get(self.position)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == b'.')
+                {
+                    self.position += 1;
+                }
+                if matches!(self.text.get(self.position), Some(b'e' | b'E')) {
+                    self.position += 1;
+                    if matches!(self.text.get(self.position), Some(b'+' | b'-')) {
+                        self.position += 1;
+                    }
+                    while self.text.get(self.position).is_some_and(u8::is_ascii_digit) {
+                        self.position += 1;
+                    }
+                }
+                std::str::from_utf8(&self.text[start..self.position])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(|| self.error())
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = self.position;
+                while self
+                    .text
+                    .get(self.position)
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                {
+                    self.position += 1;
+                }
+                let name = std::str::from_utf8(&self.text[start..self.position])
+                    .map_err(|_| self.error())?;
+                let function: Option<fn(f64) -> f64> = match name {
+                    "sin" => Some(f64::sin),
+                    "cos" => Some(f64::cos),
+                    "tan" => Some(f64::tan),
+                    "exp" => Some(f64::exp),
+                    "ln" => Some(f64::ln),
+                    "sqrt" => Some(f64::sqrt),
+                    _ => None,
+                };
+                match function {
+                    Some(function) => {
+                        if self.peek() != Some(b'(') {
+                            return Err(self.error());
+                        }
+                        Ok(function(self.primary()?))
+                    }
+                    None if name == "pi" => Ok(PI),
+                    None => self
+                        .env
+                        .get(name)
+                        .copied()
+                        .ok_or_else(|| format!("unknown parameter {}", name)),
+                }
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+/// Decompose the gate into the gates of the basis.
+fn lower(
+    gate: Gate,
+    basis: &BTreeSet<String>,
+    operations: &mut Vec<Operation>,
+) -> std::result::Result<(), String> {
+    if basis.contains(&gate.name) {
+        operations.push(Operation::Gate(gate));
+        return Ok(());
+    }
+    let gates = match gate.name.as_str() {
+        "u3" => lower_u3(&gate, basis)?,
+        "cx" if basis.contains("cz") => {
+            let (control, target) = (gate.qubits[0], gate.qubits[1]);
+            vec![
+                gate.derive("h", vec![], vec![target]),
+                gate.derive("cz", vec![], vec![control, target]),
+                gate.derive("h", vec![], vec![target]),
+            ]
+        }
+        _ => definition(&gate).ok_or_else(|| gate.name.clone())?,
+    };
+    for gate in gates {
+        lower(gate, basis, operations)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the `u3` gate with the single qubit gates of the basis.
+fn lower_u3(gate: &Gate, basis: &BTreeSet<String>) -> std::result::Result<Vec<Gate>, String> {
+    let (theta, phi, lambda) = (gate.params[0], gate.params[1], gate.params[2]);
+    let qubit = gate.qubits.clone();
+    let has = |name: &str| basis.contains(name);
+
+    if has("u") {
+        return Ok(vec![gate.derive("u", gate.params.clone(), qubit)]);
+    }
+    if theta.abs() < EPSILON {
+        for name in ["u1", "rz"] {
+            if has(name) {
+                return Ok(vec![gate.derive(name, vec![phi + lambda], qubit)]);
+            }
+        }
+    }
+    if has("rz") && has("ry") {
+        return Ok(vec![
+            gate.derive("rz", vec![lambda], qubit.clone()),
+            gate.derive("ry", vec![theta], qubit.clone()),
+            gate.derive("rz", vec![phi], qubit),
+        ]);
+    }
+    if has("rz") && has("sx") {
+        return Ok(vec![
+            gate.derive("rz", vec![lambda], qubit.clone()),
+            gate.derive("sx", vec![], qubit.clone()),
+            gate.derive("rz", vec![theta + PI], qubit.clone()),
+            gate.derive("sx", vec![], qubit.clone()),
+            gate.derive("rz", vec![phi + PI], qubit),
+        ]);
+    }
+    if has("rz") && has("rx") {
+        return Ok(vec![
+            gate.derive("rz", vec![lambda - PI / 2.0], qubit.clone()),
+            gate.derive("rx", vec![theta], qubit.clone()),
+            gate.derive("rz", vec![phi + PI / 2.0], qubit),
+        ]);
+    }
+    Err(gate.name.clone())
+}
+
+/// The definition of the gate of `qelib1.inc` with other gates, down to `u3`
+/// and `cx`. The global phase is not kept.
+fn definition(gate: &Gate) -> Option<Vec<Gate>> {
+    let p = &gate.params;
+    let q = &gate.qubits;
+    let g = |name: &str, params: &[f64], qubits: &[usize]| {
+        gate.derive(name, params.to_vec(), qubits.to_vec())
+    };
+    Some(match gate.name.as_str() {
+        "id" => vec![],
+        "u" => vec![g("u3", p, q)],
+        "u2" => vec![g("u3", &[PI / 2.0, p[0], p[1]], q)],
+        "u1" => vec![g("u3", &[0.0, 0.0, p[0]], q)],
+        "rz" => vec![g("u1", p, q)],
+        "rx" => vec![g("u3", &[p[0], -PI / 2.0, PI / 2.0], q)],
+        "ry" => vec![g("u3", &[p[0], 0.0, 0.0], q)],
+        "x" => vec![g("u3", &[PI, 0.0, PI], q)],
+        "y" => vec![g("u3", &[PI, PI / 2.0, PI / 2.0], q)],
+        "z" => vec![g("u1", &[PI], q)],
+        "h" => vec![g("u2", &[0.0, PI], q)],
+        "s" => vec![g("u1", &[PI / 2.0], q)],
+        "sdg" => vec![g("u1", &[-PI / 2.0], q)],
+        "t" => vec![g("u1", &[PI / 4.0], q)],
+        "tdg" => vec![g("u1", &[-PI / 4.0], q)],
+        "sx" => vec![g("rx", &[PI / 2.0], q)],
+        "sxdg" => vec![g("rx", &[-PI / 2.0], q)],
+        "cz" => vec![g("h", &[], &q[1..]), g("cx", &[], q), g("h", &[], &q[1..])],
+        "cy" => vec![
+            g("sdg", &[], &q[1..]),
+            g("cx", &[], q),
+            g("s", &[], &q[1..]),
+        ],
+        "ch" => vec![
+            g("h", &[], &q[1..]),
+            g("sdg", &[], &q[1..]),
+            g("cx", &[], q),
+            g("h", &[], &q[1..]),
+            g("t", &[], &q[1..]),
+            g("cx", &[], q),
+            g("t", &[], &q[1..]),
+            g("h", &[], &q[1..]),
+            g("s", &[], &q[1..]),
+            g("x", &[], &q[1..]),
+            g("s", &[], &q[..1]),
+        ],
+        "swap" => vec![
+            g("cx", &[], &[q[0], q[1]]),
+            g("cx", &[], &[q[1], q[0]]),
+            g("cx", &[], &[q[0], q[1]]),
+        ],
+        "crx" => vec![
+            g("u1", &[PI / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("u3", &[-p[0] / 2.0, 0.0, 0.0], &q[1..]),
+            g("cx", &[], q),
+            g("u3", &[p[0] / 2.0, -PI / 2.0, 0.0], &q[1..]),
+        ],
+        "cry" => vec![
+            g("ry", &[p[0] / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("ry", &[-p[0] / 2.0], &q[1..]),
+            g("cx", &[], q),
+        ],
+        "crz" => vec![
+            g("u1", &[p[0] / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("u1", &[-p[0] / 2.0], &q[1..]),
+            g("cx", &[], q),
+        ],
+        "cu1" => vec![
+            g("u1", &[p[0] / 2.0], &q[..1]),
+            g("cx", &[], q),
+            g("u1", &[-p[0] / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("u1", &[p[0] / 2.0], &q[1..]),
+        ],
+        "cu3" => vec![
+            g("u1", &[(p[2] + p[1]) / 2.0], &q[..1]),
+            g("u1", &[(p[2] - p[1]) / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("u3", &[-p[0] / 2.0, 0.0, -(p[1] + p[2]) / 2.0], &q[1..]),
+            g("cx", &[], q),
+            g("u3", &[p[0] / 2.0, p[1], 0.0], &q[1..]),
+        ],
+        "rzz" => vec![g("cx", &[], q), g("u1", &[p[0]], &q[1..]), g("cx", &[], q)],
+        "ccx" => {
+            let (a, b, c) = (q[0], q[1], q[2]);
+            vec![
+                g("h", &[], &[c]),
+                g("cx", &[], &[b, c]),
+                g("tdg", &[], &[c]),
+                g("cx", &[], &[a, c]),
+                g("t", &[], &[c]),
+                g("cx", &[], &[b, c]),
+                g("tdg", &[], &[c]),
+                g("cx", &[], &[a, c]),
+                g("t", &[], &[b]),
+                g("t", &[], &[c]),
+                g("h", &[], &[c]),
+                g("cx", &[], &[a, b]),
+                g("t", &[], &[a]),
+                g("tdg", &[], &[b]),
+                g("cx", &[], &[a, b]),
+            ]
+        }
+        "cswap" => vec![
+            g("cx", &[], &[q[2], q[1]]),
+            g("ccx", &[], q),
+            g("cx", &[], &[q[2], q[1]]),
+        ],
+        _ => return None,
+    })
+}
+
+/// Combine the gate into the previous adjacent gate on the same qubits.
+/// Return whether they are combined, the previous gate is then replaced with
+/// the product, which may be the [identity](is_identity).
+fn combine(previous: &mut Gate, gate: &Gate) -> bool {
+    let same_qubits = previous.qubits == gate.qubits;
+    let symmetric = matches!(gate.name.as_str(), "cz" | "swap")
+        && previous.qubits.iter().collect::<BTreeSet<_>>()
+            == gate.qubits.iter().collect::<BTreeSet<_>>();
+
+    if previous.name == gate.name
+        && SELF_INVERSE_GATES.contains(&gate.name.as_str())
+        && (same_qubits || symmetric)
+    {
+        *previous = previous.derive("id", vec![], previous.qubits.clone());
+        return true;
+    }
+    if same_qubits
+        && INVERSE_GATES.iter().any(|&(a, b)| {
+            (previous.name == a && gate.name == b) || (previous.name == b && gate.name == a)
+        })
+    {
+        *previous = previous.derive("id", vec![], previous.qubits.clone());
+        return true;
+    }
+    if !same_qubits || previous.name != gate.name {
+        return false;
+    }
+    if let Some((_, period)) = ROTATION_GATES.iter().find(|(name, _)| *name == gate.name) {
+        let angle = (previous.params[0] + gate.params[0]).rem_euclid(*period);
+        previous.params[0] = if angle > period / 2.0 {
+            angle - period
+        } else {
+            angle
+        };
+        return true;
+    }
+    if gate.name == "u3" {
+        let matrix = u3_matrix(&gate.params) * u3_matrix(&previous.params);
+        previous.params = u3_params(&matrix);
+        return true;
+    }
+    false
+}
+
+/// Whether the gate does nothing up to the global phase.
+fn is_identity(gate: &Gate) -> bool {
+    let angle_is_zero = |angle: f64| {
+        let angle = angle.rem_euclid(2.0 * PI);
+        angle < EPSILON || 2.0 * PI - angle < EPSILON
+    };
+    match gate.name.as_str() {
+        "id" => true,
+        "u3" => gate.params[0].abs() < EPSILON && angle_is_zero(gate.params[1] + gate.params[2]),
+        name if ROTATION_GATES.iter().any(|(rotation, _)| *rotation == name) => {
+            gate.params[0].abs() < EPSILON
+        }
+        _ => false,
+    }
+}
+
+/// A complex number.
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn from_polar(r: f64, angle: f64) -> Complex {
+        Complex {
+            re: r * angle.cos(),
+            im: r * angle.sin(),
+        }
+    }
+
+    fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Complex {
+        Complex {
+            re: -self.re,
+            im: -self.im,
+        }
+    }
+}
+
+/// A 2x2 complex matrix of a single qubit gate.
+#[derive(Clone, Copy, Debug)]
+struct Matrix([[Complex; 2]; 2]);
+
+impl Mul for Matrix {
+    type Output = Matrix;
+    fn mul(self, other: Matrix) -> Matrix {
+        let (a, b) = (self.0, other.0);
+        let entry = |i: usize, j: usize| a[i][0] * b[0][j] + a[i][1] * b[1][j];
+        Matrix([[entry(0, 0), entry(0, 1)], [entry(1, 0), entry(1, 1)]])
+    }
+}
+
+/// The matrix of `u3(theta, phi, lambda)`.
+fn u3_matrix(params: &[f64]) -> Matrix {
+    let (theta, phi, lambda) = (params[0], params[1], params[2]);
+    let (cos, sin) = ((theta / 2.0).cos(), (theta / 2.0).sin());
+    Matrix([
+        [
+            Complex::from_polar(cos, 0.0),
+            -Complex::from_polar(sin, lambda),
+        ],
+        [
+            Complex::from_polar(sin, phi),
+            Complex::from_polar(cos, phi + lambda),
+        ],
+    ])
+}
+
+/// The parameters of the `u3` gate of the matrix, up to the global phase.
+fn u3_params(matrix: &Matrix) -> Vec<f64> {
+    let m = matrix.0;
+    let (cos, sin) = (m[0][0].abs(), m[1][0].abs());
+    let theta = 2.0 * sin.atan2(cos);
+    if sin < EPSILON {
+        return vec![0.0, 0.0, m[1][1].arg() - m[0][0].arg()];
+    }
+    if cos < EPSILON {
+        let phase = (-m[0][1]).arg();
+        return vec![theta, m[1][0].arg() - phase, 0.0];
+    }
+    let phase = m[0][0].arg();
+    vec![theta, m[1][0].arg() - phase, (-m[0][1]).arg() - phase]
+}
+
+/// ## Circuit Depth
+/// The depth of the optimized circuit, `None` if the circuit can not be
+/// parsed.
+pub fn circuit_depth(source: &str) -> Option<usize> {
+    if is_qasm3(source) {
+        return None;
+    }
+    let mut circuit = Circuit::parse(source).ok()?;
+    circuit.optimize();
+    Some(circuit.depth())
+}
+
+/// ## Transpile
+/// Transpile the circuit for the agent with the given gate set, an agent
+/// without gate set supports all the gates, so the circuit is only
+/// optimized. A circuit that can not be parsed is returned as is. If a gate
+/// can not be decomposed into the gate set, return an invalid request error.
+pub fn transpile(source: &str, gates: Option<&[String]>) -> Result<String> {
+    if is_qasm3(source) {
+        return Ok(source.to_owned());
+    }
+    let mut circuit = match Circuit::parse(source) {
+        Ok(circuit) => circuit,
+        Err(reason) => {
+            info!(
+                "Circuit is sent as is, it can not be transpiled: {}",
+                reason
+            );
+            return Ok(source.to_owned());
+        }
+    };
+
+    if let Some(gates) = gates {
+        let basis: BTreeSet<String> = gates.iter().map(|gate| gate.to_lowercase()).collect();
+        circuit.decompose(&basis).map_err(|gate| {
+            Error::InvalidRequest(format!(
+                "gate {} can not be decomposed into the gates {:?} of the agent",
+                gate, gates
+            ))
+        })?;
+    }
+    circuit.optimize();
+    Ok(circuit.to_qasm())
+}
+
+/// ## Can Transpile
+/// Whether the circuit can be [transpiled](transpile) for the agent with the
+/// given gate set, that is every gate can be decomposed into it. A circuit
+/// that can not be parsed is sent as is, so it can run on any agent.
+pub fn can_transpile(source: &str, gates: Option<&[String]>) -> bool {
+    let Some(gates) = gates else {
+        return true;
+    };
+    if is_qasm3(source) {
+        return true;
+    }
+    let Ok(mut circuit) = Circuit::parse(source) else {
+        return true;
+    };
+    let basis: BTreeSet<String> = gates.iter().map(|gate| gate.to_lowercase()).collect();
+    circuit.decompose(&basis).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_GATES: &str = r#"
+        OPENQASM 2.0;
+        include "qelib1.inc";
+        gate majority a, b, c { cx c, b; cx c, a; ccx a, b, c; }
+        qreg q[3];
+        h q[0]; x q[1]; y q[2]; z q[0]; s q[1]; sdg q[2]; t q[0]; tdg q[1];
+        sx q[2]; sxdg q[0]; id q[1];
+        rx(0.3) q[0]; ry(-1.2) q[1]; rz(pi/5) q[2];
+        u1(0.7) q[0]; u2(0.1, -0.4) q[1]; u3(1.1, 0.2, -2.3) q[2]; u(0.5, 1.5, 2.5) q[0];
+        U(0.9, -0.8, 0.6) q[1]; p(1.3) q[2];
+        cx q[0], q[1]; CX q[2], q[0]; cy q[1], q[2]; cz q[2], q[1]; ch q[0], q[2];
+        swap q[0], q[1];
+        crx(0.4) q[1], q[0]; cry(1.7) q[2], q[1]; crz(-0.9) q[0], q[2];
+        cu1(2.1) q[1], q[2]; cu3(0.6, -1.1, 0.8) q[2], q[0]; rzz(1.4) q[0], q[1];
+        ccx q[0], q[1], q[2]; cswap q[2], q[0], q[1];
+        majority q[0], q[1], q[2];
+    "#;
+
+    fn c(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    /// The matrix of the gate, written independently of the decompositions
+    /// under test. The first qubit of the gate is the most significant bit.
+    fn gate_matrix(name: &str, p: &[f64]) -> Vec<Vec<Complex>> {
+        let single = |m: [[Complex; 2]; 2]| m.iter().map(|row| row.to_vec()).collect();
+        let r = std::f64::consts::FRAC_1_SQRT_2;
+        let (one, zero, i) = (c(1.0, 0.0), c(0.0, 0.0), c(0.0, 1.0));
+        let rotation = |angle: f64| ((angle / 2.0).cos(), (angle / 2.0).sin());
+        let controlled = |target: Vec<Vec<Complex>>| {
+            let size = target.len();
+            let mut matrix = vec![vec![zero; 2 * size]; 2 * size];
+            for k in 0..size {
+                matrix[k][k] = one;
+                for l in 0..size {
+                    matrix[size + k][size + l] = target[k][l];
+                }
+            }
+            matrix
+        };
+        match name {
+            "id" => single([[one, zero], [zero, one]]),
+            "h" => single([[c(r, 0.0), c(r, 0.0)], [c(r, 0.0), c(-r, 0.0)]]),
+            "x" => single([[zero, one], [one, zero]]),
+            "y" => single([[zero, -i], [i, zero]]),
+            "z" => gate_matrix("u1", &[PI]),
+            "s" => gate_matrix("u1", &[PI / 2.0]),
+            "sdg" => gate_matrix("u1", &[-PI / 2.0]),
+            "t" => gate_matrix("u1", &[PI / 4.0]),
+            "tdg" => gate_matrix("u1", &[-PI / 4.0]),
+            "sx" => single([[c(0.5, 0.5), c(0.5, -0.5)], [c(0.5, -0.5), c(0.5, 0.5)]]),
+            "sxdg" => single([[c(0.5, -0.5), c(0.5, 0.5)], [c(0.5, 0.5), c(0.5, -0.5)]]),
+            "rx" => {
+                let (cos, sin) = rotation(p[0]);
+                single([[c(cos, 0.0), c(0.0, -sin)], [c(0.0, -sin), c(cos, 0.0)]])
+            }
+            "ry" => {
+                let (cos, sin) = rotation(p[0]);
+                single([[c(cos, 0.0), c(-sin, 0.0)], [c(sin, 0.0), c(cos, 0.0)]])
+            }
+            "rz" => single([
+                [Complex::from_polar(1.0, -p[0] / 2.0), zero],
+                [zero, Complex::from_polar(1.0, p[0] / 2.0)],
+            ]),
+            "u1" => single([[one, zero], [zero, Complex::from_polar(1.0, p[0])]]),
+            "u2" => gate_matrix("u3", &[PI / 2.0, p[0], p[1]]),
+            "u3" | "u" => single(u3_matrix(p).0),
+            "cx" => controlled(gate_matrix("x", &[])),
+            "cy" => controlled(gate_matrix("y", &[])),
+            "cz" => controlled(gate_matrix("z", &[])),
+            "ch" => controlled(gate_matrix("h", &[])),
+            "crx" => controlled(gate_matrix("rx", p)),
+            "cry" => controlled(gate_matrix("ry", p)),
+            "crz" => controlled(gate_matrix("rz", p)),
+            "cu1" => controlled(gate_matrix("u1", p)),
+            "cu3" => controlled(gate_matrix("u3", p)),
+            "ccx" => controlled(gate_matrix("cx", &[])),
+            "cswap" => controlled(gate_matrix("swap", &[])),
+            "swap" => {
+                let mut matrix = vec![vec![zero; 4]; 4];
+                for (k, l) in [(0, 0), (1, 2), (2, 1), (3, 3)] {
+                    matrix[k][l] = one;
+                }
+                matrix
+            }
+            "rzz" => {
+                let mut matrix = vec![vec![zero; 4]; 4];
+                for (k, sign) in [(0, -1.0), (1, 1.0), (2, 1.0), (3, -1.0)] {
+                    matrix[k][k] = Complex::from_polar(1.0, sign * p[0] / 2.0);
+                }
+                matrix
+            }
+            name => panic!("no matrix of gate {}", name),
+        }
+    }
+
+    /// The unitary of the circuit, the column `k` is the state of the basis
+    /// state `k`, the qubit 0 is the least significant bit.
+    fn unitary(circuit: &Circuit) -> Vec<Vec<Complex>> {
+        let size = 1 << circuit.qubit_count();
+        (0..size)
+            .map(|k| {
+                let mut state = vec![c(0.0, 0.0); size];
+                state[k] = c(1.0, 0.0);
+                for operation in &circuit.operations {
+                    let Operation::Gate(gate) = operation else {
+                        panic!("unexpected operation {:?}", operation);
+                    };
+                    let matrix = gate_matrix(&gate.name, &gate.params);
+                    let bits = gate.qubits.len();
+                    let local = |index: usize| {
+                        (0..bits).fold(0, |local, b| local << 1 | (index >> gate.qubits[b] & 1))
+                    };
+                    let mut next = vec![c(0.0, 0.0); size];
+                    for (index, &amplitude) in state.iter().enumerate() {
+                        let column = local(index);
+                        for (row, entries) in matrix.iter().enumerate() {
+                            let target = (0..bits).fold(index, |target, b| {
+                                let qubit = gate.qubits[b];
+                                let bit = row >> (bits - 1 - b) & 1;
+                                target & !(1 << qubit) | bit << qubit
+                            });
+                            next[target] = next[target] + entries[column] * amplitude;
+                        }
+                    }
+                    state = next;
+                }
+                state
+            })
+            .collect()
+    }
+
+    /// Assert that the unitaries are equal up to the global phase.
+    fn assert_equivalent(expected: &[Vec<Complex>], actual: &[Vec<Complex>]) {
+        let (k, l) = (0..expected.len())
+            .flat_map(|k| (0..expected.len()).map(move |l| (k, l)))
+            .max_by(|&(a, b), &(x, y)| expected[a][b].abs().total_cmp(&expected[x][y].abs()))
+            .unwrap();
+        let phase = actual[k][l].arg() - expected[k][l].arg();
+        for (expected, actual) in expected.iter().flatten().zip(actual.iter().flatten()) {
+            let difference = *actual + -(*expected * Complex::from_polar(1.0, phase));
+            assert!(
+                difference.abs() < 1e-9,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    fn gate_names(circuit: &Circuit) -> BTreeSet<String> {
+        circuit
+            .operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Gate(gate) => Some(gate.name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decompose_preserves_unitary() {
+        let expected = unitary(&Circuit::parse(ALL_GATES).unwrap());
+        for basis in [
+            &["u3", "cx"][..],
+            &["rz", "sx", "cx"],
+            &["rz", "ry", "cz"],
+            &["rz", "rx", "cx"],
+            &["u", "cz"],
+            &["h", "s", "t", "u3", "cx"],
+        ] {
+            let basis: BTreeSet<String> = basis.iter().map(|gate| gate.to_string()).collect();
+            let mut circuit = Circuit::parse(ALL_GATES).unwrap();
+            circuit.decompose(&basis).unwrap();
+            assert!(gate_names(&circuit).is_subset(&basis), "basis {:?}", basis);
+            assert_equivalent(&expected, &unitary(&circuit));
+        }
+    }
+
+    #[test]
+    fn decompose_returns_the_missing_gate() {
+        let mut circuit =
+            Circuit::parse("OPENQASM 2.0; qreg q[2]; h q[0]; cx q[0], q[1];").unwrap();
+        let basis = BTreeSet::from(["u3".to_owned()]);
+        assert_eq!(circuit.decompose(&basis), Err("cx".to_owned()));
+    }
+
+    #[test]
+    fn optimize_preserves_unitary_and_is_idempotent() {
+        let source = r#"
+            OPENQASM 2.0;
+            include "qelib1.inc";
+            qreg q[3];
+            h q[0]; h q[0]; s q[1]; sdg q[1]; x q[2]; id q[2]; t q[0];
+            rz(0.5) q[1]; rz(-1.5) q[1]; rx(pi) q[2]; rx(pi) q[2];
+            u3(0.3, 0.2, 0.1) q[0]; u3(1.2, -0.7, 0.4) q[0];
+            cz q[0], q[1]; cz q[1], q[0]; swap q[1], q[2]; swap q[2], q[1];
+            cx q[0], q[2]; h q[2]; cx q[0], q[2]; crz(1.0) q[1], q[2]; crz(0.5) q[1], q[2];
+            sx q[1]; sxdg q[1]; rzz(0.2) q[0], q[1];
+        "#;
+        let expected = unitary(&Circuit::parse(source).unwrap());
+        let mut circuit = Circuit::parse(source).unwrap();
+        let count = circuit.operations.len();
+        circuit.optimize();
+        assert!(circuit.operations.len() < count);
+        assert_equivalent(&expected, &unitary(&circuit));
+
+        let optimized = circuit.operations.clone();
+        circuit.optimize();
+        assert_eq!(circuit.operations, optimized);
+
+        let mut decomposed = Circuit::parse(ALL_GATES).unwrap();
+        decomposed
+            .decompose(&BTreeSet::from(["u3".to_owned(), "cx".to_owned()]))
+            .unwrap();
+        decomposed.optimize();
+        let optimized = decomposed.operations.clone();
+        assert_equivalent(
+            &unitary(&Circuit::parse(ALL_GATES).unwrap()),
+            &unitary(&decomposed),
+        );
+        decomposed.optimize();
+        assert_eq!(decomposed.operations, optimized);
+    }
+
+    #[test]
+    fn optimize_cancels_inverse_gates() {
+        let mut circuit = Circuit::parse(
+            "OPENQASM 2.0; qreg q[2]; h q[0]; s q[1]; cx q[0], q[1]; cx q[0], q[1]; sdg q[1]; h q[0];",
+        )
+        .unwrap();
+        circuit.optimize();
+        assert!(circuit.operations.is_empty());
+    }
+
+    #[test]
+    fn optimize_keeps_separated_and_conditioned_gates() {
+        let mut circuit = Circuit::parse(
+            "OPENQASM 2.0; qreg q[2]; creg c[1]; h q[0]; cx q[0], q[1]; h q[0]; \
+             if(c==1) x q[1]; if(c==1) x q[1]; measure q[0] -> c[0]; x q[0]; measure q[0] -> c[0];",
+        )
+        .unwrap();
+        let operations = circuit.operations.clone();
+        circuit.optimize();
+        assert_eq!(circuit.operations, operations);
+    }
+
+    #[test]
+    fn u3_params_round_trip() {
+        for params in [
+            [0.0, 0.0, 0.0],
+            [0.0, 0.4, 1.1],
+            [PI, 0.3, -0.2],
+            [1.2, -0.7, 2.9],
+            [-0.5, 3.0, -3.0],
+        ] {
+            let matrix = u3_matrix(&params);
+            let round_trip = u3_matrix(&u3_params(&matrix));
+            let entries = |m: Matrix| vec![m.0[0].to_vec(), m.0[1].to_vec()];
+            assert_equivalent(&entries(matrix), &entries(round_trip));
+        }
+    }
+
+    #[test]
+    fn depth_counts_layers() {
+        assert_eq!(
+            circuit_depth(
+                "OPENQASM 2.0; qreg q[3]; h q[0]; h q[1]; cx q[0], q[1]; barrier q; x q[2]; h q[0]; h q[0];"
+            ),
+            Some(3)
+        );
+        assert_eq!(circuit_depth("OPENQASM 3.0; qubit[1] q; h q[0];"), None);
+    }
+
+    #[test]
+    fn parse_rejects_recursive_and_oversized_circuits() {
+        let recursive = "OPENQASM 2.0; gate g a { g a; } qreg q[1]; g q[0];";
+        assert!(Circuit::parse(recursive).is_err());
+        let forward = "OPENQASM 2.0; gate f a { g a; } gate g a { x a; } qreg q[1]; f q[0];";
+        assert!(Circuit::parse(forward).is_err());
+        let twice = "OPENQASM 2.0; gate g a { x a; } gate g a { y a; } qreg q[1]; g q[0];";
+        assert!(Circuit::parse(twice).is_err());
+        assert!(Circuit::parse("OPENQASM 2.0; qreg q[3000000]; h q; h q;").is_err());
+        assert!(Circuit::parse("OPENQASM 2.0; qreg q[1000]; qreg r[1000];").is_err());
+
+        let mut nested = "OPENQASM 2.0; gate g0 a { x a; }".to_owned();
+        for level in 1..40 {
+            nested += &format!(" gate g{level} a {{ g{0} a; g{0} a; }}", level - 1);
+        }
+        nested += " qreg q[1]; g39 q[0];";
+        assert!(Circuit::parse(&nested).is_err());
+        assert_eq!(circuit_depth(&nested), None);
+        let repeated = format!(
+            "OPENQASM 2.0; qreg q[1024]; {}",
+            "x q;".repeat(MAX_OPERATIONS / 1024 + 1)
+        );
+        assert!(Circuit::parse(&repeated).is_err());
+
+        let shadowing = "OPENQASM 2.0; gate h a { h a; x a; } qreg q[1]; h q[0];";
+        assert_eq!(Circuit::parse(shadowing).unwrap().operations.len(), 2);
+    }
+
+    #[test]
+    fn transpile_rejects_undecomposable_gates() {
+        let source = "OPENQASM 2.0; qreg q[2]; cx q[0], q[1];";
+        let gates = ["h".to_owned()];
+        assert!(matches!(
+            transpile(source, Some(&gates)),
+            Err(Error::InvalidRequest(_))
+        ));
+        let unparsed = "OPENQASM 2.0; qreg q[1]; opaque g a; g q[0];";
+        assert_eq!(transpile(unparsed, Some(&gates)).unwrap(), unparsed);
+    }
+
+    #[test]
+    fn can_transpile_matches_transpile() {
+        let source = "OPENQASM 2.0; qreg q[2]; h q[0]; cx q[0], q[1];";
+        let unparsed = "OPENQASM 2.0; qreg q[1]; opaque g a; g q[0];";
+        let bases: [&[&str]; 3] = [&["h"], &["u3", "cx"], &["rz", "sx", "cz"]];
+        for basis in bases {
+            let gates: Vec<String> = basis.iter().map(|gate| gate.to_string()).collect();
+            assert_eq!(
+                can_transpile(source, Some(&gates)),
+                transpile(source, Some(&gates)).is_ok()
+            );
+            assert!(can_transpile(unparsed, Some(&gates)));
+        }
+        assert!(!can_transpile(source, Some(&["h".to_owned()])));
+        assert!(can_transpile(source, None));
+    }
+}